@@ -3,6 +3,7 @@ use std::net::TcpStream;
 use clap::{arg, Command};
 use kvs::DbCommand;
 use log::{info, error};
+use serde::Deserialize;
 
 fn main() -> () {
 
@@ -54,8 +55,8 @@ fn main() -> () {
             let set_command = DbCommand::Set(key.to_string(), value.clone().to_string());
             let set_command = serde_json::to_string(&set_command).unwrap();
 
-            tcp_stream.write(set_command.as_bytes()).unwrap();
-            let result_command : DbCommand = serde_json::from_reader(tcp_stream).unwrap();
+            tcp_stream.write_all(set_command.as_bytes()).unwrap();
+            let result_command = read_result(&tcp_stream);
             info!("SET Result = {:?}", result_command);
         },
         Some(("get", args)) => {
@@ -68,8 +69,8 @@ fn main() -> () {
             let get_command = DbCommand::Get(key.to_string());
             let get_command = serde_json::to_string(&get_command).unwrap();
 
-            tcp_stream.write(get_command.as_bytes()).unwrap();
-            let result_command : DbCommand = serde_json::from_reader(tcp_stream).unwrap();
+            tcp_stream.write_all(get_command.as_bytes()).unwrap();
+            let result_command = read_result(&tcp_stream);
             info!("GET Result = {:?}", result_command);
             match result_command {
                 DbCommand::GetResult(x) => {
@@ -93,8 +94,8 @@ fn main() -> () {
             let rm_command = DbCommand::Rm(key.to_string());
             let rm_command = serde_json::to_string(&rm_command).unwrap();
 
-            tcp_stream.write(rm_command.as_bytes()).unwrap();
-            let result_command : DbCommand = serde_json::from_reader(tcp_stream).unwrap();
+            tcp_stream.write_all(rm_command.as_bytes()).unwrap();
+            let result_command = read_result(&tcp_stream);
             match result_command {
                 DbCommand::Error(x) => {
                     error!("{}", x);
@@ -117,3 +118,10 @@ fn connect_to_tcp(address : &str) -> TcpStream {
     info!("Connected to IP:PORT={}", address);
     tcp_stream
 }
+
+// The server keeps the connection open for more commands, so only the result is
+// read instead of the whole stream.
+fn read_result(tcp_stream : &TcpStream) -> DbCommand {
+    let mut de = serde_json::Deserializer::from_reader(tcp_stream);
+    DbCommand::deserialize(&mut de).unwrap()
+}
@@ -1,8 +1,10 @@
-use std::{net::TcpListener, io::{Write, BufReader, BufRead}, env::current_dir, path::{Path}, fs::OpenOptions};
+use std::{net::{TcpListener, TcpStream}, io::{Write, BufReader, BufRead, BufWriter}, env::current_dir, path::{Path, PathBuf}, fs::OpenOptions};
+use std::sync::{Arc, PoisonError, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
 use clap::{Command, arg};
 use kvs::{Result, DbCommand, KvStore, KvsEngine, KvsError};
 use log::{info, error};
-use serde::Deserialize;
 
 fn main() -> Result<()> {
 
@@ -21,16 +23,33 @@ fn main() -> Result<()> {
         .default_value("kvs"),
         arg!(--addr <ADDR> "Provide IP:PORT")
         .required(false)
-        .default_value("127.0.0.1:4000")
+        .default_value("127.0.0.1:4000"),
+        arg!(--"data-dir" <DIR> "Provide Data Directory, the current directory by default")
+        .required(false),
+        arg!(--"max-connections" <COUNT> "Provide the number of clients served at once")
+        .required(false)
+        .value_parser(clap::value_parser!(usize))
+        .default_value("64")
     ])
     .version(version)
     .get_matches();
 
     let address = matches.get_one::<String>("addr").unwrap();
     let engine = matches.get_one::<String>("engine").unwrap();
-    let cur_dir = current_dir().unwrap();
+    let max_connections = *matches.get_one::<usize>("max-connections").unwrap();
+    let cur_dir = match matches.get_one::<String>("data-dir") {
+        Some(dir) => PathBuf::from(dir),
+        None => current_dir().unwrap()
+    };
+    std::fs::create_dir_all(&cur_dir)?;
     info!("Opening Database at Location={}", &cur_dir.display());
-    let mut kv_store : Box<dyn KvsEngine> = Box::new(KvStore::open(&cur_dir)?);
+    let kv_store : Box<dyn KvsEngine> = match KvStore::open(&cur_dir) {
+        Ok(kv_store) => Box::new(kv_store),
+        Err(e) => {
+            error!("Unable to open Database, error={}", e);
+            std::process::exit(1);
+        }
+    };
     
     let engine_in_file = get_existing_engine(&cur_dir.as_path());
     if let Some(x) = engine_in_file {
@@ -49,70 +68,140 @@ fn main() -> Result<()> {
     info!("Started Listening on IP:PORT={}", address);
     info!("KvsServer Engine={}", engine);
 
-    let tcp_listener = TcpListener::bind(address).unwrap();
+    // gets only need a read lock, so they run in parallel
+    let kv_store = Arc::new(RwLock::new(kv_store));
+    let connections = Arc::new(AtomicUsize::new(0));
+    let tcp_listener = TcpListener::bind(address)?;
     for stream in tcp_listener.incoming() {
-        let mut tcp_stream = stream.unwrap();
-        info!("Connected to Client...");
-        let mut de = serde_json::Deserializer::from_reader(&tcp_stream);
-        let command = DbCommand::deserialize(&mut de)?;
+        let mut tcp_stream = match stream {
+            Ok(tcp_stream) => tcp_stream,
+            Err(e) => {
+                error!("Unable to accept connection, error={}", e);
+                continue;
+            }
+        };
+        // each client has its own thread, so their number is bounded
+        let connection = Connection::open(&connections);
+        if connection.count > max_connections {
+            error!("Refusing connection, {} clients are connected", max_connections);
+            let error = DbCommand::Error("Too many connections".to_owned());
+            if let Err(e) = serde_json::to_writer(&mut tcp_stream, &error) {
+                error!("Unable to refuse connection, error={}", e);
+            }
+            continue;
+        }
+        let kv_store = Arc::clone(&kv_store);
+        thread::spawn(move || {
+            if let Err(e) = serve_client(&kv_store, tcp_stream) {
+                error!("Connection closed, error={}", e);
+            }
+            drop(connection);
+        });
+    }
+
+    Ok(())
+}
+
+// Counts a connection until it is dropped.
+struct Connection {
+    connections : Arc<AtomicUsize>,
+    // the number of connections, this one included
+    count : usize
+}
+
+impl Connection {
+    fn open(connections : &Arc<AtomicUsize>) -> Connection {
+        let count = connections.fetch_add(1, Ordering::SeqCst) + 1;
+        Connection { connections : Arc::clone(connections), count }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Executes the commands of a client until it closes the connection.
+fn serve_client(kv_store : &RwLock<Box<dyn KvsEngine>>, tcp_stream : TcpStream) -> Result<()> {
+    info!("Connected to Client={}", tcp_stream.peer_addr()?);
+    let reader = BufReader::new(tcp_stream.try_clone()?);
+    let mut writer = BufWriter::new(tcp_stream);
+    let commands = serde_json::Deserializer::from_reader(reader).into_iter::<DbCommand>();
+    for command in commands {
         match command {
-            DbCommand::Set(a, b) => {
-                info!("Executing Command=SET Key={}, Value={}", a, b);
-                let result = kv_store.set(a.to_string(), b.to_string());
-                let data_to_write;
-                if result.is_err() {
-                    let error = result.err().unwrap().to_string();
-                    let error = DbCommand::Error(error);
-                    data_to_write = serde_json::to_string(&error).unwrap();
-                    error!("Error command=SET, error={:?}", error)
-                } else {
-                    let command = DbCommand::SetResult(a.to_string());
-                    data_to_write = serde_json::to_string(&command).unwrap(); 
-                }
-                tcp_stream.write(data_to_write.as_bytes()).unwrap();
-                info!("Command=SET Key={}, Value={} executed successfully.", a.to_string(), b);
-            },
-            DbCommand::Get(a) => {
-                info!("Executing Command=GET Key={}", a);
-                let result = kv_store.get(a.to_string());
-                let command : DbCommand;
-                    if let Some(x) = result.unwrap() {
-                        command = DbCommand::GetResult(x);
-                    } else {
-                        command = DbCommand::Error(KvsError::KeyNotFound.to_string());
-                    }
-                let data_to_write = serde_json::to_string(&command).unwrap(); 
-                tcp_stream.write(data_to_write.as_bytes()).unwrap();
-                info!("Command=GET Key={} executed successfully.", a);
+            Ok(command) => {
+                let result = execute(kv_store, command);
+                serde_json::to_writer(&mut writer, &result)?;
+                writer.flush()?;
             },
-            DbCommand::Rm(a) => {
-                info!("Executing Command=RM Key={}", a);
-                let result = kv_store.remove(a.to_string());
-                let data_to_write;
-                if result.is_err() {
-                    let error = result.err().unwrap().to_string();
-                    let error = DbCommand::Error(error);
-                    data_to_write = serde_json::to_string(&error).unwrap();
-                    error!("Error Command=RM, error={:?}", error)
-                } else {
-                    let command = DbCommand::RmResult();
-                    data_to_write = serde_json::to_string(&command).unwrap(); 
-                }
-                tcp_stream.write(data_to_write.as_bytes()).unwrap();
-                info!("Command=RM Key={} executed successfully.", a);
-            }, 
-            _ => {
-                let command = DbCommand::Error("Unknown Command".to_owned());
-                let data_to_write = serde_json::to_string(&command).unwrap();
-                tcp_stream.write(data_to_write.as_bytes()).unwrap();
-                info!("Command=UNKNOWN, Returning Error");
+            Err(e) => {
+                // the next command can't be found after invalid JSON, so the connection is closed
+                let error = DbCommand::Error(format!("Invalid command: {}", e));
+                serde_json::to_writer(&mut writer, &error)?;
+                writer.flush()?;
+                return Err(KvsError::from(e));
             }
         }
     }
-
+    info!("Client disconnected");
     Ok(())
 }
 
+fn execute(kv_store : &RwLock<Box<dyn KvsEngine>>, command : DbCommand) -> DbCommand {
+    // the store returns its failures as errors, so a panic of another connection
+    // leaves it usable
+    match command {
+        DbCommand::Set(a, b) => {
+            info!("Executing Command=SET Key={}, Value={}", a, b);
+            let mut kv_store = kv_store.write().unwrap_or_else(PoisonError::into_inner);
+            match kv_store.set(a.to_string(), b) {
+                Ok(()) => {
+                    info!("Command=SET Key={} executed successfully.", a);
+                    DbCommand::SetResult(a)
+                },
+                Err(e) => {
+                    error!("Error command=SET, error={}", e);
+                    DbCommand::Error(e.to_string())
+                }
+            }
+        },
+        DbCommand::Get(a) => {
+            info!("Executing Command=GET Key={}", a);
+            let kv_store = kv_store.read().unwrap_or_else(PoisonError::into_inner);
+            match kv_store.get(a.to_string()) {
+                Ok(Some(x)) => {
+                    info!("Command=GET Key={} executed successfully.", a);
+                    DbCommand::GetResult(x)
+                },
+                Ok(None) => DbCommand::Error(KvsError::KeyNotFound.to_string()),
+                Err(e) => {
+                    error!("Error command=GET, error={}", e);
+                    DbCommand::Error(e.to_string())
+                }
+            }
+        },
+        DbCommand::Rm(a) => {
+            info!("Executing Command=RM Key={}", a);
+            let mut kv_store = kv_store.write().unwrap_or_else(PoisonError::into_inner);
+            match kv_store.remove(a.to_string()) {
+                Ok(()) => {
+                    info!("Command=RM Key={} executed successfully.", a);
+                    DbCommand::RmResult()
+                },
+                Err(e) => {
+                    error!("Error Command=RM, error={}", e);
+                    DbCommand::Error(e.to_string())
+                }
+            }
+        },
+        _ => {
+            info!("Command=UNKNOWN, Returning Error");
+            DbCommand::Error("Unknown Command".to_owned())
+        }
+    }
+}
+
 fn get_existing_engine(cur_dir : &Path) ->  Option<String> {
     let config_file = cur_dir.join(".config");
 
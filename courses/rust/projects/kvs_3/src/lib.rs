@@ -2,7 +2,7 @@ extern crate core;
 
 use rand::Rng;
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::time::{SystemTime, UNIX_EPOCH};
 use failure::Fail;
 use serde::{Serialize, Deserialize};
@@ -11,17 +11,20 @@ use std::io;
 use std::io::prelude::*;
 use std::io::{SeekFrom, Seek};
 use std::io::{BufReader, BufWriter};
+use fs2::FileExt;
 
 use crate::KvsCommand::{Rm, Set};
 use crate::KvsError::KeyNotFound;
 
 pub type Result<T> = std::result::Result<T, KvsError>;
 
-pub trait KvsEngine {
+// Shared by the connections of the server, which run in their own threads. Reads
+// only borrow the engine, so they can run at the same time.
+pub trait KvsEngine: Send + Sync {
 
     fn set(&mut self, key: String, value: String) -> Result<()>;
 
-    fn get(&mut self, key: String) -> Result<Option<String>>;
+    fn get(&self, key: String) -> Result<Option<String>>;
 
     fn remove(&mut self, key: String) -> Result<()>;
 }
@@ -34,7 +37,11 @@ pub enum KvsError {
     #[fail(display = "Error Serializing or Deserializing {}", _0)]
     SerdeError(serde_json::Error),
     #[fail(display = "Key not found")]
-    KeyNotFound
+    KeyNotFound,
+    #[fail(display = "The data directory {} is already in use", _0)]
+    DirectoryLocked(String),
+    #[fail(display = "No Set command found in file {} at offset {}", _0, _1)]
+    NoSetCommand(u32, u64)
 }
 
 impl From<io::Error> for KvsError {
@@ -80,25 +87,28 @@ impl KvsCommand {
 pub struct KvStore {
     dir_path : PathBuf,
     files : HashMap<u32, KvsFile>,
-    map : HashMap<String, KvsEntry>,
+    // every version of each key kept in the log, oldest first
+    map : HashMap<String, Vec<KvsEntry>>,
     cur_offset : u64,
-    cur_index : u32
+    cur_index : u32,
+    last_timestamp : u128,
+    // how long in milliseconds the overwritten versions are kept for `get_at`
+    retention : u128,
+    // locked until the store is dropped, so that one store at a time uses the directory
+    _lock_file : File
 }
 
 struct KvsEntry {
     file_index : u32,
     timestamp : u128,
-    offset : u64
+    offset : u64,
+    removed : bool
 }
 
 impl KvsEntry {
 
-    fn new(file_index : u32, timestamp : u128, offset : u64) -> Self {
-        KvsEntry {file_index, timestamp, offset}
-    }
-
-    fn get_timestamp(&self) -> u128 {
-        self.timestamp
+    fn new(file_index : u32, timestamp : u128, offset : u64, removed : bool) -> Self {
+        KvsEntry {file_index, timestamp, offset, removed}
     }
 
 }
@@ -113,48 +123,24 @@ struct KvsFile {
 impl KvsEngine for KvStore {
 
     fn set(&mut self, key: String, value: String) -> Result<()> {
-        let timestamp = Self::get_timestamp();
+        let timestamp = self.next_timestamp();
         let command = KvsCommand::set(key.clone(), value.clone(), timestamp);
-        let json_str = serde_json::to_string(&command)?;
-        let cur_file = self.files.get_mut(&self.cur_index).unwrap();
-        let bytes_written = cur_file.buf_writer.write(json_str.as_bytes())?;
-        cur_file.buf_writer.flush()?;
-        self.map.insert(key,KvsEntry::new(cur_file.index, timestamp, self.cur_offset));
-        self.cur_offset += bytes_written as u64;
-        self.check_cur_file_over_size();
-        self.compaction_required();
-        Ok(())
+        self.append(key, command, timestamp, false)
     }
 
-    fn get(&mut self, key: String) -> Result<Option<String>> {
-        match self.map.get(&key) {
-            Some(entry) => {
-                    let kvs_file = self.files.get_mut(&entry.file_index).unwrap();
-                    kvs_file.buf_reader.seek(SeekFrom::Start(entry.offset.clone()))?;
-                    let kvs_command = serde_json::Deserializer::from_reader(&mut kvs_file.buf_reader)
-                        .into_iter::<KvsCommand>().next().unwrap()?;
-                    match kvs_command {
-                        Set(_, v, _) => Ok(Some(v)),
-                        _ => panic!("No Set command found")
-                    }
-            }
-            None => Ok(None),
-        }
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.get_at(key, u128::MAX)
     }
 
     fn remove(&mut self, key: String) -> Result<()> {
-        if self.map.contains_key(&key) {
-            let timestamp = Self::get_timestamp();
+        let exists = match self.map.get(&key).and_then(|versions| versions.last()) {
+            Some(entry) => !entry.removed,
+            None => false
+        };
+        if exists {
+            let timestamp = self.next_timestamp();
             let command = KvsCommand::rm(key.clone(), timestamp);
-            let json_str = serde_json::to_string(&command)?;
-            let cur_file = self.files.get_mut(&self.cur_index).unwrap();
-            let bytes_written = cur_file.buf_writer.write(json_str.as_bytes())?;
-            cur_file.buf_writer.flush()?;
-            self.cur_offset += bytes_written as u64;
-            self.map.remove(&key);
-            self.check_cur_file_over_size();
-            self.compaction_required();
-            Ok(())
+            self.append(key, command, timestamp, true)
         } else {
             Err(KeyNotFound)
         }
@@ -163,6 +149,72 @@ impl KvsEngine for KvStore {
 
 impl KvStore {
 
+    // Returns the value the key had at `timestamp`, that is the value of the last
+    // write at or before it. Versions older than the retention window may have
+    // been dropped by a compaction.
+    pub fn get_at(&self, key : String, timestamp : u128) -> Result<Option<String>> {
+        let entry = match self.map.get(&key) {
+            Some(versions) => versions.iter().rev().find(|entry| entry.timestamp <= timestamp),
+            None => None
+        };
+        match entry {
+            Some(entry) if !entry.removed => {
+                let (file_index, offset) = (entry.file_index, entry.offset);
+                Ok(Some(self.read_value(file_index, offset)?))
+            },
+            _ => Ok(None)
+        }
+    }
+
+    // The timestamp of the last write, to read the values as they were then with
+    // `get_at`.
+    pub fn last_timestamp(&self) -> u128 {
+        self.last_timestamp
+    }
+
+    // Keeps the versions overwritten or removed within the last `retention`
+    // milliseconds when compacting. Only the latest versions are kept by default.
+    pub fn set_retention(&mut self, retention : u128) {
+        self.retention = retention;
+    }
+
+    // Opens its own reader of the file, so that reads don't share a file position.
+    fn read_value(&self, file_index : u32, offset : u64) -> Result<String> {
+        let kvs_file = match self.files.get(&file_index) {
+            Some(kvs_file) => kvs_file,
+            None => return Err(KvsError::NoSetCommand(file_index, offset))
+        };
+        let mut buf_reader = BufReader::new(File::open(&kvs_file.file_path)?);
+        buf_reader.seek(SeekFrom::Start(offset))?;
+        let kvs_command = serde_json::Deserializer::from_reader(buf_reader)
+            .into_iter::<KvsCommand>().next();
+        match kvs_command {
+            Some(Ok(Set(_, v, _))) => Ok(v),
+            Some(Err(e)) => Err(KvsError::from(e)),
+            _ => Err(KvsError::NoSetCommand(file_index, offset))
+        }
+    }
+
+    fn append(&mut self, key : String, command : KvsCommand, timestamp : u128, removed : bool) -> Result<()> {
+        let json_str = serde_json::to_string(&command)?;
+        let cur_file = self.files.get_mut(&self.cur_index).unwrap();
+        let bytes_written = cur_file.buf_writer.write(json_str.as_bytes())?;
+        cur_file.buf_writer.flush()?;
+        let entry = KvsEntry::new(cur_file.index, timestamp, self.cur_offset, removed);
+        self.map.entry(key).or_default().push(entry);
+        self.cur_offset += bytes_written as u64;
+        self.check_cur_file_over_size();
+        // the command is written even if the compaction fails, the next write tries again
+        self.compaction_required()
+    }
+
+    // The wall-clock time in milliseconds, but always after the last write so that
+    // the versions of a key written within the same millisecond stay ordered.
+    fn next_timestamp(&mut self) -> u128 {
+        self.last_timestamp = Self::get_timestamp().max(self.last_timestamp + 1);
+        self.last_timestamp
+    }
+
     fn get_timestamp() -> u128 {
         SystemTime::now()
         .duration_since(UNIX_EPOCH)
@@ -182,6 +234,21 @@ impl KvStore {
         Self::create_kvs_file(&file_path, index)
     }
 
+    fn lock_dir(dir_path : &Path) -> Result<File> {
+        let lock_file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(dir_path.join("LOCK"))?;
+        match lock_file.try_lock_exclusive() {
+            Ok(()) => Ok(lock_file),
+            Err(e) if e.kind() == fs2::lock_contended_error().kind() => {
+                Err(KvsError::DirectoryLocked(dir_path.display().to_string()))
+            },
+            Err(e) => Err(KvsError::IO(e))
+        }
+    }
+
     fn find_all_file_in_dir(dir_path : &PathBuf) -> Vec<PathBuf> {
         let mut files_vec = Vec::new();
         for entry in std::fs::read_dir(dir_path).unwrap() {
@@ -238,9 +305,8 @@ impl KvStore {
         buf_writer
     }
 
-    fn read_from_file(kvs_file : &mut KvsFile) -> Result<(HashMap<String, KvsEntry>, HashMap<String, KvsEntry>)> {    
-        let mut set_map = HashMap::new();
-        let mut del_map = HashMap::new();
+    fn read_from_file(kvs_file : &mut KvsFile) -> Result<Vec<(String, KvsEntry)>> {
+        let mut entries = Vec::new();
 
         let mut itr = serde_json::Deserializer::from_reader(&mut kvs_file.buf_reader)
                 .into_iter::<KvsCommand>();
@@ -253,10 +319,10 @@ impl KvStore {
                     let kvs_command = kvs_command_result.unwrap();
                     match kvs_command {
                         Set(key, _, time_stamp) => {
-                            set_map.insert(key, KvsEntry::new(kvs_file.index, time_stamp, offset));
+                            entries.push((key, KvsEntry::new(kvs_file.index, time_stamp, offset, false)));
                         },
                         Rm(key, time_stamp) => {
-                            del_map.insert(key, KvsEntry::new(kvs_file.index, time_stamp, offset));
+                            entries.push((key, KvsEntry::new(kvs_file.index, time_stamp, offset, true)));
                         }
                     };
                 },
@@ -266,40 +332,37 @@ impl KvStore {
             offset = itr_offset as u64;
         }
         kvs_file.buf_reader.seek(SeekFrom::Start(0)).unwrap();
-        Ok((set_map, del_map))
+        Ok(entries)
     }
 
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
         let dir_path = path.into();
+        let lock_file = Self::lock_dir(&dir_path)?;
         let file_paths = Self::find_all_file_in_dir(&dir_path);
         let mut files = Self::get_kvs_files(file_paths);
-        let mut set_map : HashMap<String, KvsEntry> = HashMap::new();
-        let mut del_map : HashMap<String, KvsEntry> = HashMap::new();
+        let mut map : HashMap<String, Vec<KvsEntry>> = HashMap::new();
         for mut file in &mut files {
-            let out = Self::read_from_file(&mut file).unwrap();
-            let s_map = out.0;
-            for s_map_entry in s_map {
-                if set_map.contains_key(&s_map_entry.0) {
-                    let cur_value = set_map.get(&s_map_entry.0).unwrap();
-                    if s_map_entry.1.timestamp > cur_value.get_timestamp() {
-                        set_map.insert(s_map_entry.0, s_map_entry.1);
-                    }
-                } else {
-                    set_map.insert(s_map_entry.0, s_map_entry.1);
-                }
+            for (key, entry) in Self::read_from_file(&mut file).unwrap() {
+                map.entry(key).or_default().push(entry);
             }
+        }
 
-            let d_map = out.1;
-            for d_map_entry in d_map {
-                if del_map.contains_key(&d_map_entry.0) {
-                    let cur_value = del_map.get(&d_map_entry.0).unwrap();
-                    if d_map_entry.1.timestamp > cur_value.get_timestamp() {
-                        del_map.insert(d_map_entry.0, d_map_entry.1);
-                    } 
+        // a compaction interrupted before deleting the old files leaves copies of
+        // the versions it rewrote, and logs written before the timestamps were
+        // unique can hold several writes in the same millisecond. The sort keeps
+        // the log order of equal timestamps, so the last one is the newest write.
+        let mut last_timestamp = 0;
+        for versions in map.values_mut() {
+            versions.sort_by_key(|entry| entry.timestamp);
+            versions.dedup_by(|later, kept| {
+                if later.timestamp == kept.timestamp {
+                    std::mem::swap(later, kept);
+                    true
                 } else {
-                    del_map.insert(d_map_entry.0, d_map_entry.1);
+                    false
                 }
-            }
+            });
+            last_timestamp = last_timestamp.max(versions.last().unwrap().timestamp);
         }
 
         let mut files_map : HashMap<u32, KvsFile> = HashMap::new();
@@ -307,20 +370,6 @@ impl KvStore {
             files_map.insert(file.index, file);
         }
 
-        let mut final_map = HashMap::new();
-        for entry in set_map {
-            final_map.insert(entry.0, entry.1);
-        }
-
-        for entry in del_map {
-            if final_map.contains_key(&entry.0) {
-                let cur_val = final_map.get(&entry.0).unwrap();
-                if cur_val.get_timestamp() < entry.1.timestamp {
-                    final_map.remove(&entry.0);
-                }
-            }
-        }
-
         let cur_index = files_map.len() as u32;
         let cur_file = Self::create_new_file(&dir_path, cur_index);
         files_map.insert(cur_index, cur_file);
@@ -328,9 +377,12 @@ impl KvStore {
         Ok(KvStore {
             dir_path,
             files : files_map,
-            map : final_map,
+            map,
             cur_offset : 0,
-            cur_index
+            cur_index,
+            last_timestamp,
+            retention : 0,
+            _lock_file : lock_file
         })
 
     }
@@ -345,10 +397,11 @@ impl KvStore {
         }
     }
 
-    fn compaction_required(&mut self) -> () {
-        if self.files.len() == 4 {
-            self.compact().unwrap();
+    fn compaction_required(&mut self) -> Result<()> {
+        if self.files.len() >= 4 {
+            self.compact()?;
         }
+        Ok(())
     }
 
     fn get_file_index(&self) -> u32 {
@@ -361,26 +414,50 @@ impl KvStore {
         }
     }
 
-    fn compact(&mut self) -> Result<()> {
+    // Rewrites the versions still needed into a new file and deletes the others:
+    // those written within the retention window, and the one each key had at the
+    // start of the window unless the key was removed by then.
+    pub fn compact(&mut self) -> Result<()> {
 
         let compaction_file_index = self.get_file_index();
         self.files.insert(compaction_file_index, Self::create_new_file(&self.dir_path, compaction_file_index));
 
+        // the timestamps of writes in quick succession may run ahead of the clock
+        let now = Self::get_timestamp().max(self.last_timestamp);
+        let horizon = now.saturating_sub(self.retention);
         let keys = self.map.keys().map(|x| x.clone()).collect::<Vec<String>>();
-        
+
+        let mut new_map = HashMap::new();
         let mut offset = 0;
-        for entry in keys {
-            let value = self.get(entry.clone()).unwrap().unwrap();
-            let kvs_entry = self.map.get(&entry).unwrap();
-            let command = KvsCommand::set(entry.clone(), value.clone(),kvs_entry.get_timestamp());
-            let json_str = serde_json::to_string(&command)?;
-            let compaction_file = self.files.get_mut(&compaction_file_index).unwrap();
-            let bytes_written = compaction_file.buf_writer.write(json_str.as_bytes())?;
-            compaction_file.buf_writer.flush()?;
-            self.map.insert(entry.clone(), KvsEntry::new(compaction_file_index, kvs_entry.get_timestamp(), offset));
-            offset += bytes_written as u64;
+        for key in keys {
+            let versions = self.map.get(&key).unwrap();
+            let newer = versions.iter().position(|entry| entry.timestamp > horizon).unwrap_or(versions.len());
+            let start = if newer > 0 && !versions[newer - 1].removed { newer - 1 } else { newer };
+            let kept = versions[start..].iter()
+                .map(|entry| (entry.file_index, entry.offset, entry.timestamp, entry.removed))
+                .collect::<Vec<_>>();
+
+            let mut new_versions = Vec::new();
+            for (file_index, old_offset, timestamp, removed) in kept {
+                let command = if removed {
+                    KvsCommand::rm(key.clone(), timestamp)
+                } else {
+                    KvsCommand::set(key.clone(), self.read_value(file_index, old_offset)?, timestamp)
+                };
+                let json_str = serde_json::to_string(&command)?;
+                let compaction_file = self.files.get_mut(&compaction_file_index).unwrap();
+                let bytes_written = compaction_file.buf_writer.write(json_str.as_bytes())?;
+                compaction_file.buf_writer.flush()?;
+                new_versions.push(KvsEntry::new(compaction_file_index, timestamp, offset, removed));
+                offset += bytes_written as u64;
+            }
+            if !new_versions.is_empty() {
+                new_map.insert(key, new_versions);
+            }
         }
-        
+        self.map = new_map;
+
+
         let cur_file_index = self.get_file_index();
         self.files.insert(cur_file_index, Self::create_new_file(&self.dir_path, cur_file_index));
         self.cur_index = cur_file_index;
@@ -396,7 +473,7 @@ impl KvStore {
                 // DO Nothing
             } else {
             let file = self.files.remove(&file_index).unwrap();
-            std::fs::remove_file(file.file_path).unwrap();
+            std::fs::remove_file(file.file_path)?;
             }
         }
 
@@ -1,4 +1,4 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{KvStore, KvsEngine, KvsError, Result};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -16,7 +16,7 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
@@ -55,7 +55,7 @@ fn get_non_existent_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     Ok(())
@@ -114,7 +114,7 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content
-        let mut store = KvStore::open(temp_dir.path())?;
+        let store = KvStore::open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             assert_eq!(store.get(key)?, Some(format!("{}", iter)));
@@ -124,3 +124,116 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// Should not open a directory which another store has open
+#[test]
+fn open_locked_directory() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::DirectoryLocked(dir)) => {
+            assert_eq!(dir, temp_dir.path().display().to_string())
+        }
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("the directory is opened twice"),
+    }
+
+    drop(store);
+    KvStore::open(temp_dir.path())?;
+    Ok(())
+}
+
+// Should read older versions of a key, and drop them once compacted out of the
+// retention window
+#[test]
+fn get_value_at_timestamp() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let t1 = store.last_timestamp();
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    let t2 = store.last_timestamp();
+    store.remove("key1".to_owned())?;
+    let t3 = store.last_timestamp();
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.set("key2".to_owned(), "value1".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert!(t1 < t2 && t2 < t3);
+
+    assert_eq!(store.get_at("key1".to_owned(), t1 - 1)?, None);
+    assert_eq!(store.get_at("key1".to_owned(), t1)?, Some("value1".to_owned()));
+    assert_eq!(store.get_at("key1".to_owned(), t2)?, Some("value2".to_owned()));
+    assert_eq!(store.get_at("key1".to_owned(), t3)?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert!(store.remove("key2".to_owned()).is_err());
+
+    // Open from disk again and compact within the retention window
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set_retention(60_000);
+    store.compact()?;
+    assert_eq!(store.get_at("key1".to_owned(), t1)?, Some("value1".to_owned()));
+    assert_eq!(store.get_at("key1".to_owned(), t3)?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    store.set("key1".to_owned(), "value4".to_owned())?;
+    assert!(store.last_timestamp() > t3);
+
+    // Without retention only the latest versions survive
+    store.set_retention(0);
+    store.compact()?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_at("key1".to_owned(), t2)?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert!(store.remove("key2".to_owned()).is_err());
+
+    Ok(())
+}
+
+// Should keep the newest of two writes with the same timestamp, as logs written
+// before the timestamps were unique may hold
+#[test]
+fn same_timestamp_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::write(
+        temp_dir.path().join("kvs0_0.bin"),
+        r#"{"Set":["key1","value1",5]}{"Set":["key1","value2",5]}{"Set":["key2","value1",5]}{"Rm":["key2",5]}"#,
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get_at("key1".to_owned(), 5)?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// Should serve gets from several threads sharing the store
+#[test]
+fn concurrent_gets() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    let store = std::sync::Arc::new(store);
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let store = std::sync::Arc::clone(&store);
+            std::thread::spawn(move || -> Result<()> {
+                for i in 0..100 {
+                    assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for reader in readers {
+        reader.join().unwrap()?;
+    }
+
+    Ok(())
+}
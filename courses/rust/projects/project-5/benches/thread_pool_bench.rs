@@ -0,0 +1,82 @@
+use criterion::{criterion_group, criterion_main, Bencher, Benchmark, Criterion};
+use crossbeam_utils::sync::WaitGroup;
+use kvs::thread_pool::*;
+use kvs::{KvStore, KvsEngine};
+use tempfile::TempDir;
+use tokio::prelude::*;
+
+const THREADS: u32 = 4;
+const TASKS: usize = 1000;
+const KEYS: usize = 1000;
+
+// Spawns many short tasks from outside the pool.
+fn spawn<P: ThreadPool>(b: &mut Bencher) {
+    let pool = P::new(THREADS).unwrap();
+    b.iter(|| {
+        let wg = WaitGroup::new();
+        for _ in 0..TASKS {
+            let wg = wg.clone();
+            pool.spawn(move || drop(wg));
+        }
+        wg.wait();
+    })
+}
+
+// Spawns tasks which spawn short tasks themselves.
+fn spawn_nested<P: ThreadPool>(b: &mut Bencher) {
+    let pool = P::new(THREADS).unwrap();
+    b.iter(|| {
+        let wg = WaitGroup::new();
+        for _ in 0..TASKS / 100 {
+            let wg = wg.clone();
+            let inner = pool.clone();
+            pool.spawn(move || {
+                for _ in 0..100 {
+                    let wg = wg.clone();
+                    inner.spawn(move || drop(wg));
+                }
+            });
+        }
+        wg.wait();
+    })
+}
+
+// Sets then gets keys of a `KvStore`, all requests being in flight at once.
+fn kvs_set_get<P: ThreadPool>(b: &mut Bencher) {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::<P>::open(temp_dir.path(), THREADS).unwrap();
+    b.iter(|| {
+        let sets = (0..KEYS).map(|i| store.set(format!("key{}", i), "value".to_owned()));
+        future::join_all(sets).wait().unwrap();
+        let gets = (0..KEYS).map(|i| store.get(format!("key{}", i)));
+        future::join_all(gets).wait().unwrap();
+    })
+}
+
+fn thread_pool_bench(c: &mut Criterion) {
+    c.bench(
+        "spawn",
+        Benchmark::new("naive", spawn::<NaiveThreadPool>)
+            .with_function("shared_queue", spawn::<SharedQueueThreadPool>)
+            .with_function("rayon", spawn::<RayonThreadPool>)
+            .with_function("work_stealing", spawn::<WorkStealingThreadPool>),
+    );
+    c.bench(
+        "spawn_nested",
+        Benchmark::new("naive", spawn_nested::<NaiveThreadPool>)
+            .with_function("shared_queue", spawn_nested::<SharedQueueThreadPool>)
+            .with_function("rayon", spawn_nested::<RayonThreadPool>)
+            .with_function("work_stealing", spawn_nested::<WorkStealingThreadPool>),
+    );
+    c.bench(
+        "kvs_set_get",
+        Benchmark::new("naive", kvs_set_get::<NaiveThreadPool>)
+            .with_function("shared_queue", kvs_set_get::<SharedQueueThreadPool>)
+            .with_function("rayon", kvs_set_get::<RayonThreadPool>)
+            .with_function("work_stealing", kvs_set_get::<WorkStealingThreadPool>)
+            .sample_size(10),
+    );
+}
+
+criterion_group!(benches, thread_pool_bench);
+criterion_main!(benches);
@@ -0,0 +1,295 @@
+//! Offline maintenance of data directories.
+//!
+//! `LogDir` works directly on the `<gen>.log` files of a `KvStore`. The other
+//! functions move data between engines through the `KvsEngine` trait. They must
+//! not be used while a `kvs-server` has the same directory open. `LogDir` takes
+//! the same lock as `KvStore` and refuses such a directory.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+use tokio::prelude::*;
+
+use crate::bulk::{PairFormat, PairReader, PairWriter};
+use crate::engines::encryption::Keyring;
+use crate::engines::kvs::{log_path, sorted_gen_list, BufReaderWithPos, Command};
+use crate::{EncryptionKey, FileStorage, KvsEngine, KvsError, Result};
+
+/// A single record read from a log file.
+#[derive(Debug, Clone)]
+pub struct LogRecord {
+    /// Generation number of the log file containing the record.
+    pub gen: u64,
+    /// Byte offset of the record in the log file.
+    pub offset: u64,
+    /// Length in bytes of the serialized record.
+    pub len: u64,
+    /// The key the record applies to.
+    pub key: String,
+    /// What the record does to the key.
+    pub value: RecordValue,
+}
+
+/// The operation of a `LogRecord`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecordValue {
+    /// The record sets the key to the value.
+    Set(String),
+    /// The record sets the key to a value encrypted with a key that wasn't given,
+    /// whose fingerprint is kept.
+    Encrypted(String),
+    /// The record removes the key.
+    Remove,
+}
+
+/// A log file whose content can't be fully deserialized.
+#[derive(Debug, Clone)]
+pub struct Corruption {
+    /// Generation number of the corrupted log file.
+    pub gen: u64,
+    /// Offset of the first byte that can't be read as a record.
+    ///
+    /// Everything before it is valid.
+    pub offset: u64,
+    /// Number of bytes from `offset` to the end of the file.
+    pub len: u64,
+    /// Why the record at `offset` can't be read.
+    pub reason: String,
+}
+
+/// Space usage of a single log file.
+#[derive(Debug, Clone)]
+pub struct GenerationStats {
+    /// Generation number of the log file.
+    pub gen: u64,
+    /// Number of records in the log file.
+    pub records: u64,
+    /// Bytes occupied by records the store still refers to.
+    pub live_bytes: u64,
+    /// Bytes that would be reclaimed by a compaction.
+    pub stale_bytes: u64,
+}
+
+/// A `KvStore` data directory opened for offline inspection.
+pub struct LogDir {
+    path: PathBuf,
+    // decrypts the values of the records
+    keys: Vec<EncryptionKey>,
+    // keeps the directory locked while it is inspected or repaired
+    _storage: FileStorage,
+}
+
+impl LogDir {
+    /// Opens the data directory at the given path.
+    ///
+    /// Unlike `KvStore::open`, this never creates the directory or modifies a
+    /// log file. It only creates the `LOCK` file, to lock the directory until the
+    /// `LogDir` is dropped, and returns `KvsError::DirectoryLocked` if a store
+    /// has it open. Like a store, it deletes the temporary files left by an
+    /// interrupted compaction.
+    pub fn open(path: impl Into<PathBuf>) -> Result<LogDir> {
+        let path = path.into();
+        // make sure the directory exists and is readable
+        sorted_gen_list(&path)?;
+        let storage = FileStorage::open(&path)?;
+        Ok(LogDir {
+            path,
+            keys: Vec::new(),
+            _storage: storage,
+        })
+    }
+
+    /// Adds a key to decrypt the values encrypted with it.
+    ///
+    /// Without it, their records are still checked but their values are reported
+    /// as `RecordValue::Encrypted`.
+    pub fn decryption_key(&mut self, key: EncryptionKey) -> &mut LogDir {
+        self.keys.push(key);
+        self
+    }
+
+    /// Returns the generation numbers of all log files in ascending order.
+    pub fn generations(&self) -> Result<Vec<u64>> {
+        sorted_gen_list(&self.path)
+    }
+
+    /// Reads all valid records of the given generation and passes them to `f`
+    /// in file order.
+    ///
+    /// Reading stops at the first record that can't be deserialized, which is
+    /// returned as a `Corruption`. Values encrypted with a key that wasn't given
+    /// can't be checked beyond their record, so they are returned encrypted.
+    pub fn scan_generation<F>(&self, gen: u64, mut f: F) -> Result<Option<Corruption>>
+    where
+        F: FnMut(LogRecord),
+    {
+        let keyring = Keyring::new(None, self.keys.clone());
+        let file = File::open(log_path(&self.path, gen))?;
+        let file_len = file.metadata()?.len();
+        let mut reader = BufReaderWithPos::new(file)?;
+        let mut stream = Deserializer::from_reader(&mut reader).into_iter::<Command>();
+        let mut pos = 0;
+        while let Some(cmd) = stream.next() {
+            // a value that can't be decompressed is as corrupted as a malformed command
+            let record = cmd.map_err(KvsError::from).and_then(|cmd| {
+                let key = match &cmd {
+                    Command::Set { key, .. } | Command::Remove { key } => key.clone(),
+                };
+                let value = match &cmd {
+                    Command::Set {
+                        key_id: Some(id), ..
+                    } if keyring.find(id).is_err() => RecordValue::Encrypted(id.clone()),
+                    _ => match cmd.into_value(&keyring)? {
+                        Some(value) => RecordValue::Set(value),
+                        None => RecordValue::Remove,
+                    },
+                };
+                Ok((key, value))
+            });
+            let (key, value) = match record {
+                Ok(record) => record,
+                Err(e) => {
+                    return Ok(Some(Corruption {
+                        gen,
+                        offset: pos,
+                        len: file_len - pos,
+                        reason: format!("{}", e),
+                    }));
+                }
+            };
+            let new_pos = stream.byte_offset() as u64;
+            f(LogRecord {
+                gen,
+                offset: pos,
+                len: new_pos - pos,
+                key,
+                value,
+            });
+            pos = new_pos;
+        }
+        Ok(None)
+    }
+
+    /// Replays every log file and returns all the corruptions found.
+    pub fn verify(&self) -> Result<Vec<Corruption>> {
+        let mut corruptions = Vec::new();
+        for gen in self.generations()? {
+            if let Some(corruption) = self.scan_generation(gen, |_| {})? {
+                corruptions.push(corruption);
+            }
+        }
+        Ok(corruptions)
+    }
+
+    /// Returns the live and stale bytes of every log file.
+    ///
+    /// A record is live if it is the latest `Set` of its key and the key has not been
+    /// removed afterwards. Everything else can be dropped by a compaction.
+    pub fn stats(&self) -> Result<Vec<GenerationStats>> {
+        let gen_list = self.generations()?;
+        let mut stats: Vec<GenerationStats> = gen_list
+            .iter()
+            .map(|&gen| GenerationStats {
+                gen,
+                records: 0,
+                live_bytes: 0,
+                stale_bytes: 0,
+            })
+            .collect();
+        // key -> (index in `stats`, record length) of the latest live record
+        let mut index: HashMap<String, (usize, u64)> = HashMap::new();
+        for (i, &gen) in gen_list.iter().enumerate() {
+            let mut corrupted_tail = 0;
+            if let Some(corruption) = self.scan_generation(gen, |record| {
+                stats[i].records += 1;
+                stats[i].live_bytes += record.len;
+                if let Some((j, len)) = index.remove(&record.key) {
+                    stats[j].live_bytes -= len;
+                    stats[j].stale_bytes += len;
+                }
+                if record.value != RecordValue::Remove {
+                    index.insert(record.key, (i, record.len));
+                } else {
+                    // a "remove" record is stale as soon as it is written
+                    stats[i].live_bytes -= record.len;
+                    stats[i].stale_bytes += record.len;
+                }
+            })? {
+                corrupted_tail = corruption.len;
+            }
+            stats[i].stale_bytes += corrupted_tail;
+        }
+        Ok(stats)
+    }
+
+    /// Truncates every log file to its last valid record.
+    ///
+    /// Returns the corruptions that have been cut off.
+    pub fn repair(&self) -> Result<Vec<Corruption>> {
+        let corruptions = self.verify()?;
+        for corruption in &corruptions {
+            let file = OpenOptions::new()
+                .write(true)
+                .open(log_path(&self.path, corruption.gen))?;
+            file.set_len(corruption.offset)?;
+            file.sync_all()?;
+        }
+        Ok(corruptions)
+    }
+}
+
+/// A key/value pair in the portable backup format.
+///
+/// A backup is a file with one JSON-serialized `Pair` per line (NDJSON).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Pair {
+    /// The key.
+    pub key: String,
+    /// The value.
+    pub value: String,
+}
+
+/// Writes all key/value pairs of `engine` to `writer` in the backup format.
+///
+/// Returns the number of pairs written.
+pub fn export<E: KvsEngine, W: Write>(engine: &E, writer: W) -> Result<u64> {
+    let mut writer = PairWriter::new(writer, PairFormat::Ndjson);
+    let mut count = 0;
+    for res in engine.scan(String::new()).wait() {
+        let (key, value) = res?;
+        writer.write(key, value)?;
+        count += 1;
+    }
+    writer.flush()?;
+    Ok(count)
+}
+
+/// Sets all key/value pairs read from `reader` in the backup format into `engine`.
+///
+/// Empty lines are skipped. Returns the number of pairs imported.
+pub fn import<E: KvsEngine, R: BufRead>(engine: &E, reader: R) -> Result<u64> {
+    let mut count = 0;
+    for pair in PairReader::new(reader, PairFormat::Ndjson) {
+        let (key, value) = pair?;
+        engine.set(key, value).wait()?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Copies all key/value pairs from `src` to `dst`.
+///
+/// Returns the number of pairs copied.
+pub fn copy<S: KvsEngine, D: KvsEngine>(src: &S, dst: &D) -> Result<u64> {
+    let mut count = 0;
+    for res in src.scan(String::new()).wait() {
+        let (key, value) = res?;
+        dst.set(key, value).wait()?;
+        count += 1;
+    }
+    Ok(count)
+}
@@ -0,0 +1,507 @@
+#[macro_use]
+extern crate clap;
+
+use clap::AppSettings;
+use kvs::admin::{self, LogDir, RecordValue};
+use kvs::thread_pool::{NaiveThreadPool, RayonThreadPool};
+use kvs::{
+    EncryptionKey, KvStore, KvStoreOptions, KvsEngine, KvsError, LsmKvsEngine, Result,
+    SledKvsEngine,
+};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use structopt::StructOpt;
+
+// files and directories created by sled in its data directory
+const SLED_FILES: &[&str] = &["conf", "db", "blobs"];
+const SLED_SNAPSHOT_PREFIX: &str = "snap.";
+// files created by the lsm engine in its data directory
+const LSM_FILES: &[&str] = &["MANIFEST", "MANIFEST.tmp"];
+const LSM_EXTENSIONS: &[&str] = &["sst", "wal"];
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+    name = "kvs-admin",
+    raw(global_settings = "&[\
+                           AppSettings::DisableHelpSubcommand,\
+                           AppSettings::VersionlessSubcommands]")
+)]
+struct Opt {
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    #[structopt(
+        name = "dump",
+        about = "Print every record with its generation and offset"
+    )]
+    Dump {
+        #[structopt(
+            long = "encryption-key-file",
+            help = "Decrypts the values encrypted with the key in FILE",
+            value_name = "FILE",
+            parse(from_os_str)
+        )]
+        encryption_key_file: Option<PathBuf>,
+        #[structopt(
+            long = "old-encryption-key-file",
+            help = "Decrypts the values written before a key rotation with the key in FILE",
+            value_name = "FILE",
+            number_of_values = 1,
+            parse(from_os_str)
+        )]
+        old_encryption_key_files: Vec<PathBuf>,
+        #[structopt(
+            name = "DIR",
+            help = "The data directory",
+            default_value = ".",
+            parse(from_os_str)
+        )]
+        dir: PathBuf,
+    },
+    #[structopt(name = "verify", about = "Replay the logs and report corrupt records")]
+    Verify {
+        #[structopt(
+            name = "DIR",
+            help = "The data directory",
+            default_value = ".",
+            parse(from_os_str)
+        )]
+        dir: PathBuf,
+    },
+    #[structopt(name = "stats", about = "Print live and stale bytes per generation")]
+    Stats {
+        #[structopt(
+            name = "DIR",
+            help = "The data directory",
+            default_value = ".",
+            parse(from_os_str)
+        )]
+        dir: PathBuf,
+    },
+    #[structopt(name = "compact", about = "Run a compaction")]
+    Compact {
+        #[structopt(
+            name = "DIR",
+            help = "The data directory",
+            default_value = ".",
+            parse(from_os_str)
+        )]
+        dir: PathBuf,
+    },
+    #[structopt(
+        name = "repair",
+        about = "Truncate corrupt records at the end of the logs"
+    )]
+    Repair {
+        #[structopt(
+            name = "DIR",
+            help = "The data directory",
+            default_value = ".",
+            parse(from_os_str)
+        )]
+        dir: PathBuf,
+    },
+    #[structopt(name = "migrate", about = "Move the data to another storage engine")]
+    Migrate {
+        #[structopt(
+            long,
+            help = "The current storage engine",
+            value_name = "ENGINE-NAME",
+            raw(possible_values = "&Engine::variants()")
+        )]
+        from: Engine,
+        #[structopt(
+            long,
+            help = "The new storage engine",
+            value_name = "ENGINE-NAME",
+            raw(possible_values = "&Engine::variants()")
+        )]
+        to: Engine,
+        #[structopt(
+            name = "DIR",
+            help = "The data directory",
+            default_value = ".",
+            parse(from_os_str)
+        )]
+        dir: PathBuf,
+    },
+    #[structopt(name = "export", about = "Write all key/value pairs as NDJSON")]
+    Export {
+        #[structopt(
+            long,
+            help = "Sets the storage engine, defaults to the one of the data directory",
+            value_name = "ENGINE-NAME",
+            raw(possible_values = "&Engine::variants()")
+        )]
+        engine: Option<Engine>,
+        #[structopt(
+            long,
+            short,
+            help = "Writes to the given file instead of stdout",
+            parse(from_os_str)
+        )]
+        output: Option<PathBuf>,
+        #[structopt(
+            long = "encryption-key-file",
+            help = "Decrypts the values encrypted with the key in FILE",
+            value_name = "FILE",
+            parse(from_os_str)
+        )]
+        encryption_key_file: Option<PathBuf>,
+        #[structopt(
+            long = "old-encryption-key-file",
+            help = "Decrypts the values written before a key rotation with the key in FILE",
+            value_name = "FILE",
+            number_of_values = 1,
+            parse(from_os_str)
+        )]
+        old_encryption_key_files: Vec<PathBuf>,
+        #[structopt(
+            name = "DIR",
+            help = "The data directory",
+            default_value = ".",
+            parse(from_os_str)
+        )]
+        dir: PathBuf,
+    },
+    #[structopt(name = "import", about = "Set all key/value pairs read as NDJSON")]
+    Import {
+        #[structopt(
+            long,
+            help = "Sets the storage engine, defaults to the one of the data directory",
+            value_name = "ENGINE-NAME",
+            raw(possible_values = "&Engine::variants()")
+        )]
+        engine: Option<Engine>,
+        #[structopt(
+            long,
+            short,
+            help = "Reads from the given file instead of stdin",
+            parse(from_os_str)
+        )]
+        input: Option<PathBuf>,
+        #[structopt(
+            name = "DIR",
+            help = "The data directory",
+            default_value = ".",
+            parse(from_os_str)
+        )]
+        dir: PathBuf,
+    },
+}
+
+arg_enum! {
+    #[allow(non_camel_case_types)]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    enum Engine {
+        kvs,
+        sled,
+        lsm
+    }
+}
+
+fn main() {
+    let opt = Opt::from_args();
+    if let Err(e) = run(opt) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+fn run(opt: Opt) -> Result<()> {
+    match opt.command {
+        Command::Dump {
+            encryption_key_file,
+            old_encryption_key_files,
+            dir,
+        } => {
+            let mut log_dir = open_log_dir(&dir)?;
+            for path in encryption_key_file.iter().chain(&old_encryption_key_files) {
+                log_dir.decryption_key(EncryptionKey::from_file(path)?);
+            }
+            for gen in log_dir.generations()? {
+                let corruption = log_dir.scan_generation(gen, |record| match record.value {
+                    RecordValue::Set(value) => println!(
+                        "{}\t{}\t{}\tset\t{}\t{}",
+                        record.gen, record.offset, record.len, record.key, value
+                    ),
+                    RecordValue::Encrypted(id) => println!(
+                        "{}\t{}\t{}\tencrypted\t{}\t{}",
+                        record.gen, record.offset, record.len, record.key, id
+                    ),
+                    RecordValue::Remove => println!(
+                        "{}\t{}\t{}\trm\t{}",
+                        record.gen, record.offset, record.len, record.key
+                    ),
+                })?;
+                if let Some(corruption) = corruption {
+                    println!(
+                        "{}\t{}\t{}\tcorrupt\t{}",
+                        corruption.gen, corruption.offset, corruption.len, corruption.reason
+                    );
+                }
+            }
+        }
+        Command::Verify { dir } => {
+            let corruptions = open_log_dir(&dir)?.verify()?;
+            for corruption in &corruptions {
+                println!(
+                    "{}.log: {} corrupt bytes at offset {}: {}",
+                    corruption.gen, corruption.len, corruption.offset, corruption.reason
+                );
+            }
+            if !corruptions.is_empty() {
+                return Err(KvsError::StringError(format!(
+                    "{} corrupt log file(s) found",
+                    corruptions.len()
+                )));
+            }
+            println!("OK");
+        }
+        Command::Stats { dir } => {
+            let stats = open_log_dir(&dir)?.stats()?;
+            println!("gen\trecords\tlive\tstale");
+            let (mut live, mut stale) = (0, 0);
+            for gen in &stats {
+                println!(
+                    "{}\t{}\t{}\t{}",
+                    gen.gen, gen.records, gen.live_bytes, gen.stale_bytes
+                );
+                live += gen.live_bytes;
+                stale += gen.stale_bytes;
+            }
+            println!("total\t\t{}\t{}", live, stale);
+        }
+        Command::Compact { dir } => {
+            // make sure we don't create log files in a directory of another engine
+            open_log_dir(&dir)?;
+            KvStore::<NaiveThreadPool>::open(dir, 1)?.compact()?;
+        }
+        Command::Repair { dir } => {
+            for corruption in open_log_dir(&dir)?.repair()? {
+                println!(
+                    "{}.log: truncated {} bytes at offset {}",
+                    corruption.gen, corruption.len, corruption.offset
+                );
+            }
+        }
+        Command::Migrate { from, to, dir } => {
+            let count = migrate(from, to, &dir)?;
+            println!("Migrated {} pairs from {} to {}", count, from, to);
+        }
+        Command::Export {
+            engine,
+            output,
+            encryption_key_file,
+            old_encryption_key_files,
+            dir,
+        } => {
+            let engine = engine_of(engine, &dir)?;
+            let has_keys = encryption_key_file.is_some() || !old_encryption_key_files.is_empty();
+            if has_keys && engine != Engine::kvs {
+                return Err(KvsError::StringError(format!(
+                    "Encryption keys only apply to the kvs engine, not {}",
+                    engine
+                )));
+            }
+            let writer: Box<dyn Write> = match output {
+                Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+                None => Box::new(BufWriter::new(io::stdout())),
+            };
+            let count = match engine {
+                Engine::kvs => {
+                    let mut options = KvStoreOptions::new();
+                    if let Some(path) = &encryption_key_file {
+                        options.encryption_key(EncryptionKey::from_file(path)?);
+                    }
+                    for path in &old_encryption_key_files {
+                        options.old_encryption_key(EncryptionKey::from_file(path)?);
+                    }
+                    let store: KvStore<RayonThreadPool> =
+                        options.open(&dir, num_cpus::get() as u32)?;
+                    admin::export(&store, writer)?
+                }
+                Engine::sled => admin::export(&open_sled(&dir)?, writer)?,
+                Engine::lsm => admin::export(&open_lsm(&dir)?, writer)?,
+            };
+            eprintln!("Exported {} pairs", count);
+        }
+        Command::Import { engine, input, dir } => {
+            let reader: Box<dyn Read> = match input {
+                Some(path) => Box::new(File::open(path)?),
+                None => Box::new(io::stdin()),
+            };
+            let reader = BufReader::new(reader);
+            let engine = engine_of(engine, &dir)?;
+            let count = match engine {
+                Engine::kvs => admin::import(&open_kvs(&dir)?, reader)?,
+                Engine::sled => admin::import(&open_sled(&dir)?, reader)?,
+                Engine::lsm => admin::import(&open_lsm(&dir)?, reader)?,
+            };
+            fs::write(dir.join("engine"), format!("{}", engine))?;
+            eprintln!("Imported {} pairs", count);
+        }
+    }
+    Ok(())
+}
+
+/// Copies all data of `dir` from the `from` engine to the `to` engine and
+/// replaces the files of the old engine with the new ones.
+///
+/// The new engine is filled in a staging directory first, so a failed copy
+/// leaves the old data untouched.
+fn migrate(from: Engine, to: Engine, dir: &Path) -> Result<u64> {
+    if from == to {
+        return Err(KvsError::StringError(format!(
+            "The data directory already uses the {} engine",
+            to
+        )));
+    }
+    if let Some(engine) = current_engine(dir)? {
+        if engine != from {
+            return Err(KvsError::StringError(format!(
+                "{} is a data directory of the {} engine",
+                dir.display(),
+                engine
+            )));
+        }
+    }
+    if !engine_files(to, dir)?.is_empty() {
+        return Err(KvsError::StringError(format!(
+            "{} already contains files of the {} engine",
+            dir.display(),
+            to
+        )));
+    }
+    let backup = dir.join(format!(".migrate-{}", from));
+    if backup.exists() {
+        return Err(KvsError::StringError(format!(
+            "A previous migration was interrupted, the old data is kept in {}",
+            backup.display()
+        )));
+    }
+
+    let staging = dir.join(".migrate");
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+    fs::create_dir(&staging)?;
+    let count = match from {
+        Engine::kvs => copy_to(&open_kvs(dir)?, to, &staging)?,
+        Engine::sled => copy_to(&open_sled(dir)?, to, &staging)?,
+        Engine::lsm => copy_to(&open_lsm(dir)?, to, &staging)?,
+    };
+
+    // keep the old files until the new ones are in place
+    fs::create_dir(&backup)?;
+    for path in engine_files(from, dir)? {
+        fs::rename(&path, backup.join(path.file_name().unwrap()))?;
+    }
+    for entry in fs::read_dir(&staging)? {
+        let entry = entry?;
+        fs::rename(entry.path(), dir.join(entry.file_name()))?;
+    }
+    fs::write(dir.join("engine"), format!("{}", to))?;
+    fs::remove_dir(&staging)?;
+    fs::remove_dir_all(&backup)?;
+    Ok(count)
+}
+
+/// Copies all data of `src` into a new `to` engine in `dir`.
+fn copy_to<E: KvsEngine>(src: &E, to: Engine, dir: &Path) -> Result<u64> {
+    match to {
+        Engine::kvs => admin::copy(src, &open_kvs(dir)?),
+        Engine::sled => admin::copy(src, &open_sled(dir)?),
+        Engine::lsm => admin::copy(src, &open_lsm(dir)?),
+    }
+}
+
+/// Returns the paths of all files and directories of `engine` in `dir`.
+fn engine_files(engine: Engine, dir: &Path) -> Result<Vec<PathBuf>> {
+    match engine {
+        Engine::kvs => Ok(LogDir::open(dir)?
+            .generations()?
+            .into_iter()
+            .map(|gen| dir.join(format!("{}.log", gen)))
+            .collect()),
+        Engine::sled => {
+            let mut files = Vec::new();
+            for entry in fs::read_dir(dir)? {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if SLED_FILES.contains(&name.as_str()) || name.starts_with(SLED_SNAPSHOT_PREFIX) {
+                    files.push(entry.path());
+                }
+            }
+            Ok(files)
+        }
+        Engine::lsm => {
+            let mut files = Vec::new();
+            for entry in fs::read_dir(dir)? {
+                let path = entry?.path();
+                let is_lsm_file = match (path.file_name(), path.extension()) {
+                    (Some(name), _) if LSM_FILES.iter().any(|f| name == *f) => true,
+                    (_, Some(ext)) => LSM_EXTENSIONS.iter().any(|e| ext == *e),
+                    _ => false,
+                };
+                if is_lsm_file {
+                    files.push(path);
+                }
+            }
+            Ok(files)
+        }
+    }
+}
+
+fn open_kvs(dir: &Path) -> Result<KvStore<RayonThreadPool>> {
+    KvStore::open(dir, num_cpus::get() as u32)
+}
+
+fn open_sled(dir: &Path) -> Result<SledKvsEngine<RayonThreadPool>> {
+    SledKvsEngine::new(sled::Db::start_default(dir)?, num_cpus::get() as u32)
+}
+
+fn open_lsm(dir: &Path) -> Result<LsmKvsEngine<RayonThreadPool>> {
+    LsmKvsEngine::open(dir, num_cpus::get() as u32)
+}
+
+/// Returns the engine given on the command line, or else the one recorded in `dir`,
+/// or else `kvs`.
+fn engine_of(engine: Option<Engine>, dir: &Path) -> Result<Engine> {
+    let curr_engine = current_engine(dir)?;
+    match (engine, curr_engine) {
+        (Some(engine), Some(curr_engine)) if engine != curr_engine => {
+            Err(KvsError::StringError(format!(
+                "{} is a data directory of the {} engine",
+                dir.display(),
+                curr_engine
+            )))
+        }
+        (engine, curr_engine) => Ok(engine.or(curr_engine).unwrap_or(Engine::kvs)),
+    }
+}
+
+/// Returns the engine recorded by `kvs-server` in `dir`, if any.
+fn current_engine(dir: &Path) -> Result<Option<Engine>> {
+    let engine = dir.join("engine");
+    if !engine.exists() {
+        return Ok(None);
+    }
+    fs::read_to_string(engine)?
+        .parse()
+        .map(Some)
+        .map_err(KvsError::StringError)
+}
+
+/// Opens `dir` as a `KvStore` data directory.
+///
+/// Fails if the `engine` file written by `kvs-server` names another engine.
+fn open_log_dir(dir: &Path) -> Result<LogDir> {
+    engine_of(Some(Engine::kvs), dir)?;
+    LogDir::open(dir)
+}
@@ -0,0 +1,124 @@
+use kvs::workload::{self, KeyDistribution, OperationMix, RunOptions, Workload};
+use kvs::Result;
+use std::net::SocketAddr;
+use std::process::exit;
+use std::time::Duration;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+    name = "kvs-bench",
+    about = "Loads records into a running kvs-server, then measures the throughput and latencies of a workload"
+)]
+struct Opt {
+    #[structopt(
+        long,
+        help = "Sets the server address",
+        value_name = "IP:PORT",
+        default_value = "127.0.0.1:4000",
+        parse(try_from_str)
+    )]
+    addr: SocketAddr,
+    #[structopt(
+        long,
+        help = "Starts from a core YCSB workload",
+        value_name = "NAME",
+        default_value = "a",
+        raw(possible_values = "&[\"a\", \"b\", \"c\", \"d\", \"e\", \"f\"]")
+    )]
+    workload: String,
+    #[structopt(
+        long,
+        help = "Sets the number of records loaded before the run [default: 1000]",
+        value_name = "N"
+    )]
+    records: Option<u64>,
+    #[structopt(
+        long = "value-size",
+        help = "Sets the size in bytes of the values written [default: 100]",
+        value_name = "BYTES"
+    )]
+    value_size: Option<usize>,
+    #[structopt(
+        long,
+        help = "Sets how records are chosen, instead of the one of the workload",
+        value_name = "DISTRIBUTION",
+        raw(possible_values = "&[\"uniform\", \"zipfian\", \"latest\"]"),
+        parse(try_from_str)
+    )]
+    distribution: Option<KeyDistribution>,
+    #[structopt(
+        long,
+        help = "Sets the proportions of operations, like read=0.9,update=0.1, instead of the ones of the workload",
+        value_name = "MIX",
+        parse(try_from_str)
+    )]
+    mix: Option<OperationMix>,
+    #[structopt(
+        long,
+        help = "Sets the number of connections sending operations",
+        value_name = "N",
+        default_value = "8"
+    )]
+    threads: usize,
+    #[structopt(
+        long,
+        help = "Sets how long operations are sent",
+        value_name = "SECONDS",
+        default_value = "10"
+    )]
+    duration: u64,
+    #[structopt(
+        long,
+        help = "Stops after N operations, if the duration isn't over before",
+        value_name = "N"
+    )]
+    operations: Option<u64>,
+    #[structopt(
+        long = "skip-load",
+        help = "Runs against the records loaded by a previous run"
+    )]
+    skip_load: bool,
+}
+
+fn main() {
+    let opt = Opt::from_args();
+    if let Err(e) = run(opt) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+fn run(opt: Opt) -> Result<()> {
+    let mut workload = Workload::ycsb(&opt.workload)?;
+    if let Some(records) = opt.records {
+        workload.records = records;
+    }
+    if let Some(value_size) = opt.value_size {
+        workload.value_size = value_size;
+    }
+    if let Some(distribution) = opt.distribution {
+        workload.distribution = distribution;
+    }
+    if let Some(mix) = opt.mix {
+        workload.mix = mix;
+    }
+
+    if !opt.skip_load {
+        eprintln!("Loading {} records", workload.records);
+        workload::load(opt.addr, &workload)?;
+    }
+    eprintln!(
+        "Running workload {} with {} keys and {} threads",
+        opt.workload, workload.distribution, opt.threads
+    );
+    let mut options = RunOptions::new()
+        .threads(opt.threads)
+        .duration(Duration::from_secs(opt.duration));
+    if let Some(operations) = opt.operations {
+        options = options.operations(operations);
+    }
+    let report = workload::run(opt.addr, &workload, &options)?;
+    println!("{}", report);
+    Ok(())
+}
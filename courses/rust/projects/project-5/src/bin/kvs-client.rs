@@ -1,10 +1,24 @@
 use clap::AppSettings;
-use kvs::{KvsClient, Result};
+use kvs::bulk::{self, LoadOptions, PairFormat, PairReader};
+use kvs::repl::{self, Input, OutputFormat, Session};
+use kvs::{KvsClient, KvsError, Result, WatchEvent};
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use std::env;
+use std::fs::File;
+use std::io::{self, BufWriter, Read};
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
 use tokio::prelude::*;
 
+// history of the REPL, in the home directory
+const HISTORY_FILE: &str = ".kvs_history";
+// how often `load` reports its progress
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
 #[derive(StructOpt, Debug)]
 #[structopt(
     name = "kvs-client",
@@ -60,6 +74,164 @@ enum Command {
         )]
         addr: SocketAddr,
     },
+    #[structopt(
+        name = "query",
+        about = "Print the keys whose value has a given field in a secondary index"
+    )]
+    Query {
+        #[structopt(name = "INDEX", help = "The name of the index")]
+        index: String,
+        #[structopt(name = "VALUE", help = "The value of the indexed field")]
+        value: String,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(name = "promote", about = "Turn a replica into a writable primary")]
+    Promote {
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(
+        name = "status",
+        about = "Print the replication role and lag of a server"
+    )]
+    Status {
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(
+        name = "watch",
+        about = "Print the changes of the keys starting with a given prefix"
+    )]
+    Watch {
+        #[structopt(name = "PREFIX", help = "A key prefix", default_value = "")]
+        prefix: String,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(
+        name = "load",
+        about = "Set all key/value pairs of a NDJSON or CSV file"
+    )]
+    Load {
+        #[structopt(
+            name = "FILE",
+            help = "A file of pairs, - for the standard input",
+            parse(from_os_str)
+        )]
+        file: PathBuf,
+        #[structopt(
+            long,
+            help = "Sets the format of the file, guessed from its extension by default",
+            value_name = "FORMAT",
+            raw(possible_values = "&[\"ndjson\", \"csv\"]"),
+            parse(try_from_str)
+        )]
+        format: Option<PairFormat>,
+        #[structopt(
+            long = "batch-size",
+            help = "Sets the number of pairs sent in each request",
+            value_name = "PAIRS",
+            default_value = "100"
+        )]
+        batch_size: usize,
+        #[structopt(
+            long,
+            help = "Sets the number of requests sent ahead of their responses",
+            value_name = "REQUESTS",
+            default_value = "8"
+        )]
+        concurrency: usize,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(name = "dump", about = "Print all key/value pairs as NDJSON or CSV")]
+    Dump {
+        #[structopt(
+            long,
+            help = "Only dumps the keys starting with PREFIX",
+            default_value = ""
+        )]
+        prefix: String,
+        #[structopt(
+            long,
+            help = "Sets the format of the pairs",
+            value_name = "FORMAT",
+            default_value = "ndjson",
+            raw(possible_values = "&[\"ndjson\", \"csv\"]"),
+            parse(try_from_str)
+        )]
+        format: PairFormat,
+        #[structopt(
+            short = "o",
+            long,
+            help = "Writes the pairs to FILE instead of the standard output",
+            value_name = "FILE",
+            parse(from_os_str)
+        )]
+        output: Option<PathBuf>,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(
+        name = "repl",
+        about = "Run commands interactively over a single connection"
+    )]
+    Repl {
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(
+            long,
+            help = "Sets how replies are printed",
+            value_name = "FORMAT",
+            default_value = "text",
+            raw(possible_values = "&[\"text\", \"json\"]"),
+            parse(try_from_str)
+        )]
+        output: OutputFormat,
+    },
 }
 
 fn main() {
@@ -90,6 +262,141 @@ fn run(opt: Opt) -> Result<()> {
             let client = KvsClient::connect(addr);
             client.and_then(move |client| client.remove(key)).wait()?;
         }
+        Command::Query { index, value, addr } => {
+            let (keys, _) = KvsClient::connect(addr)
+                .and_then(move |client| client.query_index(index, value))
+                .wait()?;
+            for key in keys {
+                println!("{}", key);
+            }
+        }
+        Command::Promote { addr } => {
+            KvsClient::connect(addr)
+                .and_then(|client| client.promote())
+                .wait()?;
+        }
+        Command::Status { addr } => {
+            let (status, _) = KvsClient::connect(addr)
+                .and_then(|client| client.status())
+                .wait()?;
+            println!("{}", status);
+        }
+        Command::Watch { prefix, addr } => {
+            let events = KvsClient::connect(addr)
+                .and_then(move |client| client.watch(prefix))
+                .flatten_stream();
+            for event in events.wait() {
+                match event? {
+                    WatchEvent::Set { key, value, .. } => println!("set {} {}", key, value),
+                    WatchEvent::Remove { key, .. } => println!("rm {}", key),
+                    WatchEvent::Lagged { missed } => println!("lagged: {} events missed", missed),
+                }
+            }
+        }
+        Command::Load {
+            file,
+            format,
+            batch_size,
+            concurrency,
+            addr,
+        } => {
+            let format = format.unwrap_or_else(|| PairFormat::from_path(&file));
+            let options = LoadOptions::new()
+                .batch_size(batch_size)
+                .concurrency(concurrency);
+            run_load(&file, format, options, addr)?;
+        }
+        Command::Dump {
+            prefix,
+            format,
+            output,
+            addr,
+        } => {
+            let count = match output {
+                Some(path) => {
+                    bulk::dump(addr, prefix, BufWriter::new(File::create(path)?), format)?
+                }
+                None => bulk::dump(addr, prefix, io::stdout().lock(), format)?,
+            };
+            eprintln!("{} pairs dumped", count);
+        }
+        Command::Repl { addr, output } => run_repl(addr, output)?,
+    }
+    Ok(())
+}
+
+fn run_load(file: &Path, format: PairFormat, options: LoadOptions, addr: SocketAddr) -> Result<()> {
+    let reader: Box<dyn Read + Send> = if file == Path::new("-") {
+        Box::new(io::stdin())
+    } else {
+        Box::new(File::open(file)?)
+    };
+    let start = Instant::now();
+    let mut reported = start;
+    let count = bulk::load(addr, PairReader::new(reader, format), options, |loaded| {
+        if reported.elapsed() >= PROGRESS_INTERVAL {
+            reported = Instant::now();
+            let rate = loaded as f64 / start.elapsed().as_secs_f64();
+            eprintln!("{} pairs loaded ({:.0} pairs/s)", loaded, rate);
+        }
+    })?;
+    eprintln!(
+        "{} pairs loaded in {:.2}s",
+        count,
+        start.elapsed().as_secs_f64()
+    );
+    Ok(())
+}
+
+fn run_repl(addr: SocketAddr, output: OutputFormat) -> Result<()> {
+    let mut editor = DefaultEditor::new().map_err(readline_error)?;
+    let history = env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE));
+    if let Some(history) = &history {
+        // there is no history yet on the first run
+        let _ = editor.load_history(history);
+    }
+
+    let prompt = format!("{}> ", addr);
+    let mut session = Session::new(addr);
+    loop {
+        let line = match editor.readline(&prompt) {
+            Ok(line) => line,
+            // Ctrl-C only discards the current line
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(readline_error(e)),
+        };
+        if !line.trim().is_empty() {
+            // kept even if invalid, so that it can be fixed
+            let _ = editor.add_history_entry(line.as_str());
+        }
+        let input = match repl::parse(&line) {
+            Ok(Some(input)) => input,
+            Ok(None) => continue,
+            Err(e) => {
+                eprintln!("{}", e);
+                continue;
+            }
+        };
+        match input {
+            Input::Command(command) => {
+                let start = Instant::now();
+                let result = session.execute(command);
+                println!("{}", repl::format_reply(&result, start.elapsed(), output));
+            }
+            Input::Help => println!("{}", repl::help()),
+            Input::Quit => break,
+        }
+    }
+
+    if let Some(history) = &history {
+        if let Err(e) = editor.save_history(history) {
+            eprintln!("Failed to save the history: {}", e);
+        }
     }
     Ok(())
 }
+
+fn readline_error(e: ReadlineError) -> KvsError {
+    KvsError::StringError(format!("{}", e))
+}
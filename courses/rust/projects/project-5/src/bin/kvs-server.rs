@@ -1,101 +1,339 @@
 #[macro_use]
 extern crate log;
-#[macro_use]
-extern crate clap;
 
+use kvs::config::{EngineKind, PoolKind, ServerConfig};
 use kvs::thread_pool::*;
-use kvs::{KvStore, KvsEngine, KvsServer, Result, SledKvsEngine};
-use log::LevelFilter;
+use kvs::{
+    Compression, EncryptionKey, KvStoreOptions, KvsEngine, KvsError, KvsServer,
+    LsmKvsEngineOptions, MemKvsEngine, Result, SledKvsEngine,
+};
+use log::{LevelFilter, Log, Metadata, Record};
 use std::env;
-use std::env::current_dir;
 use std::fs;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::sync::RwLock;
 use structopt::StructOpt;
 
-const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
-const DEFAULT_ENGINE: Engine = Engine::kvs;
-
 #[derive(StructOpt, Debug)]
 #[structopt(name = "kvs-server")]
 struct Opt {
     #[structopt(
         long,
-        help = "Sets the listening address",
+        help = "Reads the settings from a TOML file, which KVS_* environment variables and flags override",
+        value_name = "FILE",
+        parse(from_os_str)
+    )]
+    config: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "Sets the listening address [default: 127.0.0.1:4000]",
         value_name = "IP:PORT",
-        raw(default_value = "DEFAULT_LISTENING_ADDRESS"),
         parse(try_from_str)
     )]
-    addr: SocketAddr,
+    addr: Option<SocketAddr>,
+    #[structopt(
+        long = "data-dir",
+        help = "Sets the directory of the data [default: the current directory]",
+        value_name = "DIR",
+        parse(from_os_str)
+    )]
+    data_dir: Option<PathBuf>,
     #[structopt(
         long,
         help = "Sets the storage engine",
         value_name = "ENGINE-NAME",
-        raw(possible_values = "&Engine::variants()")
+        raw(possible_values = "&[\"kvs\", \"sled\", \"lsm\", \"memory\"]"),
+        parse(try_from_str)
     )]
-    engine: Option<Engine>,
-}
-
-arg_enum! {
-    #[allow(non_camel_case_types)]
-    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
-    enum Engine {
-        kvs,
-        sled
-    }
+    engine: Option<EngineKind>,
+    #[structopt(
+        long = "cache-size",
+        help = "Sets the size in bytes of the value cache of the kvs engine",
+        value_name = "BYTES"
+    )]
+    cache_size: Option<u64>,
+    #[structopt(
+        long,
+        help = "Sets the compression of the values written by the kvs engine",
+        value_name = "ALGORITHM",
+        raw(possible_values = "&[\"none\", \"lz4\", \"zstd\"]"),
+        parse(try_from_str)
+    )]
+    compression: Option<Compression>,
+    #[structopt(
+        long = "encryption-key-file",
+        help = "Encrypts the values written by the kvs engine with the key in FILE",
+        value_name = "FILE",
+        parse(from_os_str)
+    )]
+    encryption_key_file: Option<PathBuf>,
+    #[structopt(
+        long = "old-encryption-key-file",
+        help = "Decrypts values written before a key rotation with the key in FILE",
+        value_name = "FILE",
+        number_of_values = 1,
+        parse(from_os_str)
+    )]
+    old_encryption_key_files: Vec<PathBuf>,
+    #[structopt(
+        long,
+        help = "Indexes the field at a JSON pointer of the values of the kvs engine",
+        value_name = "NAME=POINTER",
+        number_of_values = 1,
+        parse(try_from_str = "parse_index")
+    )]
+    index: Vec<(String, String)>,
+    #[structopt(
+        long = "replica-of",
+        help = "Replicates the server at IP:PORT and only serves reads until promoted",
+        value_name = "IP:PORT",
+        parse(try_from_str)
+    )]
+    replica_of: Option<SocketAddr>,
+    #[structopt(
+        long = "queue-size",
+        help = "Bounds the number of requests waiting for a thread of the engine",
+        value_name = "REQUESTS"
+    )]
+    queue_size: Option<usize>,
+    #[structopt(
+        long = "queue-policy",
+        help = "Sets what happens to requests while the queue is full [default: reject]",
+        value_name = "POLICY",
+        raw(possible_values = "&[\"block\", \"reject\", \"caller-runs\"]"),
+        parse(try_from_str)
+    )]
+    queue_policy: Option<QueuePolicy>,
 }
 
 fn main() {
-    env_logger::builder().filter_level(LevelFilter::Info).init();
-    let mut opt = Opt::from_args();
-    let res = current_engine().and_then(move |curr_engine| {
-        if opt.engine.is_none() {
-            opt.engine = curr_engine;
-        }
-        if curr_engine.is_some() && opt.engine != curr_engine {
-            error!("Wrong engine!");
-            exit(1);
-        }
-        run(opt)
-    });
-    if let Err(e) = res {
+    let opt = Opt::from_args();
+    let replica_of = opt.replica_of;
+    // started before the config is loaded to log its warnings, then rebuilt with
+    // the configured level
+    let logger = ServerLogger::init(LevelFilter::Info);
+    let config = load_config(opt);
+    if let Ok(config) = &config {
+        logger.set_level(config.log.level);
+    }
+    if let Err(e) = config.and_then(|config| run(config, replica_of)) {
         error!("{}", e);
         exit(1);
     }
 }
 
-fn run(opt: Opt) -> Result<()> {
-    let engine = opt.engine.unwrap_or(DEFAULT_ENGINE);
+// Logs through an `env_logger` whose default level can be changed, while the
+// filters of `RUST_LOG` still take precedence.
+struct ServerLogger(RwLock<env_logger::Logger>);
+
+impl ServerLogger {
+    fn init(level: LevelFilter) -> &'static ServerLogger {
+        let logger = Box::leak(Box::new(ServerLogger(RwLock::new(build_logger(level)))));
+        log::set_logger(logger).expect("The logger is only set once.");
+        log::set_max_level(logger.0.read().unwrap().filter());
+        logger
+    }
+
+    fn set_level(&self, level: LevelFilter) {
+        let logger = build_logger(level);
+        log::set_max_level(logger.filter());
+        *self.0.write().unwrap() = logger;
+    }
+}
+
+fn build_logger(level: LevelFilter) -> env_logger::Logger {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(level.to_string()))
+        .build()
+}
+
+impl Log for ServerLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.0.read().unwrap().enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        self.0.read().unwrap().log(record)
+    }
+
+    fn flush(&self) {
+        self.0.read().unwrap().flush()
+    }
+}
+
+// Merges the settings of the config file, the environment and the flags, in
+// increasing priority.
+fn load_config(opt: Opt) -> Result<ServerConfig> {
+    let mut config = match &opt.config {
+        Some(path) => ServerConfig::from_file(path)?,
+        None => ServerConfig::default(),
+    };
+    config.apply_env(env::vars())?;
+
+    if let Some(addr) = opt.addr {
+        config.addr = addr;
+    }
+    if opt.data_dir.is_some() {
+        config.data_dir = opt.data_dir;
+    }
+    if opt.engine.is_some() {
+        config.engine = opt.engine;
+    }
+    if let Some(cache_size) = opt.cache_size {
+        config.storage.cache_size = cache_size;
+    }
+    if let Some(compression) = opt.compression {
+        config.storage.compression = compression;
+    }
+    if opt.encryption_key_file.is_some() {
+        config.storage.encryption_key_file = opt.encryption_key_file;
+    }
+    config
+        .storage
+        .old_encryption_key_files
+        .extend(opt.old_encryption_key_files);
+    config.storage.indexes.extend(opt.index);
+    if opt.queue_size.is_some() {
+        config.limits.queue_size = opt.queue_size;
+    }
+    if let Some(policy) = opt.queue_policy {
+        config.limits.queue_policy = policy;
+    }
+
+    let data_dir = data_dir(&config)?;
+    let curr_engine = current_engine(&data_dir)?;
+    if config.engine.is_none() {
+        config.engine = curr_engine;
+    }
+    // the memory engine doesn't touch the directory, so it can run anywhere
+    if curr_engine.is_some()
+        && config.engine != curr_engine
+        && config.engine != Some(EngineKind::Memory)
+    {
+        return Err(KvsError::StringError("Wrong engine!".to_owned()));
+    }
+    config.validate()?;
+    Ok(config)
+}
+
+fn run(config: ServerConfig, replica_of: Option<SocketAddr>) -> Result<()> {
+    let engine = config.engine.unwrap_or(EngineKind::Kvs);
+    let data_dir = data_dir(&config)?;
     info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
     info!("Storage engine: {}", engine);
-    info!("Listening on {}", opt.addr);
+    info!("Listening on {}", config.addr);
 
     // write engine to engine file
-    fs::write(current_dir()?.join("engine"), format!("{}", engine))?;
+    if engine != EngineKind::Memory {
+        info!("Data directory: {}", data_dir.display());
+        fs::create_dir_all(&data_dir)?;
+        fs::write(data_dir.join("engine"), format!("{}", engine))?;
+    }
+
+    let threads = config.thread_pool.threads;
+    let size = PoolSize::new(config.thread_pool.min_threads.unwrap_or(threads), threads);
+    let pool_kind = config.pool_kind();
+    info!("Thread pool: {}, {} threads", pool_kind, threads);
+    match pool_kind {
+        PoolKind::Naive => run_on(NaiveThreadPool::with_size(size)?, &config, replica_of),
+        PoolKind::SharedQueue => match config.limits.queue_size {
+            Some(capacity) => {
+                let policy = config.limits.queue_policy;
+                info!("Queue: {} requests, {}", capacity, policy);
+                let pool = SharedQueueThreadPool::bounded(size, capacity, policy)?;
+                run_on(pool, &config, replica_of)
+            }
+            None => run_on(SharedQueueThreadPool::with_size(size)?, &config, replica_of),
+        },
+        PoolKind::Rayon => run_on(RayonThreadPool::with_size(size)?, &config, replica_of),
+        PoolKind::WorkStealing => run_on(
+            WorkStealingThreadPool::with_size(size)?,
+            &config,
+            replica_of,
+        ),
+    }
+}
 
-    let concurrency = num_cpus::get() as u32;
-    match engine {
-        Engine::kvs => run_with(
-            KvStore::<RayonThreadPool>::open(env::current_dir()?, concurrency)?,
-            opt.addr,
+fn parse_index(s: &str) -> std::result::Result<(String, String), String> {
+    match s.find('=') {
+        Some(i) => Ok((s[..i].to_owned(), s[i + 1..].to_owned())),
+        None => Err(format!("expected NAME=POINTER, got {}", s)),
+    }
+}
+
+fn run_on<P: ThreadPool>(
+    pool: P,
+    config: &ServerConfig,
+    replica_of: Option<SocketAddr>,
+) -> Result<()> {
+    let data_dir = data_dir(config)?;
+    match config.engine.unwrap_or(EngineKind::Kvs) {
+        EngineKind::Kvs => {
+            let storage = &config.storage;
+            let mut options = KvStoreOptions::new();
+            options
+                .cache_capacity(storage.cache_size)
+                .compression(storage.compression)
+                .sync_writes(storage.sync_writes);
+            if let Some(threshold) = storage.compaction_threshold {
+                options.compaction_threshold(threshold);
+            }
+            if let Some(path) = &storage.encryption_key_file {
+                options.encryption_key(EncryptionKey::from_file(path)?);
+            }
+            for path in &storage.old_encryption_key_files {
+                options.old_encryption_key(EncryptionKey::from_file(path)?);
+            }
+            for (name, pointer) in &storage.indexes {
+                options.index(name.as_str(), pointer.as_str());
+            }
+            run_with(options.open_with_pool(data_dir, pool)?, config, replica_of)
+        }
+        EngineKind::Sled => run_with(
+            SledKvsEngine::with_pool(sled::Db::start_default(data_dir)?, pool),
+            config,
+            replica_of,
         ),
-        Engine::sled => run_with(
-            SledKvsEngine::<RayonThreadPool>::new(
-                sled::Db::start_default(env::current_dir()?)?,
-                concurrency,
-            )?,
-            opt.addr,
+        EngineKind::Lsm => run_with(
+            LsmKvsEngineOptions::new()
+                .sync_writes(config.storage.sync_writes)
+                .open_with_pool(data_dir, pool)?,
+            config,
+            replica_of,
         ),
+        EngineKind::Memory => run_with(MemKvsEngine::new(), config, replica_of),
+    }
+}
+
+fn run_with<E: KvsEngine>(
+    engine: E,
+    config: &ServerConfig,
+    replica_of: Option<SocketAddr>,
+) -> Result<()> {
+    let mut server = KvsServer::new(engine);
+    if let Some(primary) = replica_of {
+        info!("Replica of {}", primary);
+        server = server.replica_of(primary);
+    }
+    if let Some(max) = config.limits.max_connections {
+        server = server.max_connections(max);
     }
+    if let Some(size) = config.limits.watch_buffer {
+        server = server.watch_buffer(size);
+    }
+    server.run(config.addr)
 }
 
-pub fn run_with<E: KvsEngine>(engine: E, addr: SocketAddr) -> Result<()> {
-    let server = KvsServer::new(engine);
-    server.run(addr)
+fn data_dir(config: &ServerConfig) -> Result<PathBuf> {
+    match &config.data_dir {
+        Some(dir) => Ok(dir.clone()),
+        None => Ok(env::current_dir()?),
+    }
 }
 
-fn current_engine() -> Result<Option<Engine>> {
-    let engine = current_dir()?.join("engine");
+fn current_engine(data_dir: &Path) -> Result<Option<EngineKind>> {
+    let engine = data_dir.join("engine");
     if !engine.exists() {
         return Ok(None);
     }
@@ -0,0 +1,341 @@
+//! Bulk transfers of key/value pairs between files and a server.
+//!
+//! A file holds one pair per line, either as an NDJSON object like the backups of
+//! the `admin` module, or as a CSV record of two fields without a header row.
+//! `load` sends the pairs in batches over one connection, with several batches in
+//! flight, and `dump` streams the pairs of a server back into a file.
+
+use std::fmt;
+use std::io::{BufRead, BufReader, Lines, Read, Write};
+use std::mem;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread;
+
+use tokio::prelude::*;
+
+use crate::admin::Pair;
+use crate::client::{RequestSink, ResponseStream};
+use crate::common::{Request, Response};
+use crate::{KvsClient, KvsError, Result};
+
+/// The format of a file of pairs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PairFormat {
+    /// One JSON object with a `key` and a `value` per line.
+    Ndjson,
+    /// One CSV record with the key and the value, without a header row.
+    Csv,
+}
+
+impl PairFormat {
+    /// Guesses the format of a file from its name: CSV if it ends with `.csv`,
+    /// NDJSON otherwise.
+    pub fn from_path(path: &Path) -> PairFormat {
+        match path.extension() {
+            Some(ext) if ext.eq_ignore_ascii_case("csv") => PairFormat::Csv,
+            _ => PairFormat::Ndjson,
+        }
+    }
+}
+
+impl fmt::Display for PairFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PairFormat::Ndjson => f.write_str("ndjson"),
+            PairFormat::Csv => f.write_str("csv"),
+        }
+    }
+}
+
+impl FromStr for PairFormat {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<PairFormat> {
+        match s {
+            "ndjson" => Ok(PairFormat::Ndjson),
+            "csv" => Ok(PairFormat::Csv),
+            _ => Err(KvsError::StringError(format!("Unknown pair format: {}", s))),
+        }
+    }
+}
+
+/// An iterator over the pairs of a file.
+///
+/// Empty NDJSON lines are skipped. An invalid pair is an error naming its line.
+pub struct PairReader<R: Read> {
+    inner: ReaderInner<R>,
+}
+
+enum ReaderInner<R: Read> {
+    Ndjson {
+        lines: Lines<BufReader<R>>,
+        line: u64,
+    },
+    Csv(csv::StringRecordsIntoIter<R>),
+}
+
+impl<R: Read> PairReader<R> {
+    /// Creates a reader of the pairs in `reader`, written in `format`.
+    pub fn new(reader: R, format: PairFormat) -> PairReader<R> {
+        let inner = match format {
+            PairFormat::Ndjson => ReaderInner::Ndjson {
+                lines: BufReader::new(reader).lines(),
+                line: 0,
+            },
+            PairFormat::Csv => ReaderInner::Csv(
+                csv::ReaderBuilder::new()
+                    .has_headers(false)
+                    .flexible(true)
+                    .from_reader(reader)
+                    .into_records(),
+            ),
+        };
+        PairReader { inner }
+    }
+}
+
+impl<R: Read> Iterator for PairReader<R> {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Result<(String, String)>> {
+        match &mut self.inner {
+            ReaderInner::Ndjson { lines, line } => loop {
+                let text = match lines.next()? {
+                    Ok(text) => text,
+                    Err(e) => return Some(Err(e.into())),
+                };
+                *line += 1;
+                if text.trim().is_empty() {
+                    continue;
+                }
+                let pair = serde_json::from_str(&text)
+                    .map(|Pair { key, value }| (key, value))
+                    .map_err(|e| invalid_pair(*line, e));
+                return Some(pair);
+            },
+            ReaderInner::Csv(records) => {
+                let record = match records.next()? {
+                    Ok(record) => record,
+                    Err(e) => return Some(Err(e.into())),
+                };
+                if record.len() != 2 {
+                    let line = record.position().map_or(0, |pos| pos.line());
+                    let msg = format!("{} fields instead of 2", record.len());
+                    return Some(Err(invalid_pair(line, msg)));
+                }
+                Some(Ok((record[0].to_owned(), record[1].to_owned())))
+            }
+        }
+    }
+}
+
+fn invalid_pair(line: u64, e: impl fmt::Display) -> KvsError {
+    KvsError::StringError(format!("Invalid pair at line {}: {}", line, e))
+}
+
+/// Writes pairs to a file.
+pub struct PairWriter<W: Write> {
+    inner: WriterInner<W>,
+}
+
+enum WriterInner<W: Write> {
+    Ndjson(W),
+    // boxed, the writer holding its own buffer
+    Csv(Box<csv::Writer<W>>),
+}
+
+impl<W: Write> PairWriter<W> {
+    /// Creates a writer of pairs in `format` to `writer`.
+    pub fn new(writer: W, format: PairFormat) -> PairWriter<W> {
+        let inner = match format {
+            PairFormat::Ndjson => WriterInner::Ndjson(writer),
+            PairFormat::Csv => WriterInner::Csv(Box::new(
+                csv::WriterBuilder::new()
+                    .has_headers(false)
+                    .from_writer(writer),
+            )),
+        };
+        PairWriter { inner }
+    }
+
+    /// Writes a pair.
+    pub fn write(&mut self, key: String, value: String) -> Result<()> {
+        match &mut self.inner {
+            WriterInner::Ndjson(writer) => {
+                serde_json::to_writer(&mut *writer, &Pair { key, value })?;
+                writer.write_all(b"\n")?;
+            }
+            WriterInner::Csv(writer) => writer.write_record(&[key, value])?,
+        }
+        Ok(())
+    }
+
+    /// Flushes the pairs written so far.
+    pub fn flush(&mut self) -> Result<()> {
+        match &mut self.inner {
+            WriterInner::Ndjson(writer) => writer.flush()?,
+            WriterInner::Csv(writer) => writer.flush()?,
+        }
+        Ok(())
+    }
+}
+
+/// How `load` sends pairs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoadOptions {
+    batch_size: usize,
+    concurrency: usize,
+}
+
+impl LoadOptions {
+    /// Creates options sending batches of 100 pairs, with at most 8 batches in
+    /// flight.
+    pub fn new() -> LoadOptions {
+        LoadOptions {
+            batch_size: 100,
+            concurrency: 8,
+        }
+    }
+
+    /// Sets the number of pairs sent in each request.
+    pub fn batch_size(mut self, batch_size: usize) -> LoadOptions {
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// Sets the number of requests sent before their responses are received.
+    pub fn concurrency(mut self, concurrency: usize) -> LoadOptions {
+        self.concurrency = concurrency;
+        self
+    }
+}
+
+impl Default for LoadOptions {
+    fn default() -> LoadOptions {
+        LoadOptions::new()
+    }
+}
+
+/// Sets all `pairs` in the server at `addr`, and returns their number.
+///
+/// The pairs are read in another thread and sent in batches, which the server
+/// sets in order. `progress` is called with the number of pairs set so far each
+/// time the server is done with a batch.
+///
+/// # Errors
+///
+/// It stops at the first invalid pair or error of the server, after the batches
+/// sent before are done.
+pub fn load<I, F>(addr: SocketAddr, pairs: I, options: LoadOptions, mut progress: F) -> Result<u64>
+where
+    I: Iterator<Item = Result<(String, String)>> + Send + 'static,
+    F: FnMut(u64),
+{
+    if options.batch_size == 0 || options.concurrency == 0 {
+        return Err(KvsError::StringError(
+            "The batch size and the concurrency must be positive".to_owned(),
+        ));
+    }
+    let (requests, responses) = KvsClient::connect(addr).wait()?.into_parts();
+    // sizes of the batches sent and not done yet, including the one whose response
+    // is awaited, which is no longer in the channel
+    let (in_flight_tx, in_flight_rx) = mpsc::sync_channel(options.concurrency - 1);
+    let sender =
+        thread::spawn(move || send_batches(requests, pairs, options.batch_size, in_flight_tx));
+    let received = receive_batches(responses, in_flight_rx, &mut progress);
+    // the sender stops once the receiving end of the channel is dropped
+    let sent = sender
+        .join()
+        .map_err(|_| KvsError::StringError("The sending thread panicked".to_owned()))?;
+    let loaded = received?;
+    sent?;
+    Ok(loaded)
+}
+
+fn send_batches<I>(
+    mut requests: RequestSink,
+    pairs: I,
+    batch_size: usize,
+    in_flight: SyncSender<u64>,
+) -> Result<()>
+where
+    I: Iterator<Item = Result<(String, String)>>,
+{
+    let mut batch = Vec::with_capacity(batch_size);
+    for pair in pairs {
+        batch.push(pair?);
+        if batch.len() == batch_size {
+            let full = mem::replace(&mut batch, Vec::with_capacity(batch_size));
+            requests = match send_batch(requests, full, &in_flight)? {
+                Some(requests) => requests,
+                None => return Ok(()),
+            };
+        }
+    }
+    if !batch.is_empty() {
+        send_batch(requests, batch, &in_flight)?;
+    }
+    Ok(())
+}
+
+// Returns `None` if the receiver has stopped, which reports why.
+fn send_batch(
+    requests: RequestSink,
+    pairs: Vec<(String, String)>,
+    in_flight: &SyncSender<u64>,
+) -> Result<Option<RequestSink>> {
+    if in_flight.send(pairs.len() as u64).is_err() {
+        return Ok(None);
+    }
+    let requests = requests
+        .send(Request::SetBatch { pairs })
+        .wait()
+        .map_err(KvsError::from)?;
+    Ok(Some(requests))
+}
+
+fn receive_batches<F: FnMut(u64)>(
+    responses: ResponseStream,
+    in_flight: Receiver<u64>,
+    progress: &mut F,
+) -> Result<u64> {
+    let mut responses = responses.map_err(KvsError::from).wait();
+    let mut loaded = 0;
+    for size in in_flight {
+        match responses.next() {
+            Some(Ok(Response::SetBatch)) => {}
+            Some(Ok(Response::Err(msg))) => return Err(KvsError::StringError(msg)),
+            Some(Ok(_)) => return Err(KvsError::StringError("Invalid response".to_owned())),
+            Some(Err(e)) => return Err(e),
+            None => return Err(KvsError::StringError("No response received".to_owned())),
+        }
+        loaded += size;
+        progress(loaded);
+    }
+    Ok(loaded)
+}
+
+/// Writes all pairs of the server at `addr` whose key starts with `prefix` to
+/// `writer` in `format`, in key order, and returns their number.
+pub fn dump<W: Write>(
+    addr: SocketAddr,
+    prefix: String,
+    writer: W,
+    format: PairFormat,
+) -> Result<u64> {
+    let mut writer = PairWriter::new(writer, format);
+    let pairs = KvsClient::connect(addr)
+        .map(move |client| client.scan_stream(prefix))
+        .flatten_stream();
+    let mut count = 0;
+    for pair in pairs.wait() {
+        let (key, value) = pair?;
+        writer.write(key, value)?;
+        count += 1;
+    }
+    writer.flush()?;
+    Ok(count)
+}
@@ -1,5 +1,5 @@
 use crate::common::{Request, Response};
-use crate::KvsError;
+use crate::{KvsError, ServerStatus, WatchEvent};
 use std::net::SocketAddr;
 use tokio::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
 use tokio::io::{ReadHalf, WriteHalf};
@@ -7,10 +7,15 @@ use tokio::net::TcpStream;
 use tokio::prelude::*;
 use tokio_serde_json::{ReadJson, WriteJson};
 
+pub(crate) type ResponseStream =
+    ReadJson<FramedRead<ReadHalf<TcpStream>, LengthDelimitedCodec>, Response>;
+pub(crate) type RequestSink =
+    WriteJson<FramedWrite<WriteHalf<TcpStream>, LengthDelimitedCodec>, Request>;
+
 /// Key value store client
 pub struct KvsClient {
-    read_json: ReadJson<FramedRead<ReadHalf<TcpStream>, LengthDelimitedCodec>, Response>,
-    write_json: WriteJson<FramedWrite<WriteHalf<TcpStream>, LengthDelimitedCodec>, Request>,
+    read_json: ResponseStream,
+    write_json: RequestSink,
 }
 
 impl KvsClient {
@@ -58,13 +63,169 @@ impl KvsClient {
         self.send_request(Request::Remove { key })
             .and_then(move |(resp, client)| match resp {
                 Some(Response::Remove) => Ok(client),
+                Some(Response::KeyNotFound) => Err(KvsError::KeyNotFound),
+                Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
+                Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
+                None => Err(KvsError::StringError("No response received".to_owned())),
+            })
+    }
+
+    /// Get all key/value pairs whose key starts with `prefix`, in key order.
+    pub fn scan(
+        self,
+        prefix: String,
+    ) -> impl Future<Item = (Vec<(String, String)>, Self), Error = KvsError> {
+        self.send_request(Request::Scan { prefix })
+            .and_then(move |(resp, client)| match resp {
+                Some(Response::Scan(pairs)) => Ok((pairs, client)),
+                Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
+                Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
+                None => Err(KvsError::StringError("No response received".to_owned())),
+            })
+    }
+
+    /// Get the values of the given keys, one request after the other on this
+    /// connection.
+    pub fn get_batch(
+        self,
+        keys: Vec<String>,
+    ) -> impl Future<Item = (Vec<Option<String>>, Self), Error = KvsError> {
+        stream::iter_ok(keys).fold((Vec::new(), self), |(mut values, client), key| {
+            client.get(key).map(move |(value, client)| {
+                values.push(value);
+                (values, client)
+            })
+        })
+    }
+
+    /// Set the values of the given keys in a single request.
+    ///
+    /// The server sets the pairs in order, so the last value of a key wins. The
+    /// pairs before the first error are set.
+    pub fn set_batch(
+        self,
+        pairs: Vec<(String, String)>,
+    ) -> impl Future<Item = Self, Error = KvsError> {
+        self.send_request(Request::SetBatch { pairs })
+            .and_then(move |(resp, client)| match resp {
+                Some(Response::SetBatch) => Ok(client),
+                Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
+                Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
+                None => Err(KvsError::StringError("No response received".to_owned())),
+            })
+    }
+
+    /// Get all key/value pairs whose key starts with `prefix`, in key order.
+    ///
+    /// Unlike `scan`, the pairs arrive in chunks, so there is no limit on their
+    /// number. The connection is closed once the stream ends.
+    pub fn scan_stream(
+        self,
+        prefix: String,
+    ) -> impl Stream<Item = (String, String), Error = KvsError> {
+        let read_json = self.read_json;
+        self.write_json
+            .send(Request::ScanStream { prefix })
+            .map_err(KvsError::from)
+            .map(move |_| {
+                let closed = KvsError::StringError("The scan ended early".to_owned());
+                read_json
+                    .map_err(KvsError::from)
+                    .chain(stream::once(Err(closed)))
+                    .take_while(|resp| Ok(!matches!(resp, Response::ScanDone)))
+                    .and_then(|resp| match resp {
+                        Response::ScanChunk(pairs) => Ok(stream::iter_ok(pairs)),
+                        Response::Err(msg) => Err(KvsError::StringError(msg)),
+                        _ => Err(KvsError::StringError("Invalid response".to_owned())),
+                    })
+                    .flatten()
+            })
+            .flatten_stream()
+    }
+
+    /// Watch the changes of the keys starting with `prefix`.
+    ///
+    /// Returns the stream of events once the request is sent. The connection is
+    /// dedicated to the events afterwards, so no other request can be sent. The
+    /// stream ends when the server closes the connection.
+    pub fn watch(
+        self,
+        prefix: String,
+    ) -> impl Future<Item = impl Stream<Item = WatchEvent, Error = KvsError>, Error = KvsError>
+    {
+        let read_json = self.read_json;
+        self.write_json
+            .send(Request::Watch { prefix })
+            .map(move |_| {
+                read_json
+                    .map_err(KvsError::from)
+                    .and_then(|resp| match resp {
+                        Response::Watch(event) => Ok(event),
+                        Response::Err(msg) => Err(KvsError::StringError(msg)),
+                        _ => Err(KvsError::StringError("Invalid response".to_owned())),
+                    })
+            })
+            .map_err(|e| e.into())
+    }
+
+    /// Get the keys whose value has `value` as field of the secondary index `name`.
+    pub fn query_index(
+        self,
+        name: String,
+        value: String,
+    ) -> impl Future<Item = (Vec<String>, Self), Error = KvsError> {
+        self.send_request(Request::QueryIndex { name, value })
+            .and_then(move |(resp, client)| match resp {
+                Some(Response::QueryIndex(keys)) => Ok((keys, client)),
+                Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
+                Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
+                None => Err(KvsError::StringError("No response received".to_owned())),
+            })
+    }
+
+    /// Turn a replica into a primary which accepts writes.
+    ///
+    /// It has no effect on a primary.
+    pub fn promote(self) -> impl Future<Item = Self, Error = KvsError> {
+        self.send_request(Request::Promote)
+            .and_then(move |(resp, client)| match resp {
+                Some(Response::Promote) => Ok(client),
+                Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
+                Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
+                None => Err(KvsError::StringError("No response received".to_owned())),
+            })
+    }
+
+    /// Get the replication role of the server.
+    pub fn status(self) -> impl Future<Item = (ServerStatus, Self), Error = KvsError> {
+        self.send_request(Request::Status)
+            .and_then(move |(resp, client)| match resp {
+                Some(Response::Status(status)) => Ok((status, client)),
                 Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
                 Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
                 None => Err(KvsError::StringError("No response received".to_owned())),
             })
     }
 
-    fn send_request(
+    /// Start replicating the data of the server, see the `replication` module.
+    pub(crate) fn replicate(
+        self,
+    ) -> impl Future<Item = impl Stream<Item = Response, Error = KvsError>, Error = KvsError> {
+        let read_json = self.read_json;
+        self.write_json
+            .send(Request::Replicate)
+            .map(move |_| read_json.map_err(KvsError::from))
+            .map_err(|e| e.into())
+    }
+
+    /// Splits the connection, to send requests without waiting for the responses.
+    pub(crate) fn into_parts(self) -> (RequestSink, ResponseStream) {
+        (self.write_json, self.read_json)
+    }
+
+    /// Sends `req` and returns the response, which is `None` if the server closed
+    /// the connection.
+    pub(crate) fn send_request(
         self,
         req: Request,
     ) -> impl Future<Item = (Option<Response>, Self), Error = KvsError> {
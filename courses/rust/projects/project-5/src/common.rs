@@ -1,16 +1,37 @@
+use crate::{ServerStatus, WatchEvent};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Request {
     Get { key: String },
     Set { key: String, value: String },
+    SetBatch { pairs: Vec<(String, String)> },
     Remove { key: String },
+    Scan { prefix: String },
+    QueryIndex { name: String, value: String },
+    ScanStream { prefix: String },
+    Watch { prefix: String },
+    Replicate,
+    Promote,
+    Status,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Response {
     Get(Option<String>),
     Set,
+    SetBatch,
     Remove,
+    Scan(Vec<(String, String)>),
+    QueryIndex(Vec<String>),
+    ScanChunk(Vec<(String, String)>),
+    ScanDone,
+    Watch(WatchEvent),
+    Snapshot { key: String, value: String },
+    SnapshotDone { seq: u64 },
+    Heartbeat { published: u64 },
+    Promote,
+    Status(ServerStatus),
+    KeyNotFound,
     Err(String),
 }
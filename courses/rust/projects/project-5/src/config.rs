@@ -0,0 +1,448 @@
+//! Configuration of `kvs-server`.
+//!
+//! Settings come from a TOML file, then from `KVS_*` environment variables, which
+//! override the file. Every setting has a default, so a file only needs the ones
+//! it changes:
+//!
+//! ```toml
+//! addr = "127.0.0.1:4000"
+//! data-dir = "/var/lib/kvs"
+//! engine = "kvs"
+//!
+//! [thread-pool]
+//! kind = "shared-queue"
+//! threads = 8
+//! min-threads = 2
+//!
+//! [storage]
+//! cache-size = 67108864
+//! compression = "lz4"
+//! compaction-threshold = 4194304
+//! sync-writes = true
+//!
+//! [storage.indexes]
+//! city = "/address/city"
+//!
+//! [limits]
+//! queue-size = 1000
+//! queue-policy = "reject"
+//! max-connections = 512
+//!
+//! [log]
+//! level = "warn"
+//! ```
+//!
+//! `ServerConfig::validate` checks the settings which don't make sense together,
+//! such as a queue size with a pool that has no bounded queue.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use log::LevelFilter;
+use serde::{Deserialize, Deserializer};
+
+use crate::thread_pool::QueuePolicy;
+use crate::{Compression, KvsError, Result};
+
+const DEFAULT_ADDR: &str = "127.0.0.1:4000";
+
+/// The settings of a server.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ServerConfig {
+    /// The address the server listens on.
+    pub addr: SocketAddr,
+    /// The directory holding the data, the current directory if `None`.
+    pub data_dir: Option<PathBuf>,
+    /// The storage engine. If `None`, the engine which wrote the data directory,
+    /// or `kvs` for a new directory.
+    pub engine: Option<EngineKind>,
+    /// The threads running the operations of the engine.
+    pub thread_pool: ThreadPoolConfig,
+    /// The settings of the `kvs` engine.
+    pub storage: StorageConfig,
+    /// Bounds on the load the server accepts.
+    pub limits: LimitsConfig,
+    /// What the server logs.
+    pub log: LogConfig,
+}
+
+/// The settings of the thread pool of the engine.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ThreadPoolConfig {
+    /// The kind of pool. If `None`, a shared queue pool when `limits.queue-size`
+    /// is set, a rayon pool otherwise.
+    pub kind: Option<PoolKind>,
+    /// The number of threads, or the maximum for a pool which sizes itself.
+    /// Defaults to the number of CPUs.
+    pub threads: u32,
+    /// The number of threads a shared queue pool keeps when idle, `threads` if
+    /// `None`.
+    pub min_threads: Option<u32>,
+}
+
+/// The settings of the `kvs` engine, which the other engines don't support, but
+/// for `sync-writes` which the `lsm` engine supports as well.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct StorageConfig {
+    /// The size in bytes of the value cache, 0 to disable it.
+    pub cache_size: u64,
+    /// The compression of the values written.
+    pub compression: Compression,
+    /// The bytes of stale records which trigger a compaction, 1 MiB if `None`.
+    pub compaction_threshold: Option<u64>,
+    /// Whether writes are synced to the disk before they return.
+    pub sync_writes: bool,
+    /// The file of the key encrypting the values written.
+    pub encryption_key_file: Option<PathBuf>,
+    /// The files of keys which only decrypt values written before a rotation.
+    pub old_encryption_key_files: Vec<PathBuf>,
+    /// The JSON pointers of the secondary indexes of the values, by index name.
+    pub indexes: BTreeMap<String, String>,
+}
+
+/// Bounds on the load of a server.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct LimitsConfig {
+    /// The number of requests which may wait for a thread of the engine,
+    /// unbounded if `None`. Only a shared queue pool has a bounded queue.
+    pub queue_size: Option<usize>,
+    /// What happens to requests while the queue is full.
+    #[serde(deserialize_with = "from_str")]
+    pub queue_policy: QueuePolicy,
+    /// The number of clients connected at the same time, unbounded if `None`.
+    pub max_connections: Option<usize>,
+    /// The number of events kept for a slow watcher, 1024 if `None`.
+    pub watch_buffer: Option<usize>,
+}
+
+/// The settings of the log.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct LogConfig {
+    /// The most verbose level logged, `info` by default.
+    #[serde(deserialize_with = "from_str")]
+    pub level: LevelFilter,
+}
+
+/// A storage engine of the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EngineKind {
+    /// `KvStore`, the log-structured store of this crate.
+    Kvs,
+    /// `SledKvsEngine`.
+    Sled,
+    /// `LsmKvsEngine`.
+    Lsm,
+    /// `MemKvsEngine`, which keeps nothing on disk.
+    Memory,
+}
+
+/// A kind of thread pool, from the `thread_pool` module.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PoolKind {
+    /// `NaiveThreadPool`, which starts a thread for each operation.
+    Naive,
+    /// `SharedQueueThreadPool`.
+    SharedQueue,
+    /// `RayonThreadPool`.
+    Rayon,
+    /// `WorkStealingThreadPool`.
+    WorkStealing,
+}
+
+impl ServerConfig {
+    /// Reads a configuration from a TOML file. The settings missing from the file
+    /// keep their default.
+    ///
+    /// # Errors
+    ///
+    /// It returns an error naming the file if it can't be read or parsed, or holds
+    /// an unknown setting.
+    pub fn from_file(path: &Path) -> Result<ServerConfig> {
+        let text = fs::read_to_string(path).map_err(|e| {
+            KvsError::StringError(format!("Can't read config file {}: {}", path.display(), e))
+        })?;
+        toml::from_str(&text).map_err(|e| {
+            KvsError::StringError(format!("Invalid config file {}: {}", path.display(), e))
+        })
+    }
+
+    /// Overrides settings with the `KVS_*` variables among `vars`, usually
+    /// `std::env::vars()`.
+    ///
+    /// The variables are `KVS_ADDR`, `KVS_DATA_DIR`, `KVS_ENGINE`,
+    /// `KVS_THREAD_POOL`, `KVS_THREADS`, `KVS_MIN_THREADS`, `KVS_CACHE_SIZE`,
+    /// `KVS_COMPRESSION`, `KVS_COMPACTION_THRESHOLD`, `KVS_SYNC_WRITES`,
+    /// `KVS_ENCRYPTION_KEY_FILE`, `KVS_QUEUE_SIZE`, `KVS_QUEUE_POLICY`,
+    /// `KVS_MAX_CONNECTIONS`, `KVS_WATCH_BUFFER` and `KVS_LOG_LEVEL`. Other `KVS_*`
+    /// variables are ignored with a warning.
+    ///
+    /// # Errors
+    ///
+    /// It returns an error naming the variable if its value is invalid.
+    pub fn apply_env<I>(&mut self, vars: I) -> Result<()>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        for (name, value) in vars {
+            if !name.starts_with("KVS_") {
+                continue;
+            }
+            let value = value.as_str();
+            match name.as_str() {
+                "KVS_ADDR" => self.addr = parse_env(&name, value)?,
+                "KVS_DATA_DIR" => self.data_dir = Some(value.into()),
+                "KVS_ENGINE" => self.engine = Some(parse_env(&name, value)?),
+                "KVS_THREAD_POOL" => self.thread_pool.kind = Some(parse_env(&name, value)?),
+                "KVS_THREADS" => self.thread_pool.threads = parse_env(&name, value)?,
+                "KVS_MIN_THREADS" => self.thread_pool.min_threads = Some(parse_env(&name, value)?),
+                "KVS_CACHE_SIZE" => self.storage.cache_size = parse_env(&name, value)?,
+                "KVS_COMPRESSION" => self.storage.compression = parse_env(&name, value)?,
+                "KVS_COMPACTION_THRESHOLD" => {
+                    self.storage.compaction_threshold = Some(parse_env(&name, value)?)
+                }
+                "KVS_SYNC_WRITES" => self.storage.sync_writes = parse_env(&name, value)?,
+                "KVS_ENCRYPTION_KEY_FILE" => self.storage.encryption_key_file = Some(value.into()),
+                "KVS_QUEUE_SIZE" => self.limits.queue_size = Some(parse_env(&name, value)?),
+                "KVS_QUEUE_POLICY" => self.limits.queue_policy = parse_env(&name, value)?,
+                "KVS_MAX_CONNECTIONS" => {
+                    self.limits.max_connections = Some(parse_env(&name, value)?)
+                }
+                "KVS_WATCH_BUFFER" => self.limits.watch_buffer = Some(parse_env(&name, value)?),
+                "KVS_LOG_LEVEL" => self.log.level = parse_env(&name, value)?,
+                _ => warn!("Unknown environment variable {} is ignored", name),
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the kind of the thread pool, resolving the default.
+    pub fn pool_kind(&self) -> PoolKind {
+        match (self.thread_pool.kind, self.limits.queue_size) {
+            (Some(kind), _) => kind,
+            (None, Some(_)) => PoolKind::SharedQueue,
+            (None, None) => PoolKind::Rayon,
+        }
+    }
+
+    /// Checks that the settings are valid together.
+    ///
+    /// # Errors
+    ///
+    /// It returns an error naming the first setting which is out of range or not
+    /// supported by the engine or the thread pool.
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |msg: String| Err(KvsError::StringError(msg));
+        let pool = &self.thread_pool;
+        if pool.threads == 0 {
+            return invalid("thread-pool.threads must be positive".to_owned());
+        }
+        if let Some(min) = pool.min_threads {
+            if min > pool.threads {
+                return invalid(format!(
+                    "thread-pool.min-threads ({}) is above thread-pool.threads ({})",
+                    min, pool.threads
+                ));
+            }
+            if self.pool_kind() != PoolKind::SharedQueue {
+                return invalid(format!(
+                    "thread-pool.min-threads needs a shared-queue pool, not {}",
+                    self.pool_kind()
+                ));
+            }
+        }
+        if let Some(size) = self.limits.queue_size {
+            if size == 0 {
+                return invalid("limits.queue-size must be positive".to_owned());
+            }
+            if self.pool_kind() != PoolKind::SharedQueue {
+                return invalid(format!(
+                    "limits.queue-size needs a shared-queue pool, not {}",
+                    self.pool_kind()
+                ));
+            }
+        }
+        if self.limits.max_connections == Some(0) {
+            return invalid("limits.max-connections must be positive".to_owned());
+        }
+        if self.limits.watch_buffer == Some(0) {
+            return invalid("limits.watch-buffer must be positive".to_owned());
+        }
+        if self.storage.compaction_threshold == Some(0) {
+            return invalid("storage.compaction-threshold must be positive".to_owned());
+        }
+
+        let engine = self.engine.unwrap_or(EngineKind::Kvs);
+        if engine != EngineKind::Kvs {
+            let mut storage = self.storage.clone();
+            if engine == EngineKind::Lsm {
+                storage.sync_writes = false;
+            }
+            if let Some(setting) = storage.first_set() {
+                let engines = if setting == "sync-writes" {
+                    "the kvs and lsm engines"
+                } else {
+                    "the kvs engine"
+                };
+                return invalid(format!(
+                    "storage.{} only applies to {}, not {}",
+                    setting, engines, engine
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl StorageConfig {
+    // Returns the name of a setting which differs from its default.
+    fn first_set(&self) -> Option<&'static str> {
+        let default = StorageConfig::default();
+        if self.cache_size != default.cache_size {
+            Some("cache-size")
+        } else if self.compression != default.compression {
+            Some("compression")
+        } else if self.compaction_threshold.is_some() {
+            Some("compaction-threshold")
+        } else if self.sync_writes {
+            Some("sync-writes")
+        } else if self.encryption_key_file.is_some() {
+            Some("encryption-key-file")
+        } else if !self.old_encryption_key_files.is_empty() {
+            Some("old-encryption-key-files")
+        } else if !self.indexes.is_empty() {
+            Some("indexes")
+        } else {
+            None
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            addr: DEFAULT_ADDR.parse().unwrap(),
+            data_dir: None,
+            engine: None,
+            thread_pool: ThreadPoolConfig::default(),
+            storage: StorageConfig::default(),
+            limits: LimitsConfig::default(),
+            log: LogConfig::default(),
+        }
+    }
+}
+
+impl Default for ThreadPoolConfig {
+    fn default() -> ThreadPoolConfig {
+        ThreadPoolConfig {
+            kind: None,
+            threads: num_cpus::get() as u32,
+            min_threads: None,
+        }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> LimitsConfig {
+        LimitsConfig {
+            queue_size: None,
+            queue_policy: QueuePolicy::Reject,
+            max_connections: None,
+            watch_buffer: None,
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> LogConfig {
+        LogConfig {
+            level: LevelFilter::Info,
+        }
+    }
+}
+
+impl fmt::Display for EngineKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            EngineKind::Kvs => "kvs",
+            EngineKind::Sled => "sled",
+            EngineKind::Lsm => "lsm",
+            EngineKind::Memory => "memory",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for EngineKind {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<EngineKind> {
+        match s {
+            "kvs" => Ok(EngineKind::Kvs),
+            "sled" => Ok(EngineKind::Sled),
+            "lsm" => Ok(EngineKind::Lsm),
+            "memory" => Ok(EngineKind::Memory),
+            _ => Err(KvsError::StringError(format!("Unknown engine: {}", s))),
+        }
+    }
+}
+
+impl fmt::Display for PoolKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            PoolKind::Naive => "naive",
+            PoolKind::SharedQueue => "shared-queue",
+            PoolKind::Rayon => "rayon",
+            PoolKind::WorkStealing => "work-stealing",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for PoolKind {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<PoolKind> {
+        match s {
+            "naive" => Ok(PoolKind::Naive),
+            "shared-queue" => Ok(PoolKind::SharedQueue),
+            "rayon" => Ok(PoolKind::Rayon),
+            "work-stealing" => Ok(PoolKind::WorkStealing),
+            _ => Err(KvsError::StringError(format!(
+                "Unknown thread pool kind: {}",
+                s
+            ))),
+        }
+    }
+}
+
+fn parse_env<T>(name: &str, value: &str) -> Result<T>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    value
+        .parse()
+        .map_err(|e| KvsError::StringError(format!("Invalid {}={:?}: {}", name, value, e)))
+}
+
+// Deserializes a setting from a string with its `FromStr` implementation.
+fn from_str<'de, D, T>(deserializer: D) -> std::result::Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    let s = String::deserialize(deserializer)?;
+    s.parse().map_err(serde::de::Error::custom)
+}
@@ -0,0 +1,94 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{KvsError, Result};
+
+const ZSTD_LEVEL: i32 = 3;
+
+/// Compression algorithm applied to the values of a `KvStore`.
+///
+/// Every record in the log is tagged with the algorithm it was written with, so a
+/// store can be reopened with a different algorithm and still read its old records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    /// Values are stored as they are.
+    #[default]
+    None,
+    /// LZ4, which is fast but compresses less.
+    Lz4,
+    /// Zstandard, which compresses better but uses more CPU.
+    Zstd,
+}
+
+impl Compression {
+    pub(crate) fn is_none(&self) -> bool {
+        *self == Compression::None
+    }
+
+    /// Compresses `value` and returns it with the algorithm actually used.
+    ///
+    /// Compressed bytes are encoded in base64 to fit in the JSON log. The value is
+    /// kept uncompressed if that doesn't make it shorter.
+    pub(crate) fn compress(self, value: String) -> Result<(String, Compression)> {
+        let compressed = match self {
+            Compression::None => return Ok((value, Compression::None)),
+            Compression::Lz4 => lz4_flex::compress_prepend_size(value.as_bytes()),
+            Compression::Zstd => zstd::encode_all(value.as_bytes(), ZSTD_LEVEL)?,
+        };
+        let encoded = base64::encode(&compressed);
+        if encoded.len() < value.len() {
+            Ok((encoded, self))
+        } else {
+            Ok((value, Compression::None))
+        }
+    }
+
+    /// Restores a value returned by `compress`.
+    pub(crate) fn decompress(self, value: String) -> Result<String> {
+        if self.is_none() {
+            return Ok(value);
+        }
+        let corrupted = |e: &dyn fmt::Display| {
+            KvsError::Corrupted(format!("can't decompress {} value: {}", self, e))
+        };
+        let compressed = base64::decode(&value).map_err(|e| corrupted(&e))?;
+        let bytes = match self {
+            Compression::Lz4 => {
+                lz4_flex::decompress_size_prepended(&compressed).map_err(|e| corrupted(&e))?
+            }
+            Compression::Zstd => zstd::decode_all(&compressed[..]).map_err(|e| corrupted(&e))?,
+            Compression::None => unreachable!(),
+        };
+        Ok(String::from_utf8(bytes)?)
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Compression::None => "none",
+            Compression::Lz4 => "lz4",
+            Compression::Zstd => "zstd",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for Compression {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Compression> {
+        match s {
+            "none" => Ok(Compression::None),
+            "lz4" => Ok(Compression::Lz4),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(KvsError::StringError(format!(
+                "Unknown compression algorithm: {}",
+                s
+            ))),
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use sha2::{Digest, Sha256};
+
+use crate::{KvsError, Result};
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+
+/// A 256-bit key used to encrypt the values of a `KvStore` with ChaCha20-Poly1305.
+///
+/// Each key is identified by a fingerprint derived from its bytes. The fingerprint
+/// is stored with every encrypted record, so a store knows which key a record needs
+/// without trying them all.
+#[derive(Clone)]
+pub struct EncryptionKey {
+    cipher: ChaCha20Poly1305,
+    id: String,
+}
+
+impl EncryptionKey {
+    /// Creates a key from 32 bytes.
+    pub fn new(bytes: &[u8]) -> Result<EncryptionKey> {
+        if bytes.len() != KEY_LEN {
+            return Err(KvsError::StringError(format!(
+                "An encryption key must be {} bytes long, got {}",
+                KEY_LEN,
+                bytes.len()
+            )));
+        }
+        Ok(EncryptionKey {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(bytes)),
+            id: hex::encode(&Sha256::digest(bytes)[..8]),
+        })
+    }
+
+    /// Reads a key from a file holding its 32 bytes as 64 hexadecimal digits.
+    ///
+    /// Surrounding whitespace is ignored, so a key can be generated with
+    /// `openssl rand -hex 32 > key`.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<EncryptionKey> {
+        let path = path.as_ref();
+        let bytes = hex::decode(fs::read_to_string(path)?.trim()).map_err(|e| {
+            KvsError::StringError(format!("Invalid key file {}: {}", path.display(), e))
+        })?;
+        EncryptionKey::new(&bytes)
+    }
+
+    /// Returns the fingerprint of the key.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptionKey")
+            .field("id", &self.id)
+            .finish()
+    }
+}
+
+/// The keys a store encrypts new records with and decrypts old records with.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Keyring {
+    // encrypts new records, if any
+    current: Option<EncryptionKey>,
+    // previous keys, only used to decrypt
+    old: Vec<EncryptionKey>,
+}
+
+impl Keyring {
+    pub(crate) fn new(current: Option<EncryptionKey>, old: Vec<EncryptionKey>) -> Keyring {
+        Keyring { current, old }
+    }
+
+    /// Returns the fingerprint of the key new records are encrypted with.
+    pub(crate) fn current_id(&self) -> Option<&str> {
+        self.current.as_ref().map(EncryptionKey::id)
+    }
+
+    /// Returns the key with the given fingerprint.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::WrongEncryptionKey` if none of the keys match.
+    pub(crate) fn find(&self, id: &str) -> Result<&EncryptionKey> {
+        self.current
+            .iter()
+            .chain(&self.old)
+            .find(|key| key.id == id)
+            .ok_or_else(|| {
+                KvsError::WrongEncryptionKey(format!("no key with fingerprint {} was given", id))
+            })
+    }
+
+    /// Encrypts the value of `key` with the current key.
+    ///
+    /// Returns the value unchanged if there is no current key. Otherwise returns the
+    /// nonce and the ciphertext encoded in base64, with the fingerprint of the key.
+    /// `key` is authenticated with the value, so a value can't be moved to another key.
+    pub(crate) fn encrypt(&self, key: &str, value: String) -> Result<(String, Option<String>)> {
+        let current = match &self.current {
+            Some(current) => current,
+            None => return Ok((value, None)),
+        };
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: value.as_bytes(),
+            aad: key.as_bytes(),
+        };
+        let ciphertext = current
+            .cipher
+            .encrypt(&nonce, payload)
+            .map_err(|_| KvsError::StringError("Failed to encrypt value".to_owned()))?;
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        Ok((base64::encode(&sealed), Some(current.id.clone())))
+    }
+
+    /// Restores a value returned by `encrypt`.
+    pub(crate) fn decrypt(&self, id: &str, key: &str, value: &str) -> Result<String> {
+        let cipher = &self.find(id)?.cipher;
+        let corrupted = || KvsError::Corrupted(format!("can't decrypt the value of {:?}", key));
+        let sealed = base64::decode(value).map_err(|_| corrupted())?;
+        if sealed.len() < NONCE_LEN {
+            return Err(corrupted());
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let payload = Payload {
+            msg: ciphertext,
+            aad: key.as_bytes(),
+        };
+        let plaintext = cipher
+            .decrypt(Nonce::from_slice(nonce), payload)
+            .map_err(|_| corrupted())?;
+        Ok(String::from_utf8(plaintext)?)
+    }
+}
@@ -1,30 +1,40 @@
 use std::cell::RefCell;
 use std::collections::BTreeMap;
 use std::ffi::OsStr;
-use std::fs::{self, File, OpenOptions};
+use std::fs;
 use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::iter;
 use std::ops::Range;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
 
 use crossbeam::queue::ArrayQueue;
 use crossbeam_skiplist::SkipMap;
 use serde::{Deserialize, Serialize};
 use serde_json::Deserializer;
 use tokio::prelude::*;
-use tokio::sync::oneshot;
+use tokio::sync::{mpsc, oneshot};
 
-use super::KvsEngine;
+use super::compression::Compression;
+use super::encryption::{EncryptionKey, Keyring};
+use super::lru::{shard_of, Lru};
+use super::secondary::SecondaryIndexes;
+use super::storage::{FileStorage, SegmentMap, SegmentReader, SegmentWriter, Storage};
+use super::{send_scan, spawn_scan, KvsEngine};
 use crate::thread_pool::ThreadPool;
-use crate::{KvsError, Result};
+use crate::watch::{WatchEvent, WatchHub};
+use crate::{KvsError, ReplicationEvent, Result};
 
 const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+const CACHE_SHARDS: usize = 16;
 
 /// The `KvStore` stores string key/value pairs.
 ///
 /// Key/value pairs are persisted to disk in log files. Log files are named after
 /// monotonically increasing generation numbers with a `log` extension name.
+/// Other backends can keep the logs through the `Storage` trait, see
+/// `KvStoreOptions::open_storage`.
 /// A skip list in memory stores the keys and the value locations for fast query.
 ///
 /// ```rust
@@ -43,77 +53,302 @@ const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
 /// ```
 #[derive(Clone)]
 pub struct KvStore<P: ThreadPool> {
-    // directory for the log and other data
-    path: Arc<PathBuf>,
     // map generation number to the file reader
     index: Arc<SkipMap<String, CommandPos>>,
     writer: Arc<Mutex<KvStoreWriter>>,
     thread_pool: P,
-    reader_pool: Arc<ArrayQueue<KvStoreReader>>,
+    reader_pool: Arc<ReaderPool>,
+    cache: Option<Arc<ValueCache>>,
+    keyring: Arc<Keyring>,
+    secondary: Arc<RwLock<SecondaryIndexes>>,
 }
 
-impl<P: ThreadPool> KvStore<P> {
-    /// Opens a `KvStore` with the given path.
+/// Options and flags which can be used to configure how a `KvStore` is opened.
+///
+/// ```rust
+/// # use kvs::{KvStore, KvStoreOptions, Result};
+/// # use kvs::thread_pool::RayonThreadPool;
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// let store: KvStore<RayonThreadPool> = KvStoreOptions::new()
+///     .cache_capacity(64 * 1024 * 1024)
+///     .open(current_dir()?, 4)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct KvStoreOptions {
+    cache_capacity: u64,
+    mmap: bool,
+    compression: Compression,
+    encryption_key: Option<EncryptionKey>,
+    old_encryption_keys: Vec<EncryptionKey>,
+    compaction_threshold: Option<u64>,
+    sync_writes: bool,
+    indexes: Vec<(String, String)>,
+}
+
+impl KvStoreOptions {
+    /// Creates a blank set of options, which opens a store like `KvStore::open`.
+    pub fn new() -> KvStoreOptions {
+        KvStoreOptions::default()
+    }
+
+    /// Keeps up to `capacity` bytes of recently read keys and values in memory.
+    ///
+    /// `get` on a cached key returns without reading the log. The least recently
+    /// used values are evicted first. A capacity of 0, the default, disables the cache.
+    pub fn cache_capacity(&mut self, capacity: u64) -> &mut KvStoreOptions {
+        self.cache_capacity = capacity;
+        self
+    }
+
+    /// Reads sealed log files through memory maps instead of buffered file reads.
     ///
-    /// This will create a new directory if the given one does not exist.
+    /// A log file is sealed once the store writes to a newer generation. Sealed files
+    /// are mapped once and shared by all readers. The log file being written is still
+    /// read through a file handle. Disabled by default.
+    pub fn mmap(&mut self, mmap: bool) -> &mut KvStoreOptions {
+        self.mmap = mmap;
+        self
+    }
+
+    /// Compresses the values written from now on with the given algorithm.
     ///
-    /// `concurrency` specifies how many threads at most can read the database at the same time.
+    /// Records keep the algorithm they were written with, so logs written with
+    /// other settings remain readable. Compaction rewrites the live values with the
+    /// current algorithm. Defaults to `Compression::None`.
+    pub fn compression(&mut self, compression: Compression) -> &mut KvStoreOptions {
+        self.compression = compression;
+        self
+    }
+
+    /// Encrypts the values written from now on with the given key.
     ///
-    /// # Errors
+    /// Values are compressed first, then encrypted with ChaCha20-Poly1305. Keys are
+    /// stored in plain text because the index is rebuilt from them on open.
+    /// Unencrypted records remain readable, and compaction rewrites all live values
+    /// with this key. Opening a store that has records encrypted with another key
+    /// fails with `KvsError::WrongEncryptionKey`, unless that key is given with
+    /// `old_encryption_key`.
+    pub fn encryption_key(&mut self, key: EncryptionKey) -> &mut KvStoreOptions {
+        self.encryption_key = Some(key);
+        self
+    }
+
+    /// Adds a key which is only used to read records encrypted before a key rotation.
     ///
-    /// It propagates I/O or deserialization errors during the log replay.
-    pub fn open(path: impl Into<PathBuf>, concurrency: u32) -> Result<Self> {
-        let path = Arc::new(path.into());
-        fs::create_dir_all(&*path)?;
+    /// To rotate keys, open the store with the new key as `encryption_key` and the
+    /// previous one here, then call `KvStore::compact`. Afterwards the old key is no
+    /// longer needed.
+    pub fn old_encryption_key(&mut self, key: EncryptionKey) -> &mut KvStoreOptions {
+        self.old_encryption_keys.push(key);
+        self
+    }
+
+    /// Compacts the log once the stale records in it take more than `bytes`.
+    ///
+    /// A lower threshold keeps the log smaller at the cost of more frequent
+    /// rewrites. Defaults to 1 MiB.
+    pub fn compaction_threshold(&mut self, bytes: u64) -> &mut KvStoreOptions {
+        self.compaction_threshold = Some(bytes);
+        self
+    }
+
+    /// Syncs the log to the disk before a write returns.
+    ///
+    /// By default a write returns once the record is handed to the operating system,
+    /// so it survives a crash of the process but may be lost if the machine fails.
+    /// A compaction syncs the log it writes in any case, so the writes before it
+    /// are never lost.
+    pub fn sync_writes(&mut self, sync: bool) -> &mut KvStoreOptions {
+        self.sync_writes = sync;
+        self
+    }
+
+    /// Maintains a secondary index called `name` over the field at the JSON
+    /// pointer `pointer` of the values, like `/address/city`.
+    ///
+    /// `KvsEngine::query_index` returns the keys of a field. Values which aren't
+    /// JSON documents, or have no string, number or boolean at the pointer, are not
+    /// indexed. Indexes are kept in memory and rebuilt from the log on open.
+    pub fn index(
+        &mut self,
+        name: impl Into<String>,
+        pointer: impl Into<String>,
+    ) -> &mut KvStoreOptions {
+        self.indexes.push((name.into(), pointer.into()));
+        self
+    }
+
+    /// Opens a `KvStore` with the given path and these options.
+    ///
+    /// See `KvStore::open`.
+    pub fn open<P: ThreadPool>(
+        &self,
+        path: impl Into<PathBuf>,
+        concurrency: u32,
+    ) -> Result<KvStore<P>> {
+        let storage = FileStorage::open(path)?;
+        self.open_inner(Arc::new(storage), P::new(concurrency)?, concurrency as usize)
+    }
+
+    /// Opens a `KvStore` with the given path and these options, whose operations
+    /// run in `pool`.
+    ///
+    /// The store keeps a file reader open for each thread of the pool.
+    pub fn open_with_pool<P: ThreadPool>(
+        &self,
+        path: impl Into<PathBuf>,
+        pool: P,
+    ) -> Result<KvStore<P>> {
+        let idle_readers = pool.stats().threads.max(1);
+        self.open_inner(Arc::new(FileStorage::open(path)?), pool, idle_readers)
+    }
+
+    /// Opens a `KvStore` whose log segments are kept in `storage`, with these
+    /// options.
+    ///
+    /// `open` is the same with a `FileStorage` of the path. Reads only go through
+    /// memory maps if `mmap` is enabled and the storage supports them.
+    pub fn open_storage<P: ThreadPool>(
+        &self,
+        storage: impl Storage,
+        concurrency: u32,
+    ) -> Result<KvStore<P>> {
+        self.open_inner(Arc::new(storage), P::new(concurrency)?, concurrency as usize)
+    }
 
+    fn open_inner<P: ThreadPool>(
+        &self,
+        storage: Arc<dyn Storage>,
+        thread_pool: P,
+        idle_readers: usize,
+    ) -> Result<KvStore<P>> {
+        let mut secondary = SecondaryIndexes::new(&self.indexes)?;
         let mut readers = BTreeMap::new();
         let index = Arc::new(SkipMap::new());
 
-        let gen_list = sorted_gen_list(&path)?;
+        let gen_list = storage.list()?;
         let mut uncompacted = 0;
+        let keyring = Arc::new(Keyring::new(
+            self.encryption_key.clone(),
+            self.old_encryption_keys.clone(),
+        ));
 
         for &gen in &gen_list {
-            let mut reader = BufReaderWithPos::new(File::open(log_path(&path, gen))?)?;
-            uncompacted += load(gen, &mut reader, &*index)?;
+            let mut reader = BufReaderWithPos::new(storage.open(gen)?)?;
+            uncompacted += load(gen, &mut reader, &*index, &keyring)?;
             readers.insert(gen, reader);
         }
 
         let current_gen = gen_list.last().unwrap_or(&0) + 1;
-        let writer = new_log_file(&path, current_gen)?;
+        let writer = storage.create(current_gen)?;
+        storage.sync_dir()?;
         let safe_point = Arc::new(AtomicU64::new(0));
 
+        let maps = if self.mmap {
+            Some(Arc::new(LogMaps {
+                active_gen: AtomicU64::new(current_gen),
+                safe_point: AtomicU64::new(0),
+                maps: RwLock::new(BTreeMap::new()),
+            }))
+        } else {
+            None
+        };
+
         let reader = KvStoreReader {
-            path: Arc::clone(&path),
+            storage: Arc::clone(&storage),
             safe_point,
             readers: RefCell::new(BTreeMap::new()),
+            maps,
+        };
+
+        if !secondary.is_empty() {
+            for entry in index.iter() {
+                let cmd = reader.read_command(*entry.value())?;
+                if let Some(value) = cmd.into_value(&keyring)? {
+                    secondary.set(entry.key(), secondary.fields(&value));
+                }
+            }
+        }
+        let secondary = Arc::new(RwLock::new(secondary));
+
+        let cache = if self.cache_capacity > 0 {
+            Some(Arc::new(ValueCache::new(self.cache_capacity)))
+        } else {
+            None
         };
 
         let writer = KvStoreWriter {
             reader: reader.clone(),
             writer,
+            pos: 0,
+            torn: false,
+            dir_synced: true,
             current_gen,
             uncompacted,
-            path: Arc::clone(&path),
+            storage,
             index: Arc::clone(&index),
+            cache: cache.clone(),
+            compression: self.compression,
+            keyring: Arc::clone(&keyring),
+            compaction_threshold: self.compaction_threshold.unwrap_or(COMPACTION_THRESHOLD),
+            sync_writes: self.sync_writes,
+            secondary: Arc::clone(&secondary),
+            hub: WatchHub::new(),
         };
 
-        let thread_pool = P::new(concurrency)?;
-        let reader_pool = Arc::new(ArrayQueue::new(concurrency as usize));
-        for _ in 1..concurrency {
-            reader_pool.push(reader.clone()).unwrap();
-        }
-        reader_pool.push(reader).unwrap();
+        let reader_pool = Arc::new(ReaderPool::new(reader, idle_readers));
 
         Ok(KvStore {
-            path,
             index,
             writer: Arc::new(Mutex::new(writer)),
             thread_pool,
             reader_pool,
+            cache,
+            keyring,
+            secondary,
         })
     }
 }
 
+impl<P: ThreadPool> KvStore<P> {
+    /// Opens a `KvStore` with the given path.
+    ///
+    /// This will create a new directory if the given one does not exist. The store
+    /// locks the directory until it is dropped, with a `LOCK` file in it.
+    ///
+    /// `concurrency` specifies how many threads at most can read the database at the same time.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::DirectoryLocked` if another store has the directory
+    /// open, in this process or another one. It propagates I/O or deserialization
+    /// errors during the log replay.
+    pub fn open(path: impl Into<PathBuf>, concurrency: u32) -> Result<Self> {
+        KvStoreOptions::new().open(path, concurrency)
+    }
+
+    /// Clears stale entries in the log right away.
+    ///
+    /// Compaction is normally triggered automatically once enough stale data
+    /// has accumulated. This is mostly useful for offline maintenance.
+    pub fn compact(&self) -> Result<()> {
+        self.writer.lock().unwrap().compact()
+    }
+
+    /// Returns the statistics of the value cache.
+    ///
+    /// All counters are 0 if the cache is disabled.
+    pub fn cache_stats(&self) -> CacheStats {
+        match &self.cache {
+            Some(cache) => cache.stats(),
+            None => CacheStats::default(),
+        }
+    }
+}
+
 impl<P: ThreadPool> KvsEngine for KvStore<P> {
     /// Sets the value of a string key to a string.
     ///
@@ -124,17 +359,13 @@ impl<P: ThreadPool> KvsEngine for KvStore<P> {
     /// It propagates I/O or serialization errors during writing the log.
     fn set(&self, key: String, value: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
         let writer = self.writer.clone();
-        let (tx, rx) = oneshot::channel();
-        self.thread_pool.spawn(move || {
-            let res = writer.lock().unwrap().set(key, value);
-            if tx.send(res).is_err() {
-                error!("Receiving end is dropped");
-            }
+        let secondary = self.secondary.clone();
+        let task = self.thread_pool.spawn_with_handle(move |_| {
+            // parsed before taking the lock of the writer
+            let fields = secondary.read().unwrap().fields(&value);
+            writer.lock().unwrap().set(key, value, fields)
         });
-        Box::new(
-            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
-                .flatten(),
-        )
+        Box::new(task.flatten())
     }
 
     /// Gets the string value of a given string key.
@@ -143,32 +374,31 @@ impl<P: ThreadPool> KvsEngine for KvStore<P> {
     fn get(&self, key: String) -> Box<dyn Future<Item = Option<String>, Error = KvsError> + Send> {
         let reader_pool = self.reader_pool.clone();
         let index = self.index.clone();
-        let (tx, rx) = oneshot::channel();
-        self.thread_pool.spawn(move || {
-            let res = (|| {
-                if let Some(cmd_pos) = index.get(&key) {
-                    let reader = reader_pool.pop().unwrap();
-                    let res = if let Command::Set { value, .. } =
-                        reader.read_command(*cmd_pos.value())?
-                    {
-                        Ok(Some(value))
-                    } else {
-                        Err(KvsError::UnexpectedCommandType)
-                    };
-                    reader_pool.push(reader).unwrap();
-                    res
-                } else {
-                    Ok(None)
+        let cache = self.cache.clone();
+        let keyring = self.keyring.clone();
+        let task = self.thread_pool.spawn_with_handle(move |_| -> Result<_> {
+            if let Some(cmd_pos) = index.get(&key) {
+                let cmd_pos = *cmd_pos.value();
+                if let Some(value) = cache.as_ref().and_then(|c| c.get(&key, cmd_pos)) {
+                    return Ok(Some(value));
                 }
-            })();
-            if tx.send(res).is_err() {
-                error!("Receiving end is dropped");
+                let reader = reader_pool.take();
+                let cmd = reader.read_command(cmd_pos)?;
+                let res = if let Some(value) = cmd.into_value(&keyring)? {
+                    if let Some(cache) = &cache {
+                        cache.insert(key.clone(), cmd_pos, value.clone());
+                    }
+                    Ok(Some(value))
+                } else {
+                    Err(KvsError::UnexpectedCommandType)
+                };
+                reader_pool.put(reader);
+                res
+            } else {
+                Ok(None)
             }
         });
-        Box::new(
-            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
-                .flatten(),
-        )
+        Box::new(task.flatten())
     }
 
     /// Removes a given key.
@@ -180,16 +410,106 @@ impl<P: ThreadPool> KvsEngine for KvStore<P> {
     /// It propagates I/O or serialization errors during writing the log.
     fn remove(&self, key: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
         let writer = self.writer.clone();
-        let (tx, rx) = oneshot::channel();
-        self.thread_pool.spawn(move || {
-            let res = writer.lock().unwrap().remove(key);
-            if tx.send(res).is_err() {
-                error!("Receiving end is dropped");
-            }
+        let task = self
+            .thread_pool
+            .spawn_with_handle(move |_| writer.lock().unwrap().remove(key));
+        Box::new(task.flatten())
+    }
+
+    /// Returns all key/value pairs whose key starts with `prefix`, in key order.
+    ///
+    /// The pairs are read in a thread of the thread pool, which holds one
+    /// reader until the stream is exhausted or dropped.
+    fn scan(
+        &self,
+        prefix: String,
+    ) -> Box<dyn Stream<Item = (String, String), Error = KvsError> + Send> {
+        let reader_pool = self.reader_pool.clone();
+        let index = self.index.clone();
+        let keyring = self.keyring.clone();
+        spawn_scan(&self.thread_pool, move |tx| {
+            let reader = reader_pool.take();
+            let pairs = index
+                .range(prefix.clone()..)
+                .take_while(|entry| entry.key().starts_with(&prefix))
+                .map(|entry| -> Result<(String, String)> {
+                    let cmd = reader.read_command(*entry.value())?;
+                    if let Some(value) = cmd.into_value(&keyring)? {
+                        Ok((entry.key().clone(), value))
+                    } else {
+                        Err(KvsError::UnexpectedCommandType)
+                    }
+                });
+            send_scan(pairs, tx);
+            reader_pool.put(reader);
+        })
+    }
+
+    /// Returns the keys whose value has `value` as field of the secondary index
+    /// `name`, in key order.
+    ///
+    /// The secondary indexes are updated after the value of a key is visible, so
+    /// a concurrent `get` of a returned key may already see a newer value, which
+    /// no longer has that field, but never an older one.
+    ///
+    /// See `KvStoreOptions::index`.
+    fn query_index(
+        &self,
+        name: String,
+        value: String,
+    ) -> Box<dyn Future<Item = Vec<String>, Error = KvsError> + Send> {
+        let keys = self.secondary.read().unwrap().query(&name, &value);
+        Box::new(future::result(keys))
+    }
+
+    /// Returns the pairs of a compaction log written for the snapshot, followed by
+    /// the writes appended to the log after it.
+    ///
+    /// The writes are published by the writer as it appends them, so they come in
+    /// the order of the log, and the snapshot is taken under the same lock, so no
+    /// write is missing or sent twice.
+    fn replicate(
+        &self,
+        buffer: usize,
+    ) -> Box<dyn Stream<Item = ReplicationEvent, Error = KvsError> + Send> {
+        let writer = self.writer.clone();
+        let keyring = self.keyring.clone();
+        let (events_tx, events_rx) = oneshot::channel();
+        let snapshot = spawn_scan(&self.thread_pool, move |tx| {
+            let res = writer.lock().unwrap().snapshot(buffer);
+            let (snapshot, seq, events) = match res {
+                Ok(snapshot) => snapshot,
+                Err(e) => return send_scan(iter::once(Err(e)), tx),
+            };
+            let _ = events_tx.send((seq, events));
+            let pairs = Deserializer::from_reader(BufReader::new(snapshot))
+                .into_iter::<Command>()
+                .map(|cmd| -> Result<(String, String)> {
+                    let cmd = cmd?;
+                    let key = match &cmd {
+                        Command::Set { key, .. } => key.clone(),
+                        Command::Remove { .. } => return Err(KvsError::UnexpectedCommandType),
+                    };
+                    let value = cmd
+                        .into_value(&keyring)?
+                        .ok_or(KvsError::UnexpectedCommandType)?;
+                    Ok((key, value))
+                });
+            send_scan(pairs, tx);
         });
+        let writes = events_rx
+            .map_err(|_| KvsError::TaskCancelled)
+            .map(|(seq, events)| {
+                let events = events
+                    .map(ReplicationEvent::Write)
+                    .map_err(|e| KvsError::StringError(format!("{}", e)));
+                stream::once(Ok(ReplicationEvent::SnapshotDone { seq })).chain(events)
+            })
+            .flatten_stream();
         Box::new(
-            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
-                .flatten(),
+            snapshot
+                .map(|(key, value)| ReplicationEvent::Snapshot { key, value })
+                .chain(writes),
         )
     }
 }
@@ -201,10 +521,14 @@ impl<P: ThreadPool> KvsEngine for KvStore<P> {
 /// can read concurrently through multiple `KvStore`s in different
 /// threads.
 struct KvStoreReader {
-    path: Arc<PathBuf>,
+    storage: Arc<dyn Storage>,
     // generation of the latest compaction file
     safe_point: Arc<AtomicU64>,
-    readers: RefCell<BTreeMap<u64, BufReaderWithPos<File>>>,
+    // the open log files, only the ones which can't be mapped yet, like the
+    // active one, if memory maps are enabled
+    readers: RefCell<BTreeMap<u64, BufReaderWithPos<Box<dyn SegmentReader>>>>,
+    // memory maps shared by all readers, if enabled
+    maps: Option<Arc<LogMaps>>,
 }
 
 impl KvStoreReader {
@@ -215,6 +539,9 @@ impl KvStoreReader {
     /// in-memory index contains no entries with generation number less than safe_point.
     /// So we can safely close those file handles and the stale files can be deleted.
     fn close_stale_handles(&self) {
+        if let Some(maps) = &self.maps {
+            maps.close_stale(self.safe_point.load(Ordering::SeqCst));
+        }
         let mut readers = self.readers.borrow_mut();
         while !readers.is_empty() {
             let first_gen = *readers.keys().next().unwrap();
@@ -228,21 +555,30 @@ impl KvStoreReader {
     /// Read the log file at the given `CommandPos`.
     fn read_and<F, R>(&self, cmd_pos: CommandPos, f: F) -> Result<R>
     where
-        F: FnOnce(io::Take<&mut BufReaderWithPos<File>>) -> Result<R>,
+        F: FnOnce(&mut dyn Read) -> Result<R>,
     {
         self.close_stale_handles();
 
+        if let Some(maps) = &self.maps {
+            let end = cmd_pos.pos + cmd_pos.len;
+            if let Some(map) = maps.get(&*self.storage, cmd_pos.gen, end)? {
+                // the file may have been read before it was sealed, its handle
+                // isn't needed anymore
+                self.readers.borrow_mut().remove(&cmd_pos.gen);
+                return f(&mut &map[cmd_pos.pos as usize..end as usize]);
+            }
+        }
+
         let mut readers = self.readers.borrow_mut();
         // Open the file if we haven't opened it in this `KvStoreReader`.
         // We don't use entry API here because we want the errors to be propogated.
         if !readers.contains_key(&cmd_pos.gen) {
-            let reader = BufReaderWithPos::new(File::open(log_path(&self.path, cmd_pos.gen))?)?;
+            let reader = BufReaderWithPos::new(self.storage.open(cmd_pos.gen)?)?;
             readers.insert(cmd_pos.gen, reader);
         }
         let reader = readers.get_mut(&cmd_pos.gen).unwrap();
         reader.seek(SeekFrom::Start(cmd_pos.pos))?;
-        let cmd_reader = reader.take(cmd_pos.len);
-        f(cmd_reader)
+        f(&mut reader.take(cmd_pos.len))
     }
 
     // Read the log file at the given `CommandPos` and deserialize it to `Command`.
@@ -256,40 +592,159 @@ impl KvStoreReader {
 impl Clone for KvStoreReader {
     fn clone(&self) -> KvStoreReader {
         KvStoreReader {
-            path: Arc::clone(&self.path),
+            storage: Arc::clone(&self.storage),
             safe_point: Arc::clone(&self.safe_point),
             // don't use other KvStoreReader's readers
             readers: RefCell::new(BTreeMap::new()),
+            maps: self.maps.clone(),
+        }
+    }
+}
+
+/// The idle readers of a `KvStore`.
+///
+/// A new reader is created when none is idle, which happens when more tasks read
+/// at the same time than the pool has threads, e.g. when tasks run in the threads
+/// spawning them. Readers beyond the capacity are dropped when put back.
+struct ReaderPool {
+    idle: ArrayQueue<KvStoreReader>,
+    template: Mutex<KvStoreReader>,
+}
+
+impl ReaderPool {
+    fn new(reader: KvStoreReader, capacity: usize) -> ReaderPool {
+        let idle = ArrayQueue::new(capacity);
+        for _ in 0..capacity {
+            idle.push(reader.clone()).unwrap();
+        }
+        ReaderPool {
+            idle,
+            template: Mutex::new(reader),
+        }
+    }
+
+    fn take(&self) -> KvStoreReader {
+        self.idle
+            .pop()
+            .unwrap_or_else(|_| self.template.lock().unwrap().clone())
+    }
+
+    fn put(&self, reader: KvStoreReader) {
+        let _ = self.idle.push(reader);
+    }
+}
+
+/// Memory maps of the sealed log files.
+struct LogMaps {
+    // generation of the log file being written, all older files are sealed
+    active_gen: AtomicU64,
+    // the safe point of the last `close_stale`, only updated under the write lock
+    // of `maps`
+    safe_point: AtomicU64,
+    maps: RwLock<BTreeMap<u64, Arc<SegmentMap>>>,
+}
+
+impl LogMaps {
+    /// Returns a map of the log file of `gen` which is at least `end` bytes long.
+    ///
+    /// Returns `None` if the file is not sealed yet or if `storage` can't map it.
+    /// A compaction file counts as sealed while it is still being written, so it
+    /// is `None` as well when `end` is beyond what has been flushed so far.
+    fn get(&self, storage: &dyn Storage, gen: u64, end: u64) -> Result<Option<Arc<SegmentMap>>> {
+        if gen >= self.active_gen.load(Ordering::SeqCst) {
+            return Ok(None);
+        }
+        if let Some(map) = self.maps.read().unwrap().get(&gen) {
+            if map.len() as u64 >= end {
+                return Ok(Some(Arc::clone(map)));
+            }
+        }
+        match storage.map(gen)? {
+            Some(map) if map.len() as u64 >= end => {
+                let map = Arc::new(map);
+                let mut maps = self.maps.write().unwrap();
+                // a stale file is still read by a reader which looked its key up
+                // before the compaction, but its map is not kept
+                if gen >= self.safe_point.load(Ordering::SeqCst) {
+                    maps.insert(gen, Arc::clone(&map));
+                }
+                Ok(Some(map))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Drops the maps of files with generation number less than `safe_point`.
+    ///
+    /// Only takes the write lock if `safe_point` moved since the last call, as it
+    /// is called before every read.
+    fn close_stale(&self, safe_point: u64) {
+        if self.safe_point.load(Ordering::SeqCst) >= safe_point {
+            return;
+        }
+        let mut maps = self.maps.write().unwrap();
+        if self.safe_point.fetch_max(safe_point, Ordering::SeqCst) < safe_point {
+            let live = maps.split_off(&safe_point);
+            *maps = live;
         }
     }
 }
 
 struct KvStoreWriter {
     reader: KvStoreReader,
-    writer: BufWriterWithPos<File>,
+    // the segment of the current generation, to which whole records are written
+    writer: Box<dyn SegmentWriter>,
+    // end of the last record in the segment
+    pos: u64,
+    // whether a failed write may have left part of a record after `pos`
+    torn: bool,
+    // whether the creation of the segment survives a power loss
+    dir_synced: bool,
     current_gen: u64,
     // the number of bytes representing "stale" commands that could be
-    // deleted during a compaction
+    // deleted during a compaction, counted as stored, i.e. after compression
     uncompacted: u64,
-    path: Arc<PathBuf>,
+    storage: Arc<dyn Storage>,
     index: Arc<SkipMap<String, CommandPos>>,
+    cache: Option<Arc<ValueCache>>,
+    compression: Compression,
+    keyring: Arc<Keyring>,
+    compaction_threshold: u64,
+    sync_writes: bool,
+    secondary: Arc<RwLock<SecondaryIndexes>>,
+    // the writes appended to the log, for the replicas
+    hub: WatchHub,
 }
 
 impl KvStoreWriter {
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        let cmd = Command::set(key, value);
-        let pos = self.writer.pos;
-        serde_json::to_writer(&mut self.writer, &cmd)?;
-        self.writer.flush()?;
+    /// Sets `key` to `value`, whose fields for the secondary indexes are `fields`.
+    fn set(&mut self, key: String, value: String, fields: Vec<Option<String>>) -> Result<()> {
+        // only copy the value if a replica follows the log
+        let published = if self.hub.watches(&key) {
+            Some(value.clone())
+        } else {
+            None
+        };
+        let cmd = Command::set(key, value, self.compression, &self.keyring)?;
+        let cmd_pos = self.append(&cmd)?;
         if let Command::Set { key, .. } = cmd {
             if let Some(old_cmd) = self.index.get(&key) {
                 self.uncompacted += old_cmd.value().len;
             }
-            self.index
-                .insert(key, (self.current_gen, pos..self.writer.pos).into());
+            if let Some(cache) = &self.cache {
+                cache.remove(&key);
+            }
+            // the primary index first, so that a key found in a secondary index
+            // can always be read with that value or a newer one
+            self.index.insert(key.clone(), cmd_pos);
+            self.secondary.write().unwrap().set(&key, fields);
+            match published {
+                Some(value) => self.hub.set(&key, &value),
+                None => self.hub.skip(),
+            }
         }
 
-        if self.uncompacted > COMPACTION_THRESHOLD {
+        if self.uncompacted > self.compaction_threshold {
             self.compact()?;
         }
         Ok(())
@@ -298,18 +753,22 @@ impl KvStoreWriter {
     fn remove(&mut self, key: String) -> Result<()> {
         if self.index.contains_key(&key) {
             let cmd = Command::remove(key);
-            let pos = self.writer.pos;
-            serde_json::to_writer(&mut self.writer, &cmd)?;
-            self.writer.flush()?;
+            let cmd_pos = self.append(&cmd)?;
             if let Command::Remove { key } = cmd {
                 let old_cmd = self.index.remove(&key).expect("key not found");
+                if let Some(cache) = &self.cache {
+                    cache.remove(&key);
+                }
+                // after the primary index, as in `set`
+                self.secondary.write().unwrap().remove(&key);
+                self.hub.remove(&key);
                 self.uncompacted += old_cmd.value().len;
                 // the "remove" command itself can be deleted in the next compaction
                 // so we add its length to `uncompacted`
-                self.uncompacted += self.writer.pos - pos;
+                self.uncompacted += cmd_pos.len;
             }
 
-            if self.uncompacted > COMPACTION_THRESHOLD {
+            if self.uncompacted > self.compaction_threshold {
                 self.compact()?;
             }
             Ok(())
@@ -318,27 +777,103 @@ impl KvStoreWriter {
         }
     }
 
+    /// Compacts the log and returns a reader of the compaction log, with the writes
+    /// appended from now on and the `seq` of the first one.
+    ///
+    /// The compaction log holds exactly the writes before the returned ones. It is
+    /// opened right away, so a later compaction can't delete it before it is read.
+    fn snapshot(
+        &mut self,
+        buffer: usize,
+    ) -> Result<(Box<dyn SegmentReader>, u64, mpsc::Receiver<WatchEvent>)> {
+        self.compact()?;
+        // the compaction log is the one before the active one
+        let snapshot = self.storage.open(self.current_gen - 1)?;
+        let (seq, events) = self.hub.subscribe(String::new(), buffer);
+        Ok((snapshot, seq, events))
+    }
+
+    /// Appends `cmd` to the log and makes it durable, as far as the options require.
+    ///
+    /// If that fails, the segment is cut back to the end of the previous record.
+    /// Otherwise the next records would follow a partial one, and the log could
+    /// not be replayed past it.
+    fn append(&mut self, cmd: &Command) -> Result<CommandPos> {
+        self.repair_tail()?;
+        let record = serde_json::to_vec(cmd)?;
+        let sync_writes = self.sync_writes;
+        let writer = &mut self.writer;
+        let res = writer
+            .write_all(&record)
+            .and_then(|()| writer.flush())
+            .and_then(|()| if sync_writes { writer.sync() } else { Ok(()) });
+        // a synced record is lost with its segment if the creation isn't durable
+        let res = res.map_err(KvsError::from).and_then(|()| {
+            if sync_writes {
+                self.sync_dir()
+            } else {
+                Ok(())
+            }
+        });
+        if let Err(e) = res {
+            self.torn = true;
+            // a failed cut is tried again before the next write
+            let _ = self.repair_tail();
+            return Err(e);
+        }
+        let end = self.pos + record.len() as u64;
+        let cmd_pos = (self.current_gen, self.pos..end).into();
+        self.pos = end;
+        Ok(cmd_pos)
+    }
+
+    // Makes the creation of the segment durable, unless it already is.
+    fn sync_dir(&mut self) -> Result<()> {
+        if !self.dir_synced {
+            self.storage.sync_dir()?;
+            self.dir_synced = true;
+        }
+        Ok(())
+    }
+
+    // Cuts off what a failed write may have left after the last record.
+    fn repair_tail(&mut self) -> Result<()> {
+        if self.torn {
+            self.writer.truncate(self.pos)?;
+            self.torn = false;
+        }
+        Ok(())
+    }
+
     /// Clears stale entries in the log.
     fn compact(&mut self) -> Result<()> {
+        self.repair_tail()?;
+        // a stale log whose deletion fails is read again on the next open, it
+        // must not have lost a removal the compaction log relies on
+        self.writer.sync()?;
         // increase current gen by 2. current_gen + 1 is for the compaction file
         let compaction_gen = self.current_gen + 1;
+        self.writer = self.storage.create(self.current_gen + 2)?;
+        self.dir_synced = false;
+        self.pos = 0;
         self.current_gen += 2;
-        self.writer = new_log_file(&self.path, self.current_gen)?;
-
-        let mut compaction_writer = new_log_file(&self.path, compaction_gen)?;
+        if let Some(maps) = &self.reader.maps {
+            maps.active_gen.store(self.current_gen, Ordering::SeqCst);
+        }
 
-        let mut new_pos = 0; // pos in the new log file
-        for entry in self.index.iter() {
-            let len = self.reader.read_and(*entry.value(), |mut entry_reader| {
-                Ok(io::copy(&mut entry_reader, &mut compaction_writer)?)
-            })?;
-            self.index.insert(
-                entry.key().clone(),
-                (compaction_gen, new_pos..new_pos + len).into(),
-            );
-            new_pos += len;
+        // On failure, the index still points to the older files, which are
+        // complete. A compaction log renamed into place only holds copies of their
+        // live records, and is deleted by the next compaction.
+        let moved = self.write_compaction(compaction_gen)?;
+        // the compaction log must survive a power loss before the older files are
+        // deleted, and the new segment before its writes are acknowledged
+        self.sync_dir()?;
+        for (key, old_pos, new_pos) in moved {
+            if let Some(cache) = &self.cache {
+                cache.moved(&key, old_pos, new_pos);
+            }
+            self.index.insert(key, new_pos);
         }
-        compaction_writer.flush()?;
 
         self.reader
             .safe_point
@@ -351,39 +886,85 @@ impl KvStoreWriter {
         // its stale file handles. On Unix, the files will be deleted after all the handles
         // are closed. On Windows, the deletions below will fail and stale files are expected
         // to be deleted in the next compaction.
-
-        let stale_gens = sorted_gen_list(&self.path)?
+        //
+        // Files are deleted from the oldest and the first failure stops the deletion.
+        // Otherwise a value could outlive the file removing its key. Each deletion
+        // is synced, as a power loss could keep a later one but not an earlier one.
+        let stale_gens = self
+            .storage
+            .list()?
             .into_iter()
             .filter(|&gen| gen < compaction_gen);
         for stale_gen in stale_gens {
-            let file_path = log_path(&self.path, stale_gen);
-            if let Err(e) = fs::remove_file(&file_path) {
-                error!("{:?} cannot be deleted: {}", file_path, e);
+            let res = self
+                .storage
+                .delete(stale_gen)
+                .and_then(|()| self.storage.sync_dir());
+            if let Err(e) = res {
+                error!("Log {} cannot be deleted: {}", stale_gen, e);
+                break;
             }
         }
         self.uncompacted = 0;
 
         Ok(())
     }
-}
 
-/// Create a new log file with given generation number and add the reader to the readers map.
-///
-/// Returns the writer to the log.
-fn new_log_file(path: &Path, gen: u64) -> Result<BufWriterWithPos<File>> {
-    let path = log_path(&path, gen);
-    let writer = BufWriterWithPos::new(
-        OpenOptions::new()
-            .create(true)
-            .write(true)
-            .append(true)
-            .open(&path)?,
-    )?;
-    Ok(writer)
+    /// Writes the live entries of the index to a temporary segment, renamed to the
+    /// compaction log of `gen` once complete.
+    ///
+    /// Returns the keys with their old and new positions. The index must only
+    /// point to the new ones once the whole file is written.
+    ///
+    /// The file is synced even without `sync_writes`, as the stale segments are
+    /// deleted right after: they may hold the only copy of the live records.
+    fn write_compaction(&self, gen: u64) -> Result<Vec<(String, CommandPos, CommandPos)>> {
+        let mut compaction_writer = BufWriterWithPos::new(self.storage.create_temp(gen)?);
+        let mut moved = Vec::with_capacity(self.index.len());
+        for entry in self.index.iter() {
+            let new_pos = compaction_writer.pos;
+            let header: CommandHeader = self.reader.read_and(*entry.value(), |cmd_reader| {
+                Ok(serde_json::from_reader(cmd_reader)?)
+            })?;
+            match header {
+                CommandHeader::Set {
+                    compression,
+                    key_id,
+                } if compression != self.compression
+                    || key_id.as_deref() != self.keyring.current_id() =>
+                {
+                    // rewrite the value with the current algorithm and key
+                    let value = self
+                        .reader
+                        .read_command(*entry.value())?
+                        .into_value(&self.keyring)?
+                        .ok_or(KvsError::UnexpectedCommandType)?;
+                    let cmd = Command::set(
+                        entry.key().clone(),
+                        value,
+                        self.compression,
+                        &self.keyring,
+                    )?;
+                    serde_json::to_writer(&mut compaction_writer, &cmd)?;
+                }
+                _ => {
+                    self.reader.read_and(*entry.value(), |entry_reader| {
+                        Ok(io::copy(entry_reader, &mut compaction_writer)?)
+                    })?;
+                }
+            }
+            let moved_pos = (gen, new_pos..compaction_writer.pos).into();
+            moved.push((entry.key().clone(), *entry.value(), moved_pos));
+        }
+        compaction_writer.flush()?;
+        compaction_writer.writer.get_mut().sync()?;
+        self.storage.rename_temp(gen)?;
+        Ok(moved)
+    }
 }
 
 /// Returns sorted generation numbers in the given directory
-fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
+pub(crate) fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
     let mut gen_list: Vec<u64> = fs::read_dir(&path)?
         .flat_map(|res| -> Result<_> { Ok(res?.path()) })
         .filter(|path| path.is_file() && path.extension() == Some("log".as_ref()))
@@ -401,11 +982,13 @@ fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
 
 /// Load the whole log file and store value locations in the index map.
 ///
-/// Returns how many bytes can be saved after a compaction.
-fn load(
+/// Returns how many bytes can be saved after a compaction. Fails if a value is
+/// encrypted with a key missing from `keyring`.
+fn load<R: Read + Seek>(
     gen: u64,
-    reader: &mut BufReaderWithPos<File>,
+    reader: &mut BufReaderWithPos<R>,
     index: &SkipMap<String, CommandPos>,
+    keyring: &Keyring,
 ) -> Result<u64> {
     // To make sure we read from the beginning of the file
     let mut pos = reader.seek(SeekFrom::Start(0))?;
@@ -414,7 +997,10 @@ fn load(
     while let Some(cmd) = stream.next() {
         let new_pos = stream.byte_offset() as u64;
         match cmd? {
-            Command::Set { key, .. } => {
+            Command::Set { key, key_id, .. } => {
+                if let Some(id) = key_id {
+                    keyring.find(&id)?;
+                }
                 if let Some(old_cmd) = index.get(&key) {
                     uncompacted += old_cmd.value().len;
                 }
@@ -434,20 +1020,76 @@ fn load(
     Ok(uncompacted)
 }
 
-fn log_path(dir: &Path, gen: u64) -> PathBuf {
+pub(crate) fn log_path(dir: &Path, gen: u64) -> PathBuf {
     dir.join(format!("{}.log", gen))
 }
 
 /// Struct representing a command
 #[derive(Serialize, Deserialize, Debug)]
-enum Command {
-    Set { key: String, value: String },
-    Remove { key: String },
+pub(crate) enum Command {
+    Set {
+        key: String,
+        value: String,
+        // omitted for uncompressed values, so they are written as before compression
+        // was supported
+        #[serde(default, skip_serializing_if = "Compression::is_none")]
+        compression: Compression,
+        // fingerprint of the key the value is encrypted with, if any
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key_id: Option<String>,
+    },
+    Remove {
+        key: String,
+    },
+}
+
+/// How the value of a command is stored, read without its key and value.
+#[derive(Deserialize)]
+enum CommandHeader {
+    Set {
+        #[serde(default)]
+        compression: Compression,
+        #[serde(default)]
+        key_id: Option<String>,
+    },
+    Remove {},
 }
 
 impl Command {
-    fn set(key: String, value: String) -> Command {
-        Command::Set { key, value }
+    fn set(
+        key: String,
+        value: String,
+        compression: Compression,
+        keyring: &Keyring,
+    ) -> Result<Command> {
+        let (value, compression) = compression.compress(value)?;
+        let (value, key_id) = keyring.encrypt(&key, value)?;
+        Ok(Command::Set {
+            key,
+            value,
+            compression,
+            key_id,
+        })
+    }
+
+    /// Returns the decrypted and uncompressed value of a `Set` command, or `None`
+    /// for a `Remove`.
+    pub(crate) fn into_value(self, keyring: &Keyring) -> Result<Option<String>> {
+        match self {
+            Command::Set {
+                key,
+                value,
+                compression,
+                key_id,
+            } => {
+                let value = match key_id {
+                    Some(id) => keyring.decrypt(&id, &key, &value)?,
+                    None => value,
+                };
+                Ok(Some(compression.decompress(value)?))
+            }
+            Command::Remove { .. } => Ok(None),
+        }
     }
 
     fn remove(key: String) -> Command {
@@ -456,7 +1098,7 @@ impl Command {
 }
 
 /// Represents the position and length of a json-serialized command in the log
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 struct CommandPos {
     gen: u64,
     pos: u64,
@@ -473,13 +1115,104 @@ impl From<(u64, Range<u64>)> for CommandPos {
     }
 }
 
-struct BufReaderWithPos<R: Read + Seek> {
+/// Statistics of the value cache of a `KvStore`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Number of `get`s answered from the cache.
+    pub hits: u64,
+    /// Number of `get`s of existing keys that had to read the log.
+    pub misses: u64,
+    /// Bytes of keys and values in the cache.
+    pub size: u64,
+}
+
+/// Values recently read from the log, shared by all readers.
+///
+/// Each value is cached with the position it was read from. A value is only
+/// returned if the index still points to that position, so a cached value can
+/// never be more recent or older than the index, whatever the order of
+/// concurrent reads and writes.
+struct ValueCache {
+    shards: Vec<Mutex<Lru<CachedValue>>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+struct CachedValue {
+    pos: CommandPos,
+    value: String,
+}
+
+impl ValueCache {
+    fn new(capacity: u64) -> ValueCache {
+        let shard_capacity = capacity / CACHE_SHARDS as u64;
+        ValueCache {
+            shards: (0..CACHE_SHARDS)
+                .map(|_| Mutex::new(Lru::new(Some(shard_capacity))))
+                .collect(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    fn shard(&self, key: &str) -> MutexGuard<'_, Lru<CachedValue>> {
+        self.shards[shard_of(key, CACHE_SHARDS)].lock().unwrap()
+    }
+
+    /// Returns the value of `key` if it is cached for the command at `pos`.
+    fn get(&self, key: &str, pos: CommandPos) -> Option<String> {
+        let value = match self.shard(key).get(key) {
+            Some(cached) if cached.pos == pos => Some(cached.value.clone()),
+            _ => None,
+        };
+        let counter = if value.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        value
+    }
+
+    fn insert(&self, key: String, pos: CommandPos, value: String) {
+        let size = (key.len() + value.len()) as u64;
+        self.shard(&key)
+            .insert(key, CachedValue { pos, value }, size);
+    }
+
+    fn remove(&self, key: &str) {
+        self.shard(key).remove(key);
+    }
+
+    /// Follows a command moved by a compaction from `old` to `new`.
+    fn moved(&self, key: &str, old: CommandPos, new: CommandPos) {
+        if let Some(cached) = self.shard(key).peek_mut(key) {
+            if cached.pos == old {
+                cached.pos = new;
+            }
+        }
+    }
+
+    fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            size: self
+                .shards
+                .iter()
+                .map(|shard| shard.lock().unwrap().size())
+                .sum(),
+        }
+    }
+}
+
+pub(crate) struct BufReaderWithPos<R: Read + Seek> {
     reader: BufReader<R>,
     pos: u64,
 }
 
 impl<R: Read + Seek> BufReaderWithPos<R> {
-    fn new(mut inner: R) -> Result<Self> {
+    pub(crate) fn new(mut inner: R) -> Result<Self> {
         let pos = inner.seek(SeekFrom::Current(0))?;
         Ok(BufReaderWithPos {
             reader: BufReader::new(inner),
@@ -503,22 +1236,22 @@ impl<R: Read + Seek> Seek for BufReaderWithPos<R> {
     }
 }
 
-struct BufWriterWithPos<W: Write + Seek> {
+// Writes to a new segment, keeping track of its length.
+struct BufWriterWithPos<W: Write> {
     writer: BufWriter<W>,
     pos: u64,
 }
 
-impl<W: Write + Seek> BufWriterWithPos<W> {
-    fn new(mut inner: W) -> Result<Self> {
-        let pos = inner.seek(SeekFrom::Current(0))?;
-        Ok(BufWriterWithPos {
+impl<W: Write> BufWriterWithPos<W> {
+    fn new(inner: W) -> Self {
+        BufWriterWithPos {
             writer: BufWriter::new(inner),
-            pos,
-        })
+            pos: 0,
+        }
     }
 }
 
-impl<W: Write + Seek> Write for BufWriterWithPos<W> {
+impl<W: Write> Write for BufWriterWithPos<W> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         let len = self.writer.write(buf)?;
         self.pos += len as u64;
@@ -529,10 +1262,3 @@ impl<W: Write + Seek> Write for BufWriterWithPos<W> {
         self.writer.flush()
     }
 }
-
-impl<W: Write + Seek> Seek for BufWriterWithPos<W> {
-    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
-        self.pos = self.writer.seek(pos)?;
-        Ok(self.pos)
-    }
-}
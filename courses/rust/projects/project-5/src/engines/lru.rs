@@ -0,0 +1,104 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::hash::{Hash, Hasher};
+
+/// A map bounded by the total size of its entries, which evicts the least
+/// recently used entries first.
+///
+/// Keys are kept in order, so the entries can be listed by range.
+pub struct Lru<V> {
+    map: BTreeMap<String, Slot<V>>,
+    // keys ordered by their last use, the least recently used first
+    lru: BTreeMap<u64, String>,
+    tick: u64,
+    size: u64,
+    capacity: Option<u64>,
+}
+
+struct Slot<V> {
+    value: V,
+    size: u64,
+    // the tick of the last use, which is the key of this slot in `Lru::lru`
+    tick: u64,
+}
+
+impl<V> Lru<V> {
+    /// Creates an empty map, unbounded if `capacity` is `None`.
+    pub fn new(capacity: Option<u64>) -> Lru<V> {
+        Lru {
+            map: BTreeMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+            size: 0,
+            capacity,
+        }
+    }
+
+    /// Returns the value of `key` and marks it as the most recently used.
+    pub fn get(&mut self, key: &str) -> Option<&V> {
+        self.tick += 1;
+        let slot = self.map.get_mut(key)?;
+        self.lru.remove(&slot.tick);
+        slot.tick = self.tick;
+        self.lru.insert(self.tick, key.to_owned());
+        Some(&slot.value)
+    }
+
+    /// Returns the value of `key` without marking it as used.
+    pub fn peek_mut(&mut self, key: &str) -> Option<&mut V> {
+        self.map.get_mut(key).map(|slot| &mut slot.value)
+    }
+
+    /// Inserts an entry which accounts for `size` bytes, then evicts entries
+    /// until the total size is within the capacity.
+    pub fn insert(&mut self, key: String, value: V, size: u64) {
+        self.remove(&key);
+        self.tick += 1;
+        self.size += size;
+        self.lru.insert(self.tick, key.clone());
+        let tick = self.tick;
+        self.map.insert(key, Slot { value, size, tick });
+        self.evict();
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<V> {
+        let slot = self.map.remove(key)?;
+        self.lru.remove(&slot.tick);
+        self.size -= slot.size;
+        Some(slot.value)
+    }
+
+    /// Returns the entries whose key is not less than `start`, in key order.
+    pub fn range_from(&self, start: String) -> impl Iterator<Item = (&String, &V)> {
+        self.map
+            .range(start..)
+            .map(|(key, slot)| (key, &slot.value))
+    }
+
+    /// Total size of the entries in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn evict(&mut self) {
+        let capacity = match self.capacity {
+            Some(capacity) => capacity,
+            None => return,
+        };
+        while self.size > capacity {
+            let tick = match self.lru.keys().next() {
+                Some(&tick) => tick,
+                None => return,
+            };
+            let key = self.lru.remove(&tick).unwrap();
+            self.remove(&key);
+        }
+    }
+}
+
+/// Picks one of `num_shards` shards for a key.
+pub fn shard_of(key: &str, num_shards: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish() as usize % num_shards
+}
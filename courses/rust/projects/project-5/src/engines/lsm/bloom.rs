@@ -0,0 +1,79 @@
+use crate::{KvsError, Result};
+
+const BITS_PER_KEY: usize = 10;
+// ln(2) * BITS_PER_KEY, which minimizes the false positive rate
+const NUM_HASHES: u8 = 7;
+
+/// A bloom filter over the keys of a table.
+///
+/// It answers whether a key may be in the table without reading any block.
+/// The false positive rate is about 1% with 10 bits per key.
+pub struct BloomFilter {
+    bits: Vec<u8>,
+    num_hashes: u8,
+}
+
+impl BloomFilter {
+    /// Builds a filter from the hashes of all keys, computed by `hash`.
+    pub fn from_hashes(hashes: &[u64]) -> BloomFilter {
+        // make the filter at least 64 bits long to bound the false positive
+        // rate of small tables
+        let num_bytes = (hashes.len() * BITS_PER_KEY / 8 + 1).max(8);
+        let mut filter = BloomFilter {
+            bits: vec![0; num_bytes],
+            num_hashes: NUM_HASHES,
+        };
+        for &h in hashes {
+            for bit in filter.bit_positions(h) {
+                filter.bits[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        filter
+    }
+
+    /// Decodes a filter encoded by `encode`.
+    pub fn decode(mut bytes: Vec<u8>) -> Result<BloomFilter> {
+        let num_hashes = bytes
+            .pop()
+            .ok_or_else(|| KvsError::Corrupted("empty bloom filter".to_owned()))?;
+        Ok(BloomFilter {
+            bits: bytes,
+            num_hashes,
+        })
+    }
+
+    /// Encodes the filter as the bit array followed by the number of hashes.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = self.bits.clone();
+        bytes.push(self.num_hashes);
+        bytes
+    }
+
+    /// Returns `false` if the key is definitely not in the table.
+    pub fn may_contain(&self, key: &str) -> bool {
+        if self.bits.is_empty() {
+            return true;
+        }
+        self.bit_positions(hash(key))
+            .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    // Double hashing: the i-th probe is `h + i * delta`.
+    fn bit_positions(&self, h: u64) -> impl Iterator<Item = usize> {
+        let num_bits = self.bits.len() as u64 * 8;
+        let delta = h.rotate_left(32) | 1;
+        (0..u64::from(self.num_hashes))
+            .map(move |i| (h.wrapping_add(i.wrapping_mul(delta)) % num_bits) as usize)
+    }
+}
+
+/// 64-bit FNV-1a hash of a key.
+///
+/// Filters are persisted, so the hash must not change between runs or Rust versions,
+/// unlike the hasher of `std::collections::HashMap`.
+pub fn hash(key: &str) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+    key.bytes()
+        .fold(OFFSET_BASIS, |h, b| (h ^ u64::from(b)).wrapping_mul(PRIME))
+}
@@ -0,0 +1,50 @@
+//! The manifest records which tables make up each level.
+//!
+//! It is rewritten as a whole after every flush and compaction. The new content is
+//! written to a temporary file first and renamed over the old one, so a crash leaves
+//! either the old or the new manifest. The directory is synced after the rename,
+//! which also makes the tables and the log created before it durable.
+
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::engines::storage::sync_dir;
+use crate::Result;
+
+const MANIFEST: &str = "MANIFEST";
+const MANIFEST_TMP: &str = "MANIFEST.tmp";
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    /// The next unused file number, shared by tables and logs.
+    pub next_id: u64,
+    /// Logs with a smaller number are already flushed to tables.
+    pub log_number: u64,
+    /// Table numbers of each level, in the order of `Version::levels`.
+    pub levels: Vec<Vec<u64>>,
+}
+
+impl Manifest {
+    /// Reads the manifest in `dir`, or returns an empty one if there is none.
+    pub fn load(dir: &Path) -> Result<Manifest> {
+        let path = dir.join(MANIFEST);
+        if !path.exists() {
+            return Ok(Manifest::default());
+        }
+        Ok(serde_json::from_reader(BufReader::new(File::open(path)?))?)
+    }
+
+    pub fn store(&self, dir: &Path) -> Result<()> {
+        let tmp = dir.join(MANIFEST_TMP);
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        serde_json::to_writer(&mut writer, self)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        fs::rename(tmp, dir.join(MANIFEST))?;
+        sync_dir(dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,60 @@
+use std::iter::Peekable;
+
+use super::sstable::Entry;
+use crate::Result;
+
+pub type Source<'a> = Box<dyn Iterator<Item = Result<Entry>> + 'a>;
+
+/// Merges sorted sources into one sorted iterator.
+///
+/// Sources are given from the newest to the oldest. When several sources have the
+/// same key, only the entry of the newest one is returned.
+pub struct MergeIter<'a> {
+    sources: Vec<Peekable<Source<'a>>>,
+}
+
+impl<'a> MergeIter<'a> {
+    pub fn new(sources: Vec<Source<'a>>) -> MergeIter<'a> {
+        MergeIter {
+            sources: sources.into_iter().map(Iterator::peekable).collect(),
+        }
+    }
+}
+
+impl<'a> Iterator for MergeIter<'a> {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Result<Entry>> {
+        // the newest source with the smallest key, or the first source with an error
+        let mut min: Option<(usize, &str)> = None;
+        for (i, head) in self.sources.iter_mut().map(Peekable::peek).enumerate() {
+            match head {
+                Some(Ok((key, _))) => match min {
+                    Some((_, min_key)) if min_key <= key.as_str() => {}
+                    _ => min = Some((i, key)),
+                },
+                Some(Err(_)) => {
+                    min = Some((i, ""));
+                    break;
+                }
+                None => {}
+            }
+        }
+
+        let i = min?.0;
+        let (key, value) = match self.sources[i].next()? {
+            Ok(entry) => entry,
+            Err(e) => return Some(Err(e)),
+        };
+        // skip the older entries of the same key
+        for source in &mut self.sources {
+            while let Some(Ok((k, _))) = source.peek() {
+                if *k != key {
+                    break;
+                }
+                source.next();
+            }
+        }
+        Some(Ok((key, value)))
+    }
+}
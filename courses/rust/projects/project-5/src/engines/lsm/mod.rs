@@ -0,0 +1,591 @@
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+
+use crossbeam_skiplist::SkipMap;
+use tokio::prelude::*;
+
+use self::manifest::Manifest;
+use self::merge::{MergeIter, Source};
+use self::sstable::{table_path, Table, TableBuilder};
+use self::wal::{wal_path, Wal};
+use super::compression::Compression;
+use super::kvs::Command;
+use super::storage::{lock_dir, sync_dir};
+use super::{send_scan, spawn_scan, KvsEngine};
+use crate::thread_pool::ThreadPool;
+use crate::{KvsError, Result};
+
+mod bloom;
+mod manifest;
+mod merge;
+mod sstable;
+mod wal;
+
+// bytes of log written before the memtable is flushed to a table
+const MEMTABLE_SIZE: u64 = 1024 * 1024;
+const NUM_LEVELS: usize = 7;
+// number of level 0 tables that triggers a compaction into level 1
+const L0_COMPACTION_TRIGGER: usize = 4;
+const L1_MAX_BYTES: u64 = 10 * 1024 * 1024;
+// each level is allowed to be this many times larger than the previous one
+const LEVEL_SIZE_MULTIPLIER: u64 = 10;
+const TARGET_TABLE_SIZE: u64 = 2 * 1024 * 1024;
+
+/// Maps keys to their latest value, or to `None` if the latest write removed them.
+type MemTable = SkipMap<String, Option<String>>;
+
+/// The `LsmKvsEngine` stores string key/value pairs in a log-structured merge-tree.
+///
+/// Writes go to a write-ahead log and an in-memory memtable. When the log grows
+/// large enough, the memtable is written to a sorted table file (`<n>.sst`) in level 0.
+/// Tables are then merged into larger levels by leveled compaction, where every
+/// level except level 0 is made of tables with disjoint key ranges.
+///
+/// Unlike `KvStore`, only a small index and a bloom filter per table are kept in
+/// memory, so the keys don't need to fit in RAM. Like `KvStore`, the engine locks
+/// its directory until the last clone is dropped.
+///
+/// ```rust
+/// # use kvs::{LsmKvsEngine, Result};
+/// # use kvs::thread_pool::{ThreadPool, RayonThreadPool};
+/// # use tokio::prelude::*;
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// use kvs::KvsEngine;
+/// let engine: LsmKvsEngine<RayonThreadPool> = LsmKvsEngine::open(current_dir()?, 2)?;
+/// engine.set("key".to_owned(), "value".to_owned()).wait()?;
+/// let val = engine.get("key".to_owned()).wait()?;
+/// assert_eq!(val, Some("value".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct LsmKvsEngine<P: ThreadPool> {
+    version: Arc<RwLock<Arc<Version>>>,
+    writer: Arc<Mutex<LsmWriter>>,
+    thread_pool: P,
+}
+
+impl<P: ThreadPool> LsmKvsEngine<P> {
+    /// Opens a `LsmKvsEngine` with the given path.
+    ///
+    /// This will create a new directory if the given one does not exist.
+    ///
+    /// `concurrency` specifies the number of threads in the thread pool.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::DirectoryLocked` if another engine or store has the
+    /// directory open.
+    ///
+    /// It propagates I/O or deserialization errors while loading the tables and
+    /// replaying the logs.
+    pub fn open(path: impl Into<PathBuf>, concurrency: u32) -> Result<Self> {
+        LsmKvsEngineOptions::new().open(path, concurrency)
+    }
+
+    /// Opens a `LsmKvsEngine` with the given path, whose operations run in `pool`.
+    ///
+    /// See `LsmKvsEngine::open`.
+    pub fn open_with_pool(path: impl Into<PathBuf>, thread_pool: P) -> Result<Self> {
+        LsmKvsEngineOptions::new().open_with_pool(path, thread_pool)
+    }
+}
+
+/// Options which can be used to configure how a `LsmKvsEngine` is opened.
+///
+/// ```rust
+/// # use kvs::{LsmKvsEngine, LsmKvsEngineOptions, Result};
+/// # use kvs::thread_pool::RayonThreadPool;
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// let engine: LsmKvsEngine<RayonThreadPool> = LsmKvsEngineOptions::new()
+///     .sync_writes(true)
+///     .open(current_dir()?, 4)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct LsmKvsEngineOptions {
+    sync_writes: bool,
+}
+
+impl LsmKvsEngineOptions {
+    /// Creates a blank set of options, which opens an engine like `LsmKvsEngine::open`.
+    pub fn new() -> LsmKvsEngineOptions {
+        LsmKvsEngineOptions::default()
+    }
+
+    /// Syncs the write-ahead log to the disk before a write returns.
+    ///
+    /// By default a write returns once the command is handed to the operating
+    /// system, so it survives a crash of the process but may be lost if the
+    /// machine fails. Tables are synced when they are written in any case.
+    pub fn sync_writes(&mut self, sync: bool) -> &mut LsmKvsEngineOptions {
+        self.sync_writes = sync;
+        self
+    }
+
+    /// Opens a `LsmKvsEngine` with the given path and these options.
+    ///
+    /// See `LsmKvsEngine::open`.
+    pub fn open<P: ThreadPool>(
+        &self,
+        path: impl Into<PathBuf>,
+        concurrency: u32,
+    ) -> Result<LsmKvsEngine<P>> {
+        self.open_with_pool(path, P::new(concurrency)?)
+    }
+
+    /// Opens a `LsmKvsEngine` with the given path and these options, whose
+    /// operations run in `pool`.
+    ///
+    /// See `LsmKvsEngine::open`.
+    pub fn open_with_pool<P: ThreadPool>(
+        &self,
+        path: impl Into<PathBuf>,
+        thread_pool: P,
+    ) -> Result<LsmKvsEngine<P>> {
+        let path = path.into();
+        fs::create_dir_all(&path)?;
+        let lock = lock_dir(&path)?;
+
+        let mut manifest = Manifest::load(&path)?;
+        manifest.levels.resize(NUM_LEVELS, Vec::new());
+        let mut levels = Vec::new();
+        for ids in &manifest.levels {
+            let tables = ids
+                .iter()
+                .map(|&id| Table::open(&path, id).map(Arc::new))
+                .collect::<Result<Vec<_>>>()?;
+            levels.push(tables);
+        }
+
+        // remove tables written by an interrupted flush or compaction
+        for id in sorted_ids(&path, "sst")? {
+            if !manifest.levels.iter().any(|ids| ids.contains(&id)) {
+                remove_file(&table_path(&path, id));
+            }
+        }
+
+        let memtable = SkipMap::new();
+        let mut unflushed = 0;
+        for id in sorted_ids(&path, "wal")? {
+            if id < manifest.log_number {
+                remove_file(&wal_path(&path, id));
+                continue;
+            }
+            unflushed += wal::replay(&wal_path(&path, id), &memtable)?;
+            manifest.next_id = manifest.next_id.max(id + 1);
+        }
+
+        let log_id = manifest.next_id;
+        manifest.next_id += 1;
+        let wal = Wal::create(&wal_path(&path, log_id), self.sync_writes)?;
+        if self.sync_writes {
+            // the synced writes are lost with the log if its creation isn't durable
+            sync_dir(&path)?;
+        }
+
+        let version = Arc::new(RwLock::new(Arc::new(Version {
+            memtable: Arc::new(memtable),
+            levels,
+        })));
+        let writer = LsmWriter {
+            path,
+            version: Arc::clone(&version),
+            wal,
+            sync_writes: self.sync_writes,
+            unflushed,
+            manifest,
+            compact_pointers: vec![None; NUM_LEVELS],
+            _lock: lock,
+        };
+
+        Ok(LsmKvsEngine {
+            version,
+            writer: Arc::new(Mutex::new(writer)),
+            thread_pool,
+        })
+    }
+}
+
+impl<P: ThreadPool> KvsEngine for LsmKvsEngine<P> {
+    /// Sets the value of a string key to a string.
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log or
+    /// flushing the memtable.
+    fn set(
+        &self,
+        key: String,
+        value: String,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let writer = self.writer.clone();
+        let task = self
+            .thread_pool
+            .spawn_with_handle(move |_| writer.lock().unwrap().set(key, value));
+        Box::new(task.flatten())
+    }
+
+    /// Gets the string value of a given string key.
+    ///
+    /// Returns `None` if the given key does not exist.
+    fn get(&self, key: String) -> Box<dyn Future<Item = Option<String>, Error = KvsError> + Send> {
+        let version = self.version.clone();
+        let task = self.thread_pool.spawn_with_handle(move |_| {
+            let version = version.read().unwrap().clone();
+            version.get(&key)
+        });
+        Box::new(task.flatten())
+    }
+
+    /// Removes a given key.
+    ///
+    /// # Error
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    ///
+    /// It propagates I/O or serialization errors during writing the log or
+    /// flushing the memtable.
+    fn remove(&self, key: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let writer = self.writer.clone();
+        let task = self
+            .thread_pool
+            .spawn_with_handle(move |_| writer.lock().unwrap().remove(key));
+        Box::new(task.flatten())
+    }
+
+    /// Returns all key/value pairs whose key starts with `prefix`, in key order.
+    ///
+    /// The pairs come from the version current when the scan starts, so writes
+    /// made during the scan may not be seen.
+    fn scan(
+        &self,
+        prefix: String,
+    ) -> Box<dyn Stream<Item = (String, String), Error = KvsError> + Send> {
+        let version = self.version.clone();
+        spawn_scan(&self.thread_pool, move |tx| {
+            let version = version.read().unwrap().clone();
+            let pairs = version
+                .iter_from(&prefix)
+                .take_while(|res| match res {
+                    Ok((key, _)) => key.starts_with(&prefix),
+                    Err(_) => true,
+                })
+                .filter_map(|res| match res {
+                    Ok((key, Some(value))) => Some(Ok((key, value))),
+                    Ok((_, None)) => None,
+                    Err(e) => Some(Err(e)),
+                });
+            send_scan(pairs, tx);
+            // close the tables of this version before the stream ends
+            drop(version);
+        })
+    }
+}
+
+/// A consistent view of the memtable and the tables.
+///
+/// Flushes and compactions never modify a version but install a new one. Readers
+/// keep using the version they started with, whose table files stay readable
+/// even if a compaction deletes them in the meantime.
+struct Version {
+    memtable: Arc<MemTable>,
+    // Level 0 holds flushed memtables from the oldest to the newest, which may
+    // overlap. Other levels are sorted by key and have no overlapping tables.
+    levels: Vec<Vec<Arc<Table>>>,
+}
+
+impl Version {
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        if let Some(entry) = self.memtable.get(key) {
+            return Ok(entry.value().clone());
+        }
+        for table in self.levels[0].iter().rev() {
+            if let Some(value) = table.get(key)? {
+                return Ok(value);
+            }
+        }
+        for level in &self.levels[1..] {
+            let i = first_table_from(level, key);
+            if let Some(table) = level.get(i) {
+                if let Some(value) = table.get(key)? {
+                    return Ok(value);
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns all entries whose key is not less than `start`, tombstones included.
+    fn iter_from<'a>(&'a self, start: &str) -> MergeIter<'a> {
+        let mut sources: Vec<Source<'a>> =
+            vec![Box::new(self.memtable.range(start.to_owned()..).map(
+                |entry| Ok((entry.key().clone(), entry.value().clone())),
+            ))];
+        for table in self.levels[0].iter().rev() {
+            sources.push(Box::new(Table::iter_from(table, start)));
+        }
+        for level in &self.levels[1..] {
+            sources.push(level_iter(level, start));
+        }
+        MergeIter::new(sources)
+    }
+}
+
+/// Iterates over the entries of a sorted level whose key is not less than `start`.
+fn level_iter<'a>(tables: &'a [Arc<Table>], start: &str) -> Source<'a> {
+    let first = first_table_from(tables, start);
+    let start = start.to_owned();
+    Box::new(
+        tables[first..]
+            .iter()
+            .flat_map(move |table| Table::iter_from(table, &start)),
+    )
+}
+
+/// Returns the index of the first table of a sorted level that may contain keys
+/// not less than `key`.
+fn first_table_from(tables: &[Arc<Table>], key: &str) -> usize {
+    match tables.binary_search_by(|table| table.largest().cmp(key)) {
+        Ok(i) | Err(i) => i,
+    }
+}
+
+fn max_bytes_for_level(level: usize) -> u64 {
+    L1_MAX_BYTES * LEVEL_SIZE_MULTIPLIER.pow(level as u32 - 1)
+}
+
+struct LsmWriter {
+    path: PathBuf,
+    version: Arc<RwLock<Arc<Version>>>,
+    wal: Wal,
+    sync_writes: bool,
+    // bytes of log written since the last flush
+    unflushed: u64,
+    manifest: Manifest,
+    // The largest key of the last compaction in each level. The next compaction
+    // of the level starts after it, so the whole key range gets compacted in turn.
+    compact_pointers: Vec<Option<String>>,
+    // keeps the directory locked until the last clone of the engine is dropped
+    _lock: File,
+}
+
+impl LsmWriter {
+    fn current(&self) -> Arc<Version> {
+        self.version.read().unwrap().clone()
+    }
+
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        let cmd = Command::Set {
+            key,
+            value,
+            compression: Compression::None,
+            key_id: None,
+        };
+        self.unflushed += self.wal.append(&cmd)?;
+        if let Command::Set { key, value, .. } = cmd {
+            self.current().memtable.insert(key, Some(value));
+        }
+        self.maybe_flush()
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        let version = self.current();
+        if version.get(&key)?.is_none() {
+            return Err(KvsError::KeyNotFound);
+        }
+        let cmd = Command::Remove { key };
+        self.unflushed += self.wal.append(&cmd)?;
+        if let Command::Remove { key } = cmd {
+            version.memtable.insert(key, None);
+        }
+        self.maybe_flush()
+    }
+
+    fn maybe_flush(&mut self) -> Result<()> {
+        if self.unflushed > MEMTABLE_SIZE {
+            self.flush()?;
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the memtable to a new level 0 table and starts a new log.
+    fn flush(&mut self) -> Result<()> {
+        let version = self.current();
+        let log_number = self.new_id();
+        let wal = Wal::create(&wal_path(&self.path, log_number), self.sync_writes)?;
+
+        let mut levels = version.levels.clone();
+        if !version.memtable.is_empty() {
+            let id = self.new_id();
+            let mut builder = TableBuilder::create(&table_path(&self.path, id))?;
+            for entry in version.memtable.iter() {
+                builder.add(entry.key(), entry.value().as_deref())?;
+            }
+            builder.finish()?;
+            levels[0].push(Arc::new(Table::open(&self.path, id)?));
+        }
+
+        self.manifest.log_number = log_number;
+        self.install(Version {
+            memtable: Arc::new(SkipMap::new()),
+            levels,
+        })?;
+        self.wal = wal;
+        self.unflushed = 0;
+
+        for id in sorted_ids(&self.path, "wal")? {
+            if id < log_number {
+                remove_file(&wal_path(&self.path, id));
+            }
+        }
+        Ok(())
+    }
+
+    /// Compacts levels until none of them exceeds its limit.
+    fn compact(&mut self) -> Result<()> {
+        while let Some(level) = self.pick_level() {
+            self.compact_level(level)?;
+        }
+        Ok(())
+    }
+
+    fn pick_level(&self) -> Option<usize> {
+        let version = self.current();
+        if version.levels[0].len() >= L0_COMPACTION_TRIGGER {
+            return Some(0);
+        }
+        (1..NUM_LEVELS - 1).find(|&level| {
+            version.levels[level].iter().map(|t| t.size()).sum::<u64>() > max_bytes_for_level(level)
+        })
+    }
+
+    /// Merges tables of `level` with the overlapping tables of the next level.
+    ///
+    /// All level 0 tables are compacted at once because they may overlap each other.
+    /// For other levels, a single table is picked.
+    fn compact_level(&mut self, level: usize) -> Result<()> {
+        let version = self.current();
+        // inputs from the newest to the oldest
+        let inputs: Vec<Arc<Table>> = if level == 0 {
+            version.levels[0].iter().rev().cloned().collect()
+        } else {
+            let tables = &version.levels[level];
+            let i = match &self.compact_pointers[level] {
+                Some(pointer) => tables
+                    .iter()
+                    .position(|table| table.smallest() > pointer.as_str())
+                    .unwrap_or(0),
+                None => 0,
+            };
+            vec![tables[i].clone()]
+        };
+        let smallest = inputs
+            .iter()
+            .map(|t| t.smallest())
+            .min()
+            .unwrap()
+            .to_owned();
+        let largest = inputs.iter().map(|t| t.largest()).max().unwrap().to_owned();
+        let (overlapping, mut next_level): (Vec<_>, Vec<_>) = version.levels[level + 1]
+            .iter()
+            .cloned()
+            .partition(|table| table.overlaps(&smallest, &largest));
+        // a tombstone is only needed while older values may exist in deeper levels
+        let drop_tombstones = version.levels[level + 2..].iter().all(Vec::is_empty);
+
+        let mut sources: Vec<Source> = inputs
+            .iter()
+            .map(|table| Box::new(Table::iter_from(table, "")) as Source)
+            .collect();
+        sources.push(level_iter(&overlapping, ""));
+        let mut builder: Option<(u64, TableBuilder)> = None;
+        for entry in MergeIter::new(sources) {
+            let (key, value) = entry?;
+            if value.is_none() && drop_tombstones {
+                continue;
+            }
+            if builder.is_none() {
+                let id = self.new_id();
+                builder = Some((id, TableBuilder::create(&table_path(&self.path, id))?));
+            }
+            let (_, table_builder) = builder.as_mut().unwrap();
+            table_builder.add(&key, value.as_deref())?;
+            if table_builder.file_size() >= TARGET_TABLE_SIZE {
+                let (id, table_builder) = builder.take().unwrap();
+                table_builder.finish()?;
+                next_level.push(Arc::new(Table::open(&self.path, id)?));
+            }
+        }
+        if let Some((id, table_builder)) = builder {
+            table_builder.finish()?;
+            next_level.push(Arc::new(Table::open(&self.path, id)?));
+        }
+        next_level.sort_by(|a, b| a.smallest().cmp(b.smallest()));
+
+        let mut levels = version.levels.clone();
+        levels[level].retain(|table| inputs.iter().all(|input| input.id() != table.id()));
+        levels[level + 1] = next_level;
+        self.install(Version {
+            memtable: version.memtable.clone(),
+            levels,
+        })?;
+        self.compact_pointers[level] = Some(largest);
+
+        // Readers of older versions may still have the files open. On Unix, the
+        // files are deleted after they are closed. On Windows, the deletion fails
+        // and the files are removed as orphans the next time the engine is opened.
+        for table in inputs.iter().chain(&overlapping) {
+            remove_file(&table_path(&self.path, table.id()));
+        }
+        Ok(())
+    }
+
+    /// Records `version` in the manifest and makes it the current version.
+    fn install(&mut self, version: Version) -> Result<()> {
+        self.manifest.levels = version
+            .levels
+            .iter()
+            .map(|tables| tables.iter().map(|table| table.id()).collect())
+            .collect();
+        self.manifest.store(&self.path)?;
+        *self.version.write().unwrap() = Arc::new(version);
+        Ok(())
+    }
+
+    fn new_id(&mut self) -> u64 {
+        let id = self.manifest.next_id;
+        self.manifest.next_id += 1;
+        id
+    }
+}
+
+/// Returns the sorted numbers of the files with the given extension in `dir`.
+fn sorted_ids(dir: &Path, extension: &str) -> Result<Vec<u64>> {
+    let mut ids: Vec<u64> = fs::read_dir(dir)?
+        .flat_map(|res| -> Result<_> { Ok(res?.path()) })
+        .filter(|path| path.is_file() && path.extension() == Some(extension.as_ref()))
+        .flat_map(|path| {
+            path.file_stem()
+                .and_then(OsStr::to_str)
+                .map(str::parse::<u64>)
+        })
+        .flatten()
+        .collect();
+    ids.sort_unstable();
+    Ok(ids)
+}
+
+fn remove_file(path: &Path) {
+    if let Err(e) = fs::remove_file(path) {
+        error!("{:?} cannot be deleted: {}", path, e);
+    }
+}
@@ -0,0 +1,415 @@
+//! Sorted string tables.
+//!
+//! A table file is laid out as:
+//!
+//! ```text
+//! [data block]* [index] [bloom filter] [footer]
+//! ```
+//!
+//! Data blocks hold entries in key order and are about `BLOCK_SIZE` bytes long.
+//! The index stores the smallest key of the table and, for every block, its last key
+//! and location. The footer has a fixed length and locates the index and the filter.
+//! Integers are encoded in little endian and strings are prefixed with their length.
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use super::bloom::{self, BloomFilter};
+use crate::{KvsError, Result};
+
+const BLOCK_SIZE: usize = 4 * 1024;
+const FOOTER_LEN: u64 = 40;
+// "kvs_sst1"
+const MAGIC: u64 = 0x6b76_735f_7373_7431;
+
+const TOMBSTONE: u8 = 0;
+const VALUE: u8 = 1;
+
+/// A key with its value, or `None` if the key is removed.
+pub type Entry = (String, Option<String>);
+
+pub fn table_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}.sst", id))
+}
+
+/// Location of a data block in the table file.
+struct BlockHandle {
+    last_key: String,
+    offset: u64,
+    len: u64,
+}
+
+/// Writes a new table file.
+///
+/// Entries must be added in strictly increasing key order.
+pub struct TableBuilder {
+    writer: BufWriter<File>,
+    // offset of the current block
+    offset: u64,
+    block: Vec<u8>,
+    smallest: Option<String>,
+    last_key: String,
+    index: Vec<BlockHandle>,
+    hashes: Vec<u64>,
+}
+
+impl TableBuilder {
+    pub fn create(path: &Path) -> Result<TableBuilder> {
+        let file = OpenOptions::new().create_new(true).write(true).open(path)?;
+        Ok(TableBuilder {
+            writer: BufWriter::new(file),
+            offset: 0,
+            block: Vec::with_capacity(BLOCK_SIZE * 2),
+            smallest: None,
+            last_key: String::new(),
+            index: Vec::new(),
+            hashes: Vec::new(),
+        })
+    }
+
+    pub fn add(&mut self, key: &str, value: Option<&str>) -> Result<()> {
+        debug_assert!(self.smallest.is_none() || self.last_key.as_str() < key);
+        put_str(&mut self.block, key);
+        match value {
+            Some(value) => {
+                self.block.push(VALUE);
+                put_str(&mut self.block, value);
+            }
+            None => self.block.push(TOMBSTONE),
+        }
+        if self.smallest.is_none() {
+            self.smallest = Some(key.to_owned());
+        }
+        self.last_key.clear();
+        self.last_key.push_str(key);
+        self.hashes.push(bloom::hash(key));
+
+        if self.block.len() >= BLOCK_SIZE {
+            self.finish_block()?;
+        }
+        Ok(())
+    }
+
+    /// Returns the size the file would have if no more entries were added,
+    /// without the index and the filter.
+    pub fn file_size(&self) -> u64 {
+        self.offset + self.block.len() as u64
+    }
+
+    /// Writes the index, the filter and the footer, and syncs the file to disk.
+    pub fn finish(mut self) -> Result<()> {
+        self.finish_block()?;
+        let smallest = self
+            .smallest
+            .take()
+            .ok_or_else(|| KvsError::StringError("Can't write an empty table".to_owned()))?;
+
+        let mut index = Vec::new();
+        put_str(&mut index, &smallest);
+        put_u64(&mut index, self.index.len() as u64);
+        for handle in &self.index {
+            put_str(&mut index, &handle.last_key);
+            put_u64(&mut index, handle.offset);
+            put_u64(&mut index, handle.len);
+        }
+        let filter = BloomFilter::from_hashes(&self.hashes).encode();
+
+        let index_offset = self.offset;
+        let filter_offset = index_offset + index.len() as u64;
+        let mut footer = Vec::with_capacity(FOOTER_LEN as usize);
+        put_u64(&mut footer, index_offset);
+        put_u64(&mut footer, index.len() as u64);
+        put_u64(&mut footer, filter_offset);
+        put_u64(&mut footer, filter.len() as u64);
+        put_u64(&mut footer, MAGIC);
+
+        self.writer.write_all(&index)?;
+        self.writer.write_all(&filter)?;
+        self.writer.write_all(&footer)?;
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+        Ok(())
+    }
+
+    fn finish_block(&mut self) -> Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        self.writer.write_all(&self.block)?;
+        self.index.push(BlockHandle {
+            last_key: self.last_key.clone(),
+            offset: self.offset,
+            len: self.block.len() as u64,
+        });
+        self.offset += self.block.len() as u64;
+        self.block.clear();
+        Ok(())
+    }
+}
+
+/// An immutable table file opened for reading.
+///
+/// Only the index and the bloom filter are kept in memory. Blocks are read from the
+/// file on demand, with positional reads which don't move a shared cursor, so
+/// concurrent reads of a table don't wait for each other.
+pub struct Table {
+    id: u64,
+    file: File,
+    smallest: String,
+    index: Vec<BlockHandle>,
+    filter: BloomFilter,
+    size: u64,
+}
+
+impl Table {
+    pub fn open(dir: &Path, id: u64) -> Result<Table> {
+        let path = table_path(dir, id);
+        let file = File::open(&path)?;
+        let size = file.metadata()?.len();
+        let corrupted = |what: &str| KvsError::Corrupted(format!("{:?}: {}", path, what));
+        if size < FOOTER_LEN {
+            return Err(corrupted("file too short"));
+        }
+
+        let footer = read_at(&file, size - FOOTER_LEN, FOOTER_LEN)?;
+        let mut footer = Decoder::new(&footer);
+        let index_offset = footer.u64()?;
+        let index_len = footer.u64()?;
+        let filter_offset = footer.u64()?;
+        let filter_len = footer.u64()?;
+        if footer.u64()? != MAGIC {
+            return Err(corrupted("bad magic number"));
+        }
+        if index_offset + index_len > size || filter_offset + filter_len > size {
+            return Err(corrupted("index or filter out of bounds"));
+        }
+
+        let index = read_at(&file, index_offset, index_len)?;
+        let mut index = Decoder::new(&index);
+        let smallest = index.string()?;
+        let num_blocks = index.u64()?;
+        let mut handles = Vec::new();
+        for _ in 0..num_blocks {
+            handles.push(BlockHandle {
+                last_key: index.string()?,
+                offset: index.u64()?,
+                len: index.u64()?,
+            });
+        }
+        if handles.is_empty() {
+            return Err(corrupted("no data block"));
+        }
+        let filter = BloomFilter::decode(read_at(&file, filter_offset, filter_len)?)?;
+
+        Ok(Table {
+            id,
+            file,
+            smallest,
+            index: handles,
+            filter,
+            size,
+        })
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn smallest(&self) -> &str {
+        &self.smallest
+    }
+
+    pub fn largest(&self) -> &str {
+        &self.index.last().unwrap().last_key
+    }
+
+    /// Size of the table file in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Returns whether the table may contain keys in `smallest..=largest`.
+    pub fn overlaps(&self, smallest: &str, largest: &str) -> bool {
+        self.smallest() <= largest && self.largest() >= smallest
+    }
+
+    /// Looks up a key.
+    ///
+    /// Returns `Some(None)` if the table has a tombstone for the key.
+    pub fn get(&self, key: &str) -> Result<Option<Option<String>>> {
+        if key < self.smallest() || key > self.largest() || !self.filter.may_contain(key) {
+            return Ok(None);
+        }
+        let block = self.block_of(key);
+        Ok(self
+            .read_block(block)?
+            .into_iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value))
+    }
+
+    /// Returns an iterator over the entries whose key is not less than `start`.
+    pub fn iter_from(table: &Arc<Table>, start: &str) -> TableIter {
+        TableIter {
+            next_block: table.block_of(start),
+            table: Arc::clone(table),
+            entries: Vec::new().into_iter(),
+            start: start.to_owned(),
+        }
+    }
+
+    // Returns the first block that may contain `key`, or the number of blocks if
+    // `key` is larger than all keys.
+    fn block_of(&self, key: &str) -> usize {
+        match self
+            .index
+            .binary_search_by(|handle| handle.last_key.as_str().cmp(key))
+        {
+            Ok(i) | Err(i) => i,
+        }
+    }
+
+    fn read_block(&self, i: usize) -> Result<Vec<Entry>> {
+        let handle = &self.index[i];
+        let block = read_at(&self.file, handle.offset, handle.len)?;
+        let mut block = Decoder::new(&block);
+        let mut entries = Vec::new();
+        while !block.is_empty() {
+            let key = block.string()?;
+            let value = match block.u8()? {
+                VALUE => Some(block.string()?),
+                TOMBSTONE => None,
+                tag => {
+                    return Err(KvsError::Corrupted(format!(
+                        "table {}: unknown entry tag {}",
+                        self.id, tag
+                    )))
+                }
+            };
+            entries.push((key, value));
+        }
+        Ok(entries)
+    }
+}
+
+/// Iterator over the entries of a table, which reads one block at a time.
+pub struct TableIter {
+    table: Arc<Table>,
+    next_block: usize,
+    entries: std::vec::IntoIter<Entry>,
+    start: String,
+}
+
+impl Iterator for TableIter {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Result<Entry>> {
+        loop {
+            if let Some(entry) = self.entries.next() {
+                return Some(Ok(entry));
+            }
+            if self.next_block >= self.table.index.len() {
+                return None;
+            }
+            match self.table.read_block(self.next_block) {
+                Ok(entries) => {
+                    self.next_block += 1;
+                    let start = &self.start;
+                    self.entries = entries
+                        .into_iter()
+                        .skip_while(|(key, _)| key < start)
+                        .collect::<Vec<_>>()
+                        .into_iter();
+                }
+                Err(e) => {
+                    self.next_block = self.table.index.len();
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn read_at(file: &File, offset: u64, len: u64) -> Result<Vec<u8>> {
+    use std::os::unix::fs::FileExt;
+
+    let mut buf = vec![0; len as usize];
+    file.read_exact_at(&mut buf, offset)?;
+    Ok(buf)
+}
+
+// `seek_read` moves the cursor of the file, but no read relies on it
+#[cfg(windows)]
+fn read_at(file: &File, offset: u64, len: u64) -> Result<Vec<u8>> {
+    use std::io::{Error, ErrorKind};
+    use std::os::windows::fs::FileExt;
+
+    let mut buf = vec![0; len as usize];
+    let mut read = 0;
+    while read < buf.len() {
+        match file.seek_read(&mut buf[read..], offset + read as u64) {
+            Ok(0) => {
+                return Err(
+                    Error::new(ErrorKind::UnexpectedEof, "failed to fill whole buffer").into(),
+                )
+            }
+            Ok(n) => read += n,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(buf)
+}
+
+fn put_u64(buf: &mut Vec<u8>, n: u64) {
+    buf.extend_from_slice(&n.to_le_bytes());
+}
+
+fn put_str(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(&(s.len() as u32).to_le_bytes());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+/// Reads the values written by `put_*` back from a buffer.
+struct Decoder<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn new(buf: &'a [u8]) -> Decoder<'a> {
+        Decoder { buf }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.buf.len() < len {
+            return Err(KvsError::Corrupted("unexpected end of block".to_owned()));
+        }
+        let (head, tail) = self.buf.split_at(len);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        let len = u32::from_le_bytes(bytes) as usize;
+        Ok(String::from_utf8(self.take(len)?.to_vec())?)
+    }
+}
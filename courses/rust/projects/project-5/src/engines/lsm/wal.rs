@@ -0,0 +1,79 @@
+//! Write-ahead logs of the memtable.
+//!
+//! Every write is appended to the current log before it goes into the memtable,
+//! so the memtable can be rebuilt after a restart. A log is deleted once the
+//! memtable it belongs to is flushed to a table.
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use serde_json::Deserializer;
+
+use super::MemTable;
+use crate::engines::kvs::Command;
+use crate::Result;
+
+pub fn wal_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}.wal", id))
+}
+
+pub struct Wal {
+    writer: BufWriter<File>,
+    // whether every command is synced to the disk
+    sync: bool,
+}
+
+impl Wal {
+    pub fn create(path: &Path, sync: bool) -> Result<Wal> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Wal {
+            writer: BufWriter::new(file),
+            sync,
+        })
+    }
+
+    /// Appends a command to the log, and syncs it if the log was created so.
+    ///
+    /// Returns the number of bytes written.
+    pub fn append(&mut self, cmd: &Command) -> Result<u64> {
+        let buf = serde_json::to_vec(cmd)?;
+        self.writer.write_all(&buf)?;
+        self.writer.flush()?;
+        if self.sync {
+            self.writer.get_ref().sync_data()?;
+        }
+        Ok(buf.len() as u64)
+    }
+}
+
+/// Applies all commands of the log at `path` to `memtable`.
+///
+/// A command cut off at the end of the log was never acknowledged, so it is skipped.
+/// Returns the size of the log.
+pub fn replay(path: &Path, memtable: &MemTable) -> Result<u64> {
+    let file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let stream = Deserializer::from_reader(BufReader::new(file)).into_iter::<Command>();
+    for cmd in stream {
+        match cmd {
+            Ok(Command::Set {
+                key,
+                value,
+                compression,
+                ..
+            }) => {
+                memtable.insert(key, Some(compression.decompress(value)?));
+            }
+            Ok(Command::Remove { key }) => {
+                memtable.insert(key, None);
+            }
+            Err(ref e) if e.is_eof() => {
+                warn!("{:?} ends with an incomplete command", path);
+                break;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(len)
+}
@@ -0,0 +1,249 @@
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use tokio::prelude::*;
+
+use super::lru::{shard_of, Lru};
+use super::KvsEngine;
+use crate::{KvsError, ReplicationEvent};
+
+const NUM_SHARDS: usize = 16;
+
+/// The `MemKvsEngine` stores string key/value pairs in memory only.
+///
+/// Keys are spread over several independently locked shards, so operations on
+/// different keys rarely wait for each other. Operations complete immediately
+/// without going through a thread pool.
+///
+/// The engine can be given a capacity in bytes of keys and values. Once it is
+/// exceeded, the least recently used keys are evicted, which makes the engine
+/// usable as a cache. See `CachingKvsEngine`.
+///
+/// ```rust
+/// # use kvs::{MemKvsEngine, Result};
+/// # use tokio::prelude::*;
+/// # fn try_main() -> Result<()> {
+/// use kvs::KvsEngine;
+/// let engine = MemKvsEngine::with_capacity(1024 * 1024);
+/// engine.set("key".to_owned(), "value".to_owned()).wait()?;
+/// let val = engine.get("key".to_owned()).wait()?;
+/// assert_eq!(val, Some("value".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct MemKvsEngine {
+    shards: Arc<Vec<Mutex<Shard>>>,
+}
+
+impl MemKvsEngine {
+    /// Creates an empty engine without capacity limit.
+    pub fn new() -> MemKvsEngine {
+        MemKvsEngine::with_shard_capacity(None)
+    }
+
+    /// Creates an empty engine holding at most `capacity` bytes of keys and values.
+    ///
+    /// The capacity is divided evenly among the shards, so keys may be evicted a
+    /// little before the whole engine is full.
+    pub fn with_capacity(capacity: u64) -> MemKvsEngine {
+        MemKvsEngine::with_shard_capacity(Some(capacity / NUM_SHARDS as u64))
+    }
+
+    fn with_shard_capacity(capacity: Option<u64>) -> MemKvsEngine {
+        let shards = (0..NUM_SHARDS)
+            .map(|_| {
+                Mutex::new(Shard {
+                    entries: Lru::new(capacity),
+                    epoch: 0,
+                })
+            })
+            .collect();
+        MemKvsEngine {
+            shards: Arc::new(shards),
+        }
+    }
+
+    fn shard(&self, key: &str) -> MutexGuard<'_, Shard> {
+        self.shards[shard_of(key, NUM_SHARDS)].lock().unwrap()
+    }
+}
+
+impl Default for MemKvsEngine {
+    fn default() -> MemKvsEngine {
+        MemKvsEngine::new()
+    }
+}
+
+impl KvsEngine for MemKvsEngine {
+    fn set(
+        &self,
+        key: String,
+        value: String,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.shard(&key).insert(key, value);
+        Box::new(future::ok(()))
+    }
+
+    fn get(&self, key: String) -> Box<dyn Future<Item = Option<String>, Error = KvsError> + Send> {
+        Box::new(future::ok(self.shard(&key).get(&key)))
+    }
+
+    fn remove(&self, key: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        match self.shard(&key).remove(&key) {
+            Some(_) => Box::new(future::ok(())),
+            None => Box::new(future::err(KvsError::KeyNotFound)),
+        }
+    }
+
+    /// Returns all key/value pairs whose key starts with `prefix`, in key order.
+    ///
+    /// The pairs are copied when `scan` is called. Scanning doesn't count as a use
+    /// of the keys for eviction.
+    fn scan(
+        &self,
+        prefix: String,
+    ) -> Box<dyn Stream<Item = (String, String), Error = KvsError> + Send> {
+        let mut pairs = Vec::new();
+        for shard in self.shards.iter() {
+            let shard = shard.lock().unwrap();
+            pairs.extend(
+                shard
+                    .entries
+                    .range_from(prefix.clone())
+                    .take_while(|(key, _)| key.starts_with(&prefix))
+                    .map(|(key, value)| (key.clone(), value.clone())),
+            );
+        }
+        pairs.sort_unstable();
+        Box::new(stream::iter_ok(pairs))
+    }
+}
+
+struct Shard {
+    entries: Lru<String>,
+    // incremented whenever a key is invalidated, see `CachingKvsEngine`
+    epoch: u64,
+}
+
+impl Shard {
+    fn get(&mut self, key: &str) -> Option<String> {
+        self.entries.get(key).cloned()
+    }
+
+    fn insert(&mut self, key: String, value: String) {
+        let size = (key.len() + value.len()) as u64;
+        self.entries.insert(key, value, size);
+    }
+
+    fn remove(&mut self, key: &str) -> Option<String> {
+        self.entries.remove(key)
+    }
+
+    fn invalidate(&mut self, key: &str) {
+        self.remove(key);
+        self.epoch += 1;
+    }
+}
+
+/// A `KvsEngine` that caches the values of another engine in memory.
+///
+/// Values read through `get` are kept in a `MemKvsEngine` of the given capacity.
+/// `set` and `remove` go straight to the underlying engine and drop the key from
+/// the cache once they complete, so the cache never returns a value older than
+/// the last completed write. `scan` is not cached.
+#[derive(Clone)]
+pub struct CachingKvsEngine<E: KvsEngine> {
+    engine: E,
+    cache: MemKvsEngine,
+}
+
+impl<E: KvsEngine> CachingKvsEngine<E> {
+    /// Creates a cache of at most `capacity` bytes in front of `engine`.
+    pub fn new(engine: E, capacity: u64) -> CachingKvsEngine<E> {
+        CachingKvsEngine {
+            engine,
+            cache: MemKvsEngine::with_capacity(capacity),
+        }
+    }
+
+    /// Returns the underlying engine.
+    pub fn engine(&self) -> &E {
+        &self.engine
+    }
+
+    fn invalidate_after<T, F>(
+        &self,
+        key: String,
+        f: F,
+    ) -> Box<dyn Future<Item = T, Error = KvsError> + Send>
+    where
+        F: Future<Item = T, Error = KvsError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let cache = self.cache.clone();
+        Box::new(f.then(move |res| {
+            cache.shard(&key).invalidate(&key);
+            res
+        }))
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for CachingKvsEngine<E> {
+    fn set(
+        &self,
+        key: String,
+        value: String,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let write = self.engine.set(key.clone(), value);
+        self.invalidate_after(key, write)
+    }
+
+    fn get(&self, key: String) -> Box<dyn Future<Item = Option<String>, Error = KvsError> + Send> {
+        let epoch = {
+            let mut shard = self.cache.shard(&key);
+            if let Some(value) = shard.get(&key) {
+                return Box::new(future::ok(Some(value)));
+            }
+            shard.epoch
+        };
+        let cache = self.cache.clone();
+        Box::new(self.engine.get(key.clone()).map(move |value| {
+            let mut shard = cache.shard(&key);
+            // a write may have completed after the value was read, which makes
+            // the value stale
+            if shard.epoch == epoch {
+                if let Some(value) = &value {
+                    shard.insert(key, value.clone());
+                }
+            }
+            value
+        }))
+    }
+
+    fn remove(&self, key: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let write = self.engine.remove(key.clone());
+        self.invalidate_after(key, write)
+    }
+
+    fn scan(
+        &self,
+        prefix: String,
+    ) -> Box<dyn Stream<Item = (String, String), Error = KvsError> + Send> {
+        self.engine.scan(prefix)
+    }
+
+    fn query_index(
+        &self,
+        name: String,
+        value: String,
+    ) -> Box<dyn Future<Item = Vec<String>, Error = KvsError> + Send> {
+        self.engine.query_index(name, value)
+    }
+
+    fn replicate(
+        &self,
+        buffer: usize,
+    ) -> Box<dyn Stream<Item = ReplicationEvent, Error = KvsError> + Send> {
+        self.engine.replicate(buffer)
+    }
+}
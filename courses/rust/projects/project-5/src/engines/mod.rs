@@ -1,11 +1,27 @@
-pub use self::kvs::KvStore;
+pub use self::compression::Compression;
+pub use self::encryption::EncryptionKey;
+pub use self::kvs::{CacheStats, KvStore, KvStoreOptions};
+pub use self::lsm::{LsmKvsEngine, LsmKvsEngineOptions};
+pub use self::memory::{CachingKvsEngine, MemKvsEngine};
 pub use self::sled::SledKvsEngine;
-use crate::KvsError;
+pub use self::storage::{
+    FileStorage, MemStorage, SegmentMap, SegmentReader, SegmentWriter, Storage,
+};
+use crate::thread_pool::ThreadPool;
+use crate::{KvsError, ReplicationEvent, Result};
 
-use tokio::prelude::Future;
+use tokio::prelude::*;
+use tokio::sync::mpsc;
 
-mod kvs;
+mod compression;
+pub(crate) mod encryption;
+pub(crate) mod kvs;
+mod lru;
+mod lsm;
+mod memory;
+mod secondary;
 mod sled;
+mod storage;
 
 /// Trait for a key value storage engine.
 pub trait KvsEngine: Clone + Send + 'static {
@@ -25,4 +41,95 @@ pub trait KvsEngine: Clone + Send + 'static {
     ///
     /// It returns `KvsError::KeyNotFound` if the given key is not found.
     fn remove(&self, key: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send>;
+
+    /// Returns all key/value pairs whose key starts with `prefix`, in key order.
+    ///
+    /// The pairs are produced lazily, so the whole result never needs to be held
+    /// in memory.
+    fn scan(
+        &self,
+        prefix: String,
+    ) -> Box<dyn Stream<Item = (String, String), Error = KvsError> + Send>;
+
+    /// Returns the keys whose value has `value` as field of the secondary index
+    /// `name`, in key order.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::UnknownIndex` if the engine has no such index, which
+    /// is always the case for engines without secondary indexes.
+    fn query_index(
+        &self,
+        name: String,
+        _value: String,
+    ) -> Box<dyn Future<Item = Vec<String>, Error = KvsError> + Send> {
+        Box::new(future::err(KvsError::UnknownIndex(name)))
+    }
+
+    /// Returns a snapshot of all pairs, followed by the writes applied after it in
+    /// the order the engine applies them, which keeps a replica up to date.
+    ///
+    /// Up to `buffer` writes are kept until they are received. Once the buffer is
+    /// full, writes are dropped and reported with a `WatchEvent::Lagged`.
+    ///
+    /// # Errors
+    ///
+    /// The stream fails with `KvsError::ReplicationUnsupported` if the engine has
+    /// no log to ship, which is the case for all engines but `KvStore`.
+    fn replicate(
+        &self,
+        _buffer: usize,
+    ) -> Box<dyn Stream<Item = ReplicationEvent, Error = KvsError> + Send> {
+        Box::new(stream::once(Err(KvsError::ReplicationUnsupported)))
+    }
+}
+
+/// How many pairs a `scan` reads ahead of its consumer.
+const SCAN_BUFFER: usize = 64;
+
+type ScanSender = mpsc::Sender<Result<(String, String)>>;
+
+/// Runs `scan` in a thread of `pool`, and returns the stream of the pairs it sends.
+///
+/// The stream fails with the error of `ThreadPool::try_spawn` if the pool rejects
+/// the scan.
+fn spawn_scan<P, F>(
+    pool: &P,
+    scan: F,
+) -> Box<dyn Stream<Item = (String, String), Error = KvsError> + Send>
+where
+    P: ThreadPool,
+    F: FnOnce(&mut ScanSender) + Send + 'static,
+{
+    let (mut tx, rx) = mpsc::channel(SCAN_BUFFER);
+    if let Err(e) = pool.try_spawn(move || scan(&mut tx)) {
+        return Box::new(stream::once(Err(e)));
+    }
+    let rx = rx
+        .map_err(|e| KvsError::StringError(format!("{}", e)))
+        .and_then(|res| res);
+    Box::new(rx)
+}
+
+/// Sends the pairs produced by `pairs` to the stream returned by `scan`.
+///
+/// It blocks when the consumer falls `SCAN_BUFFER` pairs behind, so it must be
+/// called in a thread of the thread pool. It stops at the first error or when the
+/// stream is dropped. The stream ends when `tx` is dropped, so callers should
+/// release everything the scan uses before that.
+fn send_scan<I>(pairs: I, tx: &mut ScanSender)
+where
+    I: Iterator<Item = Result<(String, String)>>,
+{
+    for res in pairs {
+        let is_err = res.is_err();
+        let ready = future::poll_fn(|| tx.poll_ready()).wait();
+        if ready.is_err() || tx.try_send(res).is_err() {
+            debug!("Scan stops because the receiving end is dropped");
+            return;
+        }
+        if is_err {
+            return;
+        }
+    }
 }
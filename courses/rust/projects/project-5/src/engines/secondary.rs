@@ -0,0 +1,121 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use serde_json::Value;
+
+use crate::{KvsError, Result};
+
+/// The secondary indexes of a `KvStore`, which map a field of JSON values back
+/// to their keys.
+///
+/// Each index has a JSON pointer to the indexed field. A value is only indexed if
+/// it is a JSON document with a string, a number or a boolean at that pointer.
+/// Strings are indexed by their content, other fields by their JSON text.
+#[derive(Default)]
+pub(crate) struct SecondaryIndexes {
+    indexes: BTreeMap<String, SecondaryIndex>,
+}
+
+#[derive(Default)]
+struct SecondaryIndex {
+    pointer: String,
+    // (field, key) of all indexed keys, to find the keys of a field in order
+    entries: BTreeSet<(String, String)>,
+    // the field of each indexed key, to remove its entry when it changes
+    fields: HashMap<String, String>,
+}
+
+impl SecondaryIndexes {
+    /// Creates empty indexes from pairs of names and JSON pointers.
+    ///
+    /// Fails if a pointer isn't empty and doesn't start with a `/`, or if a name is
+    /// used twice.
+    pub(crate) fn new(declared: &[(String, String)]) -> Result<SecondaryIndexes> {
+        let mut indexes = BTreeMap::new();
+        for (name, pointer) in declared {
+            if !pointer.is_empty() && !pointer.starts_with('/') {
+                return Err(KvsError::StringError(format!(
+                    "Invalid JSON pointer of index {}: {}",
+                    name, pointer
+                )));
+            }
+            let index = SecondaryIndex {
+                pointer: pointer.clone(),
+                ..SecondaryIndex::default()
+            };
+            if indexes.insert(name.clone(), index).is_some() {
+                return Err(KvsError::StringError(format!(
+                    "Index {} is declared twice",
+                    name
+                )));
+            }
+        }
+        Ok(SecondaryIndexes { indexes })
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.indexes.is_empty()
+    }
+
+    /// Returns the field of `value` for each index, in the order of the names.
+    ///
+    /// It is computed before the write, so that parsing the value doesn't delay
+    /// the other writers.
+    pub(crate) fn fields(&self, value: &str) -> Vec<Option<String>> {
+        if self.is_empty() {
+            return Vec::new();
+        }
+        let doc = serde_json::from_str::<Value>(value).ok();
+        self.indexes
+            .values()
+            .map(|index| doc.as_ref().and_then(|doc| field(doc, &index.pointer)))
+            .collect()
+    }
+
+    /// Indexes `key` with the fields returned by `fields` for its new value.
+    pub(crate) fn set(&mut self, key: &str, fields: Vec<Option<String>>) {
+        for (index, field) in self.indexes.values_mut().zip(fields) {
+            index.remove(key);
+            if let Some(field) = field {
+                index.entries.insert((field.clone(), key.to_owned()));
+                index.fields.insert(key.to_owned(), field);
+            }
+        }
+    }
+
+    /// Removes `key` from all indexes.
+    pub(crate) fn remove(&mut self, key: &str) {
+        for index in self.indexes.values_mut() {
+            index.remove(key);
+        }
+    }
+
+    /// Returns the keys whose field in the index `name` is `field`, in key order.
+    pub(crate) fn query(&self, name: &str, field: &str) -> Result<Vec<String>> {
+        let index = self
+            .indexes
+            .get(name)
+            .ok_or_else(|| KvsError::UnknownIndex(name.to_owned()))?;
+        Ok(index
+            .entries
+            .range((field.to_owned(), String::new())..)
+            .take_while(|(f, _)| f == field)
+            .map(|(_, key)| key.clone())
+            .collect())
+    }
+}
+
+impl SecondaryIndex {
+    fn remove(&mut self, key: &str) {
+        if let Some(field) = self.fields.remove(key) {
+            self.entries.remove(&(field, key.to_owned()));
+        }
+    }
+}
+
+fn field(doc: &Value, pointer: &str) -> Option<String> {
+    match doc.pointer(pointer)? {
+        Value::String(s) => Some(s.clone()),
+        field @ Value::Number(_) | field @ Value::Bool(_) => Some(field.to_string()),
+        _ => None,
+    }
+}
@@ -1,8 +1,8 @@
+use super::{send_scan, spawn_scan};
 use crate::thread_pool::ThreadPool;
 use crate::{KvsEngine, KvsError, Result};
 use sled::Db;
 use tokio::prelude::*;
-use tokio::sync::oneshot;
 
 /// Wrapper of `sled::Db`
 #[derive(Clone)]
@@ -17,68 +17,66 @@ impl<P: ThreadPool> SledKvsEngine<P> {
     /// Operations are run in the given thread pool. `concurrency` specifies the number of
     /// threads in the thread pool.
     pub fn new(db: Db, concurrency: u32) -> Result<Self> {
-        let pool = P::new(concurrency)?;
-        Ok(SledKvsEngine { pool, db })
+        Ok(SledKvsEngine::with_pool(db, P::new(concurrency)?))
+    }
+
+    /// Creates a `SledKvsEngine` from `sled::Db` whose operations run in `pool`.
+    pub fn with_pool(db: Db, pool: P) -> Self {
+        SledKvsEngine { pool, db }
     }
 }
 
 impl<P: ThreadPool> KvsEngine for SledKvsEngine<P> {
     fn set(&self, key: String, value: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
         let db = self.db.clone();
-        let (tx, rx) = oneshot::channel();
-        self.pool.spawn(move || {
-            let res = db
-                .set(key, value.into_bytes())
+        let task = self.pool.spawn_with_handle(move |_| {
+            db.set(key, value.into_bytes())
                 .and_then(|_| db.flush())
                 .map(|_| ())
-                .map_err(KvsError::from);
-            if tx.send(res).is_err() {
-                error!("Receiving end is dropped");
-            }
+                .map_err(KvsError::from)
         });
-        Box::new(
-            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
-                .flatten(),
-        )
+        Box::new(task.flatten())
     }
 
     fn get(&self, key: String) -> Box<dyn Future<Item = Option<String>, Error = KvsError> + Send> {
         let db = self.db.clone();
-        let (tx, rx) = oneshot::channel();
-        self.pool.spawn(move || {
-            let res = (move || {
-                Ok(db
-                    .get(key)?
-                    .map(|i_vec| AsRef::<[u8]>::as_ref(&i_vec).to_vec())
-                    .map(String::from_utf8)
-                    .transpose()?)
-            })();
-            if tx.send(res).is_err() {
-                error!("Receiving end is dropped");
-            }
+        let task = self.pool.spawn_with_handle(move |_| -> Result<_> {
+            Ok(db
+                .get(key)?
+                .map(|i_vec| AsRef::<[u8]>::as_ref(&i_vec).to_vec())
+                .map(String::from_utf8)
+                .transpose()?)
         });
-        Box::new(
-            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
-                .flatten(),
-        )
+        Box::new(task.flatten())
     }
 
     fn remove(&self, key: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
         let db = self.db.clone();
-        let (tx, rx) = oneshot::channel();
-        self.pool.spawn(move || {
-            let res = (|| {
-                db.del(key)?.ok_or(KvsError::KeyNotFound)?;
-                db.flush()?;
-                Ok(())
-            })();
-            if tx.send(res).is_err() {
-                error!("Receiving end is dropped");
-            }
+        let task = self.pool.spawn_with_handle(move |_| -> Result<()> {
+            db.del(key)?.ok_or(KvsError::KeyNotFound)?;
+            db.flush()?;
+            Ok(())
         });
-        Box::new(
-            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
-                .flatten(),
-        )
+        Box::new(task.flatten())
+    }
+
+    fn scan(
+        &self,
+        prefix: String,
+    ) -> Box<dyn Stream<Item = (String, String), Error = KvsError> + Send> {
+        let db = self.db.clone();
+        spawn_scan(&self.pool, move |tx| {
+            let pairs = db
+                .scan_prefix(prefix)
+                .map(|res| -> Result<(String, String)> {
+                    let (key, value) = res?;
+                    let key = String::from_utf8(AsRef::<[u8]>::as_ref(&key).to_vec())?;
+                    let value = String::from_utf8(AsRef::<[u8]>::as_ref(&value).to_vec())?;
+                    Ok((key, value))
+                });
+            send_scan(pairs, tx);
+            // don't keep the database open after the stream ends
+            drop(db);
+        })
     }
 }
@@ -0,0 +1,398 @@
+//! Where a `KvStore` keeps its log segments.
+//!
+//! A segment is the log of one generation. The store appends to the segment of
+//! its current generation, reads any of them at random positions, and deletes the
+//! segments made stale by a compaction. Indexing and compaction only go through
+//! `Storage`, so they work the same on any backend.
+//!
+//! A compaction writes its segment as a temporary one, which is never listed,
+//! and renames it once it is complete and synced. The creations, renames and
+//! deletions of segments only survive a power loss once the storage is synced
+//! with `sync_dir`, and the store syncs it before relying on them.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+
+use fs2::FileExt;
+use memmap2::Mmap;
+
+use super::kvs::{log_path, sorted_gen_list};
+use crate::{KvsError, Result};
+
+// the file locked by the store which has the directory open
+const LOCK_FILE: &str = "LOCK";
+// the extension of the temporary segments, after `.log`
+const TEMP_EXTENSION: &str = "tmp";
+
+/// The segments of a `KvStore`.
+///
+/// `FileStorage` keeps them in a directory and `MemStorage` in memory. A storage
+/// is used by a single store, which never writes to a segment it didn't create
+/// since it was opened.
+pub trait Storage: Send + Sync + 'static {
+    /// Returns the generations of the existing segments in ascending order.
+    fn list(&self) -> Result<Vec<u64>>;
+
+    /// Opens the segment of `gen` for reading.
+    fn open(&self, gen: u64) -> Result<Box<dyn SegmentReader>>;
+
+    /// Creates the empty segment of `gen`, to which records are appended.
+    fn create(&self, gen: u64) -> Result<Box<dyn SegmentWriter>>;
+
+    /// Creates the empty temporary segment of `gen`, replacing any.
+    ///
+    /// It is neither listed nor opened until `rename_temp` makes it the segment
+    /// of `gen`, so one left by a failure is ignored.
+    fn create_temp(&self, gen: u64) -> Result<Box<dyn SegmentWriter>>;
+
+    /// Atomically replaces the segment of `gen` by its temporary segment.
+    fn rename_temp(&self, gen: u64) -> Result<()>;
+
+    /// Deletes the segment of `gen`.
+    ///
+    /// Readers which have the segment open may still read it.
+    fn delete(&self, gen: u64) -> Result<()>;
+
+    /// Makes the creations, renames and deletions of segments durable.
+    fn sync_dir(&self) -> Result<()>;
+
+    /// Maps the segment of `gen` in memory, if the storage supports it.
+    ///
+    /// Only called on segments which are no longer written.
+    fn map(&self, _gen: u64) -> Result<Option<SegmentMap>> {
+        Ok(None)
+    }
+}
+
+/// The bytes of a segment mapped in memory by `Storage::map`.
+pub struct SegmentMap(Box<dyn AsRef<[u8]> + Send + Sync>);
+
+impl SegmentMap {
+    /// Wraps the mapped bytes, which are unmapped when the `SegmentMap` is dropped.
+    pub fn new(bytes: impl AsRef<[u8]> + Send + Sync + 'static) -> SegmentMap {
+        SegmentMap(Box::new(bytes))
+    }
+}
+
+impl Deref for SegmentMap {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        (*self.0).as_ref()
+    }
+}
+
+/// A segment opened for reading.
+pub trait SegmentReader: Read + Seek + Send {}
+
+impl<R: Read + Seek + Send> SegmentReader for R {}
+
+/// A segment opened for appending.
+///
+/// A write is visible to the readers once flushed, but only survives a power
+/// loss once synced.
+pub trait SegmentWriter: Write + Send {
+    /// Makes everything written so far durable.
+    fn sync(&mut self) -> io::Result<()>;
+
+    /// Cuts the segment to `len` bytes, the following writes appending from there.
+    fn truncate(&mut self, len: u64) -> io::Result<()>;
+}
+
+/// Segments stored as `<gen>.log` files in a directory.
+///
+/// A temporary segment is a `<gen>.log.tmp` file, deleted when the directory is
+/// opened again.
+pub struct FileStorage {
+    path: PathBuf,
+    // keeps the directory locked until the storage is dropped
+    _lock: File,
+}
+
+impl FileStorage {
+    /// Opens the directory at `path`, creating it if it does not exist.
+    ///
+    /// The directory is locked with a `LOCK` file in it until the storage is
+    /// dropped. It returns `KvsError::DirectoryLocked` if another storage has the
+    /// directory open, in this process or another one. The temporary segments
+    /// left by an interrupted compaction are deleted.
+    pub fn open(path: impl Into<PathBuf>) -> Result<FileStorage> {
+        let path = path.into();
+        fs::create_dir_all(&path)?;
+        let lock = lock_dir(&path)?;
+        for entry in fs::read_dir(&path)? {
+            let entry_path = entry?.path();
+            if entry_path.is_file() && entry_path.extension() == Some(TEMP_EXTENSION.as_ref()) {
+                fs::remove_file(entry_path)?;
+            }
+        }
+        Ok(FileStorage { path, _lock: lock })
+    }
+
+    fn temp_path(&self, gen: u64) -> PathBuf {
+        self.path.join(format!("{}.log.{}", gen, TEMP_EXTENSION))
+    }
+}
+
+impl Storage for FileStorage {
+    fn list(&self) -> Result<Vec<u64>> {
+        sorted_gen_list(&self.path)
+    }
+
+    fn open(&self, gen: u64) -> Result<Box<dyn SegmentReader>> {
+        Ok(Box::new(File::open(log_path(&self.path, gen))?))
+    }
+
+    fn create(&self, gen: u64) -> Result<Box<dyn SegmentWriter>> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(log_path(&self.path, gen))?;
+        Ok(Box::new(file))
+    }
+
+    fn create_temp(&self, gen: u64) -> Result<Box<dyn SegmentWriter>> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(self.temp_path(gen))?;
+        Ok(Box::new(file))
+    }
+
+    fn rename_temp(&self, gen: u64) -> Result<()> {
+        Ok(fs::rename(self.temp_path(gen), log_path(&self.path, gen))?)
+    }
+
+    fn delete(&self, gen: u64) -> Result<()> {
+        Ok(fs::remove_file(log_path(&self.path, gen))?)
+    }
+
+    fn sync_dir(&self) -> Result<()> {
+        Ok(sync_dir(&self.path)?)
+    }
+
+    fn map(&self, gen: u64) -> Result<Option<SegmentMap>> {
+        let file = File::open(log_path(&self.path, gen))?;
+        // an empty file can't be mapped
+        if file.metadata()?.len() == 0 {
+            return Ok(None);
+        }
+        // The segment is never modified once it is no longer written, so the map
+        // stays valid until it is dropped.
+        let map = unsafe { Mmap::map(&file)? };
+        Ok(Some(SegmentMap::new(map)))
+    }
+}
+
+/// Locks the directory at `path` with a `LOCK` file in it, until the returned
+/// file is closed.
+///
+/// It returns `KvsError::DirectoryLocked` if the directory is already locked, in
+/// this process or another one.
+pub(crate) fn lock_dir(path: &Path) -> Result<File> {
+    let lock = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(false)
+        .open(path.join(LOCK_FILE))?;
+    // the lock is released once the file is closed, even if the process is killed
+    match lock.try_lock_exclusive() {
+        Ok(()) => Ok(lock),
+        Err(e) if e.kind() == fs2::lock_contended_error().kind() => {
+            Err(KvsError::DirectoryLocked(path.display().to_string()))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Makes the creations, renames and deletions of files in the directory at `path`
+/// durable.
+#[cfg(unix)]
+pub(crate) fn sync_dir(path: &Path) -> io::Result<()> {
+    File::open(path)?.sync_all()
+}
+
+// a directory can't be opened as a file on other platforms
+#[cfg(not(unix))]
+pub(crate) fn sync_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+impl SegmentWriter for File {
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_data()
+    }
+
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        // writes go to the end of a file opened for appending, wherever it is
+        self.set_len(len)
+    }
+}
+
+/// Segments kept in memory, which are lost once the last clone is dropped.
+///
+/// Clones share the same segments, so a store can be opened again from a clone
+/// once the previous store is dropped. Two stores must not be opened on the same
+/// segments at the same time.
+///
+/// ```rust
+/// # use kvs::{KvStore, KvStoreOptions, MemStorage, Result};
+/// # use kvs::thread_pool::RayonThreadPool;
+/// # use tokio::prelude::*;
+/// # fn try_main() -> Result<()> {
+/// use kvs::KvsEngine;
+/// let storage = MemStorage::new();
+/// let store: KvStore<RayonThreadPool> =
+///     KvStoreOptions::new().open_storage(storage.clone(), 2)?;
+/// store.set("key".to_owned(), "value".to_owned()).wait()?;
+/// drop(store);
+///
+/// let store: KvStore<RayonThreadPool> = KvStoreOptions::new().open_storage(storage, 2)?;
+/// assert_eq!(store.get("key".to_owned()).wait()?, Some("value".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Default)]
+pub struct MemStorage {
+    segments: Arc<Mutex<BTreeMap<u64, Segment>>>,
+    temps: Arc<Mutex<BTreeMap<u64, Segment>>>,
+}
+
+type Segment = Arc<RwLock<Vec<u8>>>;
+
+impl MemStorage {
+    /// Creates a storage without segments.
+    pub fn new() -> MemStorage {
+        MemStorage::default()
+    }
+
+    fn segment(&self, gen: u64) -> Result<Segment> {
+        match self.segments.lock().unwrap().get(&gen) {
+            Some(segment) => Ok(Arc::clone(segment)),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Segment {} not found", gen),
+            )
+            .into()),
+        }
+    }
+}
+
+impl Storage for MemStorage {
+    fn list(&self) -> Result<Vec<u64>> {
+        Ok(self.segments.lock().unwrap().keys().cloned().collect())
+    }
+
+    fn open(&self, gen: u64) -> Result<Box<dyn SegmentReader>> {
+        Ok(Box::new(MemSegmentReader {
+            segment: self.segment(gen)?,
+            pos: 0,
+        }))
+    }
+
+    fn create(&self, gen: u64) -> Result<Box<dyn SegmentWriter>> {
+        let segment = Arc::clone(self.segments.lock().unwrap().entry(gen).or_default());
+        Ok(Box::new(MemSegmentWriter { segment }))
+    }
+
+    fn create_temp(&self, gen: u64) -> Result<Box<dyn SegmentWriter>> {
+        let segment = Segment::default();
+        self.temps.lock().unwrap().insert(gen, Arc::clone(&segment));
+        Ok(Box::new(MemSegmentWriter { segment }))
+    }
+
+    fn rename_temp(&self, gen: u64) -> Result<()> {
+        match self.temps.lock().unwrap().remove(&gen) {
+            Some(segment) => {
+                self.segments.lock().unwrap().insert(gen, segment);
+                Ok(())
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Temporary segment {} not found", gen),
+            )
+            .into()),
+        }
+    }
+
+    fn delete(&self, gen: u64) -> Result<()> {
+        // open readers keep their own reference to the segment
+        self.segment(gen)?;
+        self.segments.lock().unwrap().remove(&gen);
+        Ok(())
+    }
+
+    fn sync_dir(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+struct MemSegmentReader {
+    segment: Segment,
+    pos: u64,
+}
+
+impl Read for MemSegmentReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let segment = self.segment.read().unwrap();
+        let start = (self.pos as usize).min(segment.len());
+        let len = buf.len().min(segment.len() - start);
+        buf[..len].copy_from_slice(&segment[start..start + len]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Seek for MemSegmentReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(pos) => {
+                self.pos = pos;
+                return Ok(pos);
+            }
+            SeekFrom::End(offset) => (self.segment.read().unwrap().len() as u64, offset),
+            SeekFrom::Current(offset) => (self.pos, offset),
+        };
+        match base.checked_add_signed(offset) {
+            Some(pos) => {
+                self.pos = pos;
+                Ok(pos)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+struct MemSegmentWriter {
+    segment: Segment,
+}
+
+impl Write for MemSegmentWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.segment.write().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SegmentWriter for MemSegmentWriter {
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        self.segment.write().unwrap().truncate(len as usize);
+        Ok(())
+    }
+}
@@ -11,6 +11,9 @@ pub enum KvsError {
     /// Serialization or deserialization error
     #[fail(display = "serde_json error: {}", _0)]
     Serde(#[cause] serde_json::Error),
+    /// CSV reading or writing error
+    #[fail(display = "CSV error: {}", _0)]
+    Csv(#[cause] csv::Error),
     /// Removing non-existent key error
     #[fail(display = "Key not found")]
     KeyNotFound,
@@ -18,6 +21,33 @@ pub enum KvsError {
     /// It indicated a corrupted log or a program bug.
     #[fail(display = "Unexpected command type")]
     UnexpectedCommandType,
+    /// A write was sent to a replica.
+    #[fail(display = "The server is a read-only replica")]
+    ReadOnlyReplica,
+    /// A replica asked for the log of an engine which doesn't have one.
+    #[fail(display = "The storage engine doesn't support replication")]
+    ReplicationUnsupported,
+    /// A record is encrypted with a key that wasn't given to the store.
+    #[fail(display = "Wrong encryption key: {}", _0)]
+    WrongEncryptionKey(String),
+    /// The queue of a thread pool is full and rejects new tasks.
+    #[fail(display = "Server busy: too many pending requests")]
+    ServerBusy,
+    /// A task of a thread pool panicked.
+    #[fail(display = "The task panicked")]
+    TaskPanicked,
+    /// A task of a thread pool was cancelled or dropped before it ran.
+    #[fail(display = "The task was cancelled")]
+    TaskCancelled,
+    /// The data directory is opened by another store, likely in another process.
+    #[fail(display = "The data directory {} is already in use", _0)]
+    DirectoryLocked(String),
+    /// A query names a secondary index the engine doesn't have.
+    #[fail(display = "Unknown index: {}", _0)]
+    UnknownIndex(String),
+    /// A data file can't be decoded.
+    #[fail(display = "Corrupted data: {}", _0)]
+    Corrupted(String),
     /// Key or value is invalid UTF-8 sequence
     #[fail(display = "UTF-8 error: {}", _0)]
     Utf8(#[cause] FromUtf8Error),
@@ -41,6 +71,12 @@ impl From<serde_json::Error> for KvsError {
     }
 }
 
+impl From<csv::Error> for KvsError {
+    fn from(err: csv::Error) -> KvsError {
+        KvsError::Csv(err)
+    }
+}
+
 impl From<FromUtf8Error> for KvsError {
     fn from(err: FromUtf8Error) -> KvsError {
         KvsError::Utf8(err)
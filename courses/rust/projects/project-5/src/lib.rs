@@ -5,13 +5,28 @@
 extern crate log;
 
 pub use client::KvsClient;
-pub use engines::{KvStore, KvsEngine, SledKvsEngine};
+pub use engines::{
+    CacheStats, CachingKvsEngine, Compression, EncryptionKey, FileStorage, KvStore,
+    KvStoreOptions, KvsEngine, LsmKvsEngine, LsmKvsEngineOptions, MemKvsEngine, MemStorage,
+    SegmentMap, SegmentReader, SegmentWriter, SledKvsEngine, Storage,
+};
 pub use error::{KvsError, Result};
+pub use replication::{ReplicationEvent, ServerStatus};
 pub use server::KvsServer;
+pub use sharding::{HashRing, Migration, ShardedKvsClient};
+pub use watch::WatchEvent;
 
+pub mod admin;
+pub mod bulk;
 mod client;
 mod common;
+pub mod config;
 mod engines;
 mod error;
+mod replication;
+pub mod repl;
 mod server;
+mod sharding;
 pub mod thread_pool;
+mod watch;
+pub mod workload;
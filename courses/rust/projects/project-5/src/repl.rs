@@ -0,0 +1,357 @@
+//! Interactive sessions of `kvs-client repl`.
+//!
+//! A line holds one command, whose words are split like a shell does, so a value
+//! containing spaces can be quoted: `set greeting "hello world"`. A `Session` keeps
+//! one connection to the server across commands, even when the server replies with
+//! an error. An error of the connection itself closes it, and the next command
+//! connects again.
+
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde_json::{json, Map, Value};
+use tokio::prelude::*;
+
+use crate::common::{Request, Response};
+use crate::{KvsClient, KvsError, Result, ServerStatus};
+
+// name, arguments and description of the commands, in the order of the help
+const COMMANDS: &[(&str, &str, &str)] = &[
+    ("get", "KEY", "Gets the value of KEY"),
+    ("set", "KEY VALUE", "Sets the value of KEY"),
+    ("rm", "KEY", "Removes KEY"),
+    (
+        "scan",
+        "[PREFIX]",
+        "Lists the pairs whose key starts with PREFIX",
+    ),
+    ("mget", "KEY...", "Gets the values of several keys"),
+    ("mset", "KEY VALUE...", "Sets the values of several keys"),
+    ("status", "", "Prints the replication role of the server"),
+    ("promote", "", "Turns a replica into a primary"),
+    ("help", "", "Prints this help"),
+    ("quit", "", "Ends the session, like `exit` or Ctrl-D"),
+];
+
+/// A line read by the REPL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Input {
+    /// A request to the server.
+    Command(ReplCommand),
+    /// Prints the list of commands.
+    Help,
+    /// Ends the session.
+    Quit,
+}
+
+/// A request to the server, as typed in the REPL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplCommand {
+    /// `get KEY`
+    Get {
+        /// The key to read.
+        key: String,
+    },
+    /// `set KEY VALUE`
+    Set {
+        /// The key to write.
+        key: String,
+        /// The new value of the key.
+        value: String,
+    },
+    /// `rm KEY`
+    Remove {
+        /// The key to remove.
+        key: String,
+    },
+    /// `scan [PREFIX]`
+    Scan {
+        /// The prefix of the keys to list, empty for all keys.
+        prefix: String,
+    },
+    /// `mget KEY...`
+    GetBatch {
+        /// The keys to read.
+        keys: Vec<String>,
+    },
+    /// `mset KEY VALUE...`
+    SetBatch {
+        /// The pairs to write.
+        pairs: Vec<(String, String)>,
+    },
+    /// `status`
+    Status,
+    /// `promote`
+    Promote,
+}
+
+/// The answer of the server to a `ReplCommand`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    /// The value of a key, `None` if the key is not found.
+    Value(Option<String>),
+    /// The values of several keys, with their keys.
+    Values(Vec<(String, Option<String>)>),
+    /// Key/value pairs, in key order.
+    Pairs(Vec<(String, String)>),
+    /// The replication role of the server.
+    Status(ServerStatus),
+    /// The command succeeded without a result.
+    Done,
+}
+
+/// How the REPL prints replies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Plain text, followed by the duration of the command.
+    Text,
+    /// One JSON object per command, with either a `result` or an `error`, and the
+    /// duration of the command in `elapsed_ms`.
+    Json,
+}
+
+/// Parses a line of the REPL. Returns `None` if the line is blank.
+///
+/// # Errors
+///
+/// It returns an error naming the problem if the command is unknown, its
+/// arguments don't match, or a quote is not closed.
+pub fn parse(line: &str) -> Result<Option<Input>> {
+    let words =
+        shlex::split(line).ok_or_else(|| KvsError::StringError("Unclosed quote".to_owned()))?;
+    let (name, args) = match words.split_first() {
+        Some((name, args)) => (name.as_str(), args),
+        None => return Ok(None),
+    };
+    let command = match (name, args) {
+        ("get", [key]) => ReplCommand::Get { key: key.clone() },
+        ("set", [key, value]) => ReplCommand::Set {
+            key: key.clone(),
+            value: value.clone(),
+        },
+        ("rm", [key]) => ReplCommand::Remove { key: key.clone() },
+        ("scan", []) => ReplCommand::Scan {
+            prefix: String::new(),
+        },
+        ("scan", [prefix]) => ReplCommand::Scan {
+            prefix: prefix.clone(),
+        },
+        ("mget", keys) if !keys.is_empty() => ReplCommand::GetBatch {
+            keys: keys.to_vec(),
+        },
+        ("mset", args) if !args.is_empty() && args.len() % 2 == 0 => ReplCommand::SetBatch {
+            pairs: args
+                .chunks(2)
+                .map(|pair| (pair[0].clone(), pair[1].clone()))
+                .collect(),
+        },
+        ("status", []) => ReplCommand::Status,
+        ("promote", []) => ReplCommand::Promote,
+        ("help", []) => return Ok(Some(Input::Help)),
+        ("quit", []) | ("exit", []) => return Ok(Some(Input::Quit)),
+        _ => {
+            let msg = match COMMANDS.iter().find(|(command, _, _)| *command == name) {
+                Some((command, args, _)) => format!("Usage: {} {}", command, args),
+                None => format!("Unknown command: {}, try `help`", name),
+            };
+            return Err(KvsError::StringError(msg.trim_end().to_owned()));
+        }
+    };
+    Ok(Some(Input::Command(command)))
+}
+
+/// Returns the list of commands, one per line.
+pub fn help() -> String {
+    COMMANDS
+        .iter()
+        .map(|(name, args, about)| format!("{:<22}{}", format!("{} {}", name, args), about))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Formats the result of a command which took `elapsed`, without a final newline.
+pub fn format_reply(result: &Result<Reply>, elapsed: Duration, format: OutputFormat) -> String {
+    let elapsed_ms = elapsed.as_micros() as f64 / 1000.0;
+    match format {
+        OutputFormat::Text => {
+            let text = match result {
+                Ok(reply) => reply.to_string(),
+                Err(e) => format!("error: {}", e),
+            };
+            format!("{}\n({:.3} ms)", text, elapsed_ms)
+        }
+        OutputFormat::Json => {
+            let output = match result {
+                Ok(reply) => json!({ "result": reply.to_json(), "elapsed_ms": elapsed_ms }),
+                Err(e) => json!({ "error": e.to_string(), "elapsed_ms": elapsed_ms }),
+            };
+            output.to_string()
+        }
+    }
+}
+
+/// A connection to a server which runs the commands of the REPL.
+pub struct Session {
+    addr: SocketAddr,
+    client: Option<KvsClient>,
+}
+
+impl Session {
+    /// Creates a session with the server at `addr`. It connects on the first
+    /// command.
+    pub fn new(addr: SocketAddr) -> Session {
+        Session { addr, client: None }
+    }
+
+    /// Sends `command` to the server and waits for its reply.
+    ///
+    /// # Errors
+    ///
+    /// It propagates the errors of the connection and the errors returned by the
+    /// server, such as `Key not found`.
+    pub fn execute(&mut self, command: ReplCommand) -> Result<Reply> {
+        match command {
+            ReplCommand::Get { key } => match self.request(Request::Get { key })? {
+                Response::Get(value) => Ok(Reply::Value(value)),
+                _ => Err(invalid_response()),
+            },
+            ReplCommand::Set { key, value } => match self.request(Request::Set { key, value })? {
+                Response::Set => Ok(Reply::Done),
+                _ => Err(invalid_response()),
+            },
+            ReplCommand::Remove { key } => match self.request(Request::Remove { key })? {
+                Response::Remove => Ok(Reply::Done),
+                _ => Err(invalid_response()),
+            },
+            // the pairs are streamed on a connection of their own, closed at the end
+            ReplCommand::Scan { prefix } => {
+                let pairs = KvsClient::connect(self.addr)
+                    .map(|client| client.scan_stream(prefix))
+                    .flatten_stream()
+                    .collect()
+                    .wait()?;
+                Ok(Reply::Pairs(pairs))
+            }
+            ReplCommand::GetBatch { keys } => {
+                let mut values = Vec::with_capacity(keys.len());
+                for key in keys {
+                    match self.request(Request::Get { key: key.clone() })? {
+                        Response::Get(value) => values.push((key, value)),
+                        _ => return Err(invalid_response()),
+                    }
+                }
+                Ok(Reply::Values(values))
+            }
+            ReplCommand::SetBatch { pairs } => match self.request(Request::SetBatch { pairs })? {
+                Response::SetBatch => Ok(Reply::Done),
+                _ => Err(invalid_response()),
+            },
+            ReplCommand::Status => match self.request(Request::Status)? {
+                Response::Status(status) => Ok(Reply::Status(status)),
+                _ => Err(invalid_response()),
+            },
+            ReplCommand::Promote => match self.request(Request::Promote)? {
+                Response::Promote => Ok(Reply::Done),
+                _ => Err(invalid_response()),
+            },
+        }
+    }
+
+    // Sends `req` on the connection of the session, connecting first if needed.
+    // The connection is kept unless it fails.
+    fn request(&mut self, req: Request) -> Result<Response> {
+        let client = match self.client.take() {
+            Some(client) => client,
+            None => KvsClient::connect(self.addr).wait()?,
+        };
+        let (resp, client) = client.send_request(req).wait()?;
+        let resp = resp.ok_or_else(|| KvsError::StringError("No response received".to_owned()))?;
+        self.client = Some(client);
+        match resp {
+            Response::KeyNotFound => Err(KvsError::KeyNotFound),
+            Response::Err(msg) => Err(KvsError::StringError(msg)),
+            resp => Ok(resp),
+        }
+    }
+}
+
+fn invalid_response() -> KvsError {
+    KvsError::StringError("Invalid response".to_owned())
+}
+
+impl Reply {
+    fn to_json(&self) -> Value {
+        match self {
+            Reply::Value(value) => json!(value),
+            Reply::Values(values) => Value::Object(
+                values
+                    .iter()
+                    .map(|(key, value)| (key.clone(), json!(value)))
+                    .collect::<Map<_, _>>(),
+            ),
+            Reply::Pairs(pairs) => Value::Object(
+                pairs
+                    .iter()
+                    .map(|(key, value)| (key.clone(), json!(value)))
+                    .collect::<Map<_, _>>(),
+            ),
+            Reply::Status(status) => json!(status),
+            Reply::Done => Value::Null,
+        }
+    }
+}
+
+impl fmt::Display for Reply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reply::Value(Some(value)) => write!(f, "{}", value),
+            Reply::Value(None) => write!(f, "Key not found"),
+            Reply::Values(values) => {
+                let lines: Vec<_> = values
+                    .iter()
+                    .map(|(key, value)| match value {
+                        Some(value) => format!("{} {}", key, value),
+                        None => format!("{} (not found)", key),
+                    })
+                    .collect();
+                write!(f, "{}", lines.join("\n"))
+            }
+            Reply::Pairs(pairs) if pairs.is_empty() => write!(f, "(no keys)"),
+            Reply::Pairs(pairs) => {
+                let lines: Vec<_> = pairs
+                    .iter()
+                    .map(|(key, value)| format!("{} {}", key, value))
+                    .collect();
+                write!(f, "{}", lines.join("\n"))
+            }
+            Reply::Status(status) => write!(f, "{}", status),
+            Reply::Done => write!(f, "OK"),
+        }
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OutputFormat::Text => f.write_str("text"),
+            OutputFormat::Json => f.write_str("json"),
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<OutputFormat> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(KvsError::StringError(format!(
+                "Unknown output format: {}",
+                s
+            ))),
+        }
+    }
+}
@@ -0,0 +1,269 @@
+//! Primary/replica replication between servers.
+//!
+//! A replica connects to its primary with a `Replicate` request. The primary
+//! ships its log, see `KvsEngine::replicate`: the pairs of a compaction log
+//! written for the replica, then the writes appended to the log from then on, in
+//! log order. Heartbeats carry the number of writes shipped by the primary, which
+//! tells the replica how far behind it is.
+//!
+//! If the replica falls behind by more than the watch buffer of the primary, or
+//! loses the connection, it starts over with a new snapshot.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use tokio::prelude::future::{self, Loop};
+use tokio::prelude::*;
+use tokio::timer::Delay;
+
+use crate::common::Response;
+use crate::watch::WatchHub;
+use crate::{KvsClient, KvsEngine, KvsError, WatchEvent};
+
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The replication role of a server, as reported by `KvsClient::status`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ServerStatus {
+    /// The server accepts writes.
+    Primary,
+    /// The server copies the data of another server and only serves reads.
+    Replica {
+        /// Address of the primary.
+        primary: SocketAddr,
+        /// Whether the replica is currently receiving writes from the primary.
+        connected: bool,
+        /// Number of writes of the primary the replica has applied.
+        applied: u64,
+        /// Number of writes of the primary the replica knows of but hasn't applied.
+        lag: u64,
+    },
+}
+
+impl fmt::Display for ServerStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerStatus::Primary => write!(f, "primary"),
+            ServerStatus::Replica {
+                primary,
+                connected,
+                applied,
+                lag,
+            } => {
+                let state = if *connected {
+                    "connected"
+                } else {
+                    "disconnected"
+                };
+                write!(
+                    f,
+                    "replica of {} ({}), applied {} writes, lag {} writes",
+                    primary, state, applied, lag
+                )
+            }
+        }
+    }
+}
+
+/// A message of the stream returned by `KvsEngine::replicate`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplicationEvent {
+    /// A pair of the snapshot.
+    Snapshot {
+        /// The key.
+        key: String,
+        /// The value.
+        value: String,
+    },
+    /// The snapshot is complete.
+    SnapshotDone {
+        /// Position of the first write after the snapshot among all writes of the
+        /// engine.
+        seq: u64,
+    },
+    /// A write applied after the snapshot, or `WatchEvent::Lagged` if the
+    /// consumer fell behind and has to start over.
+    Write(WatchEvent),
+}
+
+/// State of a replica shared by the server and the replication task.
+pub(crate) struct Replica {
+    primary: SocketAddr,
+    promoted: AtomicBool,
+    connected: AtomicBool,
+    applied: AtomicU64,
+    // writes published by the primary, according to its last message
+    published: AtomicU64,
+}
+
+impl Replica {
+    pub(crate) fn new(primary: SocketAddr) -> Replica {
+        Replica {
+            primary,
+            promoted: AtomicBool::new(false),
+            connected: AtomicBool::new(false),
+            applied: AtomicU64::new(0),
+            published: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns whether the server still refuses writes.
+    pub(crate) fn is_read_only(&self) -> bool {
+        !self.promoted.load(Ordering::SeqCst)
+    }
+
+    /// Turns the replica into a primary, which stops the replication.
+    ///
+    /// Writes already received from the primary may still be applied afterwards.
+    pub(crate) fn promote(&self) {
+        self.promoted.store(true, Ordering::SeqCst);
+    }
+
+    pub(crate) fn status(&self) -> ServerStatus {
+        if !self.is_read_only() {
+            return ServerStatus::Primary;
+        }
+        let applied = self.applied.load(Ordering::SeqCst);
+        ServerStatus::Replica {
+            primary: self.primary,
+            connected: self.connected.load(Ordering::SeqCst),
+            applied,
+            lag: self
+                .published
+                .load(Ordering::SeqCst)
+                .saturating_sub(applied),
+        }
+    }
+
+    // Records that the primary has published at least `published` writes.
+    fn seen(&self, published: u64) {
+        self.published.fetch_max(published, Ordering::SeqCst);
+    }
+
+    fn applied(&self, applied: u64) {
+        self.applied.store(applied, Ordering::SeqCst);
+        self.seen(applied);
+    }
+}
+
+/// Copies the data of the primary into `engine` until the replica is promoted.
+///
+/// Replicated writes are published to `hub`, so the replica can be watched as well.
+pub(crate) fn replicate<E: KvsEngine>(
+    engine: E,
+    hub: WatchHub,
+    replica: Arc<Replica>,
+) -> impl Future<Item = (), Error = ()> {
+    future::loop_fn((), move |()| {
+        let replica = Arc::clone(&replica);
+        sync(engine.clone(), hub.clone(), Arc::clone(&replica)).then(
+            move |res| -> Box<dyn Future<Item = Loop<(), ()>, Error = ()> + Send> {
+                replica.connected.store(false, Ordering::SeqCst);
+                if !replica.is_read_only() {
+                    info!("Promoted to primary, replication stopped");
+                    return Box::new(future::ok(Loop::Break(())));
+                }
+                match res {
+                    Ok(()) => warn!("Primary {} closed the connection", replica.primary),
+                    Err(e) => error!("Replication from {} failed: {}", replica.primary, e),
+                }
+                Box::new(
+                    Delay::new(Instant::now() + RETRY_INTERVAL).then(|_| Ok(Loop::Continue(()))),
+                )
+            },
+        )
+    })
+}
+
+// Receives a snapshot and the following writes of the primary, until the
+// connection is closed or the replica is promoted.
+fn sync<E: KvsEngine>(
+    engine: E,
+    hub: WatchHub,
+    replica: Arc<Replica>,
+) -> impl Future<Item = (), Error = KvsError> {
+    KvsClient::connect(replica.primary)
+        .and_then(|client| client.replicate())
+        .and_then(move |messages| {
+            info!("Replicating from {}", replica.primary);
+            replica.connected.store(true, Ordering::SeqCst);
+            // keys of the snapshot, until it is complete
+            let mut snapshot = Some(HashSet::new());
+            let promoted = Arc::clone(&replica);
+            messages
+                .take_while(move |_| Ok(promoted.is_read_only()))
+                .for_each(
+                    move |msg| -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+                        match msg {
+                            Response::Snapshot { key, value } => {
+                                if let Some(keys) = &mut snapshot {
+                                    keys.insert(key.clone());
+                                }
+                                Box::new(engine.set(key, value))
+                            }
+                            Response::SnapshotDone { seq } => {
+                                let keys = snapshot.take().unwrap_or_default();
+                                let replica = Arc::clone(&replica);
+                                Box::new(
+                                    remove_missing(engine.clone(), keys)
+                                        .map(move |_| replica.applied(seq)),
+                                )
+                            }
+                            Response::Watch(WatchEvent::Set { seq, key, value }) => {
+                                let (hub, replica) = (hub.clone(), Arc::clone(&replica));
+                                Box::new(engine.set(key.clone(), value.clone()).map(move |_| {
+                                    hub.set(&key, &value);
+                                    replica.applied(seq + 1);
+                                }))
+                            }
+                            Response::Watch(WatchEvent::Remove { seq, key }) => {
+                                let (hub, replica) = (hub.clone(), Arc::clone(&replica));
+                                Box::new(remove(&engine, key.clone()).map(move |_| {
+                                    hub.remove(&key);
+                                    replica.applied(seq + 1);
+                                }))
+                            }
+                            Response::Watch(WatchEvent::Lagged { missed }) => {
+                                Box::new(future::err(KvsError::StringError(format!(
+                                    "Missed {} writes of the primary, starting over",
+                                    missed
+                                ))))
+                            }
+                            Response::Heartbeat { published } => {
+                                replica.seen(published);
+                                Box::new(future::ok(()))
+                            }
+                            Response::Err(msg) => Box::new(future::err(KvsError::StringError(msg))),
+                            _ => Box::new(future::err(KvsError::StringError(
+                                "Invalid response".to_owned(),
+                            ))),
+                        }
+                    },
+                )
+        })
+}
+
+// Removes the keys of `engine` which are not in the snapshot.
+fn remove_missing<E: KvsEngine>(
+    engine: E,
+    keys: HashSet<String>,
+) -> impl Future<Item = (), Error = KvsError> {
+    engine
+        .scan(String::new())
+        .filter(move |(key, _)| !keys.contains(key))
+        .for_each(move |(key, _)| remove(&engine, key))
+}
+
+// A key may already be missing when its removal is replicated, if the snapshot
+// was read after the removal.
+fn remove<E: KvsEngine>(engine: &E, key: String) -> impl Future<Item = (), Error = KvsError> {
+    engine.remove(key).then(|res| match res {
+        Err(KvsError::KeyNotFound) => Ok(()),
+        res => res,
+    })
+}
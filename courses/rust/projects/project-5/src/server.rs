@@ -1,58 +1,272 @@
 use crate::common::{Request, Response};
+use crate::replication::{self, Replica, ReplicationEvent, ServerStatus};
+use crate::watch::{WatchEvent, WatchHub};
 use crate::{KvsEngine, KvsError, Result};
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::prelude::*;
+use tokio::timer::Interval;
 use tokio_serde_json::{ReadJson, WriteJson};
 
+const DEFAULT_WATCH_BUFFER: usize = 1024;
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+// pairs per response of a streamed scan, which keeps frames small
+const SCAN_CHUNK: usize = 1000;
+
 /// The server of a key value store.
+///
+/// Besides single requests, a client can watch the keys with a given prefix. Its
+/// connection then only carries `WatchEvent`s, one for each successful `set` or
+/// `remove` served afterwards.
+///
+/// A server can also be a read-only replica of another server until it is promoted.
 pub struct KvsServer<E: KvsEngine> {
     engine: E,
+    watch_buffer: usize,
+    replica_of: Option<SocketAddr>,
+    max_connections: Option<usize>,
 }
 
 impl<E: KvsEngine> KvsServer<E> {
     /// Create a `KvsServer` with a given storage engine.
     pub fn new(engine: E) -> Self {
-        KvsServer { engine }
+        KvsServer {
+            engine,
+            watch_buffer: DEFAULT_WATCH_BUFFER,
+            replica_of: None,
+            max_connections: None,
+        }
+    }
+
+    /// Makes the server a replica of the server at `primary`.
+    ///
+    /// The replica copies all data of the primary into its engine, then applies
+    /// the writes of the primary as they happen. It serves reads and refuses
+    /// writes with `KvsError::ReadOnlyReplica` until it is promoted.
+    ///
+    /// The primary must use an engine which ships its log, see
+    /// `KvsEngine::replicate`.
+    pub fn replica_of(mut self, primary: SocketAddr) -> Self {
+        self.replica_of = Some(primary);
+        self
+    }
+
+    /// Sets how many events are kept for a watcher or a replica that doesn't read
+    /// them.
+    ///
+    /// Once the buffer is full, new events are dropped and the watcher receives a
+    /// `WatchEvent::Lagged` with their number when it catches up. A replica starts
+    /// over instead.
+    pub fn watch_buffer(mut self, size: usize) -> Self {
+        self.watch_buffer = size;
+        self
+    }
+
+    /// Limits the number of clients connected at the same time, watchers included.
+    ///
+    /// A connection accepted above the limit is closed right away.
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.max_connections = Some(max);
+        self
     }
 
     /// Run the server listening on the given address
     pub fn run(self, addr: SocketAddr) -> Result<()> {
         let listener = TcpListener::bind(&addr)?;
-        let server = listener
-            .incoming()
-            .map_err(|e| error!("IO error: {}", e))
-            .for_each(move |tcp| {
-                let engine = self.engine.clone();
-                serve(engine, tcp).map_err(|e| error!("Error on serving client: {}", e))
-            });
+        let handler = Handler {
+            engine: self.engine,
+            hub: WatchHub::new(),
+            watch_buffer: self.watch_buffer,
+            replica: self
+                .replica_of
+                .map(|primary| Arc::new(Replica::new(primary))),
+        };
+        let max_connections = self.max_connections.unwrap_or(usize::MAX);
+        let connections = Arc::new(AtomicUsize::new(0));
+        let server = future::lazy(move || {
+            if let Some(replica) = &handler.replica {
+                tokio::spawn(replication::replicate(
+                    handler.engine.clone(),
+                    handler.hub.clone(),
+                    Arc::clone(replica),
+                ));
+            }
+            listener
+                .incoming()
+                .map_err(|e| error!("IO error: {}", e))
+                .for_each(move |tcp| {
+                    if connections.fetch_add(1, Ordering::SeqCst) >= max_connections {
+                        connections.fetch_sub(1, Ordering::SeqCst);
+                        warn!("Too many connections, closing the new one");
+                        return Ok(());
+                    }
+                    let connections = Arc::clone(&connections);
+                    // serve connections concurrently, a watcher keeps its connection open
+                    tokio::spawn(serve(handler.clone(), tcp).then(move |res| {
+                        connections.fetch_sub(1, Ordering::SeqCst);
+                        res.map_err(|e| error!("Error on serving client: {}", e))
+                    }));
+                    Ok(())
+                })
+        });
         tokio::run(server);
         Ok(())
     }
 }
 
-fn serve<E: KvsEngine>(engine: E, tcp: TcpStream) -> impl Future<Item = (), Error = KvsError> {
+/// Everything needed to answer requests, shared by all connections.
+#[derive(Clone)]
+struct Handler<E: KvsEngine> {
+    engine: E,
+    hub: WatchHub,
+    watch_buffer: usize,
+    // `None` if the server was started as a primary
+    replica: Option<Arc<Replica>>,
+}
+
+impl<E: KvsEngine> Handler<E> {
+    /// Returns the responses to a request, usually just one.
+    fn handle(&self, req: Request) -> Box<dyn Stream<Item = Response, Error = KvsError> + Send> {
+        let is_write = matches!(
+            req,
+            Request::Set { .. } | Request::SetBatch { .. } | Request::Remove { .. }
+        );
+        if is_write && self.replica.as_ref().is_some_and(|r| r.is_read_only()) {
+            return Box::new(stream::once(Err(KvsError::ReadOnlyReplica)));
+        }
+
+        match req {
+            Request::Get { key } => Box::new(self.engine.get(key).map(Response::Get).into_stream()),
+            Request::Set { key, value } => {
+                Box::new(self.set(key, value).map(|_| Response::Set).into_stream())
+            }
+            // the pairs are set one after the other, so the last value of a key wins
+            Request::SetBatch { pairs } => {
+                let handler = self.clone();
+                let resp = stream::iter_ok(pairs)
+                    .for_each(move |(key, value)| handler.set(key, value))
+                    .map(|_| Response::SetBatch);
+                Box::new(resp.into_stream())
+            }
+            Request::Remove { key } => {
+                let hub = self.hub.clone();
+                let resp = self.engine.remove(key.clone()).map(move |_| {
+                    hub.remove(&key);
+                    Response::Remove
+                });
+                Box::new(resp.into_stream())
+            }
+            Request::Scan { prefix } => Box::new(
+                self.engine
+                    .scan(prefix)
+                    .collect()
+                    .map(Response::Scan)
+                    .into_stream(),
+            ),
+            Request::QueryIndex { name, value } => Box::new(
+                self.engine
+                    .query_index(name, value)
+                    .map(Response::QueryIndex)
+                    .into_stream(),
+            ),
+            Request::ScanStream { prefix } => Box::new(
+                self.engine
+                    .scan(prefix)
+                    .chunks(SCAN_CHUNK)
+                    .map(Response::ScanChunk)
+                    .chain(stream::once(Ok(Response::ScanDone))),
+            ),
+            // the events never end, so later requests on this connection are not read
+            Request::Watch { prefix } => Box::new(
+                self.hub
+                    .subscribe(prefix, self.watch_buffer)
+                    .1
+                    .map(Response::Watch)
+                    .map_err(|e| KvsError::StringError(format!("{}", e))),
+            ),
+            Request::Replicate => self.replicate(),
+            Request::Promote => {
+                if let Some(replica) = &self.replica {
+                    replica.promote();
+                }
+                Box::new(stream::once(Ok(Response::Promote)))
+            }
+            Request::Status => {
+                let status = match &self.replica {
+                    Some(replica) => replica.status(),
+                    None => ServerStatus::Primary,
+                };
+                Box::new(stream::once(Ok(Response::Status(status))))
+            }
+        }
+    }
+
+    /// Sets a pair and tells the watchers of the key.
+    fn set(&self, key: String, value: String) -> impl Future<Item = (), Error = KvsError> {
+        // only copy the pair if someone watches it
+        let event = if self.hub.watches(&key) {
+            Some((self.hub.clone(), key.clone(), value.clone()))
+        } else {
+            None
+        };
+        let hub = self.hub.clone();
+        self.engine.set(key, value).map(move |_| match event {
+            Some((hub, key, value)) => hub.set(&key, &value),
+            None => hub.skip(),
+        })
+    }
+
+    /// Returns the log of the engine: a snapshot of all pairs followed by all
+    /// writes from then on, in the order of the log.
+    fn replicate(&self) -> Box<dyn Stream<Item = Response, Error = KvsError> + Send> {
+        // writes read from the log so far
+        let shipped = Arc::new(AtomicU64::new(0));
+        let events = {
+            let shipped = Arc::clone(&shipped);
+            self.engine
+                .replicate(self.watch_buffer)
+                .map(move |event| match event {
+                    ReplicationEvent::Snapshot { key, value } => Response::Snapshot { key, value },
+                    ReplicationEvent::SnapshotDone { seq } => {
+                        shipped.store(seq, Ordering::SeqCst);
+                        Response::SnapshotDone { seq }
+                    }
+                    ReplicationEvent::Write(event) => {
+                        if let WatchEvent::Set { seq, .. } | WatchEvent::Remove { seq, .. } = event
+                        {
+                            shipped.store(seq + 1, Ordering::SeqCst);
+                        }
+                        Response::Watch(event)
+                    }
+                })
+        };
+        let heartbeats = Interval::new_interval(HEARTBEAT_INTERVAL)
+            .map(move |_| Response::Heartbeat {
+                published: shipped.load(Ordering::SeqCst),
+            })
+            .map_err(|e| KvsError::StringError(format!("{}", e)));
+        Box::new(events.select(heartbeats))
+    }
+}
+
+fn serve<E: KvsEngine>(
+    handler: Handler<E>,
+    tcp: TcpStream,
+) -> impl Future<Item = (), Error = KvsError> {
     let (read_half, write_half) = tcp.split();
     let read_json = ReadJson::new(FramedRead::new(read_half, LengthDelimitedCodec::new()));
     let resp_stream = read_json
         .map_err(KvsError::from)
-        .and_then(
-            move |req| -> Box<dyn Future<Item = Response, Error = KvsError> + Send> {
-                match req {
-                    Request::Get { key } => Box::new(engine.get(key).map(Response::Get)),
-                    Request::Set { key, value } => {
-                        Box::new(engine.set(key, value).map(|_| Response::Set))
-                    }
-                    Request::Remove { key } => {
-                        Box::new(engine.remove(key).map(|_| Response::Remove))
-                    }
-                }
-            },
-        )
+        .map(move |req| handler.handle(req))
+        .flatten()
         .then(|resp| -> Result<Response> {
             match resp {
                 Ok(resp) => Ok(resp),
+                Err(KvsError::KeyNotFound) => Ok(Response::KeyNotFound),
                 Err(e) => Ok(Response::Err(format!("{}", e))),
             }
         });
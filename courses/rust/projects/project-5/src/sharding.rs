@@ -0,0 +1,485 @@
+//! Client-side sharding of the keys over several servers.
+//!
+//! Every server owns a number of points, its virtual nodes, on a ring of 64-bit
+//! hashes. A key belongs to the server owning the first point at or after the hash
+//! of the key, wrapping around at the end of the ring. Adding or removing a server
+//! only moves the keys of the ranges next to its points, and the virtual nodes
+//! spread those ranges evenly over the other servers.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use tokio::prelude::future;
+use tokio::prelude::*;
+
+use crate::{KvsClient, KvsError, Result};
+
+const DEFAULT_VNODES: usize = 64;
+// how many pairs of a server a migration looks at before moving their keys
+const MIGRATION_CHUNK: usize = 1000;
+// how many idle connections to each server a client keeps
+const IDLE_CONNECTIONS: usize = 4;
+// how many bytes of keys and values a request sets at most, half the 8 MiB
+// limit of a frame
+const BATCH_BYTES: usize = 4 << 20;
+
+/// A consistent hash ring mapping keys to servers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HashRing {
+    vnodes: usize,
+    nodes: Vec<SocketAddr>,
+    // point on the ring -> node owning the range ending at that point
+    points: BTreeMap<u64, SocketAddr>,
+}
+
+/// A range of the ring whose keys move to another server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Migration {
+    /// Hash after which the range starts, excluded.
+    pub start: u64,
+    /// Hash at which the range ends, included. It is lower than `start` if the
+    /// range wraps around the end of the ring.
+    pub end: u64,
+    /// Server owning the keys of the range before the change.
+    pub from: SocketAddr,
+    /// Server owning the keys of the range after the change.
+    pub to: SocketAddr,
+}
+
+impl Migration {
+    /// Returns whether the range contains `hash`.
+    pub fn contains(&self, hash: u64) -> bool {
+        if self.start < self.end {
+            self.start < hash && hash <= self.end
+        } else {
+            self.start < hash || hash <= self.end
+        }
+    }
+}
+
+impl HashRing {
+    /// Creates a ring of `nodes` with `vnodes` points per node.
+    ///
+    /// # Errors
+    ///
+    /// It returns an error if there are no nodes or `vnodes` is 0.
+    pub fn new(nodes: Vec<SocketAddr>, vnodes: usize) -> Result<HashRing> {
+        if nodes.is_empty() || vnodes == 0 {
+            return Err(KvsError::StringError(
+                "A hash ring needs at least one node and one virtual node".to_owned(),
+            ));
+        }
+        let mut ring = HashRing {
+            vnodes,
+            nodes: Vec::new(),
+            points: BTreeMap::new(),
+        };
+        for node in nodes {
+            ring.insert(node);
+        }
+        Ok(ring)
+    }
+
+    /// Returns the nodes of the ring.
+    pub fn nodes(&self) -> &[SocketAddr] {
+        &self.nodes
+    }
+
+    /// Returns the position of `key` on the ring, to compare with a `Migration`.
+    pub fn position(key: &str) -> u64 {
+        hash(key.as_bytes())
+    }
+
+    /// Returns the node which owns `key`.
+    pub fn node_of(&self, key: &str) -> SocketAddr {
+        self.node_of_hash(HashRing::position(key))
+    }
+
+    /// Returns the ring with `node` added, with the ranges which move to it.
+    pub fn add_node(&self, node: SocketAddr) -> (HashRing, Vec<Migration>) {
+        let mut ring = self.clone();
+        ring.insert(node);
+        let migrations = self.migrations(&ring);
+        (ring, migrations)
+    }
+
+    /// Returns the ring with `node` removed, with the ranges which move from it.
+    ///
+    /// # Errors
+    ///
+    /// It returns an error if `node` is the last node of the ring.
+    pub fn remove_node(&self, node: SocketAddr) -> Result<(HashRing, Vec<Migration>)> {
+        let nodes = self.nodes.iter().cloned().filter(|&n| n != node).collect();
+        let ring = HashRing::new(nodes, self.vnodes)?;
+        let migrations = self.migrations(&ring);
+        Ok((ring, migrations))
+    }
+
+    /// Returns the ranges owned by different nodes in `self` and `other`.
+    ///
+    /// Adjacent ranges moving between the same nodes are merged.
+    pub fn migrations(&self, other: &HashRing) -> Vec<Migration> {
+        let mut bounds: Vec<u64> = self
+            .points
+            .keys()
+            .chain(other.points.keys())
+            .cloned()
+            .collect();
+        bounds.sort_unstable();
+        bounds.dedup();
+
+        let mut migrations: Vec<Migration> = Vec::new();
+        let mut start = *bounds.last().unwrap();
+        for &end in &bounds {
+            // every hash of (start, end] belongs to the same node in each ring
+            let (from, to) = (self.node_of_hash(end), other.node_of_hash(end));
+            if from != to {
+                match migrations.last_mut() {
+                    Some(last) if last.end == start && last.from == from && last.to == to => {
+                        last.end = end
+                    }
+                    _ => migrations.push(Migration {
+                        start,
+                        end,
+                        from,
+                        to,
+                    }),
+                }
+            }
+            start = end;
+        }
+        // the first range may continue the last one across the end of the ring
+        if migrations.len() > 1 {
+            let (first, last) = (migrations[0], migrations[migrations.len() - 1]);
+            if last.end == first.start && last.from == first.from && last.to == first.to {
+                migrations[0].start = last.start;
+                migrations.pop();
+            }
+        }
+        migrations
+    }
+
+    fn insert(&mut self, node: SocketAddr) {
+        if self.nodes.contains(&node) {
+            return;
+        }
+        self.nodes.push(node);
+        for i in 0..self.vnodes {
+            let point = hash(format!("{}-{}", node, i).as_bytes());
+            // on a collision, the point keeps a single owner whatever the order
+            // the nodes were added in
+            let owner = self.points.entry(point).or_insert(node);
+            if node < *owner {
+                *owner = node;
+            }
+        }
+    }
+
+    fn node_of_hash(&self, hash: u64) -> SocketAddr {
+        let (_, node) = self
+            .points
+            .range(hash..)
+            .next()
+            .or_else(|| self.points.iter().next())
+            .expect("a ring has at least one point");
+        *node
+    }
+}
+
+// 64-bit FNV-1a, followed by the finalizer of MurmurHash3 so that keys differing
+// in their last bytes land far apart on the ring
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325_u64;
+    for &byte in bytes {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+/// A client of several `KvsServer`s, each holding a part of the keys.
+///
+/// Requests reuse the connections of the previous ones, which the clones of a
+/// client share. A connection on which a request fails is closed.
+#[derive(Clone)]
+pub struct ShardedKvsClient {
+    ring: HashRing,
+    pool: ConnectionPool,
+}
+
+impl fmt::Debug for ShardedKvsClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ShardedKvsClient")
+            .field("ring", &self.ring)
+            .finish()
+    }
+}
+
+impl ShardedKvsClient {
+    /// Creates a client spreading the keys over `nodes`.
+    pub fn new(nodes: Vec<SocketAddr>) -> Result<ShardedKvsClient> {
+        Ok(ShardedKvsClient::with_ring(HashRing::new(
+            nodes,
+            DEFAULT_VNODES,
+        )?))
+    }
+
+    /// Creates a client using the given ring.
+    pub fn with_ring(ring: HashRing) -> ShardedKvsClient {
+        ShardedKvsClient {
+            ring,
+            pool: ConnectionPool::default(),
+        }
+    }
+
+    /// Returns the ring the keys are routed with.
+    pub fn ring(&self) -> &HashRing {
+        &self.ring
+    }
+
+    /// Get the value of a given key.
+    pub fn get(&self, key: String) -> impl Future<Item = Option<String>, Error = KvsError> {
+        self.pool
+            .request(self.ring.node_of(&key), |client| client.get(key))
+    }
+
+    /// Set the value of a string key.
+    pub fn set(&self, key: String, value: String) -> impl Future<Item = (), Error = KvsError> {
+        self.pool.request(self.ring.node_of(&key), |client| {
+            client.set(key, value).map(|client| ((), client))
+        })
+    }
+
+    /// Remove a string key.
+    pub fn remove(&self, key: String) -> impl Future<Item = (), Error = KvsError> {
+        self.pool.request(self.ring.node_of(&key), |client| {
+            client.remove(key).map(|client| ((), client))
+        })
+    }
+
+    /// Get the values of several keys, in the order of `keys`.
+    ///
+    /// The servers are queried concurrently, with one connection each.
+    pub fn get_batch(
+        &self,
+        keys: Vec<String>,
+    ) -> impl Future<Item = Vec<Option<String>>, Error = KvsError> {
+        let len = keys.len();
+        let shards = self.group(keys.into_iter().enumerate(), |(_, key)| key);
+        let pool = self.pool.clone();
+        let gets = shards.into_iter().map(move |(node, keys)| {
+            let (positions, keys): (Vec<_>, Vec<_>) = keys.into_iter().unzip();
+            pool.request(node, |client| client.get_batch(keys))
+                .map(move |values| positions.into_iter().zip(values))
+        });
+        future::join_all(gets).map(move |shards| {
+            let mut values = vec![None; len];
+            for (i, value) in shards.into_iter().flatten() {
+                values[i] = value;
+            }
+            values
+        })
+    }
+
+    /// Set the values of several keys.
+    ///
+    /// The servers are written concurrently, with one connection each and as few
+    /// requests as the size of a frame allows. If it fails, some of the pairs may
+    /// have been written.
+    pub fn set_batch(
+        &self,
+        pairs: Vec<(String, String)>,
+    ) -> impl Future<Item = (), Error = KvsError> {
+        let shards = self.group(pairs, |(key, _)| key);
+        let pool = self.pool.clone();
+        let sets = shards
+            .into_iter()
+            .map(move |(node, pairs)| pool.set_pairs(node, pairs));
+        future::join_all(sets).map(|_| ())
+    }
+
+    /// Get all key/value pairs whose key starts with `prefix`, in key order.
+    ///
+    /// All servers are scanned concurrently.
+    pub fn scan(
+        &self,
+        prefix: String,
+    ) -> impl Future<Item = Vec<(String, String)>, Error = KvsError> {
+        let scans: Vec<_> = self
+            .ring
+            .nodes()
+            .iter()
+            .map(|&node| {
+                let prefix = prefix.clone();
+                self.pool
+                    .connect(node)
+                    .map(move |client| client.scan_stream(prefix))
+                    .flatten_stream()
+                    .collect()
+            })
+            .collect();
+        future::join_all(scans).map(|shards| {
+            let mut pairs: Vec<_> = shards.into_iter().flatten().collect();
+            pairs.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+            pairs
+        })
+    }
+
+    /// Moves the keys whose owner differs in `to` to their new owner.
+    ///
+    /// Each key is set on its new owner before it is removed from its old owner.
+    /// The client should switch to `to` once it completes. Writes served by the
+    /// old owners meanwhile may be lost, so the keys shouldn't be written during a
+    /// migration. Returns the number of keys moved.
+    ///
+    /// The pairs of each server are streamed and moved chunk by chunk, so there is
+    /// no limit on their number. The pairs of a chunk moving to the same server
+    /// are set with as few requests as `set_batch` sends.
+    pub fn migrate(&self, to: &HashRing) -> impl Future<Item = u64, Error = KvsError> {
+        let mut sources: HashMap<SocketAddr, Vec<Migration>> = HashMap::new();
+        for migration in self.ring.migrations(to) {
+            sources.entry(migration.from).or_default().push(migration);
+        }
+        let pool = self.pool.clone();
+        let moves = sources.into_iter().map(move |(from, migrations)| {
+            let pool = pool.clone();
+            pool.connect(from)
+                .map(|client| client.scan_stream(String::new()))
+                .flatten_stream()
+                .chunks(MIGRATION_CHUNK)
+                .fold(0, move |count, pairs| {
+                    let mut targets: HashMap<SocketAddr, Vec<(String, String)>> = HashMap::new();
+                    for (key, value) in pairs {
+                        let hash = HashRing::position(&key);
+                        if let Some(m) = migrations.iter().find(|m| m.contains(hash)) {
+                            targets.entry(m.to).or_default().push((key, value));
+                        }
+                    }
+                    let chunk_count = targets.values().map(Vec::len).sum::<usize>() as u64;
+                    let pool = pool.clone();
+                    stream::iter_ok(targets)
+                        .for_each(move |(to, pairs)| move_pairs(&pool, from, to, pairs))
+                        .map(move |_| count + chunk_count)
+                })
+        });
+        future::join_all(moves).map(|counts| counts.into_iter().sum())
+    }
+
+    // Groups items by the node owning their key, keeping their order.
+    fn group<T, I, F>(&self, items: I, key: F) -> HashMap<SocketAddr, Vec<T>>
+    where
+        I: IntoIterator<Item = T>,
+        F: Fn(&T) -> &String,
+    {
+        let mut shards: HashMap<SocketAddr, Vec<T>> = HashMap::new();
+        for item in items {
+            shards
+                .entry(self.ring.node_of(key(&item)))
+                .or_default()
+                .push(item);
+        }
+        shards
+    }
+}
+
+/// Idle connections to the servers, shared by the clones of a client.
+#[derive(Clone, Default)]
+struct ConnectionPool {
+    idle: Arc<Mutex<HashMap<SocketAddr, Vec<KvsClient>>>>,
+}
+
+impl ConnectionPool {
+    // Takes an idle connection to `node`, or opens a new one.
+    fn connect(&self, node: SocketAddr) -> impl Future<Item = KvsClient, Error = KvsError> {
+        let idle = self.idle.lock().unwrap().get_mut(&node).and_then(Vec::pop);
+        match idle {
+            Some(client) => future::Either::A(future::ok(client)),
+            None => future::Either::B(KvsClient::connect(node)),
+        }
+    }
+
+    // Sends a request to `node`, keeping the connection for the next ones if it
+    // succeeds.
+    fn request<R, F, Fut>(
+        &self,
+        node: SocketAddr,
+        request: F,
+    ) -> impl Future<Item = R, Error = KvsError>
+    where
+        F: FnOnce(KvsClient) -> Fut,
+        Fut: Future<Item = (R, KvsClient), Error = KvsError>,
+    {
+        let pool = self.clone();
+        self.connect(node)
+            .and_then(request)
+            .map(move |(result, client)| {
+                let mut idle = pool.idle.lock().unwrap();
+                let clients = idle.entry(node).or_default();
+                if clients.len() < IDLE_CONNECTIONS {
+                    clients.push(client);
+                }
+                result
+            })
+    }
+
+    // Sets `pairs` on `node` in order, with as few requests as the size of a frame
+    // allows.
+    fn set_pairs(
+        &self,
+        node: SocketAddr,
+        pairs: Vec<(String, String)>,
+    ) -> impl Future<Item = (), Error = KvsError> {
+        self.request(node, |client| {
+            stream::iter_ok(batches(pairs))
+                .fold(client, |client, batch| client.set_batch(batch))
+                .map(|client| ((), client))
+        })
+    }
+}
+
+// Splits `pairs` into batches of at most `BATCH_BYTES`, or of a single pair.
+fn batches(pairs: Vec<(String, String)>) -> Vec<Vec<(String, String)>> {
+    let mut batches = Vec::new();
+    let mut batch = Vec::new();
+    let mut bytes = 0;
+    for (key, value) in pairs {
+        let len = key.len() + value.len();
+        if !batch.is_empty() && bytes + len > BATCH_BYTES {
+            batches.push(std::mem::take(&mut batch));
+            bytes = 0;
+        }
+        bytes += len;
+        batch.push((key, value));
+    }
+    if !batch.is_empty() {
+        batches.push(batch);
+    }
+    batches
+}
+
+// Sets `pairs` on `to`, then removes their keys from `from`.
+fn move_pairs(
+    pool: &ConnectionPool,
+    from: SocketAddr,
+    to: SocketAddr,
+    pairs: Vec<(String, String)>,
+) -> impl Future<Item = (), Error = KvsError> {
+    let keys: Vec<String> = pairs.iter().map(|(key, _)| key.clone()).collect();
+    let pool = pool.clone();
+    pool.set_pairs(to, pairs).and_then(move |()| {
+        stream::iter_ok(keys).for_each(move |key| {
+            pool.request(from, |client| client.remove(key).map(|client| ((), client)))
+                // the key may have been removed since the scan
+                .then(|res| match res {
+                    Err(KvsError::KeyNotFound) => Ok(()),
+                    res => res,
+                })
+        })
+    })
+}
@@ -0,0 +1,118 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use tokio::prelude::*;
+use tokio::sync::oneshot;
+
+use crate::{KvsError, Result};
+
+/// A future of the value returned by a task spawned with
+/// `ThreadPool::spawn_with_handle`.
+///
+/// It fails with `KvsError::TaskPanicked` if the task panics, and with
+/// `KvsError::TaskCancelled` if the task never ran because it was cancelled or the
+/// pool was shut down. Dropping the handle doesn't cancel the task.
+pub struct JoinHandle<T> {
+    rx: oneshot::Receiver<Result<T>>,
+    token: CancelToken,
+    // set if the pool refused the task
+    error: Option<KvsError>,
+}
+
+/// Tells a task it should stop.
+///
+/// Cancellation is cooperative: a task which has started runs until it returns,
+/// and should check `is_cancelled` where it can stop early.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl<T> JoinHandle<T> {
+    /// Cancels the task.
+    ///
+    /// The task doesn't run if it hasn't started yet. Otherwise, the value it
+    /// returns is still delivered.
+    pub fn cancel(&self) {
+        self.token.cancel();
+    }
+
+    /// Makes the handle fail with `error` instead of waiting for the task.
+    pub(super) fn fail(mut self, error: KvsError) -> JoinHandle<T> {
+        self.error = Some(error);
+        self
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Item = T;
+    type Error = KvsError;
+
+    fn poll(&mut self) -> Poll<T, KvsError> {
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+        match self.rx.poll() {
+            Ok(Async::Ready(res)) => res.map(Async::Ready),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            // the task was dropped without running
+            Err(_) => Err(KvsError::TaskCancelled),
+        }
+    }
+}
+
+impl CancelToken {
+    /// Requests the cancellation of the task.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns whether the cancellation of the task was requested.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Wraps `job` into a task for `ThreadPool::spawn` which sends its result to the
+/// returned handle.
+pub(super) fn task<F, T>(job: F) -> (JoinHandle<T>, impl FnOnce() + Send + 'static)
+where
+    F: FnOnce(&CancelToken) -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let (tx, rx) = oneshot::channel();
+    let token = CancelToken::default();
+    let handle = JoinHandle {
+        rx,
+        token: token.clone(),
+        error: None,
+    };
+    let task = move || {
+        if token.is_cancelled() {
+            return;
+        }
+        let mut result = ResultSender(Some(tx));
+        let value = job(&token);
+        result.send(Ok(value));
+    };
+    (handle, task)
+}
+
+// Reports a panic of the task to its handle, the pool handles the panic itself.
+struct ResultSender<T>(Option<oneshot::Sender<Result<T>>>);
+
+impl<T> ResultSender<T> {
+    fn send(&mut self, result: Result<T>) {
+        if let Some(tx) = self.0.take() {
+            // the handle may have been dropped, nobody waits for the value then
+            let _ = tx.send(result);
+        }
+    }
+}
+
+impl<T> Drop for ResultSender<T> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.send(Err(KvsError::TaskPanicked));
+        }
+    }
+}
@@ -1,15 +1,25 @@
 //! This module provides various thread pools. All thread pools should implement
 //! the `ThreadPool` trait.
 
-use crate::Result;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
 
+use crate::{KvsError, Result};
+
+mod join_handle;
 mod naive;
 mod rayon;
 mod shared_queue;
+mod stats;
+mod work_stealing;
 
+pub use self::join_handle::{CancelToken, JoinHandle};
 pub use self::naive::NaiveThreadPool;
 pub use self::rayon::RayonThreadPool;
 pub use self::shared_queue::SharedQueueThreadPool;
+pub use self::stats::ThreadPoolStats;
+pub use self::work_stealing::WorkStealingThreadPool;
 
 /// The trait that all thread pools should implement.
 pub trait ThreadPool: Clone + Send + 'static {
@@ -22,12 +32,168 @@ pub trait ThreadPool: Clone + Send + 'static {
     where
         Self: Sized;
 
+    /// Creates a thread pool whose number of threads stays within `size`.
+    ///
+    /// Pools with a fixed number of threads start `size.max()` threads.
+    fn with_size(size: PoolSize) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        size.validate()?;
+        Self::new(size.max)
+    }
+
     /// Spawns a function into the thread pool.
     ///
     /// Spawning always succeeds, but if the function panics the threadpool continues
     /// to operate with the same number of threads &mdash; the thread count is not
-    /// reduced nor is the thread pool destroyed, corrupted or invalidated.
+    /// reduced nor is the thread pool destroyed, corrupted or invalidated. Panics
+    /// are logged and counted in `stats`.
+    ///
+    /// A function spawned after `shutdown` is dropped without being run.
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static;
+
+    /// Spawns a function into the thread pool, unless the pool is overloaded.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::ServerBusy` if the pool has a bounded queue which is
+    /// full and rejects new jobs. The function is dropped then.
+    ///
+    /// A pool may also return `KvsError::TaskCancelled` for a function spawned
+    /// after `shutdown`, which `SharedQueueThreadPool` does. The others drop it
+    /// like `spawn` does and return `Ok(())`.
+    fn try_spawn<F>(&self, job: F) -> Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.spawn(job);
+        Ok(())
+    }
+
+    /// Spawns a function into the thread pool, returning a future of its value.
+    ///
+    /// The function receives a token telling whether `JoinHandle::cancel` was
+    /// called. A panic of the function fails the future with
+    /// `KvsError::TaskPanicked` and is otherwise handled like with `spawn`. If the
+    /// pool rejects the function like `try_spawn`, the future fails with the error.
+    fn spawn_with_handle<F, T>(&self, job: F) -> JoinHandle<T>
+    where
+        F: FnOnce(&CancelToken) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (handle, task) = join_handle::task(job);
+        match self.try_spawn(task) {
+            Ok(()) => handle,
+            Err(e) => handle.fail(e),
+        }
+    }
+
+    /// Stops accepting new jobs. The jobs spawned before still run.
+    fn shutdown(&self);
+
+    /// Shuts the pool down and waits until all the jobs spawned before are done and
+    /// the threads of the pool have exited.
+    ///
+    /// It must not be called from a job of the pool, which would wait for itself.
+    fn join(&self);
+
+    /// Returns the current state of the pool.
+    fn stats(&self) -> ThreadPoolStats;
+}
+
+/// Bounds on the number of threads of a pool.
+///
+/// A pool which sizes itself starts `min` threads, adds threads up to `max` while
+/// jobs wait for a free thread, and stops the threads above `min` which have had
+/// no job for `keep_alive`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolSize {
+    min: u32,
+    max: u32,
+    keep_alive: Duration,
+}
+
+impl PoolSize {
+    /// Creates bounds of `min` to `max` threads, with a keep-alive of 60 seconds.
+    pub fn new(min: u32, max: u32) -> PoolSize {
+        PoolSize {
+            min,
+            max,
+            keep_alive: Duration::from_secs(60),
+        }
+    }
+
+    /// Sets how long a thread above the minimum may stay idle.
+    pub fn keep_alive(mut self, keep_alive: Duration) -> PoolSize {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    /// Returns the minimum number of threads.
+    pub fn min(&self) -> u32 {
+        self.min
+    }
+
+    /// Returns the maximum number of threads.
+    pub fn max(&self) -> u32 {
+        self.max
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.max == 0 || self.min > self.max {
+            return Err(KvsError::StringError(format!(
+                "Invalid thread pool size: {} to {} threads",
+                self.min, self.max
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// What a pool with a bounded queue does with a job spawned while the queue is full.
+///
+/// `Block` and `CallerRuns` hold up the spawning thread. On a worker of the tokio
+/// runtime of `KvsServer`, the other tasks of the worker are handed to another
+/// thread first, up to the blocking limit of the runtime, 100 threads by default.
+/// Beyond it, the worker blocks with its tasks, which deadlocks if the jobs in the
+/// pool wait for them, as scans do to send their pairs. `Reject` never blocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueuePolicy {
+    /// The spawning thread waits until there is room in the queue. Tasks which
+    /// spawn tasks in their own pool may wait for themselves then.
+    Block,
+    /// The job is rejected with `KvsError::ServerBusy`.
+    Reject,
+    /// The spawning thread runs the job itself, which slows it down.
+    CallerRuns,
+}
+
+impl fmt::Display for QueuePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            QueuePolicy::Block => "block",
+            QueuePolicy::Reject => "reject",
+            QueuePolicy::CallerRuns => "caller-runs",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for QueuePolicy {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<QueuePolicy> {
+        match s {
+            "block" => Ok(QueuePolicy::Block),
+            "reject" => Ok(QueuePolicy::Reject),
+            "caller-runs" => Ok(QueuePolicy::CallerRuns),
+            _ => Err(KvsError::StringError(format!(
+                "Unknown queue policy: {}",
+                s
+            ))),
+        }
+    }
 }
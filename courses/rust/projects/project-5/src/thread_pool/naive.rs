@@ -1,22 +1,47 @@
+use std::sync::Arc;
 use std::thread;
 
-use super::ThreadPool;
+use super::stats::Tracker;
+use super::{ThreadPool, ThreadPoolStats};
 use crate::Result;
 
 /// It is actually not a thread pool. It spawns a new thread every time
 /// the `spawn` method is called.
 #[derive(Clone)]
-pub struct NaiveThreadPool;
+pub struct NaiveThreadPool(Arc<Tracker>);
 
 impl ThreadPool for NaiveThreadPool {
     fn new(_threads: u32) -> Result<Self> {
-        Ok(NaiveThreadPool)
+        Ok(NaiveThreadPool(Arc::new(Tracker::new())))
     }
 
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        thread::spawn(job);
+        if !self.0.queue() {
+            return;
+        }
+        let tracker = Arc::clone(&self.0);
+        thread::spawn(move || {
+            let _running = tracker.start();
+            job()
+        });
+    }
+
+    fn shutdown(&self) {
+        self.0.shutdown();
+    }
+
+    fn join(&self) {
+        self.shutdown();
+        self.0.wait(false);
+    }
+
+    /// Every queued or active job has a thread of its own.
+    fn stats(&self) -> ThreadPoolStats {
+        let mut stats = self.0.stats();
+        stats.threads = stats.queued + stats.active;
+        stats
     }
 }
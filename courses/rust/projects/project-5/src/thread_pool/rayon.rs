@@ -1,24 +1,57 @@
-use super::ThreadPool;
+use super::stats::Tracker;
+use super::{ThreadPool, ThreadPoolStats};
 use crate::{KvsError, Result};
 use std::sync::Arc;
 
 /// Wrapper of rayon::ThreadPool
 #[derive(Clone)]
-pub struct RayonThreadPool(Arc<rayon::ThreadPool>);
+pub struct RayonThreadPool {
+    pool: Arc<rayon::ThreadPool>,
+    tracker: Arc<Tracker>,
+}
 
 impl ThreadPool for RayonThreadPool {
     fn new(threads: u32) -> Result<Self> {
         let pool = rayon::ThreadPoolBuilder::new()
             .num_threads(threads as usize)
+            // panics are counted by the tracker, rayon would abort the process
+            .panic_handler(|_| ())
             .build()
             .map_err(|e| KvsError::StringError(format!("{}", e)))?;
-        Ok(RayonThreadPool(Arc::new(pool)))
+        Ok(RayonThreadPool {
+            pool: Arc::new(pool),
+            tracker: Arc::new(Tracker::new()),
+        })
     }
 
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        self.0.spawn(job)
+        if !self.tracker.queue() {
+            return;
+        }
+        let tracker = Arc::clone(&self.tracker);
+        self.pool.spawn(move || {
+            let _running = tracker.start();
+            job()
+        })
+    }
+
+    fn shutdown(&self) {
+        self.tracker.shutdown();
+    }
+
+    /// The threads are owned by rayon and exit when the last clone of the pool is
+    /// dropped, so it only waits for the jobs.
+    fn join(&self) {
+        self.shutdown();
+        self.tracker.wait(false);
+    }
+
+    fn stats(&self) -> ThreadPoolStats {
+        let mut stats = self.tracker.stats();
+        stats.threads = self.pool.current_num_threads();
+        stats
     }
 }
@@ -1,70 +1,253 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
 use std::thread;
 
-use super::ThreadPool;
-use crate::Result;
+use super::stats::Tracker;
+use super::{PoolSize, QueuePolicy, ThreadPool, ThreadPoolStats};
+use crate::{KvsError, Result};
 
-use crossbeam::channel::{self, Receiver, Sender};
+use crossbeam::channel::{self, Receiver, RecvTimeoutError, Sender, TrySendError};
+use tokio::prelude::Async;
 
 // Note for Rust training course: the thread pool is not implemented using
 // `catch_unwind` because it would require the task to be `UnwindSafe`.
 
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
 /// A thread pool using a shared queue inside.
 ///
 /// If a spawned task panics, the old thread will be destroyed and a new one will be
 /// created. It fails silently when any failure to create the thread at the OS level
 /// is captured after the thread pool is created. So, the thread number in the pool
-/// can decrease to zero, then spawning a task to the thread pool will panic.
+/// can decrease to zero.
+///
+/// Created with `with_size`, the pool adds a thread when a task is spawned while no
+/// thread is waiting for one, and stops the threads which stay idle too long. The
+/// threads exit once the pool is shut down, or all its clones are dropped, and the
+/// queue is empty.
+///
+/// Created with `bounded`, the queue holds a limited number of tasks and the
+/// `QueuePolicy` decides what happens to the tasks spawned while it is full.
 #[derive(Clone)]
 pub struct SharedQueueThreadPool {
-    tx: Sender<Box<dyn FnOnce() + Send + 'static>>,
+    handle: Arc<Handle>,
+}
+
+// Shuts the pool down when the last clone of the pool is dropped.
+struct Handle(Arc<Shared>);
+
+struct Shared {
+    // `None` once the pool is shut down, so that the threads see the channel
+    // disconnected when it is empty
+    tx: RwLock<Option<Sender<Job>>>,
+    rx: Receiver<Job>,
+    size: PoolSize,
+    policy: QueuePolicy,
+    // threads waiting for a task
+    idle: AtomicUsize,
+    tracker: Tracker,
 }
 
 impl ThreadPool for SharedQueueThreadPool {
     fn new(threads: u32) -> Result<Self> {
-        let (tx, rx) = channel::unbounded::<Box<dyn FnOnce() + Send + 'static>>();
-        for _ in 0..threads {
-            let rx = TaskReceiver(rx.clone());
-            thread::Builder::new().spawn(move || run_tasks(rx))?;
-        }
-        Ok(SharedQueueThreadPool { tx })
+        SharedQueueThreadPool::with_size(PoolSize::new(threads, threads))
+    }
+
+    fn with_size(size: PoolSize) -> Result<Self> {
+        SharedQueueThreadPool::start(size, channel::unbounded(), QueuePolicy::Block)
     }
 
-    /// Spawns a function into the thread pool.
-    ///
-    /// # Panics
-    ///
-    /// Panics if the thread pool has no thread.
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        self.tx
-            .send(Box::new(job))
-            .expect("The thread pool has no thread.");
+        match self.try_spawn(job) {
+            // the tracker already logged it
+            Ok(()) | Err(KvsError::TaskCancelled) => {}
+            Err(e) => warn!("Dropped a job: {}", e),
+        }
+    }
+
+    fn try_spawn<F>(&self, job: F) -> Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let shared = &self.handle.0;
+        {
+            let guard = shared.tx.read().unwrap();
+            let tx = match &*guard {
+                Some(tx) if shared.tracker.queue() => tx,
+                _ => return Err(KvsError::TaskCancelled),
+            };
+            if let Err(e) = tx.try_send(Box::new(job)) {
+                let job = match e {
+                    TrySendError::Full(job) => job,
+                    TrySendError::Disconnected(_) => unreachable!("The pool keeps a receiver."),
+                };
+                // a new thread will soon make room, but not for this job
+                shared.add_thread();
+                match shared.policy {
+                    QueuePolicy::Block => {
+                        blocking(|| tx.send(job)).expect("The pool keeps a receiver.")
+                    }
+                    QueuePolicy::Reject => {
+                        shared.tracker.unqueue();
+                        return Err(KvsError::ServerBusy);
+                    }
+                    QueuePolicy::CallerRuns => {
+                        // `shutdown` must not wait for the job
+                        drop(guard);
+                        let _running = shared.tracker.start();
+                        blocking(job);
+                        return Ok(());
+                    }
+                }
+            }
+        }
+        if shared.idle.load(Ordering::SeqCst) == 0 {
+            shared.add_thread();
+        }
+        Ok(())
+    }
+
+    fn shutdown(&self) {
+        self.handle.0.shutdown();
+    }
+
+    fn join(&self) {
+        self.shutdown();
+        self.handle.0.tracker.wait(true);
+    }
+
+    fn stats(&self) -> ThreadPoolStats {
+        self.handle.0.tracker.stats()
     }
 }
 
-#[derive(Clone)]
-struct TaskReceiver(Receiver<Box<dyn FnOnce() + Send + 'static>>);
+impl SharedQueueThreadPool {
+    /// Creates a thread pool whose queue holds at most `capacity` tasks not
+    /// started yet. Tasks spawned while the queue is full are handled according
+    /// to `policy`.
+    ///
+    /// With `QueuePolicy::Reject`, `spawn` drops such a task with a warning, and
+    /// `try_spawn` returns `KvsError::ServerBusy`.
+    ///
+    /// Whatever its queue, the pool makes `try_spawn` return
+    /// `KvsError::TaskCancelled` for a task spawned after `shutdown`.
+    pub fn bounded(size: PoolSize, capacity: usize, policy: QueuePolicy) -> Result<Self> {
+        if capacity == 0 {
+            return Err(KvsError::StringError(
+                "The queue capacity must be positive".to_owned(),
+            ));
+        }
+        SharedQueueThreadPool::start(size, channel::bounded(capacity), policy)
+    }
+
+    fn start(
+        size: PoolSize,
+        (tx, rx): (Sender<Job>, Receiver<Job>),
+        policy: QueuePolicy,
+    ) -> Result<Self> {
+        size.validate()?;
+        let shared = Arc::new(Shared {
+            tx: RwLock::new(Some(tx)),
+            rx,
+            size,
+            policy,
+            idle: AtomicUsize::new(0),
+            tracker: Tracker::new(),
+        });
+        let handle = Arc::new(Handle(Arc::clone(&shared)));
+        for _ in 0..size.min {
+            shared.tracker.add_thread(usize::MAX);
+            if let Err(e) = spawn_thread(Arc::clone(&shared)) {
+                shared.tracker.thread_exited();
+                return Err(e);
+            }
+        }
+        Ok(SharedQueueThreadPool { handle })
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        self.0.shutdown();
+    }
+}
+
+impl Shared {
+    fn shutdown(&self) {
+        let mut tx = self.tx.write().unwrap();
+        self.tracker.shutdown();
+        tx.take();
+    }
 
-impl Drop for TaskReceiver {
+    fn add_thread(self: &Arc<Self>) {
+        if self.tracker.add_thread(self.size.max as usize) {
+            if let Err(e) = spawn_thread(Arc::clone(self)) {
+                self.tracker.thread_exited();
+                error!("Failed to spawn a thread: {}", e);
+            }
+        }
+    }
+}
+
+// Runs `f`, which may block, on a worker of a tokio runtime after handing the
+// other tasks of the worker to another thread, so that they are not blocked too.
+// Outside of a runtime, or once the runtime has no blocking slot left, `f` just
+// blocks the calling thread.
+fn blocking<T>(f: impl FnOnce() -> T) -> T {
+    let mut f = Some(f);
+    match tokio_threadpool::blocking(|| (f.take().unwrap())()) {
+        Ok(Async::Ready(value)) => value,
+        _ => (f.take().expect("The closure has not run."))(),
+    }
+}
+
+fn spawn_thread(shared: Arc<Shared>) -> Result<()> {
+    thread::Builder::new().spawn(move || run_tasks(WorkerThread(shared)))?;
+    Ok(())
+}
+
+struct WorkerThread(Arc<Shared>);
+
+impl Drop for WorkerThread {
     fn drop(&mut self) {
         if thread::panicking() {
-            let rx = self.clone();
-            if let Err(e) = thread::Builder::new().spawn(move || run_tasks(rx)) {
+            if let Err(e) = spawn_thread(Arc::clone(&self.0)) {
+                self.0.tracker.thread_exited();
                 error!("Failed to spawn a thread: {}", e);
             }
         }
     }
 }
 
-fn run_tasks(rx: TaskReceiver) {
+fn run_tasks(thread: WorkerThread) {
+    let shared = &thread.0;
     loop {
-        match rx.0.recv() {
+        shared.idle.fetch_add(1, Ordering::SeqCst);
+        let task = shared.rx.recv_timeout(shared.size.keep_alive);
+        shared.idle.fetch_sub(1, Ordering::SeqCst);
+        match task {
             Ok(task) => {
+                let _running = shared.tracker.start();
                 task();
             }
-            Err(_) => debug!("Thread exits because the thread pool is destroyed."),
+            Err(RecvTimeoutError::Timeout) => {
+                if shared.tracker.remove_thread(shared.size.min as usize) {
+                    // a task sent meanwhile may have seen this thread idle
+                    if shared.rx.is_empty() {
+                        debug!("Thread exits because it has been idle for too long.");
+                        return;
+                    }
+                    shared.tracker.add_thread(usize::MAX);
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                debug!("Thread exits because the thread pool is shut down.");
+                shared.tracker.thread_exited();
+                return;
+            }
         }
     }
 }
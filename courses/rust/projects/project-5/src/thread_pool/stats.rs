@@ -0,0 +1,172 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+
+/// A snapshot of the state of a thread pool.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ThreadPoolStats {
+    /// Number of threads of the pool.
+    pub threads: usize,
+    /// Number of threads running a job.
+    pub active: usize,
+    /// Number of jobs spawned but not started yet.
+    pub queued: usize,
+    /// Number of jobs which panicked since the pool was created.
+    pub panics: u64,
+}
+
+const SHUT_DOWN: usize = 1;
+const ONE_JOB: usize = 2;
+
+/// Counts the jobs and threads of a pool, and lets `join` wait for them.
+pub(super) struct Tracker {
+    // twice the number of queued jobs, plus `SHUT_DOWN` once the pool is shut down,
+    // so that no job is queued after the shutdown
+    state: AtomicUsize,
+    active: AtomicUsize,
+    threads: AtomicUsize,
+    panics: AtomicU64,
+    lock: Mutex<()>,
+    changed: Condvar,
+}
+
+impl Tracker {
+    pub(super) fn new() -> Tracker {
+        Tracker {
+            state: AtomicUsize::new(0),
+            active: AtomicUsize::new(0),
+            threads: AtomicUsize::new(0),
+            panics: AtomicU64::new(0),
+            lock: Mutex::new(()),
+            changed: Condvar::new(),
+        }
+    }
+
+    pub(super) fn stats(&self) -> ThreadPoolStats {
+        ThreadPoolStats {
+            threads: self.threads.load(Ordering::SeqCst),
+            active: self.active.load(Ordering::SeqCst),
+            queued: self.queued(),
+            panics: self.panics.load(Ordering::SeqCst),
+        }
+    }
+
+    /// Counts a new job. Returns false if the pool is shut down, in which case the
+    /// job must be dropped.
+    pub(super) fn queue(&self) -> bool {
+        let queued = self
+            .state
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |state| {
+                if state & SHUT_DOWN == 0 {
+                    Some(state + ONE_JOB)
+                } else {
+                    None
+                }
+            })
+            .is_ok();
+        if !queued {
+            warn!("Dropped a job spawned after the thread pool was shut down");
+        }
+        queued
+    }
+
+    /// Uncounts a queued job which was not accepted after all.
+    pub(super) fn unqueue(&self) {
+        self.state.fetch_sub(ONE_JOB, Ordering::SeqCst);
+        if self.queued() == 0 && self.active.load(Ordering::SeqCst) == 0 {
+            self.notify();
+        }
+    }
+
+    pub(super) fn queued(&self) -> usize {
+        self.state.load(Ordering::SeqCst) / ONE_JOB
+    }
+
+    pub(super) fn shutdown(&self) {
+        self.state.fetch_or(SHUT_DOWN, Ordering::SeqCst);
+        self.notify();
+    }
+
+    pub(super) fn is_shut_down(&self) -> bool {
+        self.state.load(Ordering::SeqCst) & SHUT_DOWN != 0
+    }
+
+    /// Counts a queued job as running until the returned guard is dropped.
+    pub(super) fn start(&self) -> Running<'_> {
+        // counted as active first, so the job is never missing from both counts
+        self.active.fetch_add(1, Ordering::SeqCst);
+        self.state.fetch_sub(ONE_JOB, Ordering::SeqCst);
+        Running(self)
+    }
+
+    /// Counts a new thread, unless the pool already has `max` threads.
+    pub(super) fn add_thread(&self, max: usize) -> bool {
+        self.threads
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |threads| {
+                if threads < max {
+                    Some(threads + 1)
+                } else {
+                    None
+                }
+            })
+            .is_ok()
+    }
+
+    /// Uncounts an idle thread, unless the pool has only `min` threads left.
+    pub(super) fn remove_thread(&self, min: usize) -> bool {
+        let removed = self
+            .threads
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |threads| {
+                if threads > min {
+                    Some(threads - 1)
+                } else {
+                    None
+                }
+            })
+            .is_ok();
+        if removed && self.threads.load(Ordering::SeqCst) == 0 {
+            self.notify();
+        }
+        removed
+    }
+
+    /// Uncounts an exiting thread.
+    pub(super) fn thread_exited(&self) {
+        if self.threads.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.notify();
+        }
+    }
+
+    /// Waits until all jobs are done and, if `threads` is true, all threads have
+    /// exited.
+    pub(super) fn wait(&self, threads: bool) {
+        let mut guard = self.lock.lock().unwrap();
+        while self.queued() > 0
+            || self.active.load(Ordering::SeqCst) > 0
+            || (threads && self.threads.load(Ordering::SeqCst) > 0)
+        {
+            guard = self.changed.wait(guard).unwrap();
+        }
+    }
+
+    fn notify(&self) {
+        let _guard = self.lock.lock().unwrap();
+        self.changed.notify_all();
+    }
+}
+
+/// A running job, counted as a panic if it is dropped while unwinding.
+pub(super) struct Running<'a>(&'a Tracker);
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        let tracker = self.0;
+        if thread::panicking() {
+            tracker.panics.fetch_add(1, Ordering::SeqCst);
+            error!("A job of the thread pool panicked");
+        }
+        if tracker.active.fetch_sub(1, Ordering::SeqCst) == 1 && tracker.queued() == 0 {
+            tracker.notify();
+        }
+    }
+}
@@ -0,0 +1,233 @@
+use std::cell::RefCell;
+use std::iter;
+use std::sync::atomic::{self, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use super::stats::Tracker;
+use super::{ThreadPool, ThreadPoolStats};
+use crate::Result;
+
+use crossbeam::deque::{Injector, Steal, Stealer, Worker};
+use crossbeam::utils::Backoff;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A thread pool in which every thread has its own queue of tasks.
+///
+/// Tasks spawned from outside the pool go to a global queue, from which idle
+/// threads take them in batches. A task spawned by a task running in the pool goes
+/// to the queue of its thread. A thread without tasks steals from the other
+/// threads, so threads rarely contend on the same queue.
+///
+/// Like `SharedQueueThreadPool`, a thread whose task panics is replaced by a new
+/// one, which takes over its queue. The threads exit once the pool is shut down,
+/// or all its clones are dropped, and no task is left.
+#[derive(Clone)]
+pub struct WorkStealingThreadPool {
+    handle: Arc<Handle>,
+}
+
+// Shuts the threads down when the last clone of the pool is dropped.
+struct Handle {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    injector: Injector<Job>,
+    stealers: Vec<Stealer<Job>>,
+    // number of threads waiting for a task
+    sleeping: AtomicUsize,
+    lock: Mutex<()>,
+    wakeup: Condvar,
+    tracker: Tracker,
+}
+
+// The queue of the current thread, if it belongs to a pool.
+struct LocalQueue {
+    pool: usize,
+    worker: Worker<Job>,
+}
+
+thread_local! {
+    static LOCAL: RefCell<Option<LocalQueue>> = const { RefCell::new(None) };
+}
+
+impl ThreadPool for WorkStealingThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        let workers: Vec<_> = (0..threads).map(|_| Worker::new_fifo()).collect();
+        let shared = Arc::new(Shared {
+            injector: Injector::new(),
+            stealers: workers.iter().map(Worker::stealer).collect(),
+            sleeping: AtomicUsize::new(0),
+            lock: Mutex::new(()),
+            wakeup: Condvar::new(),
+            tracker: Tracker::new(),
+        });
+        // dropping the handle on error stops the threads already spawned
+        let handle = Arc::new(Handle {
+            shared: Arc::clone(&shared),
+        });
+        for worker in workers {
+            shared.tracker.add_thread(usize::MAX);
+            if let Err(e) = spawn_thread(Arc::clone(&shared), worker) {
+                shared.tracker.thread_exited();
+                return Err(e);
+            }
+        }
+        Ok(WorkStealingThreadPool { handle })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let shared = &self.handle.shared;
+        if !shared.tracker.queue() {
+            return;
+        }
+        let job = LOCAL.with(|local| match &*local.borrow() {
+            Some(local) if local.pool == shared.id() => {
+                local.worker.push(Box::new(job));
+                None
+            }
+            _ => Some(job),
+        });
+        if let Some(job) = job {
+            shared.injector.push(Box::new(job));
+        }
+        shared.notify();
+    }
+
+    fn shutdown(&self) {
+        self.handle.shared.shutdown();
+    }
+
+    fn join(&self) {
+        self.shutdown();
+        self.handle.shared.tracker.wait(true);
+    }
+
+    fn stats(&self) -> ThreadPoolStats {
+        self.handle.shared.tracker.stats()
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        self.shared.shutdown();
+    }
+}
+
+impl Shared {
+    fn id(&self) -> usize {
+        self as *const Shared as usize
+    }
+
+    fn shutdown(&self) {
+        self.tracker.shutdown();
+        let _guard = self.lock.lock().unwrap();
+        self.wakeup.notify_all();
+    }
+
+    // Wakes up a thread if any is waiting for a task.
+    fn notify(&self) {
+        // pairs with the fence in `next_job`: either the task is seen by the thread
+        // checking the queues again, or the thread is seen sleeping here
+        atomic::fence(Ordering::SeqCst);
+        if self.sleeping.load(Ordering::SeqCst) > 0 {
+            let _guard = self.lock.lock().unwrap();
+            self.wakeup.notify_one();
+        }
+    }
+
+    // Takes a task from the local queue, else from the global queue, else from
+    // the other threads.
+    fn find_job(&self, local: &Worker<Job>) -> Option<Job> {
+        local.pop().or_else(|| {
+            iter::repeat_with(|| {
+                self.injector
+                    .steal_batch_and_pop(local)
+                    .or_else(|| self.stealers.iter().map(Stealer::steal).collect())
+            })
+            .find(|steal| !steal.is_retry())
+            .and_then(Steal::success)
+        })
+    }
+
+    // Waits for a task. Returns `None` once the pool is shut down and no task is left.
+    fn next_job(&self) -> Option<Job> {
+        let find_job =
+            || LOCAL.with(|local| self.find_job(&local.borrow().as_ref().unwrap().worker));
+        // spin for a while before sleeping, tasks often come in bursts
+        let backoff = Backoff::new();
+        loop {
+            if let Some(job) = find_job() {
+                return Some(job);
+            }
+            if !backoff.is_completed() {
+                backoff.snooze();
+                continue;
+            }
+            if self.tracker.is_shut_down() {
+                // the remaining tasks may still be on their way to a queue, or in
+                // the queue of a busy thread
+                if self.tracker.queued() == 0 {
+                    return None;
+                }
+                thread::yield_now();
+                continue;
+            }
+            let guard = self.lock.lock().unwrap();
+            self.sleeping.fetch_add(1, Ordering::SeqCst);
+            atomic::fence(Ordering::SeqCst);
+            let job = find_job();
+            let guard = if job.is_none() && !self.tracker.is_shut_down() {
+                self.wakeup.wait(guard).unwrap()
+            } else {
+                guard
+            };
+            self.sleeping.fetch_sub(1, Ordering::SeqCst);
+            drop(guard);
+            if job.is_some() {
+                return job;
+            }
+        }
+    }
+}
+
+fn spawn_thread(shared: Arc<Shared>, worker: Worker<Job>) -> Result<()> {
+    thread::Builder::new().spawn(move || {
+        LOCAL.with(|local| {
+            *local.borrow_mut() = Some(LocalQueue {
+                pool: shared.id(),
+                worker,
+            })
+        });
+        let thread = WorkerThread(shared);
+        while let Some(job) = thread.0.next_job() {
+            let _running = thread.0.tracker.start();
+            job();
+        }
+        thread.0.tracker.thread_exited();
+    })?;
+    Ok(())
+}
+
+struct WorkerThread(Arc<Shared>);
+
+impl Drop for WorkerThread {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            // the new thread takes over the queue, which other threads can still
+            // steal from if it fails to spawn
+            let local = LOCAL.with(|local| local.borrow_mut().take());
+            if let Some(local) = local {
+                if let Err(e) = spawn_thread(Arc::clone(&self.0), local.worker) {
+                    self.0.tracker.thread_exited();
+                    error!("Failed to spawn a thread: {}", e);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+/// A change of a watched key, sent to the clients that watch it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WatchEvent {
+    /// A key was set.
+    Set {
+        /// Position of the write among all writes served by the server.
+        seq: u64,
+        /// The key.
+        key: String,
+        /// The new value.
+        value: String,
+    },
+    /// A key was removed.
+    Remove {
+        /// Position of the write among all writes served by the server.
+        seq: u64,
+        /// The key.
+        key: String,
+    },
+    /// The watcher didn't read events fast enough, so some were dropped.
+    ///
+    /// Later events are still delivered. A client that needs every change should
+    /// read the current values again.
+    Lagged {
+        /// Number of events dropped since the last delivered one.
+        missed: u64,
+    },
+}
+
+/// Distributes the writes served by a `KvsServer`, or applied by a `KvStore`, to
+/// their watchers.
+#[derive(Clone)]
+pub(crate) struct WatchHub {
+    inner: Arc<Mutex<HubInner>>,
+}
+
+struct HubInner {
+    next_seq: u64,
+    watchers: Vec<Watcher>,
+}
+
+struct Watcher {
+    prefix: String,
+    tx: mpsc::Sender<WatchEvent>,
+    // events dropped because the buffer was full, not reported yet
+    missed: u64,
+}
+
+impl WatchHub {
+    pub(crate) fn new() -> WatchHub {
+        WatchHub {
+            inner: Arc::new(Mutex::new(HubInner {
+                next_seq: 0,
+                watchers: Vec::new(),
+            })),
+        }
+    }
+
+    /// Returns the events of the keys starting with `prefix` from now on, with
+    /// the `seq` of the first event that may be received. Up to `buffer` events
+    /// are kept until they are received.
+    ///
+    /// The watcher is dropped at its next event after the receiver is dropped.
+    pub(crate) fn subscribe(
+        &self,
+        prefix: String,
+        buffer: usize,
+    ) -> (u64, mpsc::Receiver<WatchEvent>) {
+        let (tx, rx) = mpsc::channel(buffer);
+        let mut inner = self.inner.lock().unwrap();
+        inner.watchers.push(Watcher {
+            prefix,
+            tx,
+            missed: 0,
+        });
+        (inner.next_seq, rx)
+    }
+
+    /// Returns whether any watcher is interested in `key`.
+    pub(crate) fn watches(&self, key: &str) -> bool {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .watchers
+            .iter()
+            .any(|watcher| key.starts_with(&watcher.prefix))
+    }
+
+    /// Publishes a successful `set`.
+    pub(crate) fn set(&self, key: &str, value: &str) {
+        self.publish(key, |seq| WatchEvent::Set {
+            seq,
+            key: key.to_owned(),
+            value: value.to_owned(),
+        });
+    }
+
+    /// Counts a successful write of a key nobody watches.
+    pub(crate) fn skip(&self) {
+        self.inner.lock().unwrap().next_seq += 1;
+    }
+
+    /// Publishes a successful `remove`.
+    pub(crate) fn remove(&self, key: &str) {
+        self.publish(key, |seq| WatchEvent::Remove {
+            seq,
+            key: key.to_owned(),
+        });
+    }
+
+    fn publish<F>(&self, key: &str, event: F)
+    where
+        F: Fn(u64) -> WatchEvent,
+    {
+        let mut inner = self.inner.lock().unwrap();
+        let seq = inner.next_seq;
+        inner.next_seq += 1;
+        inner
+            .watchers
+            .retain_mut(|watcher| !key.starts_with(&watcher.prefix) || watcher.send(event(seq)));
+    }
+}
+
+impl Watcher {
+    // Never blocks: an event that doesn't fit in the buffer is counted as missed.
+    // Returns false if the receiver is dropped.
+    fn send(&mut self, event: WatchEvent) -> bool {
+        if self.missed > 0 {
+            let lagged = WatchEvent::Lagged {
+                missed: self.missed,
+            };
+            match self.tx.try_send(lagged) {
+                Ok(()) => self.missed = 0,
+                Err(e) if e.is_closed() => return false,
+                Err(_) => {
+                    self.missed += 1;
+                    return true;
+                }
+            }
+        }
+        match self.tx.try_send(event) {
+            Ok(()) => true,
+            Err(e) if e.is_closed() => false,
+            Err(_) => {
+                self.missed += 1;
+                true
+            }
+        }
+    }
+}
@@ -0,0 +1,727 @@
+//! Load generation against a running server, modeled on the YCSB workloads.
+//!
+//! A `Workload` describes the records of the store, how keys are chosen and the
+//! mix of operations. `load` inserts the records, then `run` sends operations from
+//! several connections for a given time and returns a `Report` of the throughput
+//! and latencies of each kind of operation.
+//!
+//! Record `i` has the key `user` followed by `i` on 10 digits. A scan lists the
+//! keys sharing all but the last digit of a chosen key, so at most 10 records.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rand::distributions::Alphanumeric;
+use rand::rngs::SmallRng;
+use rand::{FromEntropy, Rng};
+use tokio::prelude::*;
+
+use crate::bulk::{self, LoadOptions};
+use crate::{KvsClient, KvsError, Result};
+
+const DEFAULT_ZIPFIAN_CONSTANT: f64 = 0.99;
+
+// a histogram has this many buckets per power of two, so a latency is recorded
+// within 1/32 of its value
+const SUB_BUCKET_BITS: u32 = 5;
+const SUB_BUCKETS: u64 = 1 << SUB_BUCKET_BITS;
+// enough for any number of microseconds in a `u64`
+const BUCKETS: usize = (SUB_BUCKETS * (64 - SUB_BUCKET_BITS as u64 + 1)) as usize;
+
+/// A kind of operation sent by `run`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Operation {
+    /// Gets the value of a record.
+    Read,
+    /// Sets a new value to a record.
+    Update,
+    /// Sets the value of a new record, after all others.
+    Insert,
+    /// Lists the records sharing a key prefix.
+    Scan,
+    /// Gets the value of a record, then sets a new one.
+    ReadModifyWrite,
+}
+
+/// How the records read or updated are chosen.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyDistribution {
+    /// All records are equally likely.
+    Uniform,
+    /// A few records are much more likely than the others. The popular records are
+    /// scattered over the key space.
+    Zipfian,
+    /// The records inserted last are the most likely.
+    Latest,
+}
+
+/// The proportions of the kinds of operations, which need not add up to 1.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct OperationMix {
+    /// The proportion of `Operation::Read`.
+    pub read: f64,
+    /// The proportion of `Operation::Update`.
+    pub update: f64,
+    /// The proportion of `Operation::Insert`.
+    pub insert: f64,
+    /// The proportion of `Operation::Scan`.
+    pub scan: f64,
+    /// The proportion of `Operation::ReadModifyWrite`.
+    pub read_modify_write: f64,
+}
+
+/// The data and the requests of a benchmark.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Workload {
+    /// The number of records loaded before the run.
+    pub records: u64,
+    /// The size in bytes of the values written.
+    pub value_size: usize,
+    /// How the records read or updated are chosen.
+    pub distribution: KeyDistribution,
+    /// The proportions of the kinds of operations.
+    pub mix: OperationMix,
+}
+
+impl Workload {
+    /// Returns one of the core YCSB workloads, named from `a` to `f`, with 1000
+    /// records of 100 bytes:
+    ///
+    /// - A, update heavy: 50% reads, 50% updates, zipfian
+    /// - B, read mostly: 95% reads, 5% updates, zipfian
+    /// - C, read only: 100% reads, zipfian
+    /// - D, read latest: 95% reads, 5% inserts, latest
+    /// - E, short ranges: 95% scans, 5% inserts, zipfian
+    /// - F, read-modify-write: 50% reads, 50% read-modify-writes, zipfian
+    pub fn ycsb(name: &str) -> Result<Workload> {
+        let (distribution, mix) = match name.to_ascii_lowercase().as_str() {
+            "a" => (KeyDistribution::Zipfian, OperationMix::read_update(0.5)),
+            "b" => (KeyDistribution::Zipfian, OperationMix::read_update(0.95)),
+            "c" => (KeyDistribution::Zipfian, OperationMix::read_update(1.0)),
+            "d" => (
+                KeyDistribution::Latest,
+                OperationMix {
+                    read: 0.95,
+                    insert: 0.05,
+                    ..OperationMix::default()
+                },
+            ),
+            "e" => (
+                KeyDistribution::Zipfian,
+                OperationMix {
+                    scan: 0.95,
+                    insert: 0.05,
+                    ..OperationMix::default()
+                },
+            ),
+            "f" => (
+                KeyDistribution::Zipfian,
+                OperationMix {
+                    read: 0.5,
+                    read_modify_write: 0.5,
+                    ..OperationMix::default()
+                },
+            ),
+            _ => {
+                return Err(KvsError::StringError(format!(
+                    "Unknown workload: {}, expected a to f",
+                    name
+                )))
+            }
+        };
+        Ok(Workload {
+            records: 1000,
+            value_size: 100,
+            distribution,
+            mix,
+        })
+    }
+}
+
+impl OperationMix {
+    /// Returns a mix of reads in proportion `read` and updates for the rest.
+    pub fn read_update(read: f64) -> OperationMix {
+        OperationMix {
+            read,
+            update: 1.0 - read,
+            ..OperationMix::default()
+        }
+    }
+
+    fn total(&self) -> f64 {
+        self.read + self.update + self.insert + self.scan + self.read_modify_write
+    }
+
+    fn choose(&self, rng: &mut SmallRng) -> Operation {
+        let mut x = rng.gen::<f64>() * self.total();
+        let weights = [
+            (Operation::Read, self.read),
+            (Operation::Update, self.update),
+            (Operation::Insert, self.insert),
+            (Operation::Scan, self.scan),
+            (Operation::ReadModifyWrite, self.read_modify_write),
+        ];
+        for &(op, weight) in &weights {
+            if x < weight {
+                return op;
+            }
+            x -= weight;
+        }
+        // only reached through rounding errors
+        Operation::Read
+    }
+}
+
+/// How `run` sends operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RunOptions {
+    threads: usize,
+    duration: Duration,
+    operations: Option<u64>,
+}
+
+impl RunOptions {
+    /// Creates options running 8 threads for 10 seconds.
+    pub fn new() -> RunOptions {
+        RunOptions {
+            threads: 8,
+            duration: Duration::from_secs(10),
+            operations: None,
+        }
+    }
+
+    /// Sets the number of threads, each with its own connection, which send one
+    /// operation at a time.
+    pub fn threads(mut self, threads: usize) -> RunOptions {
+        self.threads = threads;
+        self
+    }
+
+    /// Sets how long operations are sent.
+    pub fn duration(mut self, duration: Duration) -> RunOptions {
+        self.duration = duration;
+        self
+    }
+
+    /// Stops after `operations` operations, if the duration isn't over before.
+    pub fn operations(mut self, operations: u64) -> RunOptions {
+        self.operations = Some(operations);
+        self
+    }
+}
+
+impl Default for RunOptions {
+    fn default() -> RunOptions {
+        RunOptions::new()
+    }
+}
+
+/// Sets the records of `workload` in the server at `addr`.
+pub fn load(addr: SocketAddr, workload: &Workload) -> Result<()> {
+    let value_size = workload.value_size;
+    let mut rng = SmallRng::from_entropy();
+    let pairs = (0..workload.records).map(move |i| Ok((key(i), value(&mut rng, value_size))));
+    bulk::load(addr, pairs, LoadOptions::new(), |_| ())?;
+    Ok(())
+}
+
+/// Sends the operations of `workload` to the server at `addr`, whose records must
+/// be loaded.
+///
+/// A failed operation is counted as an error and its connection is opened again.
+///
+/// # Errors
+///
+/// It returns an error if the options or the workload are invalid, or if a thread
+/// can't connect to the server at the start.
+pub fn run(addr: SocketAddr, workload: &Workload, options: &RunOptions) -> Result<Report> {
+    if options.threads == 0 {
+        return Err(KvsError::StringError(
+            "The number of threads must be positive".to_owned(),
+        ));
+    }
+    if workload.records == 0 || workload.mix.total() <= 0.0 {
+        return Err(KvsError::StringError(
+            "A workload needs records and operations".to_owned(),
+        ));
+    }
+    let shared = Arc::new(Shared {
+        addr,
+        workload: workload.clone(),
+        chooser: KeyChooser::new(workload.distribution, workload.records),
+        inserts: Inserts::new(workload.records),
+        remaining: AtomicU64::new(options.operations.unwrap_or(u64::MAX)),
+    });
+    let clients = (0..options.threads)
+        .map(|_| KvsClient::connect(addr).wait())
+        .collect::<Result<Vec<_>>>()?;
+
+    let start = Instant::now();
+    let deadline = start + options.duration;
+    let workers: Vec<_> = clients
+        .into_iter()
+        .map(|client| {
+            let shared = Arc::clone(&shared);
+            thread::spawn(move || shared.work(client, deadline))
+        })
+        .collect();
+    let mut operations = BTreeMap::new();
+    for worker in workers {
+        let results = worker
+            .join()
+            .map_err(|_| KvsError::StringError("A benchmark thread panicked".to_owned()))?;
+        for (op, stats) in results {
+            let total = operations.entry(op).or_insert_with(OperationStats::default);
+            total.latencies.merge(&stats.latencies);
+            total.errors += stats.errors;
+        }
+    }
+    let elapsed = start.elapsed();
+    Ok(Report {
+        elapsed,
+        operations,
+    })
+}
+
+struct Shared {
+    addr: SocketAddr,
+    workload: Workload,
+    chooser: KeyChooser,
+    inserts: Inserts,
+    remaining: AtomicU64,
+}
+
+// Hands out the records inserted during the run, and counts the records which
+// can be read: the loaded ones and the inserted ones without a gap before them,
+// as the inserts complete out of order.
+struct Inserts {
+    next: AtomicU64,
+    inserted: AtomicU64,
+    // the records inserted after a gap
+    completed: Mutex<BTreeSet<u64>>,
+}
+
+impl Inserts {
+    fn new(records: u64) -> Inserts {
+        Inserts {
+            next: AtomicU64::new(records),
+            inserted: AtomicU64::new(records),
+            completed: Mutex::new(BTreeSet::new()),
+        }
+    }
+
+    fn start(&self) -> u64 {
+        self.next.fetch_add(1, Ordering::SeqCst)
+    }
+
+    fn complete(&self, i: u64) {
+        let mut completed = self.completed.lock().unwrap();
+        completed.insert(i);
+        let mut inserted = self.inserted.load(Ordering::SeqCst);
+        while completed.remove(&inserted) {
+            inserted += 1;
+        }
+        self.inserted.store(inserted, Ordering::SeqCst);
+    }
+
+    fn inserted(&self) -> u64 {
+        self.inserted.load(Ordering::SeqCst)
+    }
+}
+
+impl Shared {
+    fn work(&self, client: KvsClient, deadline: Instant) -> BTreeMap<Operation, OperationStats> {
+        let mut rng = SmallRng::from_entropy();
+        let mut stats = BTreeMap::new();
+        let mut client = Some(client);
+        while Instant::now() < deadline && self.take_operation() {
+            let op = self.workload.mix.choose(&mut rng);
+            let start = Instant::now();
+            let result = match client.take() {
+                Some(client) => self.execute(client, op, &mut rng),
+                None => KvsClient::connect(self.addr)
+                    .wait()
+                    .and_then(|client| self.execute(client, op, &mut rng)),
+            };
+            let op_stats = stats.entry(op).or_insert_with(OperationStats::default);
+            match result {
+                Ok(next) => {
+                    op_stats.latencies.record(start.elapsed());
+                    client = Some(next);
+                }
+                Err(_) => op_stats.errors += 1,
+            }
+        }
+        stats
+    }
+
+    fn take_operation(&self) -> bool {
+        self.remaining
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            .is_ok()
+    }
+
+    fn execute(&self, client: KvsClient, op: Operation, rng: &mut SmallRng) -> Result<KvsClient> {
+        let value_size = self.workload.value_size;
+        let client = match op {
+            Operation::Read => client.get(self.chosen_key(rng)).wait()?.1,
+            Operation::Update => client
+                .set(self.chosen_key(rng), value(rng, value_size))
+                .wait()?,
+            Operation::Insert => {
+                let i = self.inserts.start();
+                let res = client.set(key(i), value(rng, value_size)).wait();
+                // a failed insert completes too, or no record after it could be
+                // read, while reading it only finds no value
+                self.inserts.complete(i);
+                res?
+            }
+            Operation::Scan => {
+                let mut prefix = self.chosen_key(rng);
+                prefix.pop();
+                client.scan(prefix).wait()?.1
+            }
+            Operation::ReadModifyWrite => {
+                let key = self.chosen_key(rng);
+                let (_, client) = client.get(key.clone()).wait()?;
+                client.set(key, value(rng, value_size)).wait()?
+            }
+        };
+        Ok(client)
+    }
+
+    fn chosen_key(&self, rng: &mut SmallRng) -> String {
+        key(self.chooser.next(rng, self.inserts.inserted()))
+    }
+}
+
+fn key(i: u64) -> String {
+    format!("user{:010}", i)
+}
+
+fn value(rng: &mut SmallRng, size: usize) -> String {
+    rng.sample_iter(&Alphanumeric).take(size).collect()
+}
+
+/// Chooses the records read or updated, among the records inserted so far.
+pub struct KeyChooser {
+    distribution: KeyDistribution,
+    zipfian: Option<Zipfian>,
+}
+
+impl KeyChooser {
+    /// Creates a chooser of records following `distribution`. A zipfian or latest
+    /// distribution is computed over the first `records` records.
+    pub fn new(distribution: KeyDistribution, records: u64) -> KeyChooser {
+        let zipfian = match distribution {
+            KeyDistribution::Uniform => None,
+            _ => Some(Zipfian::new(records, DEFAULT_ZIPFIAN_CONSTANT)),
+        };
+        KeyChooser {
+            distribution,
+            zipfian,
+        }
+    }
+
+    /// Returns the index of a record below `inserted`.
+    pub fn next<R: Rng>(&self, rng: &mut R, inserted: u64) -> u64 {
+        match (self.distribution, &self.zipfian) {
+            (KeyDistribution::Zipfian, Some(zipfian)) => {
+                fnv1a(zipfian.next(rng)) % zipfian.items.min(inserted)
+            }
+            (KeyDistribution::Latest, Some(zipfian)) => {
+                inserted - 1 - zipfian.next(rng).min(inserted - 1)
+            }
+            _ => rng.gen_range(0, inserted),
+        }
+    }
+}
+
+// Ranks from 0 to `items` - 1 following a zipfian distribution, with the algorithm
+// of "Quickly Generating Billion-Record Synthetic Databases" by Gray et al., like YCSB.
+struct Zipfian {
+    items: u64,
+    theta: f64,
+    alpha: f64,
+    zetan: f64,
+    eta: f64,
+}
+
+impl Zipfian {
+    fn new(items: u64, theta: f64) -> Zipfian {
+        let items = items.max(1);
+        let zetan: f64 = (1..=items).map(|i| 1.0 / (i as f64).powf(theta)).sum();
+        let zeta2 = 1.0 + 0.5f64.powf(theta);
+        Zipfian {
+            items,
+            theta,
+            alpha: 1.0 / (1.0 - theta),
+            zetan,
+            eta: (1.0 - (2.0 / items as f64).powf(1.0 - theta)) / (1.0 - zeta2 / zetan),
+        }
+    }
+
+    fn next<R: Rng>(&self, rng: &mut R) -> u64 {
+        let u: f64 = rng.gen();
+        let uz = u * self.zetan;
+        if uz < 1.0 {
+            return 0;
+        }
+        if uz < 1.0 + 0.5f64.powf(self.theta) {
+            return 1.min(self.items - 1);
+        }
+        let rank = self.items as f64 * (self.eta * u - self.eta + 1.0).powf(self.alpha);
+        (rank as u64).min(self.items - 1)
+    }
+}
+
+// Scatters the popular ranks of a zipfian distribution over the key space.
+fn fnv1a(value: u64) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in value.to_le_bytes().iter() {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+/// The results of `run`.
+#[derive(Debug, Clone)]
+pub struct Report {
+    elapsed: Duration,
+    operations: BTreeMap<Operation, OperationStats>,
+}
+
+/// The results of one kind of operation.
+#[derive(Debug, Clone, Default)]
+pub struct OperationStats {
+    latencies: Histogram,
+    errors: u64,
+}
+
+// The number of latencies in buckets whose width grows with their values, like
+// an HDR histogram, so that its size doesn't depend on the number of operations.
+#[derive(Clone)]
+struct Histogram {
+    counts: Box<[u64; BUCKETS]>,
+    total: u64,
+}
+
+impl Histogram {
+    fn record(&mut self, latency: Duration) {
+        let micros = latency.as_micros().min(u128::from(u64::MAX)) as u64;
+        self.counts[Histogram::bucket(micros)] += 1;
+        self.total += 1;
+    }
+
+    fn merge(&mut self, other: &Histogram) {
+        for (count, other) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *count += other;
+        }
+        self.total += other.total;
+    }
+
+    // The values below `SUB_BUCKETS` have a bucket each. Then every power of two
+    // is split into `SUB_BUCKETS` buckets.
+    fn bucket(micros: u64) -> usize {
+        if micros < SUB_BUCKETS {
+            return micros as usize;
+        }
+        let shift = 63 - micros.leading_zeros() - SUB_BUCKET_BITS;
+        let sub_bucket = (micros >> shift) - SUB_BUCKETS;
+        (SUB_BUCKETS * u64::from(shift + 1) + sub_bucket) as usize
+    }
+
+    // Returns the highest value of the bucket.
+    fn highest_value(bucket: usize) -> u64 {
+        let bucket = bucket as u64;
+        if bucket < SUB_BUCKETS {
+            return bucket;
+        }
+        let shift = bucket / SUB_BUCKETS - 1;
+        let lowest = (bucket % SUB_BUCKETS + SUB_BUCKETS) << shift;
+        lowest + ((1 << shift) - 1)
+    }
+
+    fn percentile(&self, quantile: f64) -> Duration {
+        let rank = ((quantile * self.total as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, &count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Duration::from_micros(Histogram::highest_value(bucket));
+            }
+        }
+        Duration::from_secs(0)
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram {
+            counts: Box::new([0; BUCKETS]),
+            total: 0,
+        }
+    }
+}
+
+impl fmt::Debug for Histogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Histogram")
+            .field("total", &self.total)
+            .finish()
+    }
+}
+
+impl Report {
+    /// Returns how long the run took.
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// Returns the number of successful operations per second.
+    pub fn throughput(&self) -> f64 {
+        let count: u64 = self.operations.values().map(OperationStats::count).sum();
+        count as f64 / self.elapsed.as_secs_f64()
+    }
+
+    /// Returns the results of one kind of operation, if any was sent.
+    pub fn operation(&self, op: Operation) -> Option<&OperationStats> {
+        self.operations.get(&op)
+    }
+}
+
+impl OperationStats {
+    /// Returns the number of successful operations.
+    pub fn count(&self) -> u64 {
+        self.latencies.total
+    }
+
+    /// Returns the number of failed operations.
+    pub fn errors(&self) -> u64 {
+        self.errors
+    }
+
+    /// Returns the latency which `quantile` of the successful operations don't
+    /// exceed, like 0.99 for the 99th percentile.
+    ///
+    /// The latencies are kept with a precision of about 3%, so the result may be
+    /// a little higher than the actual one.
+    pub fn percentile(&self, quantile: f64) -> Duration {
+        self.latencies.percentile(quantile)
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ms = |latency: Duration| latency.as_micros() as f64 / 1000.0;
+        let count: u64 = self.operations.values().map(OperationStats::count).sum();
+        writeln!(
+            f,
+            "{} operations in {:.2}s: {:.1} ops/s",
+            count,
+            self.elapsed.as_secs_f64(),
+            self.throughput()
+        )?;
+        write!(
+            f,
+            "{:<18}{:>10}{:>8}{:>10}{:>10}{:>10}",
+            "operation", "count", "errors", "p50 ms", "p99 ms", "p999 ms"
+        )?;
+        for (op, stats) in &self.operations {
+            write!(
+                f,
+                "\n{:<18}{:>10}{:>8}{:>10.3}{:>10.3}{:>10.3}",
+                op.to_string(),
+                stats.count(),
+                stats.errors,
+                ms(stats.percentile(0.5)),
+                ms(stats.percentile(0.99)),
+                ms(stats.percentile(0.999))
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Operation::Read => "read",
+            Operation::Update => "update",
+            Operation::Insert => "insert",
+            Operation::Scan => "scan",
+            Operation::ReadModifyWrite => "read-modify-write",
+        };
+        f.write_str(name)
+    }
+}
+
+impl fmt::Display for KeyDistribution {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            KeyDistribution::Uniform => "uniform",
+            KeyDistribution::Zipfian => "zipfian",
+            KeyDistribution::Latest => "latest",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for KeyDistribution {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<KeyDistribution> {
+        match s {
+            "uniform" => Ok(KeyDistribution::Uniform),
+            "zipfian" => Ok(KeyDistribution::Zipfian),
+            "latest" => Ok(KeyDistribution::Latest),
+            _ => Err(KvsError::StringError(format!(
+                "Unknown key distribution: {}",
+                s
+            ))),
+        }
+    }
+}
+
+/// Parses proportions like `read=0.9,update=0.1`. The operations missing from the
+/// list have a proportion of 0.
+impl FromStr for OperationMix {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<OperationMix> {
+        let mut mix = OperationMix::default();
+        for part in s.split(',') {
+            let invalid = || KvsError::StringError(format!("Invalid operation mix: {}", part));
+            let mut words = part.splitn(2, '=');
+            let (name, proportion) = match (words.next(), words.next()) {
+                (Some(name), Some(proportion)) => (name.trim(), proportion.trim()),
+                _ => return Err(invalid()),
+            };
+            let proportion: f64 = proportion.parse().map_err(|_| invalid())?;
+            if proportion < 0.0 || !proportion.is_finite() {
+                return Err(invalid());
+            }
+            match name {
+                "read" => mix.read = proportion,
+                "update" => mix.update = proportion,
+                "insert" => mix.insert = proportion,
+                "scan" => mix.scan = proportion,
+                "read-modify-write" | "rmw" => mix.read_modify_write = proportion,
+                _ => return Err(invalid()),
+            }
+        }
+        if mix.total() <= 0.0 {
+            return Err(KvsError::StringError(
+                "The operation mix is empty".to_owned(),
+            ));
+        }
+        Ok(mix)
+    }
+}
@@ -0,0 +1,358 @@
+use assert_cmd::prelude::*;
+use kvs::thread_pool::RayonThreadPool;
+use kvs::{EncryptionKey, KvStore, KvStoreOptions, KvsEngine, Result, SledKvsEngine};
+use predicates::boolean::PredicateBooleanExt;
+use predicates::str::contains;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::process::Command;
+use tempfile::TempDir;
+use tokio::prelude::*;
+
+fn fill_store(path: &Path) -> Result<()> {
+    let store = KvStore::<RayonThreadPool>::open(path, 1)?;
+    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
+    store.set("key2".to_owned(), "value2".to_owned()).wait()?;
+    store.set("key1".to_owned(), "value3".to_owned()).wait()?;
+    store.remove("key2".to_owned()).wait()?;
+    Ok(())
+}
+
+fn append_garbage(path: &Path) {
+    let mut log = fs::read_dir(path)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some("log".as_ref()))
+        .collect::<Vec<_>>();
+    log.sort();
+    let mut file = OpenOptions::new()
+        .append(true)
+        .open(log.last().unwrap())
+        .unwrap();
+    file.write_all(b"{\"Set\":{\"key\":\"ke").unwrap();
+}
+
+// `kvs-admin dump` should print all records
+#[test]
+fn admin_cli_dump() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    fill_store(temp_dir.path())?;
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["dump"])
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("set\tkey1\tvalue1"))
+        .stdout(contains("set\tkey1\tvalue3"))
+        .stdout(contains("rm\tkey2"));
+    Ok(())
+}
+
+// `kvs-admin stats` should account live and stale bytes
+#[test]
+fn admin_cli_stats() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    fill_store(temp_dir.path())?;
+
+    let output = Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["stats"])
+        .arg(temp_dir.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let total: Vec<u64> = stdout
+        .lines()
+        .find(|line| line.starts_with("total"))
+        .unwrap()
+        .split_whitespace()
+        .skip(1)
+        .map(|n| n.parse().unwrap())
+        .collect();
+    let file_size: u64 = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().metadata().unwrap().len())
+        .sum();
+    assert_eq!(total[0] + total[1], file_size);
+    assert!(total[0] > 0);
+    assert!(total[1] > 0);
+    Ok(())
+}
+
+// `kvs-admin verify` should fail on a torn record and `repair` should fix it
+#[test]
+fn admin_cli_verify_and_repair() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    fill_store(temp_dir.path())?;
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["verify"])
+        .arg(temp_dir.path())
+        .assert()
+        .success();
+
+    append_garbage(temp_dir.path());
+    assert!(KvStore::<RayonThreadPool>::open(temp_dir.path(), 1).is_err());
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["verify"])
+        .arg(temp_dir.path())
+        .assert()
+        .failure()
+        .stderr(contains("corrupt"));
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["repair"])
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("truncated"));
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["verify"])
+        .arg(temp_dir.path())
+        .assert()
+        .success();
+
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(
+        store.get("key1".to_owned()).wait()?,
+        Some("value3".to_owned())
+    );
+    assert_eq!(store.get("key2".to_owned()).wait()?, None);
+    Ok(())
+}
+
+// `kvs-admin` should refuse to repair a directory a store has open
+#[test]
+fn admin_cli_locked() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    fill_store(temp_dir.path())?;
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["repair"])
+        .arg(temp_dir.path())
+        .assert()
+        .failure()
+        .stderr(contains("already in use"));
+
+    drop(store);
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["repair"])
+        .arg(temp_dir.path())
+        .assert()
+        .success();
+    Ok(())
+}
+
+// `kvs-admin compact` should drop stale records and keep the data
+#[test]
+fn admin_cli_compact() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    fill_store(temp_dir.path())?;
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["compact"])
+        .arg(temp_dir.path())
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["stats"])
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("total\t\t"))
+        .stdout(contains("\t0\n"));
+
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(
+        store.get("key1".to_owned()).wait()?,
+        Some("value3".to_owned())
+    );
+    assert_eq!(store.get("key2".to_owned()).wait()?, None);
+    Ok(())
+}
+
+// `kvs-admin` should refuse to touch a directory of another engine
+#[test]
+fn admin_cli_wrong_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(temp_dir.path().join("engine"), "sled").unwrap();
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["compact"])
+        .arg(temp_dir.path())
+        .assert()
+        .failure()
+        .stderr(contains("sled"));
+    assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 1);
+}
+
+fn kvs_pairs(path: &Path) -> Result<Vec<(String, String)>> {
+    let store = KvStore::<RayonThreadPool>::open(path, 1)?;
+    store.scan(String::new()).collect().wait()
+}
+
+// `kvs-admin migrate` should move the data between kvs and sled back and forth
+#[test]
+fn admin_cli_migrate() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    {
+        let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+        for i in 0..100 {
+            store
+                .set(format!("key{}", i), format!("value{}", i))
+                .wait()?;
+        }
+    }
+    fs::write(temp_dir.path().join("engine"), "kvs")?;
+    let expected = kvs_pairs(temp_dir.path())?;
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["migrate", "--from", "sled", "--to", "kvs"])
+        .arg(temp_dir.path())
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["migrate", "--from", "kvs", "--to", "sled"])
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("Migrated 100 pairs"));
+    assert_eq!(fs::read_to_string(temp_dir.path().join("engine"))?, "sled");
+    assert!(fs::read_dir(temp_dir.path())?
+        .all(|entry| entry.unwrap().path().extension() != Some("log".as_ref())));
+    {
+        let db =
+            SledKvsEngine::<RayonThreadPool>::new(sled::Db::start_default(temp_dir.path())?, 1)?;
+        assert_eq!(db.scan(String::new()).collect().wait()?, expected);
+    }
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["migrate", "--from", "sled", "--to", "kvs"])
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("Migrated 100 pairs"));
+    assert_eq!(fs::read_to_string(temp_dir.path().join("engine"))?, "kvs");
+    assert_eq!(kvs_pairs(temp_dir.path())?, expected);
+    Ok(())
+}
+
+// `kvs-admin export` output should be restored by `kvs-admin import`
+#[test]
+fn admin_cli_export_import() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let backup = temp_dir.path().join("backup.ndjson");
+    let src_dir = temp_dir.path().join("src");
+    let dst_dir = temp_dir.path().join("dst");
+    {
+        let store = KvStore::<RayonThreadPool>::open(&src_dir, 1)?;
+        store.set("key1".to_owned(), "value1".to_owned()).wait()?;
+        store
+            .set("key2".to_owned(), "{\"json\": \"value\"}\n".to_owned())
+            .wait()?;
+    }
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["export", "--output"])
+        .arg(&backup)
+        .arg(&src_dir)
+        .assert()
+        .success();
+    assert_eq!(fs::read_to_string(&backup)?.lines().count(), 2);
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["import", "--engine", "sled", "--input"])
+        .arg(&backup)
+        .arg(&dst_dir)
+        .assert()
+        .success()
+        .stderr(contains("Imported 2 pairs"));
+    assert_eq!(fs::read_to_string(dst_dir.join("engine"))?, "sled");
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["export"])
+        .arg(&dst_dir)
+        .assert()
+        .success()
+        .stdout(fs::read_to_string(&backup)?);
+    Ok(())
+}
+
+// `kvs-admin` should check an encrypted store without its key, and decrypt the
+// values with it
+#[test]
+fn admin_cli_encrypted() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let key_file = temp_dir.path().join("key");
+    let data_dir = temp_dir.path().join("data");
+    fs::write(&key_file, "01".repeat(32))?;
+    {
+        let mut options = KvStoreOptions::new();
+        options.encryption_key(EncryptionKey::from_file(&key_file)?);
+        let store = options.open::<RayonThreadPool>(&data_dir, 1)?;
+        store.set("key1".to_owned(), "secret1".to_owned()).wait()?;
+        store.set("key2".to_owned(), "secret2".to_owned()).wait()?;
+        store.remove("key2".to_owned()).wait()?;
+    }
+
+    for command in &["verify", "stats"] {
+        Command::cargo_bin("kvs-admin")
+            .unwrap()
+            .arg(command)
+            .arg(&data_dir)
+            .assert()
+            .success();
+    }
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["dump"])
+        .arg(&data_dir)
+        .assert()
+        .success()
+        .stdout(contains("encrypted\tkey1\t"))
+        .stdout(contains("rm\tkey2"))
+        .stdout(contains("secret").not());
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["dump", "--encryption-key-file"])
+        .arg(&key_file)
+        .arg(&data_dir)
+        .assert()
+        .success()
+        .stdout(contains("set\tkey1\tsecret1"));
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["export", "--old-encryption-key-file"])
+        .arg(&key_file)
+        .arg(&data_dir)
+        .assert()
+        .success()
+        .stdout(contains("secret1"));
+    Ok(())
+}
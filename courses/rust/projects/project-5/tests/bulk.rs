@@ -0,0 +1,92 @@
+use kvs::bulk::{self, LoadOptions, PairFormat, PairReader};
+use kvs::{KvsClient, KvsServer, MemKvsEngine, Result};
+use std::io::Cursor;
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+use tokio::prelude::*;
+
+// Pairs should survive a round trip through both formats, including separators,
+// quotes and newlines
+#[test]
+fn pair_formats() -> Result<()> {
+    let pairs = vec![
+        ("a".to_owned(), "plain".to_owned()),
+        (
+            "b,c".to_owned(),
+            "with \"quotes\"\nand a newline".to_owned(),
+        ),
+        ("d".to_owned(), String::new()),
+    ];
+    for &format in &[PairFormat::Ndjson, PairFormat::Csv] {
+        let mut buf = Vec::new();
+        let mut writer = bulk::PairWriter::new(&mut buf, format);
+        for (key, value) in pairs.clone() {
+            writer.write(key, value)?;
+        }
+        writer.flush()?;
+        drop(writer);
+        let read = PairReader::new(Cursor::new(buf), format).collect::<Result<Vec<_>>>()?;
+        assert_eq!(read, pairs, "{}", format);
+    }
+
+    let invalid = PairReader::new(Cursor::new("a,1\nb\n"), PairFormat::Csv).nth(1);
+    assert_eq!(
+        invalid.unwrap().unwrap_err().to_string(),
+        "Invalid pair at line 2: 1 fields instead of 2"
+    );
+    let invalid = PairReader::new(Cursor::new("\n{\"key\":\"a\"}\n"), PairFormat::Ndjson).next();
+    assert!(invalid
+        .unwrap()
+        .unwrap_err()
+        .to_string()
+        .starts_with("Invalid pair at line 2"));
+    Ok(())
+}
+
+// Pairs loaded with several batches in flight should all be set, the last value of
+// a key winning, and dumped back in key order
+#[test]
+fn load_and_dump() -> Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4020".parse().unwrap();
+    thread::spawn(move || KvsServer::new(MemKvsEngine::new()).run(addr).unwrap());
+    thread::sleep(Duration::from_secs(1));
+
+    let pairs: Vec<_> = (0..1000)
+        .map(|i| Ok((format!("key{:04}", i % 900), format!("value{}", i))))
+        .collect();
+    let options = LoadOptions::new().batch_size(7).concurrency(4);
+    let mut reported = Vec::new();
+    let loaded = bulk::load(addr, pairs.into_iter(), options, |n| reported.push(n))?;
+    assert_eq!(loaded, 1000);
+    assert_eq!(reported.len(), 143);
+    assert_eq!(reported.last(), Some(&1000));
+
+    let (value, _) = KvsClient::connect(addr)
+        .and_then(|client| client.get("key0001".to_owned()))
+        .wait()?;
+    assert_eq!(value, Some("value901".to_owned()));
+
+    let mut out = Vec::new();
+    assert_eq!(
+        bulk::dump(addr, "key00".to_owned(), &mut out, PairFormat::Csv)?,
+        100
+    );
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.starts_with("key0000,value900\nkey0001,value901\n"));
+
+    // the batches before an invalid pair are set
+    let pairs = vec![
+        Ok(("new1".to_owned(), "1".to_owned())),
+        Ok(("new2".to_owned(), "2".to_owned())),
+        Err(kvs::KvsError::StringError("bad pair".to_owned())),
+    ];
+    let options = LoadOptions::new().batch_size(1);
+    let err = bulk::load(addr, pairs.into_iter(), options, |_| ()).unwrap_err();
+    assert_eq!(err.to_string(), "bad pair");
+    let (values, _) = KvsClient::connect(addr)
+        .and_then(|client| client.get_batch(vec!["new1".to_owned(), "new2".to_owned()]))
+        .wait()?;
+    assert_eq!(values, vec![Some("1".to_owned()), Some("2".to_owned())]);
+    Ok(())
+}
@@ -1,7 +1,8 @@
 use assert_cmd::prelude::*;
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
-use std::process::Command;
+use std::io::Read;
+use std::process::{Command, Stdio};
 use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
@@ -171,6 +172,31 @@ fn cli_log_configuration() {
     assert!(content.contains("127.0.0.1:4001"));
 }
 
+// `RUST_LOG` should take precedence over the configured level
+#[test]
+fn cli_log_level() {
+    let server_log = |addr: &str, rust_log: Option<&str>| {
+        let temp_dir = TempDir::new().unwrap();
+        let stderr_path = temp_dir.path().join("stderr");
+        let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+        cmd.args(&["--addr", addr])
+            .env("KVS_LOG_LEVEL", "warn")
+            .env_remove("RUST_LOG")
+            .current_dir(&temp_dir)
+            .stderr(File::create(&stderr_path).unwrap());
+        if let Some(filters) = rust_log {
+            cmd.env("RUST_LOG", filters);
+        }
+        let mut child = cmd.spawn().unwrap();
+        thread::sleep(Duration::from_secs(1));
+        child.kill().expect("server exited before killed");
+        fs::read_to_string(&stderr_path).expect("unable to read from stderr file")
+    };
+
+    assert!(!server_log("127.0.0.1:4041", None).contains("127.0.0.1:4041"));
+    assert!(server_log("127.0.0.1:4042", Some("kvs_server=info")).contains("127.0.0.1:4042"));
+}
+
 #[test]
 fn cli_wrong_engine() {
     // sled first, kvs second
@@ -335,3 +361,427 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+#[test]
+fn cli_access_server_lsm_engine() {
+    cli_access_server("lsm", "127.0.0.1:4006");
+}
+
+// The memory engine should serve requests without creating files
+#[test]
+fn cli_access_server_memory_engine() {
+    let addr = "127.0.0.1:4007";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "memory", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    child.kill().expect("server exited before killed");
+    assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+}
+
+// Values written with a key file should not appear in the data files
+#[test]
+fn cli_access_server_encrypted_kvs_engine() {
+    let addr = "127.0.0.1:4008";
+    let temp_dir = TempDir::new().unwrap();
+    let key_file = temp_dir.path().join("key");
+    fs::write(&key_file, format!("{}\n", "ab".repeat(32))).unwrap();
+    let data_dir = temp_dir.path().join("data");
+    fs::create_dir(&data_dir).unwrap();
+
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--addr", addr, "--encryption-key-file"])
+        .arg(&key_file)
+        .current_dir(&data_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "plaintext-value", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("plaintext-value\n");
+
+    child.kill().expect("server exited before killed");
+    for entry in fs::read_dir(&data_dir).unwrap() {
+        let content = fs::read(entry.unwrap().path()).unwrap();
+        assert!(!String::from_utf8_lossy(&content).contains("plaintext-value"));
+    }
+}
+
+// `kvs-client watch` should print the changes of the watched keys
+#[test]
+fn cli_watch() {
+    let addr = "127.0.0.1:4009";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "memory", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut watcher = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["watch", "user.", "--addr", addr])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    for args in &[
+        &["set", "user.1", "alice"][..],
+        &["set", "group.1", "admins"][..],
+        &["rm", "user.1"][..],
+    ] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(*args)
+            .args(&["--addr", addr])
+            .assert()
+            .success();
+    }
+    thread::sleep(Duration::from_secs(1));
+
+    watcher.kill().expect("watcher exited before killed");
+    server.kill().expect("server exited before killed");
+    let mut output = String::new();
+    watcher
+        .stdout
+        .take()
+        .unwrap()
+        .read_to_string(&mut output)
+        .unwrap();
+    assert_eq!(output, "set user.1 alice\nrm user.1\n");
+}
+
+// `kvs-client repl` should run the commands read from its input over one connection
+#[test]
+fn cli_repl() {
+    let addr = "127.0.0.1:4019";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "memory", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let output = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["repl", "--addr", addr])
+        .env("HOME", temp_dir.path())
+        .with_stdin()
+        .buffer("set key1 \"value 1\"\nget key1\nrm key2\nbogus\nscan key\nquit\nget key1\n")
+        .assert()
+        .success()
+        .stderr(contains("Unknown command: bogus"))
+        .get_output()
+        .stdout
+        .clone();
+    let output = String::from_utf8(output).unwrap();
+    let lines: Vec<_> = output
+        .lines()
+        .filter(|line| !line.ends_with(" ms)"))
+        .collect();
+    assert_eq!(
+        lines,
+        ["OK", "value 1", "error: Key not found", "key1 value 1"]
+    );
+    assert!(temp_dir.path().join(".kvs_history").exists());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["repl", "--addr", addr, "--output", "json"])
+        .env("HOME", temp_dir.path())
+        .with_stdin()
+        .buffer("get key1\n")
+        .assert()
+        .success()
+        .stdout(contains(r#""result":"value 1""#));
+
+    server.kill().expect("server exited before killed");
+}
+
+// `kvs-client load` and `kvs-client dump` should move pairs between files and a server
+#[test]
+fn cli_load_dump() {
+    let addr = "127.0.0.1:4021";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "memory", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let input = temp_dir.path().join("pairs.csv");
+    fs::write(
+        &input,
+        "user.1,\"alice, admin\"\nuser.2,bob\ngroup.1,admins\n",
+    )
+    .unwrap();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["load", "--batch-size", "2", "--addr", addr])
+        .arg(&input)
+        .assert()
+        .success()
+        .stderr(contains("3 pairs loaded"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["dump", "--prefix", "user.", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(
+            "{\"key\":\"user.1\",\"value\":\"alice, admin\"}\n\
+             {\"key\":\"user.2\",\"value\":\"bob\"}\n",
+        );
+
+    let output = temp_dir.path().join("dump.csv");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["dump", "--format", "csv", "--addr", addr, "--output"])
+        .arg(&output)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    assert_eq!(
+        fs::read_to_string(&output).unwrap(),
+        "group.1,admins\nuser.1,\"alice, admin\"\nuser.2,bob\n"
+    );
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["load", "-", "--format", "csv", "--addr", addr])
+        .with_stdin()
+        .buffer("a,1,2\n")
+        .assert()
+        .failure()
+        .stderr(contains("Invalid pair at line 1"));
+
+    server.kill().expect("server exited before killed");
+}
+
+// `kvs-server --config` should read its settings from the file, overridden by
+// `KVS_*` variables, then by flags, and warn about unknown variables
+#[test]
+fn cli_server_config() {
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().join("data");
+    let config = temp_dir.path().join("kvs.toml");
+    fs::write(
+        &config,
+        format!(
+            "addr = \"127.0.0.1:4098\"\ndata-dir = {:?}\n\n[thread-pool]\nkind = \"shared-queue\"\n",
+            data_dir
+        ),
+    )
+    .unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .arg("--config")
+        .arg(&config)
+        .env("KVS_ADDR", "127.0.0.1:4022")
+        .env("KVS_THREADS", "2")
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", "127.0.0.1:4022"])
+        .assert()
+        .success();
+    server.kill().expect("server exited before killed");
+    assert_eq!(fs::read_to_string(data_dir.join("engine")).unwrap(), "kvs");
+    assert!(!temp_dir.path().join("engine").exists());
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .arg("--config")
+        .arg(&config)
+        .args(&["--queue-size", "10", "--addr", "127.0.0.1:4023"])
+        .env("KVS_THREAD_POOL", "rayon")
+        .env("KVS_THREDS", "4")
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains(
+            "Unknown environment variable KVS_THREDS is ignored",
+        ))
+        .stderr(contains(
+            "limits.queue-size needs a shared-queue pool, not rayon",
+        ));
+
+    fs::write(&config, "[storage]\ncache_size = 10\n").unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .arg("--config")
+        .arg(&config)
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("unknown field `cache_size`"));
+}
+
+// A second server should refuse the data directory of a running one
+#[test]
+fn cli_data_dir_in_use() {
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().join("data");
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4024", "--data-dir"])
+        .arg(&data_dir)
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", "127.0.0.1:4024"])
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4025", "--data-dir"])
+        .arg(&data_dir)
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains(format!(
+            "The data directory {} is already in use",
+            data_dir.display()
+        )));
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+
+    // the lock of a killed server is released
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4025", "--data-dir"])
+        .arg(&data_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", "127.0.0.1:4025"])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    server.kill().expect("server exited before killed");
+}
+
+// `kvs-bench` should load a workload into a server and report each operation
+#[test]
+fn cli_bench() {
+    let addr = "127.0.0.1:4027";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "memory", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-bench")
+        .unwrap()
+        .args(&["--workload", "f", "--records", "100", "--threads", "2"])
+        .args(&["--operations", "200", "--addr", addr])
+        .assert()
+        .success()
+        .stderr(contains("Loading 100 records"))
+        .stdout(contains("200 operations in"))
+        .stdout(contains("read-modify-write"));
+
+    Command::cargo_bin("kvs-bench")
+        .unwrap()
+        .args(&["--mix", "read=0.5,delete=0.5", "--addr", addr])
+        .assert()
+        .failure()
+        .stderr(contains("Invalid operation mix: delete=0.5"));
+
+    server.kill().expect("server exited before killed");
+}
+
+// `kvs-client query` should print the keys of an indexed field, one per line
+#[test]
+fn cli_query_index() {
+    let addr = "127.0.0.1:4028";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "kvs", "--addr", addr, "--index", "city=/city"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    for (key, city) in &[("bob", "Paris"), ("carol", "Lyon"), ("alice", "Paris")] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["set", key, &format!(r#"{{"city": "{}"}}"#, city)])
+            .args(&["--addr", addr])
+            .assert()
+            .success();
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["query", "city", "Paris", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("alice\nbob\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["query", "zip", "75001", "--addr", addr])
+        .assert()
+        .failure()
+        .stderr(contains("Unknown index: zip"));
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--index", "city", "--addr", "127.0.0.1:4029"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("expected NAME=POINTER"));
+
+    server.kill().expect("server exited before killed");
+}
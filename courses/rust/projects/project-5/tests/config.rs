@@ -0,0 +1,151 @@
+use kvs::config::{EngineKind, PoolKind, ServerConfig};
+use kvs::thread_pool::QueuePolicy;
+use kvs::{Compression, Result};
+use log::LevelFilter;
+use std::fs;
+use std::path::PathBuf;
+use tempfile::TempDir;
+
+fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+    vars.iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect()
+}
+
+// Settings missing from a file should keep their default
+#[test]
+fn read_config_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("kvs.toml");
+    fs::write(
+        &path,
+        r#"
+addr = "127.0.0.1:5000"
+data-dir = "/var/lib/kvs"
+
+[thread-pool]
+kind = "shared-queue"
+threads = 8
+min-threads = 2
+
+[storage]
+compression = "zstd"
+compaction-threshold = 4096
+sync-writes = true
+
+[storage.indexes]
+city = "/address/city"
+
+[limits]
+queue-size = 100
+queue-policy = "caller-runs"
+
+[log]
+level = "debug"
+"#,
+    )?;
+    let config = ServerConfig::from_file(&path)?;
+    assert_eq!(config.addr, "127.0.0.1:5000".parse().unwrap());
+    assert_eq!(config.data_dir, Some(PathBuf::from("/var/lib/kvs")));
+    assert_eq!(config.engine, None);
+    assert_eq!(config.pool_kind(), PoolKind::SharedQueue);
+    assert_eq!(config.thread_pool.threads, 8);
+    assert_eq!(config.thread_pool.min_threads, Some(2));
+    assert_eq!(config.storage.cache_size, 0);
+    assert_eq!(config.storage.compression, Compression::Zstd);
+    assert_eq!(config.storage.compaction_threshold, Some(4096));
+    assert!(config.storage.sync_writes);
+    assert_eq!(config.storage.indexes["city"], "/address/city");
+    assert_eq!(config.limits.queue_size, Some(100));
+    assert_eq!(config.limits.queue_policy, QueuePolicy::CallerRuns);
+    assert_eq!(config.limits.max_connections, None);
+    assert_eq!(config.log.level, LevelFilter::Debug);
+    config.validate()?;
+
+    fs::write(&path, "[storage]\ncache_size = 10\n")?;
+    let err = ServerConfig::from_file(&path).unwrap_err().to_string();
+    assert!(err.starts_with("Invalid config file"), "{}", err);
+    assert!(err.contains("unknown field `cache_size`"), "{}", err);
+
+    fs::write(&path, "[limits]\nqueue-policy = \"drop\"\n")?;
+    let err = ServerConfig::from_file(&path).unwrap_err().to_string();
+    assert!(err.contains("Unknown queue policy: drop"), "{}", err);
+    Ok(())
+}
+
+// `KVS_*` variables should override the settings, and other variables be ignored
+#[test]
+fn environment_overrides() -> Result<()> {
+    let mut config = ServerConfig::default();
+    config.apply_env(vars(&[
+        ("KVS_ADDR", "0.0.0.0:4000"),
+        ("KVS_ENGINE", "lsm"),
+        ("KVS_THREAD_POOL", "work-stealing"),
+        ("KVS_THREADS", "3"),
+        ("KVS_MAX_CONNECTIONS", "10"),
+        ("KVS_LOG_LEVEL", "warn"),
+        ("KVS_UNKNOWN", "1"),
+        ("HOME", "/root"),
+    ]))?;
+    assert_eq!(config.addr, "0.0.0.0:4000".parse().unwrap());
+    assert_eq!(config.engine, Some(EngineKind::Lsm));
+    assert_eq!(config.pool_kind(), PoolKind::WorkStealing);
+    assert_eq!(config.thread_pool.threads, 3);
+    assert_eq!(config.limits.max_connections, Some(10));
+    assert_eq!(config.log.level, LevelFilter::Warn);
+    config.validate()?;
+
+    let err = config
+        .apply_env(vars(&[("KVS_THREADS", "many")]))
+        .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Invalid KVS_THREADS=\"many\": invalid digit found in string"
+    );
+    let err = config
+        .apply_env(vars(&[("KVS_COMPRESSION", "gzip")]))
+        .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Invalid KVS_COMPRESSION=\"gzip\": Unknown compression algorithm: gzip"
+    );
+    Ok(())
+}
+
+// Settings which don't make sense together should be rejected
+#[test]
+fn invalid_combinations() {
+    let check = |env: &[(&str, &str)]| -> String {
+        let mut config = ServerConfig::default();
+        config.apply_env(vars(env)).unwrap();
+        config.validate().unwrap_err().to_string()
+    };
+    assert_eq!(
+        check(&[("KVS_THREADS", "0")]),
+        "thread-pool.threads must be positive"
+    );
+    assert_eq!(
+        check(&[("KVS_THREADS", "2"), ("KVS_MIN_THREADS", "4")]),
+        "thread-pool.min-threads (4) is above thread-pool.threads (2)"
+    );
+    assert_eq!(
+        check(&[("KVS_THREADS", "2"), ("KVS_MIN_THREADS", "1")]),
+        "thread-pool.min-threads needs a shared-queue pool, not rayon"
+    );
+    assert_eq!(
+        check(&[("KVS_THREAD_POOL", "rayon"), ("KVS_QUEUE_SIZE", "10")]),
+        "limits.queue-size needs a shared-queue pool, not rayon"
+    );
+    assert_eq!(
+        check(&[("KVS_QUEUE_SIZE", "0")]),
+        "limits.queue-size must be positive"
+    );
+    assert_eq!(
+        check(&[("KVS_ENGINE", "sled"), ("KVS_SYNC_WRITES", "true")]),
+        "storage.sync-writes only applies to the kvs and lsm engines, not sled"
+    );
+    assert_eq!(
+        check(&[("KVS_ENGINE", "memory"), ("KVS_CACHE_SIZE", "1024")]),
+        "storage.cache-size only applies to the kvs engine, not memory"
+    );
+}
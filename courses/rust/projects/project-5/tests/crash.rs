@@ -0,0 +1,493 @@
+use kvs::admin::LogDir;
+use kvs::thread_pool::SharedQueueThreadPool;
+use kvs::{
+    FileStorage, KvStore, KvStoreOptions, KvsEngine, KvsError, Result, SegmentReader,
+    SegmentWriter, Storage,
+};
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tempfile::TempDir;
+use tokio::prelude::*;
+
+const KEYS: usize = 20;
+const OPERATIONS: usize = 300;
+const CRASHES: usize = 5;
+
+/// Failures injected beneath a store, and the bytes of each log file and the
+/// changes of the directory which would survive a power loss.
+struct Faults {
+    dir: PathBuf,
+    state: Mutex<FaultState>,
+}
+
+struct FaultState {
+    rng: SmallRng,
+    // probability of failure of every write, flush, sync, truncation, creation,
+    // rename, deletion and directory sync
+    rate: f64,
+    // bytes written and synced of the files created since the last crash,
+    // including the deleted ones whose deletion may still be undone
+    lengths: HashMap<u64, (u64, u64)>,
+    // changes of the directory since its last sync, oldest first
+    changes: Vec<Change>,
+    // number of successful renames, each completing a compaction log
+    renames: u64,
+}
+
+// A deleted file is kept as `<gen>.log.deleted` until the directory is synced,
+// and a renamed one goes back to `<gen>.log.tmp` if the rename is undone.
+enum Change {
+    Created(u64),
+    Renamed(u64),
+    Deleted(u64),
+}
+
+impl Faults {
+    fn new(dir: &Path, seed: u64, rate: f64) -> Arc<Faults> {
+        Arc::new(Faults {
+            dir: dir.to_owned(),
+            state: Mutex::new(FaultState {
+                rng: SmallRng::seed_from_u64(seed),
+                rate,
+                lengths: HashMap::new(),
+                changes: Vec::new(),
+                renames: 0,
+            }),
+        })
+    }
+
+    // `io::Error::other` needs Rust 1.74
+    #[allow(clippy::io_other_error)]
+    fn inject(&self, what: &str) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let rate = state.rate;
+        if state.rng.gen_bool(rate) {
+            Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("injected {} failure", what),
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn renames(&self) -> u64 {
+        self.state.lock().unwrap().renames
+    }
+
+    fn path(&self, gen: u64, suffix: &str) -> PathBuf {
+        self.dir.join(format!("{}.log{}", gen, suffix))
+    }
+
+    // Undoes any of the changes of the directory since its last sync, then cuts
+    // every file created since the last crash to what has been synced, plus a
+    // random part of the rest, as the disk may have written it on its own.
+    fn power_loss(&self) {
+        let mut state = self.state.lock().unwrap();
+        let changes: Vec<_> = state.changes.drain(..).rev().collect();
+        for change in changes {
+            let undone = state.rng.gen_bool(0.5);
+            match change {
+                Change::Created(gen) if undone => remove_if_exists(&self.path(gen, "")),
+                Change::Renamed(gen) if undone && self.path(gen, "").exists() => {
+                    fs::rename(self.path(gen, ""), self.path(gen, ".tmp")).unwrap()
+                }
+                Change::Deleted(gen) if undone => {
+                    fs::rename(self.path(gen, ".deleted"), self.path(gen, "")).unwrap()
+                }
+                Change::Deleted(gen) => remove_if_exists(&self.path(gen, ".deleted")),
+                _ => (),
+            }
+        }
+
+        let lengths: Vec<_> = state.lengths.drain().collect();
+        for (gen, (written, synced)) in lengths {
+            let len = state.rng.gen_range(synced, written + 1);
+            let path = self.path(gen, "");
+            if path.exists() {
+                OpenOptions::new()
+                    .write(true)
+                    .open(path)
+                    .and_then(|file| file.set_len(len))
+                    .unwrap();
+            }
+        }
+    }
+}
+
+fn remove_if_exists(path: &Path) {
+    if path.exists() {
+        fs::remove_file(path).unwrap();
+    }
+}
+
+struct FaultyStorage {
+    inner: FileStorage,
+    faults: Arc<Faults>,
+}
+
+impl Storage for FaultyStorage {
+    fn list(&self) -> Result<Vec<u64>> {
+        self.inner.list()
+    }
+
+    fn open(&self, gen: u64) -> Result<Box<dyn SegmentReader>> {
+        self.inner.open(gen)
+    }
+
+    fn create(&self, gen: u64) -> Result<Box<dyn SegmentWriter>> {
+        self.faults.inject("create")?;
+        let inner = self.inner.create(gen)?;
+        let mut state = self.faults.state.lock().unwrap();
+        state.changes.push(Change::Created(gen));
+        Ok(self.writer(inner, gen, &mut state))
+    }
+
+    fn create_temp(&self, gen: u64) -> Result<Box<dyn SegmentWriter>> {
+        self.faults.inject("create")?;
+        let inner = self.inner.create_temp(gen)?;
+        let mut state = self.faults.state.lock().unwrap();
+        Ok(self.writer(inner, gen, &mut state))
+    }
+
+    fn rename_temp(&self, gen: u64) -> Result<()> {
+        self.faults.inject("rename")?;
+        self.inner.rename_temp(gen)?;
+        let mut state = self.faults.state.lock().unwrap();
+        state.changes.push(Change::Renamed(gen));
+        state.renames += 1;
+        Ok(())
+    }
+
+    fn delete(&self, gen: u64) -> Result<()> {
+        self.faults.inject("delete")?;
+        // kept aside, as a power loss may bring it back
+        fs::rename(self.faults.path(gen, ""), self.faults.path(gen, ".deleted"))?;
+        let mut state = self.faults.state.lock().unwrap();
+        state.changes.push(Change::Deleted(gen));
+        Ok(())
+    }
+
+    fn sync_dir(&self) -> Result<()> {
+        self.faults.inject("directory sync")?;
+        self.inner.sync_dir()?;
+        let mut state = self.faults.state.lock().unwrap();
+        for change in std::mem::take(&mut state.changes) {
+            if let Change::Deleted(gen) = change {
+                fs::remove_file(self.faults.path(gen, ".deleted"))?;
+                state.lengths.remove(&gen);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FaultyStorage {
+    fn writer(
+        &self,
+        inner: Box<dyn SegmentWriter>,
+        gen: u64,
+        state: &mut FaultState,
+    ) -> Box<dyn SegmentWriter> {
+        state.lengths.insert(gen, (0, 0));
+        Box::new(FaultyWriter {
+            inner,
+            gen,
+            faults: Arc::clone(&self.faults),
+        })
+    }
+}
+
+struct FaultyWriter {
+    inner: Box<dyn SegmentWriter>,
+    gen: u64,
+    faults: Arc<Faults>,
+}
+
+impl FaultyWriter {
+    fn update(&self, f: impl FnOnce(&mut (u64, u64))) {
+        let mut state = self.faults.state.lock().unwrap();
+        if let Some(lengths) = state.lengths.get_mut(&self.gen) {
+            f(lengths);
+        }
+    }
+}
+
+impl Write for FaultyWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Err(e) = self.faults.inject("write") {
+            // part of the buffer may be written before the failure
+            let len = self
+                .faults
+                .state
+                .lock()
+                .unwrap()
+                .rng
+                .gen_range(0, buf.len());
+            self.inner.write_all(&buf[..len])?;
+            self.update(|(written, _)| *written += len as u64);
+            return Err(e);
+        }
+        let len = self.inner.write(buf)?;
+        self.update(|(written, _)| *written += len as u64);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.faults.inject("flush")?;
+        self.inner.flush()
+    }
+}
+
+impl SegmentWriter for FaultyWriter {
+    fn sync(&mut self) -> io::Result<()> {
+        self.faults.inject("sync")?;
+        self.inner.sync()?;
+        self.update(|(written, synced)| *synced = *written);
+        Ok(())
+    }
+
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        self.faults.inject("truncate")?;
+        self.inner.truncate(len)?;
+        self.update(|(written, synced)| {
+            *written = len;
+            *synced = (*synced).min(len);
+        });
+        Ok(())
+    }
+}
+
+/// The values each key had since its last durable one, which is the earliest
+/// value a power loss may bring back.
+struct History {
+    values: Vec<Option<String>>,
+    // index of the value of the last acknowledged operation, followed by the
+    // values of the operations which failed since then
+    acknowledged: usize,
+    // index of the last value known to be on the disk
+    durable: usize,
+}
+
+impl History {
+    fn new(value: Option<String>) -> History {
+        History {
+            values: vec![value],
+            acknowledged: 0,
+            durable: 0,
+        }
+    }
+}
+
+/// The values each key may have after a crash of the process or a power loss.
+///
+/// Without synced writes, a power loss may bring a key back to any value since
+/// the last successful compaction, as only the compaction makes its log durable.
+struct Model {
+    sync_writes: bool,
+    keys: BTreeMap<String, History>,
+}
+
+impl Model {
+    fn new(sync_writes: bool) -> Model {
+        Model {
+            sync_writes,
+            keys: BTreeMap::new(),
+        }
+    }
+
+    fn acknowledged(&mut self, key: &str, value: Option<String>) {
+        let history = self
+            .keys
+            .entry(key.to_owned())
+            .or_insert_with(|| History::new(None));
+        history.values.push(value);
+        history.acknowledged = history.values.len() - 1;
+        if self.sync_writes {
+            history.durable = history.acknowledged;
+        }
+    }
+
+    fn failed(&mut self, key: &str, value: Option<String>) {
+        self.keys
+            .entry(key.to_owned())
+            .or_insert_with(|| History::new(None))
+            .values
+            .push(value);
+    }
+
+    // A compaction has succeeded, and its log must survive a power loss. It holds
+    // the acknowledged values, and maybe the failed ones after.
+    fn compacted(&mut self) {
+        for history in self.keys.values_mut() {
+            history.durable = history.acknowledged;
+        }
+    }
+
+    fn removed(&self, key: &str) -> bool {
+        match self.keys.get(key) {
+            Some(history) => history.values[history.acknowledged..].contains(&None),
+            None => true,
+        }
+    }
+
+    // Checks the running store.
+    fn check(&self, store: &KvStore<SharedQueueThreadPool>, context: &str) -> Result<()> {
+        self.check_values(store, context, |history| history.acknowledged)
+            .map(|_| ())
+    }
+
+    // Checks the store reopened after a power loss. The values it has become the
+    // durable ones.
+    fn check_power_loss(
+        &mut self,
+        store: &KvStore<SharedQueueThreadPool>,
+        context: &str,
+    ) -> Result<()> {
+        let values = self.check_values(store, context, |history| history.durable)?;
+        for (key, value) in values {
+            self.keys.insert(key, History::new(value));
+        }
+        Ok(())
+    }
+
+    // Returns the value of each key, checked to be one of its values from `from`.
+    fn check_values(
+        &self,
+        store: &KvStore<SharedQueueThreadPool>,
+        context: &str,
+        from: impl Fn(&History) -> usize,
+    ) -> Result<Vec<(String, Option<String>)>> {
+        let mut values = Vec::new();
+        for (key, history) in &self.keys {
+            let value = store.get(key.clone()).wait()?;
+            let expected = &history.values[from(history)..];
+            assert!(
+                expected.contains(&value),
+                "{}: {} is {:?}, expected one of {:?}",
+                context,
+                key,
+                value,
+                expected
+            );
+            values.push((key.clone(), value));
+        }
+        for pair in store.scan(String::new()).wait() {
+            let (key, _) = pair?;
+            assert!(
+                self.keys.contains_key(&key),
+                "{}: {} appeared",
+                context,
+                key
+            );
+        }
+        Ok(values)
+    }
+}
+
+fn options(sync_writes: bool) -> KvStoreOptions {
+    let mut options = KvStoreOptions::new();
+    options.compaction_threshold(2000).sync_writes(sync_writes);
+    options
+}
+
+// Opens the store after a crash, repairing its log if it was cut in the middle
+// of a record.
+fn reopen(path: &Path, sync_writes: bool) -> Result<KvStore<SharedQueueThreadPool>> {
+    match options(sync_writes).open(path, 1) {
+        Err(KvsError::Serde(_)) => {
+            LogDir::open(path)?.repair()?;
+            options(sync_writes).open(path, 1)
+        }
+        res => res,
+    }
+}
+
+// Runs random operations against a store whose storage fails now and then, and
+// checks after each crash that every acknowledged write survives and no removed
+// key comes back. Every crash is also a power loss. Without synced writes, it
+// may lose the writes since the last compaction, but nothing before.
+fn crash_store(seed: u64, sync_writes: bool) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut rng = SmallRng::seed_from_u64(seed);
+    let mut model = Model::new(sync_writes);
+    for crash in 0..CRASHES {
+        let context = format!("seed {}, crash {}", seed, crash);
+        let faults = Faults::new(temp_dir.path(), rng.gen(), 0.03);
+        let storage = FaultyStorage {
+            inner: FileStorage::open(temp_dir.path())?,
+            faults: Arc::clone(&faults),
+        };
+        let store = match options(sync_writes).open_storage(storage, 1) {
+            Ok(store) => store,
+            // the store isn't usable if the first log file can't be created or
+            // made durable
+            Err(KvsError::Io(_)) => continue,
+            Err(e) => return Err(e),
+        };
+
+        for _ in 0..OPERATIONS {
+            let renames = faults.renames();
+            let key = format!("key{}", rng.gen_range(0, KEYS));
+            let res = if rng.gen_bool(0.2) {
+                let res = store.remove(key.clone()).wait();
+                match res {
+                    Ok(()) => model.acknowledged(&key, None),
+                    Err(KvsError::KeyNotFound) => {
+                        assert!(model.removed(&key), "{}: {} not found", context, key)
+                    }
+                    Err(_) => model.failed(&key, None),
+                }
+                res
+            } else {
+                let len = rng.gen_range(0, 200);
+                let value = format!("{}:{}", key, "x".repeat(len));
+                let res = store.set(key.clone(), value.clone()).wait();
+                match res {
+                    Ok(()) => model.acknowledged(&key, Some(value)),
+                    Err(_) => model.failed(&key, Some(value)),
+                }
+                res
+            };
+            match res {
+                // the write compacted the log if it renamed a compaction log, and
+                // the compaction failed if the write did
+                Ok(()) if faults.renames() != renames => model.compacted(),
+                Ok(()) | Err(KvsError::KeyNotFound) => (),
+                // crash now and then right after a failure, when the store has the
+                // most to repair
+                Err(_) if rng.gen_bool(0.1) => break,
+                Err(_) => (),
+            }
+        }
+        // failures must not break the reads of the running store
+        model.check(&store, &context)?;
+
+        drop(store);
+        faults.power_loss();
+        let store = reopen(temp_dir.path(), sync_writes)?;
+        model.check_power_loss(&store, &context)?;
+    }
+    Ok(())
+}
+
+#[test]
+fn crash_with_synced_writes() -> Result<()> {
+    for seed in 0..10 {
+        crash_store(seed, true)?;
+    }
+    Ok(())
+}
+
+#[test]
+fn crash_without_synced_writes() -> Result<()> {
+    for seed in 0..10 {
+        crash_store(seed, false)?;
+    }
+    Ok(())
+}
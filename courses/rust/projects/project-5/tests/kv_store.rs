@@ -1,15 +1,98 @@
-use kvs::thread_pool::RayonThreadPool;
-use kvs::{KvStore, KvsEngine, KvsError, Result};
+use crossbeam::channel;
+use kvs::thread_pool::{PoolSize, QueuePolicy, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    CacheStats, Compression, EncryptionKey, KvStore, KvStoreOptions, KvsEngine, KvsError,
+    LsmKvsEngine, LsmKvsEngineOptions, MemStorage, Result, Storage,
+};
+use std::path::Path;
 use tempfile::TempDir;
 use tokio::prelude::*;
 use tokio::runtime::Runtime;
 use walkdir::WalkDir;
 
+/// An engine stored in a directory, which all tests below run against.
+trait OpenEngine: KvsEngine + Sized {
+    fn open(path: &Path, concurrency: u32) -> Result<Self>;
+}
+
+impl OpenEngine for KvStore<RayonThreadPool> {
+    fn open(path: &Path, concurrency: u32) -> Result<Self> {
+        KvStore::open(path, concurrency)
+    }
+}
+
+impl OpenEngine for LsmKvsEngine<RayonThreadPool> {
+    fn open(path: &Path, concurrency: u32) -> Result<Self> {
+        LsmKvsEngine::open(path, concurrency)
+    }
+}
+
+// Instantiates every test for an engine in a module named after it
+macro_rules! engine_tests {
+    ($module:ident, $engine:ty) => {
+        mod $module {
+            use super::*;
+
+            #[test]
+            fn get_stored_value() -> Result<()> {
+                super::get_stored_value::<$engine>()
+            }
+
+            #[test]
+            fn overwrite_value() -> Result<()> {
+                super::overwrite_value::<$engine>()
+            }
+
+            #[test]
+            fn get_non_existent_value() -> Result<()> {
+                super::get_non_existent_value::<$engine>()
+            }
+
+            #[test]
+            fn remove_non_existent_key() -> Result<()> {
+                super::remove_non_existent_key::<$engine>()
+            }
+
+            #[test]
+            fn remove_key() -> Result<()> {
+                super::remove_key::<$engine>()
+            }
+
+            #[test]
+            fn scan_prefix() -> Result<()> {
+                super::scan_prefix::<$engine>()
+            }
+
+            #[test]
+            fn compaction() -> Result<()> {
+                super::compaction::<$engine>()
+            }
+
+            #[test]
+            fn concurrent_set() -> Result<()> {
+                super::concurrent_set::<$engine>()
+            }
+
+            #[test]
+            fn concurrent_get() -> Result<()> {
+                super::concurrent_get::<$engine>()
+            }
+
+            #[test]
+            fn directory_lock() -> Result<()> {
+                super::directory_lock::<$engine>()
+            }
+        }
+    };
+}
+
+engine_tests!(kvs_engine, KvStore<RayonThreadPool>);
+engine_tests!(lsm_engine, LsmKvsEngine<RayonThreadPool>);
+
 // Should get previously stored value
-#[test]
-fn get_stored_value() -> Result<()> {
+fn get_stored_value<E: OpenEngine>() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    let store = E::open(temp_dir.path(), 1)?;
 
     store.set("key1".to_owned(), "value1".to_owned()).wait()?;
     store.set("key2".to_owned(), "value2".to_owned()).wait()?;
@@ -25,7 +108,7 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    let store = E::open(temp_dir.path(), 1)?;
     assert_eq!(
         store.get("key1".to_owned()).wait()?,
         Some("value1".to_owned())
@@ -39,10 +122,9 @@ fn get_stored_value() -> Result<()> {
 }
 
 // Should overwrite existent value
-#[test]
-fn overwrite_value() -> Result<()> {
+fn overwrite_value<E: OpenEngine>() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    let store = E::open(temp_dir.path(), 1)?;
 
     store.set("key1".to_owned(), "value1".to_owned()).wait()?;
     assert_eq!(
@@ -57,7 +139,7 @@ fn overwrite_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    let store = E::open(temp_dir.path(), 1)?;
     assert_eq!(
         store.get("key1".to_owned()).wait()?,
         Some("value2".to_owned())
@@ -72,46 +154,68 @@ fn overwrite_value() -> Result<()> {
 }
 
 // Should get `None` when getting a non-existent key
-#[test]
-fn get_non_existent_value() -> Result<()> {
+fn get_non_existent_value<E: OpenEngine>() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    let store = E::open(temp_dir.path(), 1)?;
 
     store.set("key1".to_owned(), "value1".to_owned()).wait()?;
     assert_eq!(store.get("key2".to_owned()).wait()?, None);
 
     // Open from disk again and check persistent data
     drop(store);
-    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    let store = E::open(temp_dir.path(), 1)?;
     assert_eq!(store.get("key2".to_owned()).wait()?, None);
 
     Ok(())
 }
 
-#[test]
-fn remove_non_existent_key() -> Result<()> {
+fn remove_non_existent_key<E: OpenEngine>() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    let store = E::open(temp_dir.path(), 1)?;
     assert!(store.remove("key1".to_owned()).wait().is_err());
     Ok(())
 }
 
-#[test]
-fn remove_key() -> Result<()> {
+fn remove_key<E: OpenEngine>() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    let store = E::open(temp_dir.path(), 1)?;
     store.set("key1".to_owned(), "value1".to_owned()).wait()?;
     assert!(store.remove("key1".to_owned()).wait().is_ok());
     assert_eq!(store.get("key1".to_owned()).wait()?, None);
     Ok(())
 }
 
+// Should list the pairs with the given prefix in key order
+fn scan_prefix<E: OpenEngine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = E::open(temp_dir.path(), 1)?;
+
+    store.set("b2".to_owned(), "value1".to_owned()).wait()?;
+    store.set("a1".to_owned(), "value2".to_owned()).wait()?;
+    store.set("b1".to_owned(), "value3".to_owned()).wait()?;
+    store.set("c1".to_owned(), "value4".to_owned()).wait()?;
+    store.remove("b2".to_owned()).wait()?;
+    store.set("b3".to_owned(), "value5".to_owned()).wait()?;
+
+    let pairs: Vec<(String, String)> = store.scan("b".to_owned()).collect().wait()?;
+    assert_eq!(
+        pairs,
+        vec![
+            ("b1".to_owned(), "value3".to_owned()),
+            ("b3".to_owned(), "value5".to_owned()),
+        ]
+    );
+    assert_eq!(store.scan(String::new()).collect().wait()?.len(), 4);
+    assert!(store.scan("d".to_owned()).collect().wait()?.is_empty());
+
+    Ok(())
+}
+
 // Insert data until total size of the directory decreases.
 // Test data correctness after compaction.
-#[test]
-fn compaction() -> Result<()> {
+fn compaction<E: OpenEngine>() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    let store = E::open(temp_dir.path(), 1)?;
 
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.path()).into_iter();
@@ -141,7 +245,7 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content
-        let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+        let store = E::open(temp_dir.path(), 1)?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             assert_eq!(store.get(key).wait()?, Some(format!("{}", iter)));
@@ -152,12 +256,11 @@ fn compaction() -> Result<()> {
     panic!("No compaction detected");
 }
 
-#[test]
-fn concurrent_set() -> Result<()> {
+fn concurrent_set<E: OpenEngine>() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
 
     // concurrent set in 8 threads
-    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 8)?;
+    let store = E::open(temp_dir.path(), 8)?;
     let runtime = Runtime::new()?;
     let executor = runtime.executor();
     runtime.block_on_all(future::lazy(move || {
@@ -172,7 +275,7 @@ fn concurrent_set() -> Result<()> {
     }))?;
 
     // We only check concurrent set in this test, so we check sequentially here
-    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    let store = E::open(temp_dir.path(), 1)?;
     for i in 0..10000 {
         assert_eq!(
             store.get(format!("key{}", i)).wait()?,
@@ -183,10 +286,9 @@ fn concurrent_set() -> Result<()> {
     Ok(())
 }
 
-#[test]
-fn concurrent_get() -> Result<()> {
+fn concurrent_get<E: OpenEngine>() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 8)?;
+    let store = E::open(temp_dir.path(), 8)?;
     // We only check concurrent get in this test, so we set sequentially here
     for i in 0..100 {
         store
@@ -215,7 +317,7 @@ fn concurrent_get() -> Result<()> {
     }))?;
 
     // reload from disk and test again
-    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 8)?;
+    let store = E::open(temp_dir.path(), 8)?;
     let runtime = Runtime::new()?;
     let executor = runtime.executor();
     runtime.block_on_all(future::lazy(move || {
@@ -237,3 +339,476 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// Should refuse to open a directory which another store has open
+fn directory_lock<E: OpenEngine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = E::open(temp_dir.path(), 1)?;
+    let clone = store.clone();
+    match E::open(temp_dir.path(), 1) {
+        Err(KvsError::DirectoryLocked(dir)) => {
+            assert_eq!(dir, temp_dir.path().display().to_string())
+        }
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("the directory is opened twice"),
+    }
+
+    // the lock is released with the last clone
+    drop(store);
+    assert!(E::open(temp_dir.path(), 1).is_err());
+    drop(clone);
+    E::open(temp_dir.path(), 1)?;
+    Ok(())
+}
+
+// Write enough data for several memtable flushes and a compaction into level 1.
+// Test that removed keys stay removed and the latest values win.
+#[test]
+fn lsm_leveled_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmKvsEngine::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+
+    let value = "x".repeat(1000);
+    for iter in 0..6 {
+        for key_id in 0..1000 {
+            store
+                .set(format!("key{}", key_id), format!("{}{}", iter, value))
+                .wait()?;
+        }
+    }
+    for key_id in (0..1000).step_by(2) {
+        store.remove(format!("key{}", key_id)).wait()?;
+    }
+
+    let table_count = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("sst".as_ref()))
+            .count()
+    };
+    // 6 MB of writes are flushed at least 5 times, which triggers a compaction
+    assert!(table_count() > 0);
+    assert!(table_count() < 5);
+
+    drop(store);
+    let store = LsmKvsEngine::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    for key_id in 0..1000 {
+        let expected = if key_id % 2 == 0 {
+            None
+        } else {
+            Some(format!("5{}", value))
+        };
+        assert_eq!(store.get(format!("key{}", key_id)).wait()?, expected);
+    }
+    assert_eq!(store.scan(String::new()).collect().wait()?.len(), 500);
+
+    Ok(())
+}
+
+// Should keep the writes acknowledged with synced writes, across flushes
+#[test]
+fn lsm_synced_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut options = LsmKvsEngineOptions::new();
+    options.sync_writes(true);
+    let store: LsmKvsEngine<RayonThreadPool> = options.open(temp_dir.path(), 1)?;
+
+    // 1.2 MB of writes, which flush the memtable once
+    let value = "x".repeat(1000);
+    for key_id in 0..1200 {
+        store
+            .set(format!("key{}", key_id), format!("{}{}", key_id, value))
+            .wait()?;
+    }
+    store.remove("key0".to_owned()).wait()?;
+
+    drop(store);
+    let store: LsmKvsEngine<RayonThreadPool> = options.open(temp_dir.path(), 1)?;
+    assert_eq!(store.get("key0".to_owned()).wait()?, None);
+    for key_id in 1..1200 {
+        assert_eq!(
+            store.get(format!("key{}", key_id)).wait()?,
+            Some(format!("{}{}", key_id, value))
+        );
+    }
+    Ok(())
+}
+
+// Should answer repeated reads from the value cache and never return stale values
+#[test]
+fn kvs_value_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store: KvStore<RayonThreadPool> = KvStoreOptions::new()
+        .cache_capacity(1024 * 1024)
+        .open(temp_dir.path(), 1)?;
+    let counts = |stats: CacheStats| (stats.hits, stats.misses);
+
+    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
+    for _ in 0..3 {
+        assert_eq!(
+            store.get("key1".to_owned()).wait()?,
+            Some("value1".to_owned())
+        );
+    }
+    assert_eq!(counts(store.cache_stats()), (2, 1));
+    assert!(store.cache_stats().size > 0);
+
+    store.set("key1".to_owned(), "value2".to_owned()).wait()?;
+    assert_eq!(
+        store.get("key1".to_owned()).wait()?,
+        Some("value2".to_owned())
+    );
+    assert_eq!(counts(store.cache_stats()), (2, 2));
+
+    // cached values follow their commands to the compacted log
+    store.compact()?;
+    assert_eq!(
+        store.get("key1".to_owned()).wait()?,
+        Some("value2".to_owned())
+    );
+    assert_eq!(counts(store.cache_stats()), (3, 2));
+
+    store.remove("key1".to_owned()).wait()?;
+    assert_eq!(store.get("key1".to_owned()).wait()?, None);
+    assert_eq!(store.cache_stats().size, 0);
+
+    Ok(())
+}
+
+// Should reject operations while the queue of the pool is full, or run them in the
+// calling thread, depending on the queue policy
+#[test]
+fn kvs_bounded_queue() -> Result<()> {
+    for &policy in &[QueuePolicy::Reject, QueuePolicy::CallerRuns] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let pool = SharedQueueThreadPool::bounded(PoolSize::new(1, 1), 1, policy)?;
+        let store = KvStoreOptions::new().open_with_pool(temp_dir.path(), pool.clone())?;
+        store.set("key1".to_owned(), "value1".to_owned()).wait()?;
+
+        // one task keeps the thread busy, another one fills the queue
+        let (tx, rx) = channel::unbounded::<()>();
+        let (started_tx, started_rx) = channel::unbounded();
+        let block = move || {
+            started_tx.send(()).unwrap();
+            let _ = rx.recv();
+        };
+        pool.spawn(block.clone());
+        started_rx.recv().unwrap();
+        pool.spawn(block);
+        let set = store.set("key2".to_owned(), "value2".to_owned()).wait();
+        let scan = store.scan("key".to_owned()).collect().wait();
+        match policy {
+            QueuePolicy::Reject => {
+                assert!(matches!(set, Err(KvsError::ServerBusy)));
+                assert!(matches!(scan, Err(KvsError::ServerBusy)));
+            }
+            _ => {
+                set?;
+                assert_eq!(scan?.len(), 2);
+                assert_eq!(
+                    store.get("key2".to_owned()).wait()?,
+                    Some("value2".to_owned())
+                );
+            }
+        }
+
+        drop(tx);
+        pool.join();
+    }
+    Ok(())
+}
+
+// Should read the same data through memory maps, across compactions and reopens
+#[test]
+fn kvs_mmap_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || -> Result<KvStore<RayonThreadPool>> {
+        KvStoreOptions::new().mmap(true).open(temp_dir.path(), 4)
+    };
+    let check = |store: &KvStore<RayonThreadPool>| -> Result<()> {
+        for i in 0..1000 {
+            let expected = if i % 10 == 0 {
+                format!("new{}", i)
+            } else {
+                format!("value{}", i)
+            };
+            assert_eq!(store.get(format!("key{}", i)).wait()?, Some(expected));
+        }
+        Ok(())
+    };
+
+    let store = open()?;
+    for i in 0..1000 {
+        store
+            .set(format!("key{}", i), format!("value{}", i))
+            .wait()?;
+    }
+    store.compact()?;
+    // written to the active log file after the compaction
+    for i in (0..1000).step_by(10) {
+        store.set(format!("key{}", i), format!("new{}", i)).wait()?;
+    }
+    check(&store)?;
+
+    drop(store);
+    let store = open()?;
+    check(&store)?;
+    store.compact()?;
+    check(&store)?;
+
+    Ok(())
+}
+
+// Should compact once the stale records pass the threshold, with synced writes
+#[test]
+fn kvs_compaction_threshold() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || -> Result<KvStore<RayonThreadPool>> {
+        KvStoreOptions::new()
+            .compaction_threshold(1000)
+            .sync_writes(true)
+            .open(temp_dir.path(), 4)
+    };
+    let store = open()?;
+    for i in 0..100 {
+        store.set("key".to_owned(), format!("value{}", i)).wait()?;
+    }
+    // compactions write newer generations than the first log file
+    let compacted = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .any(|entry| {
+            entry.file_name() != "1.log" && entry.path().extension() == Some("log".as_ref())
+        });
+    assert!(compacted, "No compaction detected");
+
+    drop(store);
+    let store = open()?;
+    assert_eq!(
+        store.get("key".to_owned()).wait()?,
+        Some("value99".to_owned())
+    );
+    Ok(())
+}
+
+// Should shrink compressible values and read logs written with any setting
+#[test]
+fn kvs_compressed_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = |compression| -> Result<KvStore<RayonThreadPool>> {
+        KvStoreOptions::new()
+            .compression(compression)
+            .open(temp_dir.path(), 4)
+    };
+    let value = |i| format!("{{\"id\":{},\"tags\":[{}]}}", i, "\"tag\",".repeat(100));
+    let check = |store: &KvStore<RayonThreadPool>| -> Result<()> {
+        for i in 0..300 {
+            assert_eq!(store.get(format!("key{}", i)).wait()?, Some(value(i)));
+        }
+        let pairs: Vec<(String, String)> = store.scan("key1".to_owned()).collect().wait()?;
+        assert_eq!(pairs.len(), 111);
+        Ok(())
+    };
+    let log_size = || -> u64 {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .map(|entry| entry.unwrap().metadata().unwrap().len())
+            .sum()
+    };
+
+    let store = open(Compression::Lz4)?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), value(i)).wait()?;
+    }
+    drop(store);
+    let compressed_size = log_size();
+    assert!(compressed_size < 100 * value(0).len() as u64 / 4);
+
+    let store = open(Compression::None)?;
+    for i in 100..200 {
+        store.set(format!("key{}", i), value(i)).wait()?;
+    }
+    drop(store);
+    assert!(log_size() > compressed_size * 4);
+
+    // the log now mixes compressed and uncompressed records
+    let store = open(Compression::Zstd)?;
+    for i in 200..300 {
+        store.set(format!("key{}", i), value(i)).wait()?;
+    }
+    check(&store)?;
+    store.compact()?;
+    check(&store)?;
+    assert!(log_size() < 300 * value(0).len() as u64 / 4);
+
+    drop(store);
+    let store = open(Compression::None)?;
+    check(&store)?;
+    Ok(())
+}
+
+// Should keep values encrypted on disk and re-encrypt them with a new key
+#[test]
+fn kvs_encrypted_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let key1 = EncryptionKey::new(&[1; 32])?;
+    let key2 = EncryptionKey::new(&[2; 32])?;
+    let open = |key: Option<&EncryptionKey>, old_key: Option<&EncryptionKey>| {
+        let mut options = KvStoreOptions::new();
+        options.compression(Compression::Lz4);
+        if let Some(key) = key {
+            options.encryption_key(key.clone());
+        }
+        if let Some(key) = old_key {
+            options.old_encryption_key(key.clone());
+        }
+        options.open::<RayonThreadPool>(temp_dir.path(), 4)
+    };
+    let check = |store: &KvStore<RayonThreadPool>, n| -> Result<()> {
+        for i in 0..n {
+            assert_eq!(
+                store.get(format!("key{}", i)).wait()?,
+                Some(format!("secret{}", i))
+            );
+        }
+        Ok(())
+    };
+    let assert_no_plaintext = || {
+        for entry in WalkDir::new(temp_dir.path()).min_depth(1) {
+            let content = std::fs::read(entry.unwrap().path()).unwrap();
+            assert!(!String::from_utf8_lossy(&content).contains("secret"));
+        }
+    };
+    let assert_wrong_key = |res: Result<KvStore<RayonThreadPool>>| match res {
+        Err(KvsError::WrongEncryptionKey(_)) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("opened with a wrong key"),
+    };
+
+    let store = open(Some(&key1), None)?;
+    for i in 0..100 {
+        store
+            .set(format!("key{}", i), format!("secret{}", i))
+            .wait()?;
+    }
+    check(&store, 100)?;
+    drop(store);
+    assert_no_plaintext();
+
+    assert_wrong_key(open(None, None));
+    assert_wrong_key(open(Some(&key2), None));
+
+    // rotate to key2
+    let store = open(Some(&key2), Some(&key1))?;
+    check(&store, 100)?;
+    for i in 100..200 {
+        store
+            .set(format!("key{}", i), format!("secret{}", i))
+            .wait()?;
+    }
+    store.compact()?;
+    check(&store, 200)?;
+    drop(store);
+    assert_no_plaintext();
+
+    let store = open(Some(&key2), None)?;
+    check(&store, 200)?;
+    drop(store);
+    assert_wrong_key(open(Some(&key1), None));
+    Ok(())
+}
+
+// Should run on segments kept in memory, including compactions and reopening
+#[test]
+fn kvs_mem_storage() -> Result<()> {
+    let storage = MemStorage::new();
+    let open = || -> Result<KvStore<RayonThreadPool>> {
+        KvStoreOptions::new()
+            .compaction_threshold(1000)
+            .mmap(true)
+            .open_storage(storage.clone(), 4)
+    };
+    let store = open()?;
+    for i in 0..100 {
+        store
+            .set(format!("key{}", i % 10), format!("value{}", i))
+            .wait()?;
+    }
+    store.remove("key0".to_owned()).wait()?;
+    // compactions delete the older segments
+    assert!(storage.list()?[0] > 1);
+
+    drop(store);
+    let store = open()?;
+    assert_eq!(store.get("key0".to_owned()).wait()?, None);
+    for i in 1..10 {
+        assert_eq!(
+            store.get(format!("key{}", i)).wait()?,
+            Some(format!("value{}", 90 + i))
+        );
+    }
+    store.compact()?;
+    assert_eq!(store.scan("key".to_owned()).collect().wait()?.len(), 9);
+    Ok(())
+}
+
+// Should find the keys of an indexed field, follow updates and removals, and
+// rebuild the indexes on open
+#[test]
+fn kvs_secondary_indexes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || -> Result<KvStore<RayonThreadPool>> {
+        KvStoreOptions::new()
+            .index("city", "/address/city")
+            .index("age", "/age")
+            .open(temp_dir.path(), 4)
+    };
+    let query = |store: &KvStore<RayonThreadPool>, name: &str, value: &str| {
+        store.query_index(name.to_owned(), value.to_owned()).wait()
+    };
+
+    let store = open()?;
+    let person =
+        |city: &str, age: u32| format!(r#"{{"address": {{"city": "{}"}}, "age": {}}}"#, city, age);
+    store.set("bob".to_owned(), person("Paris", 30)).wait()?;
+    store.set("alice".to_owned(), person("Paris", 25)).wait()?;
+    store.set("carol".to_owned(), person("Lyon", 30)).wait()?;
+    // not JSON, or without the field, so only in some indexes
+    store.set("dave".to_owned(), "Paris".to_owned()).wait()?;
+    store
+        .set("eve".to_owned(), r#"{"age": 25}"#.to_owned())
+        .wait()?;
+    assert_eq!(query(&store, "city", "Paris")?, vec!["alice", "bob"]);
+    assert_eq!(query(&store, "age", "25")?, vec!["alice", "eve"]);
+    assert_eq!(query(&store, "city", "Nice")?, Vec::<String>::new());
+
+    store.set("bob".to_owned(), person("Lyon", 31)).wait()?;
+    store.remove("alice".to_owned()).wait()?;
+    assert_eq!(query(&store, "city", "Paris")?, Vec::<String>::new());
+    assert_eq!(query(&store, "city", "Lyon")?, vec!["bob", "carol"]);
+    assert_eq!(query(&store, "age", "30")?, vec!["carol"]);
+
+    drop(store);
+    let store = open()?;
+    assert_eq!(query(&store, "city", "Lyon")?, vec!["bob", "carol"]);
+    assert_eq!(query(&store, "age", "25")?, vec!["eve"]);
+    match query(&store, "zip", "75001") {
+        Err(KvsError::UnknownIndex(name)) => assert_eq!(name, "zip"),
+        res => panic!("unexpected result: {:?}", res),
+    }
+    drop(store);
+
+    // the indexes are only kept in memory, so they may change between opens
+    let store: KvStore<RayonThreadPool> = KvStoreOptions::new()
+        .index("age", "/age")
+        .open(temp_dir.path(), 4)?;
+    assert!(query(&store, "city", "Lyon").is_err());
+    assert_eq!(query(&store, "age", "31")?, vec!["bob"]);
+    drop(store);
+
+    let res: Result<KvStore<RayonThreadPool>> = KvStoreOptions::new()
+        .index("city", "address/city")
+        .open(temp_dir.path(), 4);
+    assert!(res.is_err());
+    Ok(())
+}
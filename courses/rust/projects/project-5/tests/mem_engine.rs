@@ -0,0 +1,123 @@
+use kvs::thread_pool::RayonThreadPool;
+use kvs::{CachingKvsEngine, KvStore, KvsEngine, KvsError, MemKvsEngine, Result};
+use std::thread;
+use tempfile::TempDir;
+use tokio::prelude::*;
+
+// Should behave like the disk engines, without persistence
+#[test]
+fn get_set_remove() -> Result<()> {
+    let engine = MemKvsEngine::new();
+
+    engine.set("key1".to_owned(), "value1".to_owned()).wait()?;
+    engine.set("key2".to_owned(), "value2".to_owned()).wait()?;
+    engine.set("key1".to_owned(), "value3".to_owned()).wait()?;
+    assert_eq!(
+        engine.get("key1".to_owned()).wait()?,
+        Some("value3".to_owned())
+    );
+    assert_eq!(engine.get("key3".to_owned()).wait()?, None);
+
+    engine.remove("key2".to_owned()).wait()?;
+    assert_eq!(engine.get("key2".to_owned()).wait()?, None);
+    match engine.remove("key2".to_owned()).wait() {
+        Err(KvsError::KeyNotFound) => {}
+        res => panic!("unexpected result: {:?}", res),
+    }
+    Ok(())
+}
+
+// Should list the pairs of all shards in key order
+#[test]
+fn scan_prefix() -> Result<()> {
+    let engine = MemKvsEngine::new();
+    for i in 0..100 {
+        engine.set(format!("a{:02}", i), format!("{}", i)).wait()?;
+        engine.set(format!("b{:02}", i), format!("{}", i)).wait()?;
+    }
+
+    let pairs: Vec<(String, String)> = engine.scan("a".to_owned()).collect().wait()?;
+    let expected: Vec<(String, String)> = (0..100)
+        .map(|i| (format!("a{:02}", i), format!("{}", i)))
+        .collect();
+    assert_eq!(pairs, expected);
+    Ok(())
+}
+
+// Should stay within the capacity and keep the recently used keys
+#[test]
+fn evict_least_recently_used() -> Result<()> {
+    let capacity = 16 * 1000;
+    let engine = MemKvsEngine::with_capacity(capacity);
+    let value = "x".repeat(100);
+
+    engine.set("hot".to_owned(), value.clone()).wait()?;
+    for i in 0..1000 {
+        engine.set(format!("key{}", i), value.clone()).wait()?;
+        assert_eq!(engine.get("hot".to_owned()).wait()?, Some(value.clone()));
+    }
+
+    let pairs: Vec<(String, String)> = engine.scan(String::new()).collect().wait()?;
+    let size: usize = pairs.iter().map(|(k, v)| k.len() + v.len()).sum();
+    assert!(size as u64 <= capacity);
+    assert!(pairs.len() < 1000);
+    assert_eq!(engine.get("key999".to_owned()).wait()?, Some(value));
+    Ok(())
+}
+
+#[test]
+fn concurrent_set_get() -> Result<()> {
+    let engine = MemKvsEngine::new();
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let engine = engine.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..1000 {
+                    let key = format!("key{}_{}", thread_id, i);
+                    engine.set(key.clone(), format!("value{}", i)).wait()?;
+                    assert_eq!(engine.get(key).wait()?, Some(format!("value{}", i)));
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(engine.scan(String::new()).collect().wait()?.len(), 8000);
+    Ok(())
+}
+
+// Should serve reads from the cache and drop cached values on writes
+#[test]
+fn caching_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    let engine = CachingKvsEngine::new(store, 1024 * 1024);
+
+    engine.set("key1".to_owned(), "value1".to_owned()).wait()?;
+    assert_eq!(
+        engine.get("key1".to_owned()).wait()?,
+        Some("value1".to_owned())
+    );
+
+    // a write that bypasses the cache is not seen while the key is cached
+    engine
+        .engine()
+        .set("key1".to_owned(), "value2".to_owned())
+        .wait()?;
+    assert_eq!(
+        engine.get("key1".to_owned()).wait()?,
+        Some("value1".to_owned())
+    );
+
+    engine.set("key1".to_owned(), "value3".to_owned()).wait()?;
+    assert_eq!(
+        engine.get("key1".to_owned()).wait()?,
+        Some("value3".to_owned())
+    );
+    engine.remove("key1".to_owned()).wait()?;
+    assert_eq!(engine.get("key1".to_owned()).wait()?, None);
+    assert_eq!(engine.engine().get("key1".to_owned()).wait()?, None);
+    Ok(())
+}
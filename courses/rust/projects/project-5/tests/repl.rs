@@ -0,0 +1,119 @@
+use kvs::repl::{self, Input, OutputFormat, ReplCommand, Reply, Session};
+use kvs::{KvsClient, KvsError, KvsServer, MemKvsEngine, Result, ServerStatus};
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+use tokio::prelude::*;
+
+fn command(line: &str) -> ReplCommand {
+    match repl::parse(line) {
+        Ok(Some(Input::Command(command))) => command,
+        other => panic!("{:?} parsed as {:?}", line, other),
+    }
+}
+
+// Lines should be split like a shell does, and invalid ones should name the problem
+#[test]
+fn parse_lines() {
+    assert_eq!(
+        command(r#"set greeting "hello world""#),
+        ReplCommand::Set {
+            key: "greeting".to_owned(),
+            value: "hello world".to_owned(),
+        }
+    );
+    assert_eq!(
+        command("scan"),
+        ReplCommand::Scan {
+            prefix: String::new()
+        }
+    );
+    assert_eq!(
+        command("mset a 1 b 2"),
+        ReplCommand::SetBatch {
+            pairs: vec![
+                ("a".to_owned(), "1".to_owned()),
+                ("b".to_owned(), "2".to_owned())
+            ],
+        }
+    );
+    assert_eq!(repl::parse("  ").unwrap(), None);
+    assert_eq!(repl::parse("exit").unwrap(), Some(Input::Quit));
+
+    let error = |line| repl::parse(line).unwrap_err().to_string();
+    assert_eq!(error("mset a 1 b"), "Usage: mset KEY VALUE...");
+    assert_eq!(error("status now"), "Usage: status");
+    assert_eq!(error("put a 1"), "Unknown command: put, try `help`");
+    assert_eq!(error("get 'a"), "Unclosed quote");
+}
+
+// A session should keep working after a request fails
+#[test]
+fn session_commands() -> Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4018".parse().unwrap();
+    thread::spawn(move || KvsServer::new(MemKvsEngine::new()).run(addr).unwrap());
+    thread::sleep(Duration::from_secs(1));
+
+    let mut session = Session::new(addr);
+    assert_eq!(session.execute(command("mset a 1 b 2"))?, Reply::Done);
+    assert!(session.execute(command("rm missing")).is_err());
+    assert_eq!(
+        session.execute(command("mget a missing"))?,
+        Reply::Values(vec![
+            ("a".to_owned(), Some("1".to_owned())),
+            ("missing".to_owned(), None)
+        ])
+    );
+    let scan = session.execute(command("scan"))?;
+    assert_eq!(
+        scan,
+        Reply::Pairs(vec![
+            ("a".to_owned(), "1".to_owned()),
+            ("b".to_owned(), "2".to_owned())
+        ])
+    );
+    assert_eq!(
+        session.execute(command("status"))?,
+        Reply::Status(ServerStatus::Primary)
+    );
+
+    let elapsed = Duration::from_micros(1500);
+    assert_eq!(
+        repl::format_reply(&Ok(scan), elapsed, OutputFormat::Text),
+        "a 1\nb 2\n(1.500 ms)"
+    );
+    assert_eq!(
+        repl::format_reply(&Ok(Reply::Value(None)), elapsed, OutputFormat::Json),
+        r#"{"elapsed_ms":1.5,"result":null}"#
+    );
+    Ok(())
+}
+
+// An error reply of the server should leave the connection of the session open
+#[test]
+fn session_keeps_connection() -> Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4032".parse().unwrap();
+    thread::spawn(move || {
+        KvsServer::new(MemKvsEngine::new())
+            .max_connections(1)
+            .run(addr)
+            .unwrap()
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let mut session = Session::new(addr);
+    assert_eq!(session.execute(command("set a 1"))?, Reply::Done);
+    assert!(matches!(
+        session.execute(command("rm missing")),
+        Err(KvsError::KeyNotFound)
+    ));
+    // the only connection allowed is still the one of the session
+    thread::sleep(Duration::from_millis(100));
+    let other = KvsClient::connect(addr).wait()?.get("a".to_owned()).wait();
+    assert!(other.is_err());
+    assert_eq!(
+        session.execute(command("get a"))?,
+        Reply::Value(Some("1".to_owned()))
+    );
+    Ok(())
+}
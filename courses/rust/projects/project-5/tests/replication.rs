@@ -0,0 +1,178 @@
+use kvs::thread_pool::RayonThreadPool;
+use kvs::{KvStore, KvsClient, KvsEngine, KvsServer, MemKvsEngine, Result, ServerStatus};
+use std::net::SocketAddr;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use tokio::prelude::*;
+
+fn start_server<E: KvsEngine>(server: KvsServer<E>, addr: SocketAddr) {
+    thread::spawn(move || server.run(addr).unwrap());
+    thread::sleep(Duration::from_secs(1));
+}
+
+fn get(addr: SocketAddr, key: &str) -> Result<Option<String>> {
+    let key = key.to_owned();
+    let (value, _) = KvsClient::connect(addr)
+        .and_then(move |client| client.get(key))
+        .wait()?;
+    Ok(value)
+}
+
+fn set(addr: SocketAddr, key: &str, value: &str) -> Result<()> {
+    let (key, value) = (key.to_owned(), value.to_owned());
+    KvsClient::connect(addr)
+        .and_then(move |client| client.set(key, value))
+        .wait()?;
+    Ok(())
+}
+
+fn status(addr: SocketAddr) -> Result<ServerStatus> {
+    let (status, _) = KvsClient::connect(addr)
+        .and_then(|client| client.status())
+        .wait()?;
+    Ok(status)
+}
+
+// Waits until `f` returns true, for a few seconds at most.
+fn eventually(mut f: impl FnMut() -> Result<bool>) -> Result<()> {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !f()? {
+        assert!(Instant::now() < deadline, "timed out");
+        thread::sleep(Duration::from_millis(50));
+    }
+    Ok(())
+}
+
+#[test]
+fn replicate_and_promote() -> Result<()> {
+    let primary_addr = "127.0.0.1:4012".parse().unwrap();
+    let replica_addr = "127.0.0.1:4013".parse().unwrap();
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let primary = KvStore::<RayonThreadPool>::open(temp_dir.path(), 4)?;
+    for i in 0..100 {
+        primary.set(format!("key{}", i), format!("{}", i)).wait()?;
+    }
+    start_server(KvsServer::new(primary), primary_addr);
+    // data of the replica which is not on the primary should be dropped
+    let replica = MemKvsEngine::new();
+    replica.set("stale".to_owned(), "value".to_owned()).wait()?;
+    start_server(
+        KvsServer::new(replica).replica_of(primary_addr),
+        replica_addr,
+    );
+
+    // the snapshot
+    eventually(|| Ok(get(replica_addr, "key99")?.is_some()))?;
+    for i in 0..100 {
+        assert_eq!(
+            get(replica_addr, &format!("key{}", i))?,
+            Some(format!("{}", i))
+        );
+    }
+    eventually(|| Ok(get(replica_addr, "stale")?.is_none()))?;
+
+    // the writes after the snapshot
+    set(primary_addr, "key0", "new")?;
+    KvsClient::connect(primary_addr)
+        .and_then(|client| client.remove("key1".to_owned()))
+        .wait()?;
+    eventually(|| Ok(get(replica_addr, "key0")? == Some("new".to_owned())))?;
+    eventually(|| Ok(get(replica_addr, "key1")?.is_none()))?;
+    eventually(|| match status(replica_addr)? {
+        ServerStatus::Replica {
+            primary,
+            connected,
+            applied,
+            lag,
+        } => {
+            assert_eq!(primary, primary_addr);
+            // the writes loading the primary count as well
+            Ok(connected && applied == 102 && lag == 0)
+        }
+        ServerStatus::Primary => panic!("replica reported as primary"),
+    })?;
+
+    // a replica is read-only until promoted
+    assert!(set(replica_addr, "key2", "replica").is_err());
+    KvsClient::connect(replica_addr)
+        .and_then(|client| client.promote())
+        .wait()?;
+    assert_eq!(status(replica_addr)?, ServerStatus::Primary);
+    set(replica_addr, "key2", "replica")?;
+    assert_eq!(get(replica_addr, "key2")?, Some("replica".to_owned()));
+
+    // the promoted replica no longer follows the old primary
+    set(primary_addr, "key3", "primary")?;
+    thread::sleep(Duration::from_secs(2));
+    assert_eq!(get(replica_addr, "key3")?, Some("3".to_owned()));
+    Ok(())
+}
+
+// Concurrent writes of the same key should end with the same value on both sides
+#[test]
+fn replicate_concurrent_writes() -> Result<()> {
+    let primary_addr = "127.0.0.1:4033".parse().unwrap();
+    let replica_addr = "127.0.0.1:4034".parse().unwrap();
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let primary = KvStore::<RayonThreadPool>::open(temp_dir.path(), 8)?;
+    start_server(KvsServer::new(primary), primary_addr);
+    start_server(
+        KvsServer::new(MemKvsEngine::new()).replica_of(primary_addr),
+        replica_addr,
+    );
+
+    // each writer sets every key in turn, so the last writes of a key race
+    let writers: Vec<_> = (0..8)
+        .map(|t| {
+            thread::spawn(move || -> Result<()> {
+                let sets = (0..50).flat_map(move |i| {
+                    (0..20).map(move |k| (format!("key{}", k), format!("{}-{}", t, i)))
+                });
+                KvsClient::connect(primary_addr)
+                    .and_then(move |client| {
+                        stream::iter_ok(sets)
+                            .fold(client, |client, (key, value)| client.set(key, value))
+                    })
+                    .wait()?;
+                Ok(())
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap()?;
+    }
+    set(primary_addr, "done", "")?;
+
+    eventually(|| Ok(get(replica_addr, "done")?.is_some()))?;
+    for k in 0..20 {
+        let key = format!("key{}", k);
+        assert_eq!(get(replica_addr, &key)?, get(primary_addr, &key)?);
+    }
+    Ok(())
+}
+
+// A replica of an engine without log should not get any data
+#[test]
+fn replicate_unsupported_engine() -> Result<()> {
+    let primary_addr = "127.0.0.1:4035".parse().unwrap();
+    let replica_addr = "127.0.0.1:4036".parse().unwrap();
+
+    let primary = MemKvsEngine::new();
+    primary.set("key".to_owned(), "value".to_owned()).wait()?;
+    start_server(KvsServer::new(primary), primary_addr);
+    start_server(
+        KvsServer::new(MemKvsEngine::new()).replica_of(primary_addr),
+        replica_addr,
+    );
+
+    thread::sleep(Duration::from_secs(1));
+    assert_eq!(get(replica_addr, "key")?, None);
+    match status(replica_addr)? {
+        ServerStatus::Replica { applied, .. } => assert_eq!(applied, 0),
+        ServerStatus::Primary => panic!("replica reported as primary"),
+    }
+    Ok(())
+}
@@ -0,0 +1,217 @@
+use kvs::{HashRing, KvsServer, MemKvsEngine, Result, ShardedKvsClient};
+use std::collections::HashMap;
+use std::io;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tokio::prelude::*;
+
+fn addrs(ports: &[u16]) -> Vec<SocketAddr> {
+    ports
+        .iter()
+        .map(|port| format!("127.0.0.1:{}", port).parse().unwrap())
+        .collect()
+}
+
+fn start_server(addr: SocketAddr) {
+    thread::spawn(move || KvsServer::new(MemKvsEngine::new()).run(addr).unwrap());
+    thread::sleep(Duration::from_secs(1));
+}
+
+// Keys should be spread over all nodes, and only the keys of the migrated ranges
+// should change owner.
+#[test]
+fn ring_migrations() -> Result<()> {
+    let nodes = addrs(&[5000, 5001, 5002]);
+    let ring = HashRing::new(nodes.clone(), 64)?;
+    let keys: Vec<String> = (0..3000).map(|i| format!("key{}", i)).collect();
+
+    let mut counts = HashMap::new();
+    for key in &keys {
+        *counts.entry(ring.node_of(key)).or_insert(0) += 1;
+    }
+    for node in &nodes {
+        assert!(counts[node] > 500, "{:?}", counts);
+    }
+
+    let new_node = addrs(&[5003])[0];
+    let (grown, migrations) = ring.add_node(new_node);
+    assert!(!migrations.is_empty());
+    assert!(migrations.iter().all(|m| m.to == new_node));
+    let mut moved = 0;
+    for key in &keys {
+        let hash = HashRing::position(key);
+        let migration = migrations.iter().find(|m| m.contains(hash));
+        match migration {
+            Some(m) => {
+                assert_eq!(ring.node_of(key), m.from);
+                assert_eq!(grown.node_of(key), m.to);
+                moved += 1;
+            }
+            None => assert_eq!(ring.node_of(key), grown.node_of(key)),
+        }
+    }
+    assert!(moved > 400 && moved < 1200, "{} keys moved", moved);
+
+    let (shrunk, migrations) = grown.remove_node(new_node)?;
+    assert_eq!(shrunk, ring);
+    assert!(migrations.iter().all(|m| m.from == new_node));
+
+    assert!(ring
+        .remove_node(nodes[0])?
+        .0
+        .remove_node(nodes[1])?
+        .0
+        .remove_node(nodes[2])
+        .is_err());
+    Ok(())
+}
+
+#[test]
+fn sharded_client_and_migration() -> Result<()> {
+    let nodes = addrs(&[4014, 4015, 4016]);
+    for &node in &nodes {
+        start_server(node);
+    }
+    let client = ShardedKvsClient::new(nodes.clone())?;
+
+    client.set("single".to_owned(), "1".to_owned()).wait()?;
+    assert_eq!(
+        client.get("single".to_owned()).wait()?,
+        Some("1".to_owned())
+    );
+    client.remove("single".to_owned()).wait()?;
+    assert_eq!(client.get("single".to_owned()).wait()?, None);
+
+    let pairs: Vec<_> = (0..200)
+        .map(|i| (format!("key{:03}", i), format!("value{}", i)))
+        .collect();
+    client.set_batch(pairs.clone()).wait()?;
+    let keys = vec![
+        "key007".to_owned(),
+        "missing".to_owned(),
+        "key123".to_owned(),
+    ];
+    assert_eq!(
+        client.get_batch(keys).wait()?,
+        vec![Some("value7".to_owned()), None, Some("value123".to_owned())]
+    );
+    assert_eq!(client.scan("key".to_owned()).wait()?, pairs);
+    assert_eq!(
+        client.scan("key19".to_owned()).wait()?,
+        pairs[190..].to_vec()
+    );
+
+    // each node holds a part of the keys
+    for &node in &nodes {
+        let single = ShardedKvsClient::new(vec![node])?;
+        let held = single.scan(String::new()).wait()?.len();
+        assert!(held > 0 && held < 200, "{} holds {} keys", node, held);
+    }
+
+    let new_node = addrs(&[4017])[0];
+    start_server(new_node);
+    let (ring, migrations) = client.ring().add_node(new_node);
+    assert!(!migrations.is_empty());
+    let moved = client.migrate(&ring).wait()?;
+    assert!(moved > 0 && moved < 200);
+
+    let client = ShardedKvsClient::with_ring(ring);
+    assert_eq!(client.scan(String::new()).wait()?, pairs);
+    let on_new_node = ShardedKvsClient::new(vec![new_node])?
+        .scan(String::new())
+        .wait()?;
+    assert_eq!(on_new_node.len() as u64, moved);
+    for (key, value) in &pairs {
+        assert_eq!(client.get(key.clone()).wait()?, Some(value.clone()));
+    }
+
+    // removing the node moves its keys back
+    let (ring, _) = client.ring().remove_node(new_node)?;
+    assert_eq!(client.migrate(&ring).wait()?, moved);
+    let client = ShardedKvsClient::with_ring(ring);
+    assert_eq!(client.scan(String::new()).wait()?, pairs);
+    Ok(())
+}
+
+// Servers holding more pairs than fit in a single frame should still be scanned
+// and migrated, chunk by chunk.
+#[test]
+fn migrate_large_shards() -> Result<()> {
+    let nodes = addrs(&[4030, 4031]);
+    start_server(nodes[0]);
+    start_server(nodes[1]);
+    let client = ShardedKvsClient::new(vec![nodes[0]])?;
+    // about 10 MiB, more than the 8 MiB limit of a frame
+    let pairs: Vec<_> = (0..2500)
+        .map(|i| (format!("key{:04}", i), format!("{:04}", i).repeat(1024)))
+        .collect();
+    for batch in pairs.chunks(100) {
+        client.set_batch(batch.to_vec()).wait()?;
+    }
+    assert_eq!(client.scan(String::new()).wait()?.len(), pairs.len());
+
+    let (ring, _) = client.ring().add_node(nodes[1]);
+    let moved = client.migrate(&ring).wait()?;
+    assert!(moved > 0 && moved < 2500);
+    let client = ShardedKvsClient::with_ring(ring);
+    assert_eq!(client.scan(String::new()).wait()?, pairs);
+    Ok(())
+}
+
+// Forwards the connections to `addr` to `server`, and returns how many it
+// accepted.
+fn counting_proxy(addr: SocketAddr, server: SocketAddr) -> Arc<AtomicUsize> {
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let listener = TcpListener::bind(addr).unwrap();
+    let count = Arc::clone(&accepted);
+    thread::spawn(move || {
+        for client in listener.incoming() {
+            let client = client.unwrap();
+            count.fetch_add(1, Ordering::SeqCst);
+            let server = TcpStream::connect(server).unwrap();
+            let halves = vec![
+                (client.try_clone().unwrap(), server.try_clone().unwrap()),
+                (server, client),
+            ];
+            for (mut from, mut to) in halves {
+                thread::spawn(move || {
+                    let _ = io::copy(&mut from, &mut to);
+                    let _ = to.shutdown(Shutdown::Write);
+                });
+            }
+        }
+    });
+    accepted
+}
+
+// A migration should reuse its connections instead of opening new ones for
+// every key it moves.
+#[test]
+fn migrate_reuses_connections() -> Result<()> {
+    let servers = addrs(&[4039, 4040]);
+    let nodes = addrs(&[4037, 4038]);
+    let mut accepted = Vec::new();
+    for (&node, &server) in nodes.iter().zip(&servers) {
+        start_server(server);
+        accepted.push(counting_proxy(node, server));
+    }
+    let client = ShardedKvsClient::new(vec![nodes[0]])?;
+    let pairs: Vec<_> = (0..3000)
+        .map(|i| (format!("key{:04}", i), format!("value{}", i)))
+        .collect();
+    client.set_batch(pairs.clone()).wait()?;
+
+    let (ring, _) = client.ring().add_node(nodes[1]);
+    let moved = client.migrate(&ring).wait()?;
+    assert!(moved > 0 && moved < 3000);
+    for accepted in &accepted {
+        let accepted = accepted.load(Ordering::SeqCst);
+        assert!(accepted <= 2, "{} connections", accepted);
+    }
+    let client = ShardedKvsClient::with_ring(ring);
+    assert_eq!(client.scan(String::new()).wait()?, pairs);
+    Ok(())
+}
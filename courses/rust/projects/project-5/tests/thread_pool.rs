@@ -1,10 +1,14 @@
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use kvs::thread_pool::*;
-use kvs::Result;
+use kvs::{KvsError, Result};
 
+use crossbeam::channel;
 use crossbeam_utils::sync::WaitGroup;
+use tokio::prelude::*;
 
 fn spawn_counter<P: ThreadPool>(pool: P) -> Result<()> {
     const TASK_NUM: usize = 20;
@@ -43,7 +47,120 @@ fn spawn_panic_task<P: ThreadPool>() -> Result<()> {
         })
     }
 
-    spawn_counter(pool)
+    spawn_counter(pool.clone())?;
+    pool.join();
+    assert_eq!(pool.stats().panics, TASK_NUM as u64);
+    Ok(())
+}
+
+// `join` should wait for the queued tasks, and tasks spawned afterwards should
+// never run.
+fn join_drains<P: ThreadPool>() -> Result<()> {
+    const TASK_NUM: usize = 50;
+
+    let pool = P::new(2)?;
+    let counter = Arc::new(AtomicUsize::new(0));
+    for _ in 0..TASK_NUM {
+        let counter = Arc::clone(&counter);
+        pool.spawn(move || {
+            thread::sleep(Duration::from_millis(1));
+            counter.fetch_add(1, Ordering::SeqCst);
+        })
+    }
+
+    pool.join();
+    assert_eq!(counter.load(Ordering::SeqCst), TASK_NUM);
+    let stats = pool.stats();
+    assert_eq!((stats.active, stats.queued), (0, 0));
+
+    let late = Arc::clone(&counter);
+    pool.spawn(move || {
+        late.fetch_add(1, Ordering::SeqCst);
+    });
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(counter.load(Ordering::SeqCst), TASK_NUM);
+    assert_eq!(pool.stats().queued, 0);
+    Ok(())
+}
+
+// Values, panics and cancellations should all reach the handle.
+fn spawn_with_handle<P: ThreadPool>() -> Result<()> {
+    let pool = P::new(1)?;
+    assert_eq!(pool.spawn_with_handle(|_| 40 + 2).wait()?, 42);
+
+    let panicked = pool.spawn_with_handle(|_| {
+        panic_control::disable_hook_in_current_thread();
+        panic!();
+    });
+    assert!(matches!(panicked.wait(), Err(KvsError::TaskPanicked)));
+
+    // the running task sees the cancellation, the queued one never runs
+    let (started_tx, started_rx) = channel::bounded(0);
+    let running = pool.spawn_with_handle(move |token| {
+        started_tx.send(()).unwrap();
+        while !token.is_cancelled() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        "stopped"
+    });
+    let ran = Arc::new(AtomicUsize::new(0));
+    let queued = {
+        let ran = Arc::clone(&ran);
+        pool.spawn_with_handle(move |_| ran.fetch_add(1, Ordering::SeqCst))
+    };
+    started_rx.recv().unwrap();
+    queued.cancel();
+    running.cancel();
+    assert_eq!(running.wait()?, "stopped");
+    assert!(matches!(queued.wait(), Err(KvsError::TaskCancelled)));
+    assert_eq!(ran.load(Ordering::SeqCst), 0);
+
+    // a task spawned after the shutdown is dropped
+    pool.join();
+    assert!(matches!(
+        pool.spawn_with_handle(|_| ()).wait(),
+        Err(KvsError::TaskCancelled)
+    ));
+    Ok(())
+}
+
+// Waits until `f` returns true, for a few seconds at most.
+fn eventually(f: impl Fn() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !f() {
+        assert!(Instant::now() < deadline, "timed out");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+// Tasks spawning tasks, which a work-stealing pool keeps in the queue of the
+// spawning thread.
+fn spawn_nested<P: ThreadPool>(pool: P) -> Result<()> {
+    const TASK_NUM: usize = 20;
+    const SUBTASK_NUM: usize = 50;
+
+    let wg = WaitGroup::new();
+    let counter = Arc::new(AtomicUsize::new(0));
+
+    for _ in 0..TASK_NUM {
+        let counter = Arc::clone(&counter);
+        let wg = wg.clone();
+        let inner = pool.clone();
+        pool.spawn(move || {
+            for _ in 0..SUBTASK_NUM {
+                let counter = Arc::clone(&counter);
+                let wg = wg.clone();
+                inner.spawn(move || {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    drop(wg);
+                })
+            }
+        })
+    }
+
+    wg.wait();
+    assert_eq!(counter.load(Ordering::SeqCst), TASK_NUM * SUBTASK_NUM);
+    Ok(())
 }
 
 #[test]
@@ -68,3 +185,193 @@ fn rayon_thread_pool_spawn_counter() -> Result<()> {
 fn shared_queue_thread_pool_panic_task() -> Result<()> {
     spawn_panic_task::<SharedQueueThreadPool>()
 }
+
+#[test]
+fn work_stealing_thread_pool_spawn_counter() -> Result<()> {
+    let pool = WorkStealingThreadPool::new(4)?;
+    spawn_counter(pool)
+}
+
+#[test]
+fn work_stealing_thread_pool_spawn_nested() -> Result<()> {
+    let pool = WorkStealingThreadPool::new(4)?;
+    spawn_nested(pool)
+}
+
+#[test]
+fn work_stealing_thread_pool_panic_task() -> Result<()> {
+    spawn_panic_task::<WorkStealingThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_panic_task() -> Result<()> {
+    spawn_panic_task::<RayonThreadPool>()
+}
+
+#[test]
+fn naive_thread_pool_join() -> Result<()> {
+    join_drains::<NaiveThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_join() -> Result<()> {
+    join_drains::<SharedQueueThreadPool>()?;
+    let pool = SharedQueueThreadPool::new(4)?;
+    pool.join();
+    assert_eq!(pool.stats().threads, 0);
+    // a task spawned after the shutdown never runs, which `try_spawn` tells
+    assert!(matches!(
+        pool.try_spawn(|| ()),
+        Err(KvsError::TaskCancelled)
+    ));
+    Ok(())
+}
+
+#[test]
+fn rayon_thread_pool_join() -> Result<()> {
+    join_drains::<RayonThreadPool>()
+}
+
+#[test]
+fn work_stealing_thread_pool_join() -> Result<()> {
+    join_drains::<WorkStealingThreadPool>()?;
+    let pool = WorkStealingThreadPool::new(4)?;
+    pool.join();
+    assert_eq!(pool.stats().threads, 0);
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_resize() -> Result<()> {
+    let size = PoolSize::new(1, 4).keep_alive(Duration::from_millis(200));
+    let pool = SharedQueueThreadPool::with_size(size)?;
+    assert_eq!(pool.stats().threads, 1);
+
+    // blocked tasks make the pool grow up to its maximum size
+    let (tx, rx) = channel::unbounded::<()>();
+    for _ in 0..6 {
+        let rx = rx.clone();
+        pool.spawn(move || {
+            let _ = rx.recv();
+        })
+    }
+    eventually(|| pool.stats().active == 4);
+    let stats = pool.stats();
+    assert_eq!((stats.threads, stats.queued), (4, 2));
+
+    // idle threads above the minimum stop
+    drop(tx);
+    eventually(|| pool.stats().threads == 1);
+    assert_eq!(pool.stats().active, 0);
+
+    pool.join();
+    assert_eq!(pool.stats().threads, 0);
+    assert!(SharedQueueThreadPool::with_size(PoolSize::new(2, 1)).is_err());
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_spawn_with_handle() -> Result<()> {
+    spawn_with_handle::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_spawn_with_handle() -> Result<()> {
+    spawn_with_handle::<RayonThreadPool>()
+}
+
+#[test]
+fn work_stealing_thread_pool_spawn_with_handle() -> Result<()> {
+    spawn_with_handle::<WorkStealingThreadPool>()
+}
+
+// A pool with one thread and room for one task, whose thread is busy and whose
+// queue is full until the returned sender is dropped.
+fn full_pool(policy: QueuePolicy) -> Result<(SharedQueueThreadPool, channel::Sender<()>)> {
+    let pool = SharedQueueThreadPool::bounded(PoolSize::new(1, 1), 1, policy)?;
+    let (tx, rx) = channel::unbounded::<()>();
+    for _ in 0..2 {
+        let rx = rx.clone();
+        pool.try_spawn(move || {
+            let _ = rx.recv();
+        })?;
+        // the second task must not find the first one still queued
+        eventually(|| pool.stats().active == 1);
+    }
+    assert_eq!(pool.stats().queued, 1);
+    Ok((pool, tx))
+}
+
+#[test]
+fn bounded_queue_reject() -> Result<()> {
+    let (pool, tx) = full_pool(QueuePolicy::Reject)?;
+    assert!(matches!(pool.try_spawn(|| ()), Err(KvsError::ServerBusy)));
+    assert!(matches!(
+        pool.spawn_with_handle(|_| ()).wait(),
+        Err(KvsError::ServerBusy)
+    ));
+    assert_eq!(pool.stats().queued, 1);
+
+    drop(tx);
+    pool.join();
+    assert_eq!(pool.stats(), ThreadPoolStats::default());
+    assert!(SharedQueueThreadPool::bounded(PoolSize::new(1, 1), 0, QueuePolicy::Reject).is_err());
+    Ok(())
+}
+
+#[test]
+fn bounded_queue_caller_runs() -> Result<()> {
+    let (pool, tx) = full_pool(QueuePolicy::CallerRuns)?;
+    let caller = thread::current().id();
+    let task = pool.spawn_with_handle(|_| thread::current().id());
+    assert_eq!(task.wait()?, caller);
+
+    drop(tx);
+    pool.join();
+    assert_eq!(pool.stats().queued, 0);
+    Ok(())
+}
+
+#[test]
+fn bounded_queue_block() -> Result<()> {
+    let (pool, tx) = full_pool(QueuePolicy::Block)?;
+    let (done_tx, done_rx) = channel::unbounded();
+    let spawner = {
+        let pool = pool.clone();
+        thread::spawn(move || pool.spawn(move || done_tx.send(()).unwrap()))
+    };
+    // the spawner waits for room in the queue
+    assert!(done_rx.recv_timeout(Duration::from_millis(100)).is_err());
+    assert_eq!(pool.stats().queued, 2);
+
+    drop(tx);
+    spawner.join().unwrap();
+    done_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    pool.join();
+    Ok(())
+}
+
+// Blocking on a full queue from a worker of a tokio runtime should leave the
+// other tasks of the worker running
+#[test]
+fn bounded_queue_block_in_runtime() -> Result<()> {
+    let (pool, tx) = full_pool(QueuePolicy::Block)?;
+    let (done_tx, done_rx) = channel::unbounded();
+    let mut runtime = tokio::runtime::Builder::new().core_threads(1).build()?;
+    runtime.spawn(future::lazy(move || {
+        pool.spawn(move || done_tx.send(()).unwrap());
+        Ok(())
+    }));
+    // queued behind the spawner on the single worker
+    runtime.spawn(future::lazy(move || {
+        drop(tx);
+        Ok(())
+    }));
+    if done_rx.recv_timeout(Duration::from_secs(5)).is_err() {
+        // the runtime can't be shut down with its worker blocked
+        std::mem::forget(runtime);
+        panic!("the worker blocked its other tasks");
+    }
+    runtime.shutdown_on_idle().wait().unwrap();
+    Ok(())
+}
@@ -0,0 +1,133 @@
+use kvs::{KvsClient, KvsError, KvsServer, MemKvsEngine, Result, WatchEvent};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tokio::prelude::*;
+
+fn start_server(addr: SocketAddr, watch_buffer: usize) {
+    let server = KvsServer::new(MemKvsEngine::new()).watch_buffer(watch_buffer);
+    thread::spawn(move || server.run(addr).unwrap());
+    thread::sleep(Duration::from_secs(1));
+}
+
+fn set(addr: SocketAddr, key: &str, value: &str) -> Result<()> {
+    let (key, value) = (key.to_owned(), value.to_owned());
+    KvsClient::connect(addr)
+        .and_then(move |client| client.set(key, value))
+        .wait()?;
+    Ok(())
+}
+
+fn remove(addr: SocketAddr, key: &str) -> Result<()> {
+    let key = key.to_owned();
+    KvsClient::connect(addr)
+        .and_then(move |client| client.remove(key))
+        .wait()?;
+    Ok(())
+}
+
+fn watch(
+    addr: SocketAddr,
+    prefix: &str,
+) -> Result<impl Stream<Item = WatchEvent, Error = KvsError>> {
+    let prefix = prefix.to_owned();
+    let events = KvsClient::connect(addr)
+        .and_then(move |client| client.watch(prefix))
+        .wait()?;
+    // give the server time to register the watcher
+    thread::sleep(Duration::from_millis(500));
+    Ok(events)
+}
+
+// Should receive the successful writes of the watched keys in order
+#[test]
+fn watch_prefix() -> Result<()> {
+    let addr = "127.0.0.1:4010".parse().unwrap();
+    start_server(addr, 16);
+
+    let events = watch(addr, "a")?;
+    let watcher = thread::spawn(move || events.take(3).collect().wait());
+    set(addr, "a1", "1")?;
+    set(addr, "b1", "1")?;
+    // a failed remove is not an event
+    assert!(remove(addr, "a2").is_err());
+    remove(addr, "a1")?;
+    set(addr, "a2", "2")?;
+
+    let events = watcher.join().unwrap()?;
+    let seqs: Vec<u64> = events
+        .iter()
+        .map(|event| match event {
+            WatchEvent::Set { seq, .. } | WatchEvent::Remove { seq, .. } => *seq,
+            WatchEvent::Lagged { .. } => panic!("unexpected lag"),
+        })
+        .collect();
+    assert!(seqs.windows(2).all(|w| w[0] < w[1]));
+    assert_eq!(
+        events[0],
+        WatchEvent::Set {
+            seq: seqs[0],
+            key: "a1".to_owned(),
+            value: "1".to_owned()
+        }
+    );
+    assert_eq!(
+        events[1],
+        WatchEvent::Remove {
+            seq: seqs[1],
+            key: "a1".to_owned()
+        }
+    );
+    assert_eq!(
+        events[2],
+        WatchEvent::Set {
+            seq: seqs[2],
+            key: "a2".to_owned(),
+            value: "2".to_owned()
+        }
+    );
+    Ok(())
+}
+
+// A watcher which doesn't keep up should be told how many events it missed
+#[test]
+fn watch_lagged() -> Result<()> {
+    let addr = "127.0.0.1:4011".parse().unwrap();
+    start_server(addr, 4);
+
+    let events = watch(addr, "")?.wait();
+    // far more than the socket buffers can hold
+    let value = "x".repeat(32 * 1024);
+    for i in 0..1000 {
+        set(addr, &format!("key{}", i), &value)?;
+    }
+
+    // keep writing until the watcher catches up, since an event written while
+    // its buffer is full is dropped
+    let done = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let done = done.clone();
+        thread::spawn(move || -> Result<()> {
+            while !done.load(Ordering::SeqCst) {
+                set(addr, "done", "")?;
+                thread::sleep(Duration::from_millis(100));
+            }
+            Ok(())
+        })
+    };
+
+    let mut missed = 0;
+    for event in events {
+        match event? {
+            WatchEvent::Lagged { missed: n } => missed += n,
+            WatchEvent::Set { key, .. } if key == "done" => break,
+            _ => {}
+        }
+    }
+    done.store(true, Ordering::SeqCst);
+    writer.join().unwrap()?;
+    assert!(missed > 0);
+    Ok(())
+}
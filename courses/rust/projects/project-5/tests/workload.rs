@@ -0,0 +1,129 @@
+use kvs::workload::{
+    self, KeyChooser, KeyDistribution, Operation, OperationMix, RunOptions, Workload,
+};
+use kvs::{KvsClient, KvsServer, MemKvsEngine, Result};
+use rand::rngs::SmallRng;
+use rand::SeedableRng;
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+use tokio::prelude::*;
+
+// Mixes should be parsed from lists of proportions and the presets should match
+// the core YCSB workloads
+#[test]
+fn workload_definitions() -> Result<()> {
+    let mix: OperationMix = "read=0.9, rmw=0.1".parse()?;
+    assert_eq!(mix.read, 0.9);
+    assert_eq!(mix.read_modify_write, 0.1);
+    assert_eq!(mix.update, 0.0);
+    assert!("read=0.9,delete=0.1".parse::<OperationMix>().is_err());
+    assert!("read".parse::<OperationMix>().is_err());
+    assert!("read=-1".parse::<OperationMix>().is_err());
+    assert_eq!(
+        "read=0".parse::<OperationMix>().unwrap_err().to_string(),
+        "The operation mix is empty"
+    );
+
+    let a = Workload::ycsb("a")?;
+    assert_eq!(a.records, 1000);
+    assert_eq!(a.distribution, KeyDistribution::Zipfian);
+    assert_eq!(a.mix, OperationMix::read_update(0.5));
+    let d = Workload::ycsb("D")?;
+    assert_eq!(d.distribution, KeyDistribution::Latest);
+    assert_eq!(d.mix.insert, 0.05);
+    assert_eq!(Workload::ycsb("e")?.mix.scan, 0.95);
+    assert_eq!(
+        Workload::ycsb("g").unwrap_err().to_string(),
+        "Unknown workload: g, expected a to f"
+    );
+    Ok(())
+}
+
+// Chosen records should stay below the number inserted, a zipfian distribution
+// should favor a few records and a latest one the records inserted last
+#[test]
+fn key_distributions() {
+    let mut rng = SmallRng::seed_from_u64(42);
+    let hits = |chooser: &KeyChooser, rng: &mut SmallRng, inserted: u64| {
+        let mut hits = vec![0u32; inserted as usize];
+        for _ in 0..10_000 {
+            hits[chooser.next(rng, inserted) as usize] += 1;
+        }
+        hits
+    };
+
+    let uniform = hits(
+        &KeyChooser::new(KeyDistribution::Uniform, 100),
+        &mut rng,
+        100,
+    );
+    assert!(uniform.iter().all(|&n| n > 0 && n < 300));
+
+    let mut zipfian = hits(
+        &KeyChooser::new(KeyDistribution::Zipfian, 100),
+        &mut rng,
+        100,
+    );
+    zipfian.sort_unstable_by(|a, b| b.cmp(a));
+    assert!(zipfian[0] > 1000);
+    assert!(zipfian[..10].iter().sum::<u32>() > 5000);
+
+    let latest = hits(
+        &KeyChooser::new(KeyDistribution::Latest, 100),
+        &mut rng,
+        120,
+    );
+    assert!(latest[119] > 1000);
+    assert!(latest[119] > latest[0] * 10);
+}
+
+// A short run should count every operation of the mix without errors, and the
+// inserted records should follow the loaded ones
+#[test]
+fn run_against_server() -> Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4026".parse().unwrap();
+    thread::spawn(move || KvsServer::new(MemKvsEngine::new()).run(addr).unwrap());
+    thread::sleep(Duration::from_secs(1));
+
+    let workload = Workload {
+        records: 200,
+        value_size: 10,
+        distribution: KeyDistribution::Zipfian,
+        mix: "read=1,update=1,insert=1,scan=1,rmw=1".parse()?,
+    };
+    workload::load(addr, &workload)?;
+    let options = RunOptions::new()
+        .threads(4)
+        .duration(Duration::from_secs(30))
+        .operations(1000);
+    let report = workload::run(addr, &workload, &options)?;
+
+    let mut count = 0;
+    for &op in &[
+        Operation::Read,
+        Operation::Update,
+        Operation::Insert,
+        Operation::Scan,
+        Operation::ReadModifyWrite,
+    ] {
+        let stats = report
+            .operation(op)
+            .expect("every operation should be sent");
+        assert_eq!(stats.errors(), 0);
+        assert!(stats.percentile(0.5) <= stats.percentile(0.99));
+        assert!(stats.percentile(0.99) <= stats.percentile(0.999));
+        count += stats.count();
+    }
+    assert_eq!(count, 1000);
+    assert!(report.throughput() > 0.0);
+    assert!(report.to_string().starts_with("1000 operations in "));
+
+    let inserted = report.operation(Operation::Insert).unwrap().count();
+    let (pairs, _) = KvsClient::connect(addr)
+        .and_then(|client| client.scan("user".to_owned()))
+        .wait()?;
+    assert_eq!(pairs.len() as u64, 200 + inserted);
+    assert_eq!(pairs[0].1.len(), 10);
+    Ok(())
+}